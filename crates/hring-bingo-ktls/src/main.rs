@@ -53,11 +53,11 @@ async fn async_main() -> eyre::Result<()> {
     let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
     let acceptor = Rc::new(acceptor);
 
-    let pt_h1_ln = TcpListener::bind("[::]:7080").await?;
-    info!("Serving plaintext HTTP/1.1 on {}", pt_h1_ln.local_addr()?);
-
-    let pt_h2_ln = TcpListener::bind("[::]:7082").await?;
-    info!("Serving plaintext HTTP/2 on {}", pt_h2_ln.local_addr()?);
+    let pt_ln = TcpListener::bind("[::]:7080").await?;
+    info!(
+        "Serving plaintext HTTP/1.1 and HTTP/2 (h2c) on {}",
+        pt_ln.local_addr()?
+    );
 
     let tls_ln = TcpListener::bind("[::]:7443").await?;
     info!("Serving HTTPS on {}", tls_ln.local_addr()?);
@@ -65,37 +65,18 @@ async fn async_main() -> eyre::Result<()> {
     let h1_conf = Rc::new(h1::ServerConf::default());
     let h2_conf = Rc::new(h2::ServerConf::default());
 
-    let pt_h1_loop = {
+    let pt_loop = {
         let h1_conf = h1_conf.clone();
-
-        async move {
-            while let Ok((stream, remote_addr)) = pt_h1_ln.accept().await {
-                hring::tokio_uring::spawn({
-                    let h1_conf = h1_conf.clone();
-                    async move {
-                        if let Err(e) =
-                            handle_plaintext_conn(stream, remote_addr, Proto::H1(h1_conf)).await
-                        {
-                            tracing::error!(%e, "Error handling connection");
-                        }
-                    }
-                });
-            }
-
-            Ok::<_, color_eyre::Report>(())
-        }
-    };
-
-    let pt_h2_loop = {
         let h2_conf = h2_conf.clone();
 
         async move {
-            while let Ok((stream, remote_addr)) = pt_h2_ln.accept().await {
+            while let Ok((stream, remote_addr)) = pt_ln.accept().await {
                 hring::tokio_uring::spawn({
+                    let h1_conf = h1_conf.clone();
                     let h2_conf = h2_conf.clone();
                     async move {
                         if let Err(e) =
-                            handle_plaintext_conn(stream, remote_addr, Proto::H2(h2_conf)).await
+                            handle_plaintext_conn(stream, remote_addr, h1_conf, h2_conf).await
                         {
                             tracing::error!(%e, "Error handling connection");
                         }
@@ -126,19 +107,15 @@ async fn async_main() -> eyre::Result<()> {
         Ok::<_, color_eyre::Report>(())
     };
 
-    tokio::try_join!(pt_h1_loop, pt_h2_loop, tls_loop)?;
+    tokio::try_join!(pt_loop, tls_loop)?;
     Ok(())
 }
 
-enum Proto {
-    H1(Rc<h1::ServerConf>),
-    H2(Rc<h2::ServerConf>),
-}
-
 async fn handle_plaintext_conn(
     stream: tokio::net::TcpStream,
     remote_addr: std::net::SocketAddr,
-    proto: Proto,
+    h1_conf: Rc<h1::ServerConf>,
+    h2_conf: Rc<h2::ServerConf>,
 ) -> Result<(), color_eyre::Report> {
     info!("Accepted connection from {remote_addr}");
     let buf = RollMut::alloc()?;
@@ -148,17 +125,8 @@ async fn handle_plaintext_conn(
     let stream = unsafe { TcpStream::from_raw_fd(fd) };
 
     let driver = SDriver {};
-
-    match proto {
-        Proto::H1(h1_conf) => {
-            info!("Using HTTP/1.1");
-            hring::h1::serve(stream, h1_conf, buf, driver).await?;
-        }
-        Proto::H2(h2_conf) => {
-            info!("Using HTTP/2");
-            hring::h2::serve(stream, h2_conf, buf, Rc::new(driver)).await?;
-        }
-    }
+    let outcome = hring::h2c::serve(stream, h1_conf, h2_conf, buf, Rc::new(driver)).await?;
+    info!(?outcome, "Done serving plaintext connection");
 
     Ok(())
 }
@@ -231,7 +199,8 @@ impl ServerDriver for SDriver {
         let driver = CDriver { respond };
 
         req.version = Version::HTTP_11;
-        let (transport, respond) = h1::request(transport, req, req_body, driver).await?;
+        let (transport, respond) =
+            h1::request(transport, Default::default(), req, req_body, driver).await?;
         // don't re-use transport for now
         drop(transport);
 
@@ -336,9 +305,10 @@ async fn sample_http_request() -> color_eyre::Result<()> {
         uri: "http://httpbingo.org/image/jpeg".parse().unwrap(),
         version: Version::HTTP_11,
         headers: Default::default(),
+        protocol: None,
     };
 
-    let (transport, _) = h1::request(transport, req, &mut (), driver).await?;
+    let (transport, _) = h1::request(transport, Default::default(), req, &mut (), driver).await?;
     // don't re-use transport for now
     drop(transport);
 
@@ -49,6 +49,10 @@ pub trait WriteOwned {
         }
         (Ok(()), buf)
     }
+
+    /// Closes the write half: the peer reads EOF once it has read everything
+    /// written so far, and may keep sending.
+    async fn shutdown(&self) -> std::io::Result<()>;
 }
 
 pub trait ReadWriteOwned: ReadOwned + WriteOwned {}
@@ -68,6 +72,10 @@ impl WriteOwned for TcpStream {
     async fn writev<B: IoBuf>(&self, list: Vec<B>) -> BufResult<usize, Vec<B>> {
         TcpStream::writev(self, list).await
     }
+
+    async fn shutdown(&self) -> std::io::Result<()> {
+        TcpStream::shutdown(self, std::net::Shutdown::Write)
+    }
 }
 
 /// Unites a [ReadOwned] and a [WriteOwned] into a single [ReadWriteOwned] type.
@@ -95,4 +103,8 @@ where
     async fn write<B: IoBuf>(&self, buf: B) -> BufResult<usize, B> {
         self.1.write(buf).await
     }
+
+    async fn shutdown(&self) -> std::io::Result<()> {
+        self.1.shutdown().await
+    }
 }
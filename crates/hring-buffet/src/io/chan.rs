@@ -163,25 +163,40 @@ impl ReadOwned for ChanRead {
     }
 }
 
+/// Sends whatever is written to it as `Vec<u8>` chunks. Once it's shut down,
+/// the receiver gets `None`.
 pub struct ChanWrite {
-    tx: mpsc::Sender<Vec<u8>>,
+    tx: RefCell<Option<mpsc::Sender<Vec<u8>>>>,
 }
 
 impl ChanWrite {
     pub fn new() -> (mpsc::Receiver<Vec<u8>>, Self) {
         let (tx, rx) = mpsc::channel(1);
-        (rx, Self { tx })
+        (
+            rx,
+            Self {
+                tx: RefCell::new(Some(tx)),
+            },
+        )
     }
 }
 
 impl WriteOwned for ChanWrite {
     async fn write<B: IoBuf>(&self, buf: B) -> BufResult<usize, B> {
+        let Some(tx) = self.tx.borrow().clone() else {
+            return (Err(std::io::ErrorKind::BrokenPipe.into()), buf);
+        };
         let slice = unsafe { std::slice::from_raw_parts(buf.stable_ptr(), buf.bytes_init()) };
-        match self.tx.send(slice.to_vec()).await {
+        match tx.send(slice.to_vec()).await {
             Ok(()) => (Ok(buf.bytes_init()), buf),
             Err(_) => (Err(std::io::ErrorKind::BrokenPipe.into()), buf),
         }
     }
+
+    async fn shutdown(&self) -> std::io::Result<()> {
+        self.tx.borrow_mut().take();
+        Ok(())
+    }
 }
 
 #[cfg(all(test, not(feature = "miri")))]
@@ -133,6 +133,30 @@ impl Piece {
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// Split this piece at the given index. Panics if `at > len()`.
+    pub fn split_at(self, at: usize) -> (Piece, Piece) {
+        assert!(at <= self.len(), "split_at out of bounds");
+
+        match self {
+            Piece::Static(slice) => {
+                let (left, right) = slice.split_at(at);
+                (Piece::Static(left), Piece::Static(right))
+            }
+            Piece::Vec(mut vec) => {
+                let right = vec.split_off(at);
+                (Piece::Vec(vec), Piece::Vec(right))
+            }
+            Piece::Roll(roll) => {
+                let (left, right) = roll.split_at(at);
+                (Piece::Roll(left), Piece::Roll(right))
+            }
+            Piece::HeaderName(name) => {
+                let (left, right) = name.as_str().as_bytes().split_at(at);
+                (Piece::Vec(left.to_vec()), Piece::Vec(right.to_vec()))
+            }
+        }
+    }
 }
 
 /// A list of [Piece], suitable for issuing vectored writes via io_uring.
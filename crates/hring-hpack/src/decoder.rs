@@ -234,6 +234,9 @@ pub enum DecoderError {
     /// size mandated to the decoder by the protocol. (by perfroming changes
     /// made by SizeUpdate blocks).
     InvalidMaxDynamicSize,
+    /// SizeUpdate blocks may only occur at the beginning of a header block,
+    /// before any header is decoded.
+    SizeUpdateAfterHeader,
 }
 
 /// The result returned by the `decode` method of the `Decoder`.
@@ -247,6 +250,10 @@ pub type DecoderResult = Result<Vec<(Vec<u8>, Vec<u8>)>, DecoderError>;
 pub struct Decoder<'a> {
     // The dynamic table will own its own copy of headers
     header_table: HeaderTable<'a>,
+    /// The largest size SizeUpdate blocks may set the dynamic table to, i.e.
+    /// the size the protocol mandates (e.g. SETTINGS_HEADER_TABLE_SIZE in
+    /// HTTP/2). `None` means no limit is enforced.
+    max_allowed_table_size: Option<usize>,
 }
 
 impl Default for Decoder<'_> {
@@ -278,6 +285,7 @@ impl<'a> Decoder<'a> {
     fn with_static_table(static_table: StaticTable<'a>) -> Decoder<'a> {
         Decoder {
             header_table: HeaderTable::with_static_table(static_table),
+            max_allowed_table_size: None,
         }
     }
 
@@ -288,6 +296,13 @@ impl<'a> Decoder<'a> {
             .set_max_table_size(new_max_size);
     }
 
+    /// Sets the largest size the encoder may set the dynamic table to with
+    /// SizeUpdate blocks. Larger updates make decoding fail with
+    /// `DecoderError::InvalidMaxDynamicSize`.
+    pub fn set_max_allowed_table_size(&mut self, max_size: usize) {
+        self.max_allowed_table_size = Some(max_size);
+    }
+
     /// Decodes the headers found in the given buffer `buf`. Invokes the callback `cb` for each
     /// decoded header in turn, by providing it the header name and value as `Cow` byte array
     /// slices.
@@ -308,6 +323,7 @@ impl<'a> Decoder<'a> {
         F: FnMut(Cow<[u8]>, Cow<[u8]>),
     {
         let mut current_octet_index = 0;
+        let mut decoded_header = false;
 
         while current_octet_index < buf.len() {
             // At this point we are always at the beginning of the next block
@@ -362,11 +378,16 @@ impl<'a> Decoder<'a> {
                     consumed
                 }
                 FieldRepresentation::SizeUpdate => {
+                    if decoded_header {
+                        return Err(DecoderError::SizeUpdateAfterHeader);
+                    }
                     // Handle the dynamic table size update...
-                    self.update_max_dynamic_size(buffer_leftover)
+                    current_octet_index += self.update_max_dynamic_size(buffer_leftover)?;
+                    continue;
                 }
             };
 
+            decoded_header = true;
             current_octet_index += consumed;
         }
 
@@ -456,8 +477,11 @@ impl<'a> Decoder<'a> {
     /// octet in the `SizeUpdate` block.
     ///
     /// Returns the number of octets consumed from the given buffer.
-    fn update_max_dynamic_size(&mut self, buf: &[u8]) -> usize {
-        let (new_size, consumed) = decode_integer(buf, 5).ok().unwrap();
+    fn update_max_dynamic_size(&mut self, buf: &[u8]) -> Result<usize, DecoderError> {
+        let (new_size, consumed) = decode_integer(buf, 5)?;
+        if matches!(self.max_allowed_table_size, Some(max) if new_size > max) {
+            return Err(DecoderError::InvalidMaxDynamicSize);
+        }
         self.header_table.dynamic_table.set_max_table_size(new_size);
 
         info!(
@@ -466,7 +490,7 @@ impl<'a> Decoder<'a> {
             new_size
         );
 
-        consumed
+        Ok(consumed)
     }
 }
 
@@ -1096,13 +1120,7 @@ mod tests {
             assert_eq!(actual, expected_table);
         }
         {
-            let hex_dump = [
-                0x48, 0x03, 0x33, 0x30, 0x37, 0xc1, 0xc0, 0xbf,
-                // This instructs the decoder to clear the list
-                // (it's doubtful that it would ever be found there in a real
-                // response, though...)
-                0x20,
-            ];
+            let hex_dump = [0x48, 0x03, 0x33, 0x30, 0x37, 0xc1, 0xc0, 0xbf];
 
             let header_list = decoder.decode(&hex_dump).ok().unwrap();
 
@@ -1116,6 +1134,14 @@ mod tests {
                     (b"location".to_vec(), b"https://www.example.com".to_vec()),
                 ]
             );
+        }
+        {
+            // This instructs the decoder to clear the list. Size updates must
+            // come at the beginning of a header block.
+            let hex_dump = [0x20];
+
+            let header_list = decoder.decode(&hex_dump).ok().unwrap();
+            assert!(header_list.is_empty());
             // Expect an empty table!
             let expected_table = vec![];
             let actual = decoder.header_table.dynamic_table.to_vec();
@@ -1124,6 +1150,36 @@ mod tests {
         }
     }
 
+    /// Tests that a dynamic table size update that doesn't come first in a
+    /// header block is an error, cf. RFC 7541 section 4.2
+    #[test]
+    fn test_size_update_after_header() {
+        let mut decoder = Decoder::new();
+        // an indexed header (:method GET), followed by a size update to 0
+        let hex_dump = [0x82, 0x20];
+
+        assert_eq!(
+            decoder.decode(&hex_dump),
+            Err(DecoderError::SizeUpdateAfterHeader)
+        );
+    }
+
+    /// Tests that a dynamic table size update can't exceed the size the
+    /// decoder was told is allowed, cf. RFC 7541 section 6.3
+    #[test]
+    fn test_size_update_over_max_allowed() {
+        let mut decoder = Decoder::new();
+        decoder.set_max_allowed_table_size(4096);
+
+        // a size update to 4096 is fine...
+        assert_eq!(decoder.decode(&[0x3f, 0xe1, 0x1f]), Ok(vec![]));
+        // ...but 4097 is too much
+        assert_eq!(
+            decoder.decode(&[0x3f, 0xe2, 0x1f]),
+            Err(DecoderError::InvalidMaxDynamicSize)
+        );
+    }
+
     /// Tests that a each header list from a sequence of requests is correctly
     /// decoded, when Huffman coding is used
     /// (example from: HPACK-draft-10, C.4.*)
@@ -170,6 +170,11 @@ pub fn encode_integer(value: usize, prefix_size: u8) -> Vec<u8> {
 pub struct Encoder<'a> {
     /// The header table represents the encoder's context
     header_table: HeaderTable<'a>,
+
+    /// The smallest maximum table size set since the last header block was
+    /// encoded, if it was changed at all. The decoder needs to be told about
+    /// it, cf. section `4.2.` of the HPACK spec.
+    pending_size_update: Option<usize>,
 }
 
 impl<'a> Default for Encoder<'a> {
@@ -184,9 +189,26 @@ impl<'a> Encoder<'a> {
     pub fn new() -> Encoder<'a> {
         Encoder {
             header_table: HeaderTable::with_static_table(STATIC_TABLE),
+            pending_size_update: None,
         }
     }
 
+    /// Sets a new maximum dynamic table size for the encoder, evicting
+    /// entries if needed. In HTTP/2, this must not exceed the peer's
+    /// `SETTINGS_HEADER_TABLE_SIZE`.
+    ///
+    /// The change is signaled to the decoder at the start of the next
+    /// encoded header block.
+    pub fn set_max_table_size(&mut self, new_max_size: usize) {
+        self.header_table
+            .dynamic_table
+            .set_max_table_size(new_max_size);
+        self.pending_size_update = Some(match self.pending_size_update {
+            Some(prev) => prev.min(new_max_size),
+            None => new_max_size,
+        });
+    }
+
     /// Encodes the given headers using the HPACK rules and returns a newly
     /// allocated `Vec` containing the bytes representing the encoded header
     /// set.
@@ -216,6 +238,16 @@ impl<'a> Encoder<'a> {
         I: IntoIterator<Item = (&'b [u8], &'b [u8])>,
         W: io::Write,
     {
+        if let Some(smallest) = self.pending_size_update.take() {
+            // if the size went down then back up, the decoder must see both,
+            // so that it evicts the same entries we did.
+            let current = self.header_table.dynamic_table.max_size;
+            encode_integer_into(smallest, 5, 0x20, writer)?;
+            if current != smallest {
+                encode_integer_into(current, 5, 0x20, writer)?;
+            }
+        }
+
         for header in headers {
             self.encode_header_into(header, writer)?;
         }
@@ -360,6 +392,30 @@ mod tests {
         }
     }
 
+    /// Tests that changing the maximum table size gets signaled to the
+    /// decoder, and that both end up evicting the same entries.
+    #[test]
+    fn test_max_table_size_update() {
+        let mut encoder: Encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let headers = vec![(b"custom-key".to_vec(), b"custom-value".to_vec())];
+
+        let result = encoder.encode(headers.iter().map(|h| (&h.0[..], &h.1[..])));
+        assert_eq!(decoder.decode(&result).unwrap(), headers);
+
+        encoder.set_max_table_size(0);
+        encoder.set_max_table_size(256);
+        let result = encoder.encode(headers.iter().map(|h| (&h.0[..], &h.1[..])));
+        // two size updates: down to 0, then back up to 256
+        assert_eq!(&result[..4], &[0x20, 0x3f, 0xe1, 0x01]);
+        assert_eq!(decoder.decode(&result).unwrap(), headers);
+
+        // nothing pending anymore, and the decoder agrees on the indexed entry
+        let result = encoder.encode(headers.iter().map(|h| (&h.0[..], &h.1[..])));
+        assert_eq!(result, vec![0x80 | 62]);
+        assert_eq!(decoder.decode(&result).unwrap(), headers);
+    }
+
     /// Tests that encoding only the `:method` header works.
     #[test]
     fn test_encode_only_method() {
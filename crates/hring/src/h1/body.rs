@@ -1,30 +1,84 @@
-use std::{fmt, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    rc::Rc,
+    time::Duration,
+};
 
+use eyre::Context;
 use tracing::debug;
 
 use crate::{
-    util::{read_and_parse, write_all_list},
+    util::{read_and_parse, timeout_opt, write_all_list},
     Body, BodyChunk, BodyErrorReason,
 };
-use hring_buffet::{Piece, PieceList, ReadOwned, RollMut, WriteOwned};
+use hring_buffet::{Piece, PieceList, ReadOwned, ReadWriteOwned, RollMut, WriteOwned};
 
 /// An HTTP/1.1 body, either chunked or content-length.
 pub(crate) struct H1Body<T> {
     transport: Rc<T>,
-    buf: Option<RollMut>,
     state: Decoder,
+    handover: Rc<Handover>,
+
+    /// How long a read may wait for data before the body errors out with
+    /// [BodyErrorReason::TimedOutWhileReading], if at all
+    idle_timeout: Option<Duration>,
+}
+
+/// Shared by a request body and the encoder of its response, which may take
+/// the connection over from HTTP/1.1 once the response is written.
+#[derive(Default)]
+pub(crate) struct Handover {
+    /// The connection's read buffer. Only `None` after a read error, or once
+    /// the encoder took it along with the transport.
+    buf: RefCell<Option<RollMut>>,
+
+    /// Set once a response turned the connection into a tunnel (e.g. a `101
+    /// Switching Protocols`): whatever comes after the body is passed through
+    /// as-is.
+    pub(crate) tunnel: Cell<bool>,
+
+    /// Set once the encoder took the transport and the read buffer, cf.
+    /// [crate::Encoder::write_upgrade_response]
+    pub(crate) upgraded: Cell<bool>,
+
+    /// Set while the client waits for a `100 Continue` before sending the
+    /// body, cf. https://httpwg.org/specs/rfc9110.html#field.expect. Cleared
+    /// by whichever comes first: the body being read, or a response.
+    pub(crate) expect_continue: Cell<bool>,
+
+    /// Set if the response said the connection would be closed after it
+    pub(crate) close_after_response: Cell<bool>,
+
+    /// Set once the request body is being read
+    pub(crate) body_read: Cell<bool>,
+
+    /// Set if reading the body timed out: what was left of it is lost
+    pub(crate) body_timed_out: Cell<bool>,
+
+    /// Set once the encoder wrote a final response
+    pub(crate) responded: Cell<bool>,
+}
+
+impl Handover {
+    /// Takes the read buffer, leaving the request body empty
+    pub(crate) fn take_buf(&self) -> Option<RollMut> {
+        self.buf.take()
+    }
 }
 
 #[derive(Debug)]
 enum Decoder {
-    Chunked(ChunkedDecoder),
+    Chunked(ChunkedDecoder, ChunkedLimits),
     ContentLength(ContentLengthDecoder),
+    Tunnel(TunnelDecoder),
 }
 
 #[derive(Debug)]
 enum ChunkedDecoder {
-    ReadingChunkHeader,
-    ReadingChunk { remain: u64 },
+    // `received` adds up the sizes of the chunks so far
+    ReadingChunkHeader { received: u64 },
+    ReadingChunk { remain: u64, received: u64 },
 
     // We've gotten one empty chunk
     Done,
@@ -36,12 +90,31 @@ struct ContentLengthDecoder {
     read: u64,
 }
 
+#[derive(Debug)]
+struct TunnelDecoder {
+    eof: bool,
+}
+
 #[derive(Debug)]
 pub(crate) enum H1BodyKind {
-    Chunked,
+    Chunked(ChunkedLimits),
     ContentLength(u64),
 }
 
+/// How much of a chunked body is buffered at most while parsing, besides the
+/// chunks themselves
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChunkedLimits {
+    /// Max length of a chunk size line, chunk extensions included
+    pub(crate) max_chunk_header_len: usize,
+
+    /// Max length of the trailer section, cf. https://httpwg.org/specs/rfc9112.html#chunked.trailer.section
+    pub(crate) max_trailers_len: usize,
+
+    /// Max length of the body, chunks added up, if any
+    pub(crate) max_body_len: Option<u64>,
+}
+
 impl<T> fmt::Debug for H1Body<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("H1Body")
@@ -50,66 +123,153 @@ impl<T> fmt::Debug for H1Body<T> {
     }
 }
 
-impl<T: ReadOwned> H1Body<T> {
-    pub(crate) fn new(transport: Rc<T>, buf: RollMut, kind: H1BodyKind) -> Self {
+impl<T: ReadWriteOwned> H1Body<T> {
+    pub(crate) fn new(
+        transport: Rc<T>,
+        buf: RollMut,
+        kind: H1BodyKind,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
         let state = match kind {
-            H1BodyKind::Chunked => Decoder::Chunked(ChunkedDecoder::ReadingChunkHeader),
+            H1BodyKind::Chunked(limits) => {
+                Decoder::Chunked(ChunkedDecoder::ReadingChunkHeader { received: 0 }, limits)
+            }
             H1BodyKind::ContentLength(len) => {
                 Decoder::ContentLength(ContentLengthDecoder { len, read: 0 })
             }
         };
         H1Body {
             transport,
-            buf: Some(buf),
             state,
+            handover: Rc::new(Handover {
+                buf: RefCell::new(Some(buf)),
+                ..Default::default()
+            }),
+            idle_timeout,
         }
     }
 
+    /// Shared with the encoder, which uses it to turn the connection into a
+    /// tunnel or to take it over.
+    pub(crate) fn handover(&self) -> Rc<Handover> {
+        self.handover.clone()
+    }
+
     /// Returns the inner buffer, but only if the body has been
     /// fully read.
     pub(crate) fn into_buf(self) -> Option<RollMut> {
         if !self.eof() {
             return None;
         }
-        self.buf
+        self.handover.take_buf()
     }
 }
 
-impl<T: ReadOwned> Body for H1Body<T> {
+impl<T: ReadWriteOwned> Body for H1Body<T> {
     fn content_len(&self) -> Option<u64> {
         match &self.state {
-            Decoder::Chunked(_) => None,
+            Decoder::Chunked(..) | Decoder::Tunnel(_) => None,
             Decoder::ContentLength(state) => Some(state.len),
         }
     }
 
     async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
-        if self.buf.is_none() {
+        self.handover.body_read.set(true);
+        if self.handover.expect_continue.take() && !self.eof() {
+            debug!("body wanted, sending 100 Continue");
+            let (res, _) = self
+                .transport
+                .write_all(&b"HTTP/1.1 100 Continue\r\n\r\n"[..])
+                .await;
+            res.wrap_err("writing 100 Continue downstream")?;
+        }
+
+        // the buffer isn't borrowed across reads: the decoders put it back
+        // unless they fail.
+        let mut buf = self.handover.take_buf();
+        if buf.is_none() {
             return Ok(BodyChunk::Done { trailers: None });
         }
 
-        match &mut self.state {
-            Decoder::Chunked(state) => {
-                state
-                    .next_chunk(&mut self.buf, self.transport.as_ref())
-                    .await
+        if self.handover.tunnel.get() && !matches!(self.state, Decoder::Tunnel(_)) && self.eof() {
+            debug!("request body done, connection is now a tunnel");
+            self.state = Decoder::Tunnel(TunnelDecoder { eof: false });
+        }
+
+        // tunnels may well stay quiet for a while
+        let idle_timeout = match self.state {
+            Decoder::Tunnel(_) => None,
+            _ => self.idle_timeout,
+        };
+        let transport = self.transport.as_ref();
+        let decode = async {
+            match &mut self.state {
+                Decoder::Chunked(state, limits) => {
+                    state.next_chunk(&mut buf, transport, limits).await
+                }
+                Decoder::ContentLength(state) => state.next_chunk(&mut buf, transport).await,
+                Decoder::Tunnel(state) => state.next_chunk(&mut buf, transport).await,
             }
-            Decoder::ContentLength(state) => {
-                state
-                    .next_chunk(&mut self.buf, self.transport.as_ref())
-                    .await
+        };
+        // if the timeout runs out, the buffer goes away with the pending read
+        let res = match timeout_opt(idle_timeout, decode).await {
+            Some(res) => res,
+            None => {
+                debug!("timed out reading body");
+                self.handover.body_timed_out.set(true);
+                buf = None;
+                Err(BodyErrorReason::TimedOutWhileReading
+                    .with_cx(format!("no data for {:?}", self.idle_timeout))
+                    .into())
             }
-        }
+        };
+        *self.handover.buf.borrow_mut() = buf;
+        res
     }
 
     fn eof(&self) -> bool {
         match &self.state {
-            Decoder::Chunked(state) => state.eof(),
+            Decoder::Chunked(state, _) => state.eof(),
             Decoder::ContentLength(state) => state.eof(),
+            Decoder::Tunnel(state) => state.eof,
         }
     }
 }
 
+impl TunnelDecoder {
+    async fn next_chunk(
+        &mut self,
+        buf_slot: &mut Option<RollMut>,
+        transport: &impl ReadOwned,
+    ) -> eyre::Result<BodyChunk> {
+        if self.eof {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+
+        let mut buf = buf_slot
+            .take()
+            .ok_or_else(|| BodyErrorReason::CalledNextChunkAfterError.as_err())?;
+
+        if buf.is_empty() {
+            buf.reserve()?;
+
+            let res;
+            (res, buf) = buf.read_into(usize::MAX, transport).await;
+            let n = res.map_err(|e| BodyErrorReason::ErrorWhileReadingTunnelData.with_cx(e))?;
+            if n == 0 {
+                debug!("peer closed the tunnel");
+                self.eof = true;
+                buf_slot.replace(buf);
+                return Ok(BodyChunk::Done { trailers: None });
+            }
+        }
+
+        let chunk = buf.take_all();
+        buf_slot.replace(buf);
+        Ok(BodyChunk::Chunk(chunk.into()))
+    }
+}
+
 impl ContentLengthDecoder {
     async fn next_chunk(
         &mut self,
@@ -153,6 +313,7 @@ impl ChunkedDecoder {
         &mut self,
         buf_slot: &mut Option<RollMut>,
         transport: &impl ReadOwned,
+        limits: &ChunkedLimits,
     ) -> eyre::Result<BodyChunk> {
         loop {
             let mut buf = buf_slot
@@ -166,34 +327,57 @@ impl ChunkedDecoder {
                 return Ok(BodyChunk::Done { trailers: None });
             }
 
-            if let ChunkedDecoder::ReadingChunkHeader = self {
-                let (next_buf, chunk_size) =
-                    read_and_parse(super::parse::chunk_size, transport, buf, 16)
-                        .await
-                        .map_err(|e| BodyErrorReason::InvalidChunkSize.with_cx(e))?
-                        .ok_or_else(|| BodyErrorReason::ClosedWhileReadingChunkSize.as_err())?;
+            if let ChunkedDecoder::ReadingChunkHeader { received } = *self {
+                let (next_buf, (chunk_size, extensions)) = read_and_parse(
+                    super::parse::chunk_header,
+                    transport,
+                    buf,
+                    limits.max_chunk_header_len,
+                )
+                .await
+                .map_err(|e| BodyErrorReason::InvalidChunkSize.with_cx(e))?
+                .ok_or_else(|| BodyErrorReason::ClosedWhileReadingChunkSize.as_err())?;
                 buf = next_buf;
 
+                // extensions have no meaning unless agreed upon, cf.
+                // https://httpwg.org/specs/rfc9112.html#chunked.extension
+                if !extensions.is_empty() {
+                    debug!(extensions = %extensions.to_string_lossy(), "ignoring chunk extensions");
+                }
+
                 if chunk_size == 0 {
-                    // that's the final chunk, look for the final CRLF
-                    let (next_buf, _) = read_and_parse(super::parse::crlf, transport, buf, 2)
-                        .await
-                        .map_err(|e| BodyErrorReason::InvalidChunkTerminator.with_cx(e))?
-                        .ok_or_else(|| {
-                            BodyErrorReason::ClosedWhileReadingChunkTerminator.as_err()
-                        })?;
+                    // that's the final chunk, then come trailers (if any)
+                    // and the final CRLF
+                    let (next_buf, trailers) = read_and_parse(
+                        |i| super::parse::headers_and_crlf(i, false),
+                        transport,
+                        buf,
+                        limits.max_trailers_len,
+                    )
+                    .await
+                    .map_err(|e| BodyErrorReason::InvalidTrailers.with_cx(e))?
+                    .ok_or_else(|| BodyErrorReason::ClosedWhileReadingTrailers.as_err())?;
                     buf = next_buf;
                     *self = ChunkedDecoder::Done;
                     buf_slot.replace(buf);
 
-                    // TODO: trailers
-                    return Ok(BodyChunk::Done { trailers: None });
+                    let trailers = (!trailers.is_empty()).then(|| Box::new(trailers));
+                    return Ok(BodyChunk::Done { trailers });
                 }
 
-                *self = ChunkedDecoder::ReadingChunk { remain: chunk_size }
+                let received = received.saturating_add(chunk_size);
+                if let Some(max) = limits.max_body_len.filter(|&max| received > max) {
+                    return Err(BodyErrorReason::BodyTooLarge
+                        .with_cx(format!("chunks add up to {received} bytes, max is {max}"))
+                        .into());
+                }
+                *self = ChunkedDecoder::ReadingChunk {
+                    remain: chunk_size,
+                    received,
+                }
             };
 
-            if let ChunkedDecoder::ReadingChunk { remain } = self {
+            if let ChunkedDecoder::ReadingChunk { remain, received } = self {
                 if *remain == 0 {
                     // look for CRLF terminator
                     let (next_buf, _) = read_and_parse(super::parse::crlf, transport, buf, 2)
@@ -203,7 +387,9 @@ impl ChunkedDecoder {
                             BodyErrorReason::ClosedWhileReadingChunkTerminator.as_err()
                         })?;
                     buf = next_buf;
-                    *self = ChunkedDecoder::ReadingChunkHeader;
+                    *self = ChunkedDecoder::ReadingChunkHeader {
+                        received: *received,
+                    };
                     buf_slot.replace(buf);
                     continue;
                 }
@@ -249,6 +435,15 @@ pub enum BodyWriteMode {
     // we didn't set a content-length and we're not doing chunked transfer
     // encoding, so we're not sending a body at all.
     Empty,
+
+    // the connection switched protocols (or is a CONNECT tunnel), so bytes
+    // are written as-is.
+    Tunnel,
+
+    // the peer can't decode chunked transfer encoding (HTTP/1.0) and we
+    // didn't set a content-length, so bytes are written as-is and the body
+    // ends when the connection is closed.
+    CloseDelimited,
 }
 
 pub(crate) async fn write_h1_body(
@@ -286,7 +481,7 @@ pub(crate) async fn write_h1_body_chunk(
             let list = write_all_list(transport, list).await?;
             drop(list);
         }
-        BodyWriteMode::ContentLength => {
+        BodyWriteMode::ContentLength | BodyWriteMode::Tunnel | BodyWriteMode::CloseDelimited => {
             let (res, _) = transport.write_all(chunk).await;
             res?;
         }
@@ -316,6 +511,9 @@ pub(crate) async fn write_h1_body_end(
         BodyWriteMode::Empty => {
             // nothing to do
         }
+        BodyWriteMode::Tunnel | BodyWriteMode::CloseDelimited => {
+            // the connection gets closed once the handler is done
+        }
     }
     Ok(())
 }
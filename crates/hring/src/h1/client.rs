@@ -1,42 +1,56 @@
-use std::rc::Rc;
+use std::{rc::Rc, time::Duration};
 
 use eyre::Context;
-use http::header;
+use http::{header, StatusCode};
+use tokio::sync::oneshot;
 use tracing::debug;
 
 use crate::{
     types::Request,
     util::{read_and_parse, write_all_list},
-    Body, HeadersExt, Response,
+    Body, HeadersExt,
 };
 use hring_buffet::{PieceList, ReadWriteOwned, RollMut};
 
 use super::{
-    body::{write_h1_body, BodyWriteMode, H1Body, H1BodyKind},
+    body::{write_h1_body, BodyWriteMode, ChunkedLimits, H1Body, H1BodyKind},
     encode::encode_request,
 };
 
-pub struct ClientConf {}
-
-pub trait ClientDriver {
-    type Return;
+/// HTTP/1.1 client configuration
+pub struct ClientConf {
+    /// How long to wait for a `100 Continue` before sending the body anyway,
+    /// for requests with `expect: 100-continue`, cf.
+    /// https://httpwg.org/specs/rfc9110.html#field.expect
+    pub expect_continue_timeout: Duration,
+}
 
-    async fn on_informational_response(&mut self, res: Response) -> eyre::Result<()>;
-    async fn on_final_response(
-        self,
-        res: Response,
-        body: &mut impl Body,
-    ) -> eyre::Result<Self::Return>;
+impl Default for ClientConf {
+    fn default() -> Self {
+        Self {
+            expect_continue_timeout: Duration::from_secs(1),
+        }
+    }
 }
 
+pub use crate::ClientDriver;
+
 /// Perform an HTTP/1.1 request against an HTTP/1.1 server
 ///
 /// The transport will be returned unless the server requested connection close.
+///
+/// Informational responses (e.g. `103 Early Hints`) are passed to the driver
+/// as they come. With `expect: 100-continue`, the body is only sent once the
+/// server answers with `100 Continue`, or after
+/// [ClientConf::expect_continue_timeout] without an answer. If a final
+/// response comes first, the body is only sent if that response is a 2xx,
+/// since the server is then likely to read it anyway.
 pub async fn request<T, D>(
     transport: Rc<T>,
+    conf: Rc<ClientConf>,
     mut req: Request,
     body: &mut impl Body,
-    driver: D,
+    mut driver: D,
 ) -> eyre::Result<(Option<Rc<T>>, D::Return)>
 where
     T: ReadWriteOwned,
@@ -54,26 +68,42 @@ where
         None => BodyWriteMode::Chunked,
     };
 
+    let expect_continue = mode != BodyWriteMode::Empty && req.headers.expects_100_continue();
+
     let mut list = PieceList::default();
     encode_request(req, &mut list)?;
     _ = write_all_list(transport.as_ref(), list)
         .await
         .wrap_err("writing request headers")?;
 
-    // TODO: handle `expect: 100-continue` (don't start sending body until we get a 100 response)
+    // tells the body writer whether to go ahead: `true` on `100 Continue` or
+    // a 2xx, `false` on other final responses.
+    let (continue_tx, continue_rx) = oneshot::channel::<bool>();
+    let mut continue_tx = Some(continue_tx);
 
     let send_body_fut = {
         let transport = transport.clone();
         async move {
+            if expect_continue {
+                match tokio::time::timeout(conf.expect_continue_timeout, continue_rx).await {
+                    Ok(Ok(false)) => {
+                        debug!("got a final non-2xx response instead, not sending body");
+                        return Ok(false);
+                    }
+                    Ok(_) => debug!("got 100 Continue, sending body"),
+                    Err(_) => debug!("no 100 Continue in time, sending body anyway"),
+                }
+            }
+
             match write_h1_body(transport, body, mode).await {
                 Err(err) => {
                     // TODO: find way to report this error to the driver without
                     // spawning, without ref-counting the driver, etc.
                     panic!("error writing request body: {err:?}");
                 }
-                Ok(body) => {
+                Ok(()) => {
                     debug!("done writing request body");
-                    Ok::<_, eyre::Report>(body)
+                    Ok::<_, eyre::Report>(true)
                 }
             }
         }
@@ -82,22 +112,42 @@ where
     let recv_res_fut = {
         let transport = transport.clone();
         async move {
-            let buf = RollMut::alloc()?;
-            let (buf, res) = read_and_parse(
-                super::parse::response,
-                transport.as_ref(),
-                buf,
-                // TODO: make this configurable
-                64 * 1024,
-            )
-            .await
-            .map_err(|e| eyre::eyre!("error reading response headers from server: {e:?}"))?
-            .ok_or_else(|| eyre::eyre!("server went away before sending response headers"))?;
-            debug!("client received response");
-            res.debug_print();
-
-            if res.status.is_informational() {
-                todo!("handle informational responses");
+            let mut buf = RollMut::alloc()?;
+            let res = loop {
+                let res;
+                (buf, res) = read_and_parse(
+                    super::parse::response,
+                    transport.as_ref(),
+                    buf,
+                    // TODO: make this configurable
+                    64 * 1024,
+                )
+                .await
+                .map_err(|e| eyre::eyre!("error reading response headers from server: {e:?}"))?
+                .ok_or_else(|| eyre::eyre!("server went away before sending response headers"))?;
+                debug!("client received response");
+                res.debug_print();
+
+                if !res.status.is_informational() {
+                    break res;
+                }
+
+                // cf. https://httpwg.org/specs/rfc9110.html#status.1xx
+                if res.status == StatusCode::SWITCHING_PROTOCOLS {
+                    return Err(eyre::eyre!(
+                        "server switched protocols, which we didn't ask for"
+                    ));
+                }
+                if res.status == StatusCode::CONTINUE {
+                    if let Some(tx) = continue_tx.take() {
+                        _ = tx.send(true);
+                    }
+                }
+                driver.on_informational_response(res).await?;
+            };
+
+            if let Some(tx) = continue_tx.take() {
+                _ = tx.send(res.status.is_success());
             }
 
             let chunked = res.headers.is_chunked_transfer_encoding();
@@ -111,10 +161,16 @@ where
                 if chunked {
                     // TODO: even with chunked transfer-encoding, we can announce
                     // a content length - we should probably detect errors there?
-                    H1BodyKind::Chunked
+                    // TODO: make these configurable
+                    H1BodyKind::Chunked(ChunkedLimits {
+                        max_chunk_header_len: 1024,
+                        max_trailers_len: 16 * 1024,
+                        max_body_len: None,
+                    })
                 } else {
                     H1BodyKind::ContentLength(content_len)
                 },
+                None,
             );
 
             let conn_close = res.headers.is_connection_close();
@@ -128,8 +184,13 @@ where
     };
 
     // TODO: cancel sending the body if we get a response early?
-    let (_, (ret, conn_close)) = tokio::try_join!(send_body_fut, recv_res_fut)?;
+    let (body_sent, (ret, conn_close)) = tokio::try_join!(send_body_fut, recv_res_fut)?;
 
-    let transport = if conn_close { None } else { Some(transport) };
+    // if the body wasn't sent, the server may still be waiting for it
+    let transport = if conn_close || !body_sent {
+        None
+    } else {
+        Some(transport)
+    };
     Ok((transport, ret))
 }
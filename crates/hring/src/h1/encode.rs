@@ -1,16 +1,17 @@
 use std::rc::Rc;
 
 use eyre::Context;
-use http::{StatusCode, Version};
+use http::{header, StatusCode, Version};
 
 use crate::{
-    types::{Headers, Request, Response},
+    h2::KnownErrorCode,
+    types::{has_token, Headers, Request, Response},
     util::write_all_list,
-    Encoder,
+    Encoder, Upgraded,
 };
-use hring_buffet::{Piece, PieceList, WriteOwned};
+use hring_buffet::{Piece, PieceList, ReadWriteOwned, WriteOwned};
 
-use super::body::{write_h1_body_chunk, write_h1_body_end, BodyWriteMode};
+use super::body::{write_h1_body_chunk, write_h1_body_end, BodyWriteMode, Handover};
 
 pub(crate) fn encode_request(req: Request, list: &mut PieceList) -> eyre::Result<()> {
     list.push(req.method.into_chunk());
@@ -142,13 +143,64 @@ where
     T: WriteOwned,
 {
     pub(crate) transport: Rc<T>,
+
+    /// Shared with the request body, cf. [Encoder::write_tunnel_response] and
+    /// [Encoder::write_upgrade_response]
+    pub(crate) handover: Rc<Handover>,
+
+    /// Connections are only handed over after requests without a body, so
+    /// that the read buffer only holds bytes of the new protocol
+    pub(crate) request_has_body: bool,
+
+    /// Over HTTP/1.0, persistent connections must be announced in every
+    /// response, and chunked transfer encoding isn't an option.
+    pub(crate) request_version: Version,
+
+    /// Whether the client wants the connection to stay open after this
+    /// request, cf. https://httpwg.org/specs/rfc9112.html#persistent.connections
+    pub(crate) keep_alive: bool,
+
+    /// Set if the request's content-length is over
+    /// [super::ServerConf::max_drain_bytes]: unless the handler reads the body,
+    /// the connection is closed after the response.
+    pub(crate) request_too_big_to_drain: bool,
 }
 
 impl<T> Encoder for H1Encoder<T>
 where
-    T: WriteOwned,
+    T: ReadWriteOwned + 'static,
 {
-    async fn write_response(&mut self, res: Response) -> eyre::Result<()> {
+    type Transport = T;
+
+    fn accepts_chunked_body(&self) -> bool {
+        self.request_version != Version::HTTP_10
+    }
+
+    async fn write_response(&mut self, mut res: Response) -> eyre::Result<()> {
+        if res.status == StatusCode::CONTINUE {
+            self.handover.expect_continue.set(false);
+        } else if !res.status.is_informational() {
+            if self.handover.expect_continue.take() {
+                // we never asked for the body: the client may send it anyway, or
+                // not at all, so there's no telling where the next request starts.
+                self.handover.close_after_response.set(true);
+            }
+            if self.request_too_big_to_drain && !self.handover.body_read.get() {
+                // the handler may never read the body, and it won't be drained
+                self.handover.close_after_response.set(true);
+            }
+            if has_token(&res.headers, header::CONNECTION, b"close") {
+                self.handover.close_after_response.set(true);
+            }
+
+            self.handover.responded.set(true);
+            if self.handover.close_after_response.get() {
+                res.headers.insert(header::CONNECTION, "close".into());
+            } else if self.keep_alive && self.request_version == Version::HTTP_10 {
+                res.headers.insert(header::CONNECTION, "keep-alive".into());
+            }
+        }
+
         let mut list = PieceList::default();
         encode_response(res, &mut list)?;
 
@@ -162,6 +214,31 @@ where
         Ok(())
     }
 
+    async fn write_tunnel_response(&mut self, res: Response) -> eyre::Result<()> {
+        self.write_response(res).await?;
+        self.handover.tunnel.set(true);
+        Ok(())
+    }
+
+    async fn write_upgrade_response(&mut self, res: Response) -> eyre::Result<Upgraded<T>> {
+        if self.request_has_body {
+            return Err(eyre::eyre!(
+                "can't upgrade a connection after a request with a body"
+            ));
+        }
+        let client_buf = self
+            .handover
+            .take_buf()
+            .ok_or_else(|| eyre::eyre!("connection can't be upgraded after a read error"))?;
+
+        self.write_response(res).await?;
+        self.handover.upgraded.set(true);
+        Ok(Upgraded {
+            transport: self.transport.clone(),
+            client_buf,
+        })
+    }
+
     // TODO: move `mode` into `H1Encoder`? we don't need it for h2
     async fn write_body_chunk(&mut self, chunk: Piece, mode: BodyWriteMode) -> eyre::Result<()> {
         // TODO: inline
@@ -175,8 +252,11 @@ where
 
     async fn write_trailers(&mut self, trailers: Box<Headers>) -> eyre::Result<()> {
         // TODO: check all preconditions
+        // the last chunk, then the trailer section, cf. https://httpwg.org/specs/rfc9112.html#chunked.trailer.section
         let mut list = PieceList::default();
+        list.push("0\r\n");
         encode_headers(*trailers, &mut list)?;
+        list.push("\r\n");
 
         let list = write_all_list(self.transport.as_ref(), list)
             .await
@@ -187,4 +267,8 @@ where
 
         Ok(())
     }
+
+    async fn reset_stream(&mut self, _error_code: KnownErrorCode) -> eyre::Result<()> {
+        Err(eyre::eyre!("HTTP/1.1 responses can't be reset"))
+    }
 }
@@ -3,11 +3,17 @@
 //! HTTP/1.1 https://httpwg.org/specs/rfc9112.html
 //! HTTP semantics https://httpwg.org/specs/rfc9110.html
 
-use http::{header::HeaderName, StatusCode, Version};
+use http::{
+    header::HeaderName,
+    uri::{Authority, Parts},
+    StatusCode, Uri, Version,
+};
 use nom::{
-    bytes::streaming::{tag, take, take_until, take_while1},
-    combinator::{map_res, opt},
-    sequence::{preceded, terminated},
+    branch::alt,
+    bytes::streaming::{tag, take, take_until, take_while, take_while1},
+    combinator::{map, map_opt, map_res, opt, verify},
+    multi::many0_count,
+    sequence::{terminated, tuple},
     IResult,
 };
 
@@ -15,13 +21,66 @@ use crate::{
     types::{Headers, Request, Response},
     Method,
 };
-use hring_buffet::{PieceStr, Roll, RollStr};
+use hring_buffet::{Piece, PieceStr, Roll, RollStr};
 
 const CRLF: &[u8] = b"\r\n";
 
-/// Parses a chunked transfer coding chunk size (hex text followed by CRLF)
-pub fn chunk_size(i: Roll) -> IResult<Roll, u64> {
-    terminated(u64_text_hex, tag(CRLF))(i)
+/// Parses a chunked transfer coding chunk header: the size as hex text, then
+/// chunk extensions (e.g. `;name=value`), then CRLF. Returns the size along with
+/// the raw extensions, cf. https://httpwg.org/specs/rfc9112.html#chunked.extension
+pub fn chunk_header(i: Roll) -> IResult<Roll, (u64, Roll)> {
+    let (i, size) = u64_text_hex(i)?;
+    let before_extensions = i.clone();
+    let (i, _) = many0_count(chunk_extension)(i)?;
+    let extensions_len = before_extensions.len() - i.len();
+    let extensions = before_extensions.slice(..extensions_len);
+    let (i, _) = tag(CRLF)(i)?;
+    Ok((i, (size, extensions)))
+}
+
+/// A single `;name` or `;name=value` chunk extension, where the value is a
+/// token or a quoted string
+fn chunk_extension(i: Roll) -> IResult<Roll, ()> {
+    let (i, _) = tuple((
+        take_while(is_ows),
+        tag(&b";"[..]),
+        take_while(is_ows),
+        token,
+    ))(i)?;
+    let value = alt((map(token, |_| ()), quoted_string));
+    let (i, _) = opt(tuple((
+        take_while(is_ows),
+        tag(&b"="[..]),
+        take_while(is_ows),
+        value,
+    )))(i)?;
+    Ok((i, ()))
+}
+
+/// cf. https://httpwg.org/specs/rfc9110.html#quoted.strings
+fn quoted_string(i: Roll) -> IResult<Roll, ()> {
+    let (i, _) = tag(&b"\""[..])(i)?;
+
+    // qdtext and quoted-pair allow the same bytes, except for the unescaped
+    // backslash and quote.
+    let is_text = |c: u8| c == b'\t' || c == b' ' || c.is_ascii_graphic() || c >= 0x80;
+    let mut escaped = false;
+    for (pos, c) in i.iter().enumerate() {
+        if !is_text(c) {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                i,
+                nom::error::ErrorKind::Verify,
+            )));
+        }
+        if escaped {
+            escaped = false;
+        } else if c == b'\\' {
+            escaped = true;
+        } else if c == b'"' {
+            return Ok((i.slice(pos + 1..), ()));
+        }
+    }
+    Err(nom::Err::Incomplete(nom::Needed::Unknown))
 }
 
 pub fn crlf(i: Roll) -> IResult<Roll, ()> {
@@ -29,20 +88,35 @@ pub fn crlf(i: Roll) -> IResult<Roll, ()> {
     Ok((i, ()))
 }
 
-// Looks like `GET /path HTTP/1.1\r\n`, then headers
+// Looks like `GET /path HTTP/1.1\r\n`, or `CONNECT example.org:443 HTTP/1.1\r\n`,
+// then headers. Folded header lines are a parsing error.
 pub fn request(i: Roll) -> IResult<Roll, Request> {
+    request_inner(i, false)
+}
+
+/// Like [request], but folded header lines are joined with a space, cf.
+/// https://httpwg.org/specs/rfc9112.html#line.folding
+pub fn request_unfolding(i: Roll) -> IResult<Roll, Request> {
+    request_inner(i, true)
+}
+
+fn request_inner(i: Roll, unfold: bool) -> IResult<Roll, Request> {
     let (i, method) = terminated(method, space1)(i)?;
-    let (i, path) = terminated(path, space1)(i)?;
+    let (i, uri) = match method {
+        Method::Connect => terminated(authority_form, space1)(i)?,
+        // TODO: should this take the host header into account?
+        // check what hyper does.
+        _ => terminated(map_res(path, |path| path.parse::<Uri>()), space1)(i)?,
+    };
     let (i, version) = terminated(http_version, tag(CRLF))(i)?;
-    let (i, headers) = headers_and_crlf(i)?;
+    let (i, headers) = headers_and_crlf(i, unfold)?;
 
     let request = Request {
         method,
-        // TODO: should this take the host header into account?
-        // check what hyper does.
-        uri: path.parse().unwrap(),
+        uri,
         version,
         headers,
+        protocol: None,
     };
     Ok((i, request))
 }
@@ -77,6 +151,21 @@ fn path(i: Roll) -> IResult<Roll, RollStr> {
     Ok((i, path))
 }
 
+/// The host and port a CONNECT request asks to tunnel to, without userinfo,
+/// cf. https://httpwg.org/specs/rfc9112.html#authority-form
+fn authority_form(i: Roll) -> IResult<Roll, Uri> {
+    map_opt(path, |target| {
+        let authority: Authority = target.parse().ok()?;
+        if authority.port().is_none() || authority.as_str().contains('@') {
+            return None;
+        }
+
+        let mut parts = Parts::default();
+        parts.authority = Some(authority);
+        Uri::from_parts(parts).ok()
+    })(i)
+}
+
 /// Returns true if `c` is a character that can be found in an URI
 /// cf. https://stackoverflow.com/a/7109208
 fn is_uri_char(c: u8) -> bool {
@@ -92,7 +181,8 @@ pub fn response(i: Roll) -> IResult<Roll, Response> {
     let (i, version) = terminated(http_version, space1)(i)?;
     let (i, code) = terminated(status_code, space1)(i)?;
     let (i, _reason) = terminated(take_until(CRLF), tag(CRLF))(i)?;
-    let (i, headers) = headers_and_crlf(i)?;
+    // user agents must unfold, cf. https://httpwg.org/specs/rfc9112.html#line.folding
+    let (i, headers) = headers_and_crlf(i, true)?;
 
     let response = Response {
         version,
@@ -136,7 +226,10 @@ pub fn http_version(i: Roll) -> IResult<Roll, Version> {
     Ok((i, version))
 }
 
-pub fn headers_and_crlf(mut i: Roll) -> IResult<Roll, Headers> {
+/// Parses header lines up to an empty line. Lines that start with whitespace
+/// continue the previous one (obs-fold): they're joined with a space if
+/// `unfold` is set, and are a parsing error otherwise.
+pub fn headers_and_crlf(mut i: Roll, unfold: bool) -> IResult<Roll, Headers> {
     let mut headers = Headers::default();
     loop {
         if let (i, Some(_)) = opt(tag(CRLF))(i.clone())? {
@@ -145,21 +238,61 @@ pub fn headers_and_crlf(mut i: Roll) -> IResult<Roll, Headers> {
         }
 
         let (i_next, (name, value)) = header(i)?;
-        headers.append(name, value.into());
         i = i_next;
+
+        let mut value: Piece = value.into();
+        while let (i_next, Some(_)) = opt(take_while1(is_ows))(i.clone())? {
+            if !unfold {
+                return Err(nom::Err::Error(nom::error::Error::new(
+                    i,
+                    nom::error::ErrorKind::Verify,
+                )));
+            }
+            let (i_next, more) = field_value(i_next)?;
+            i = i_next;
+            value = if value.is_empty() {
+                more.into()
+            } else {
+                [&value[..], b" ", &more[..]].concat().into()
+            };
+        }
+        headers.append(name, value);
     }
 }
 
-/// Parse a single header line
+/// Parse a single header line. There can't be whitespace between the name
+/// and the colon, cf. https://httpwg.org/specs/rfc9112.html#field.parsing
 fn header(i: Roll) -> IResult<Roll, (HeaderName, Roll)> {
     let (i, name) = map_res(take_until_and_consume(b":"), |s: Roll| {
         HeaderName::from_bytes(&s[..])
     })(i)?;
-    let (i, value) = preceded(space1, take_until_and_consume(CRLF))(i)?;
+    let (i, value) = field_value(i)?;
 
     Ok((i, (name, value)))
 }
 
+/// Parse a header value up to CRLF, without surrounding whitespace. CR, LF
+/// and NUL can't appear in values, cf. https://httpwg.org/specs/rfc9110.html#fields.values
+fn field_value(i: Roll) -> IResult<Roll, Roll> {
+    let (i, _) = take_while(is_ows)(i)?;
+    let (i, value) = verify(take_until_and_consume(CRLF), |value: &Roll| {
+        !value.iter().any(|c| matches!(c, b'\r' | b'\n' | b'\0'))
+    })(i)?;
+
+    let len = value
+        .iter()
+        .enumerate()
+        .filter(|&(_, c)| !is_ows(c))
+        .last()
+        .map_or(0, |(pos, _)| pos + 1);
+    Ok((i, value.slice(..len)))
+}
+
+/// Optional whitespace, cf. https://httpwg.org/specs/rfc9110.html#whitespace
+fn is_ows(c: u8) -> bool {
+    c == b' ' || c == b'\t'
+}
+
 /// Parse at least one SP character
 fn space1(i: Roll) -> IResult<Roll, ()> {
     let (i, _) = take_while1(|c| c == b' ')(i)?;
@@ -173,7 +306,12 @@ fn take_until_and_consume(needle: &[u8]) -> impl FnMut(Roll) -> IResult<Roll, Ro
 
 #[cfg(test)]
 mod tests {
-    use crate::h1::parse::is_delimiter;
+    use hring_buffet::RollMut;
+
+    use crate::{
+        h1::parse::{chunk_header, headers_and_crlf, is_delimiter, request},
+        Method,
+    };
 
     #[test]
     fn test_h1_parse_various_lowlevel_functions() {
@@ -182,4 +320,123 @@ mod tests {
         assert!(is_delimiter(b'\\'));
         assert!(!is_delimiter(b'B'));
     }
+
+    #[test]
+    fn test_h1_parse_request_target() {
+        fn parse(input: &str) -> Option<(Method, http::Uri)> {
+            let mut buf = RollMut::alloc().unwrap();
+            buf.put(input.as_bytes()).unwrap();
+            let (_, req) = request(buf.filled()).ok()?;
+            Some((req.method, req.uri))
+        }
+
+        let (method, uri) = parse("GET /search?q=hring HTTP/1.1\r\n\r\n").unwrap();
+        assert!(matches!(method, Method::Get));
+        assert_eq!(uri.path(), "/search");
+        assert_eq!(uri.query(), Some("q=hring"));
+
+        // CONNECT only takes the authority-form
+        let (method, uri) = parse("CONNECT example.org:443 HTTP/1.1\r\n\r\n").unwrap();
+        assert!(matches!(method, Method::Connect));
+        assert_eq!(uri.authority().unwrap().as_str(), "example.org:443");
+        assert_eq!(uri.port_u16(), Some(443));
+        assert!(uri.scheme().is_none());
+
+        let (_, uri) = parse("CONNECT [::1]:8080 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(uri.host(), Some("[::1]"));
+
+        for target in [
+            "/",
+            "example.org",
+            "user@example.org:443",
+            "http://example.org:443/",
+        ] {
+            let input = format!("CONNECT {target} HTTP/1.1\r\n\r\n");
+            assert!(parse(&input).is_none(), "{target} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_h1_parse_chunk_header() {
+        fn parse(input: &str) -> Option<(u64, String)> {
+            let mut buf = RollMut::alloc().unwrap();
+            buf.put(input.as_bytes()).unwrap();
+            let (rest, (size, extensions)) = chunk_header(buf.filled()).ok()?;
+            assert_eq!(rest.len(), 0);
+            Some((size, extensions.to_string_lossy().into()))
+        }
+
+        assert_eq!(parse("1a\r\n"), Some((0x1a, "".into())));
+        assert_eq!(parse("0;foo\r\n"), Some((0, ";foo".into())));
+        assert_eq!(
+            parse("5 ; a=b;c = \"d;\\\"e\"\r\n"),
+            Some((5, " ; a=b;c = \"d;\\\"e\"".into()))
+        );
+
+        for input in [
+            "\r\n",
+            "x\r\n",
+            "5;\r\n",
+            "5;a=\r\n",
+            "5;a=\"b\r\n",
+            "5 a\r\n",
+            "5;a=b c\r\n",
+            "fffffffffffffffff\r\n",
+        ] {
+            assert!(parse(input).is_none(), "{input:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_h1_parse_header_values() {
+        fn parse(input: &str, unfold: bool) -> Option<Vec<(String, String)>> {
+            let mut buf = RollMut::alloc().unwrap();
+            buf.put(input.as_bytes()).unwrap();
+            let (rest, headers) = headers_and_crlf(buf.filled(), unfold).ok()?;
+            assert_eq!(rest.len(), 0);
+            Some(
+                headers
+                    .iter()
+                    .map(|(name, value)| {
+                        let value = String::from_utf8(value.to_vec()).unwrap();
+                        (name.to_string(), value)
+                    })
+                    .collect(),
+            )
+        }
+        let pairs = |pairs: &[(&str, &str)]| {
+            Some(
+                pairs
+                    .iter()
+                    .map(|&(name, value)| (name.to_string(), value.to_string()))
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        // surrounding whitespace isn't part of the value
+        assert_eq!(
+            parse("a:b\r\nc: \t d e \t\r\nf:\r\n\r\n", false),
+            pairs(&[("a", "b"), ("c", "d e"), ("f", "")])
+        );
+
+        // no whitespace before the colon, no CR, LF or NUL in values
+        for input in [
+            "a : b\r\n\r\n",
+            " a: b\r\n\r\n",
+            "a: b\rc\r\n\r\n",
+            "a: b\nc: d\r\n\r\n",
+            "a: b\0\r\n\r\n",
+        ] {
+            assert!(
+                parse(input, false).is_none(),
+                "{input:?} should be rejected"
+            );
+            assert!(parse(input, true).is_none(), "{input:?} should be rejected");
+        }
+
+        // obs-fold
+        let folded = "a: b\r\n  c\r\n\td \r\ne:\r\n f\r\n\r\n";
+        assert!(parse(folded, false).is_none());
+        assert_eq!(parse(folded, true), pairs(&[("a", "b c d"), ("e", "f")]));
+    }
 }
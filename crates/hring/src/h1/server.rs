@@ -1,14 +1,17 @@
-use std::rc::Rc;
+use std::{rc::Rc, time::Duration};
 
 use eyre::Context;
+use http::{header, Version};
 use tracing::debug;
 
 use crate::{
-    h1::body::{H1Body, H1BodyKind},
-    util::{read_and_parse, SemanticError},
-    ExpectResponseHeaders, HeadersExt, Responder, ServerDriver,
+    h1::body::{ChunkedLimits, H1Body, H1BodyKind},
+    h2::{parse::Settings, H2cUpgrade},
+    types::{from_digits, has_token},
+    util::{decode_base64url, read_and_parse, timeout_opt, SemanticError},
+    Body, BodyChunk, ExpectResponseHeaders, HeadersExt, Request, Responder, ServerDriver, Timeout,
 };
-use hring_buffet::{ReadWriteOwned, RollMut};
+use hring_buffet::{ReadWriteOwned, Roll, RollMut};
 
 use super::encode::H1Encoder;
 
@@ -21,6 +24,69 @@ pub struct ServerConf {
 
     /// Max number of header records
     pub max_header_records: usize,
+
+    /// Max number of pipelined requests answered in a row, i.e. requests the
+    /// client sent before getting the response to the previous one, cf.
+    /// https://httpwg.org/specs/rfc9112.html#pipelining. The response to the
+    /// last of those has `connection: close`, and the client has to retry the
+    /// requests that weren't answered. At least one pipelined request is
+    /// answered, so zero behaves like one.
+    pub max_pipeline_depth: usize,
+
+    /// Max number of request body bytes read (and discarded) once the handler
+    /// is done, if it didn't read the whole body: e.g. after a `401
+    /// Unauthorized`, to keep the connection open. Past that, the connection
+    /// is closed. Responses to requests with a larger content-length get
+    /// `connection: close` if their handler hasn't started reading the body.
+    pub max_drain_bytes: u64,
+
+    /// How long to wait for the rest of a request body while draining it
+    pub drain_timeout: Duration,
+
+    /// Max length of a request body, if any. Requests with a larger
+    /// content-length get a `413 Payload Too Large` without calling the
+    /// handler; chunked bodies error out with [crate::BodyErrorReason::BodyTooLarge]
+    /// once they go over.
+    pub max_request_body_len: Option<u64>,
+
+    /// Max length of a request body chunk's size line, chunk extensions
+    /// included, e.g. `1a;name=value`
+    pub max_chunk_header_len: usize,
+
+    /// Max length of a request body's trailer section, e.g.
+    /// `grpc-status: 0\r\n\r\n`
+    pub max_trailers_len: usize,
+
+    /// How long a connection may sit idle before the first byte of a request,
+    /// including the first one. Past that, it's closed.
+    pub keep_alive_timeout: Duration,
+
+    /// How long the request line and headers get to come in, from their first
+    /// byte on. Past that, the client gets a `408 Request Timeout`.
+    pub header_read_timeout: Duration,
+
+    /// How long reading the request body may wait for more data. Past that,
+    /// the body errors out with [crate::BodyErrorReason::TimedOutWhileReading],
+    /// the client gets a `408 Request Timeout` unless a response was already
+    /// written, and the connection is closed. Tunnels don't time out.
+    pub body_idle_timeout: Duration,
+
+    /// How long the handler gets to handle a request, reading the body and
+    /// writing the response included, if there's a limit. Past that, the
+    /// handler is cancelled and the connection closed. If no response was
+    /// written, the client gets a `408 Request Timeout` if its body didn't
+    /// all come in, or a `503 Service Unavailable` otherwise. Tunnels count
+    /// too, so leave this off for long-lived ones.
+    pub request_deadline: Option<Duration>,
+
+    /// Accept some ambiguous requests instead of answering them with a
+    /// `400 Bad Request`: folded header lines are unfolded, identical
+    /// `content-length` values are merged, and `transfer-encoding` wins over
+    /// `content-length` (or comes with HTTP/1.0), in which case the connection
+    /// is closed after the response, cf. https://httpwg.org/specs/rfc9112.html#message.body.length
+    /// Only enable this when there's no other HTTP hop in front of or behind
+    /// hring that could disagree on where requests end.
+    pub lenient_parsing: bool,
 }
 
 impl Default for ServerConf {
@@ -29,6 +95,17 @@ impl Default for ServerConf {
             max_http_header_len: 64 * 1024,
             max_header_record_len: 4 * 1024,
             max_header_records: 128,
+            max_pipeline_depth: 16,
+            max_drain_bytes: 64 * 1024,
+            drain_timeout: Duration::from_secs(5),
+            max_request_body_len: None,
+            max_chunk_header_len: 1024,
+            max_trailers_len: 16 * 1024,
+            keep_alive_timeout: Duration::from_secs(60),
+            header_read_timeout: Duration::from_secs(30),
+            body_idle_timeout: Duration::from_secs(30),
+            request_deadline: None,
+            lenient_parsing: false,
         }
     }
 }
@@ -40,31 +117,116 @@ pub enum ServeOutcome {
     ClientClosedConnectionBetweenRequests,
     // TODO: return buffer there so we can see what they did write?
     ClientDidntSpeakHttp11,
+    /// The handler turned the connection into a tunnel (e.g. to speak
+    /// WebSocket after a `101 Switching Protocols`) and is done with it
+    Tunneled,
+    /// The handler took the connection over, cf.
+    /// [crate::Responder::write_upgrade_response]
+    Upgraded,
+    /// The client pipelined more requests than [ServerConf::max_pipeline_depth]
+    PipelineTooDeep,
+    /// The request's content-length was over [ServerConf::max_request_body_len],
+    /// it got a `413 Payload Too Large`
+    RequestBodyTooLarge,
+    /// The handler didn't read the whole request body, and draining it hit
+    /// [ServerConf::max_drain_bytes] or [ServerConf::drain_timeout]
+    RequestBodyNotDrained,
+    /// One of the [ServerConf] timeouts ran out, cf. [Timeout]
+    TimedOut(Timeout),
 }
 
 pub async fn serve(
-    transport: impl ReadWriteOwned,
+    transport: impl ReadWriteOwned + 'static,
     conf: Rc<ServerConf>,
-    mut client_buf: RollMut,
+    client_buf: RollMut,
     driver: impl ServerDriver,
 ) -> eyre::Result<ServeOutcome> {
-    let transport = Rc::new(transport);
+    match serve_inner(Rc::new(transport), &conf, client_buf, &driver, false).await? {
+        ServeExit::Done(outcome) => Ok(outcome),
+        ServeExit::H2cUpgrade { .. } => unreachable!("h2c upgrades are only accepted if enabled"),
+    }
+}
+
+pub(crate) enum ServeExit {
+    Done(ServeOutcome),
+    /// We've answered an h2c upgrade request with `101 Switching Protocols`,
+    /// the rest of the connection is HTTP/2.
+    H2cUpgrade {
+        upgrade: H2cUpgrade,
+        client_buf: RollMut,
+    },
+}
+
+/// Like [serve], but optionally accepts upgrades to h2c
+pub(crate) async fn serve_inner(
+    transport: Rc<impl ReadWriteOwned + 'static>,
+    conf: &ServerConf,
+    mut client_buf: RollMut,
+    driver: &impl ServerDriver,
+    accept_h2c: bool,
+) -> eyre::Result<ServeExit> {
+    let parse_request: fn(Roll) -> nom::IResult<Roll, Request> = if conf.lenient_parsing {
+        super::parse::request_unfolding
+    } else {
+        super::parse::request
+    };
+
+    // requests answered in a row that were already buffered when the previous
+    // response was done
+    let mut pipelined = 0;
 
     loop {
-        let req;
-        (client_buf, req) = match read_and_parse(
-            super::parse::request,
+        // the connection may sit idle for a while, but once a request starts,
+        // its headers have to come in quickly.
+        if client_buf.is_empty() {
+            client_buf.reserve()?;
+            let read = client_buf.read_into(conf.max_http_header_len, transport.as_ref());
+            let Ok((res, next_buf)) = tokio::time::timeout(conf.keep_alive_timeout, read).await else {
+                debug!("connection idle for too long, closing it");
+                return Ok(ServeExit::Done(ServeOutcome::TimedOut(
+                    Timeout::KeepAliveIdle,
+                )));
+            };
+            client_buf = next_buf;
+            match res {
+                Ok(0) => {
+                    debug!("client went away before sending request headers");
+                    return Ok(ServeExit::Done(
+                        ServeOutcome::ClientClosedConnectionBetweenRequests,
+                    ));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    debug!(?e, "error reading request header from downstream");
+                    return Ok(ServeExit::Done(ServeOutcome::ClientDidntSpeakHttp11));
+                }
+            }
+        }
+
+        let read = read_and_parse(
+            parse_request,
             transport.as_ref(),
             client_buf,
             conf.max_http_header_len,
-        )
-        .await
-        {
+        );
+        let Ok(res) = tokio::time::timeout(conf.header_read_timeout, read).await else {
+            debug!("timed out reading request headers");
+            let (res, _) = transport
+                .write_all(SemanticError::RequestTimeout.as_http_response())
+                .await;
+            res.wrap_err("writing error response downstream")?;
+            return Ok(ServeExit::Done(ServeOutcome::TimedOut(Timeout::HeaderRead)));
+        };
+
+        let req;
+        (client_buf, req) = match res {
             Ok(t) => match t {
                 Some(t) => t,
                 None => {
                     debug!("client went away before sending request headers");
-                    return Ok(ServeOutcome::ClientClosedConnectionBetweenRequests);
+                    return Ok(ServeExit::Done(
+                        ServeOutcome::ClientClosedConnectionBetweenRequests,
+                    ));
                 }
             },
             Err(e) => {
@@ -74,47 +236,365 @@ pub async fn serve(
                 }
 
                 debug!(?e, "error reading request header from downstream");
-                return Ok(ServeOutcome::ClientDidntSpeakHttp11);
+                return Ok(ServeExit::Done(ServeOutcome::ClientDidntSpeakHttp11));
             }
         };
         debug!("got request {req:?}");
 
-        let chunked = req.headers.is_chunked_transfer_encoding();
-        let connection_close = req.headers.is_connection_close();
-        let content_len = req.headers.content_length().unwrap_or_default();
+        let (body_kind, close_after_response) = match request_framing(&req, conf) {
+            Ok(t) => t,
+            Err(se) => {
+                debug!(%se, "refusing request");
+                let (res, _) = transport.write_all(se.as_http_response()).await;
+                res.wrap_err("writing error response downstream")?;
+                return Ok(ServeExit::Done(ServeOutcome::ClientDidntSpeakHttp11));
+            }
+        };
+
+        if let (H1BodyKind::ContentLength(len), Some(max)) = (&body_kind, conf.max_request_body_len)
+        {
+            if *len > max {
+                debug!(%len, %max, "request body too large");
+                let se = SemanticError::RequestBodyTooLarge;
+                let (res, _) = transport.write_all(se.as_http_response()).await;
+                res.wrap_err("writing error response downstream")?;
+                return Ok(ServeExit::Done(ServeOutcome::RequestBodyTooLarge));
+            }
+        }
+
+        if accept_h2c {
+            if let Some(settings) = h2c_upgrade_settings(&req) {
+                debug!("upgrading to h2c");
+                let (res, _) = transport
+                    .write_all(
+                        &b"HTTP/1.1 101 Switching Protocols\r\nconnection: Upgrade\r\nupgrade: h2c\r\n\r\n"[..],
+                    )
+                    .await;
+                res.wrap_err("writing 101 response downstream")?;
+
+                return Ok(ServeExit::H2cUpgrade {
+                    upgrade: H2cUpgrade { req, settings },
+                    client_buf,
+                });
+            }
+        }
+
+        // HTTP/1.0 connections only persist if the client asks for it, cf.
+        // https://httpwg.org/specs/rfc9112.html#persistent.connections
+        let keep_alive = if req.version == Version::HTTP_10 {
+            has_token(&req.headers, header::CONNECTION, b"keep-alive")
+        } else {
+            !req.headers.is_connection_close()
+        };
+        let request_version = req.version;
+        let request_has_body = !matches!(body_kind, H1BodyKind::ContentLength(0));
+        let request_too_big_to_drain =
+            matches!(body_kind, H1BodyKind::ContentLength(len) if len > conf.max_drain_bytes);
 
         let mut req_body = H1Body::new(
             transport.clone(),
             client_buf,
-            if chunked {
-                H1BodyKind::Chunked
-            } else {
-                H1BodyKind::ContentLength(content_len)
-            },
+            body_kind,
+            Some(conf.body_idle_timeout),
         );
 
+        // past the max depth, this is the last request we answer, and the
+        // response has to say so.
+        let pipeline_full = pipelined >= conf.max_pipeline_depth.max(1);
+
+        let handover = req_body.handover();
+        handover
+            .close_after_response
+            .set(close_after_response || pipeline_full);
+        // HTTP/1.0 clients don't know about `100 Continue`, cf.
+        // https://httpwg.org/specs/rfc9110.html#field.expect
+        handover.expect_continue.set(
+            request_has_body
+                && req.version == Version::HTTP_11
+                && req.headers.expects_100_continue(),
+        );
         let responder = Responder {
             encoder: H1Encoder {
                 transport: transport.clone(),
+                handover: handover.clone(),
+                request_has_body,
+                request_version,
+                keep_alive,
+                request_too_big_to_drain,
             },
             state: ExpectResponseHeaders,
         };
 
-        let resp = driver
-            .handle(req, &mut req_body, responder)
-            .await
-            .wrap_err("handling request")?;
+        let handle = driver.handle(req, &mut req_body, responder);
+        let Some(res) = timeout_opt(conf.request_deadline, handle).await else {
+            debug!("request deadline exceeded, closing connection");
+            if !handover.responded.get() {
+                let se = if req_body.eof() {
+                    SemanticError::DeadlineExceeded
+                } else {
+                    SemanticError::RequestTimeout
+                };
+                let (res, _) = transport.write_all(se.as_http_response()).await;
+                res.wrap_err("writing error response downstream")?;
+            }
+            return Ok(ServeExit::Done(ServeOutcome::TimedOut(
+                Timeout::RequestDeadline,
+            )));
+        };
+
+        // the rest of the body is lost, so the next request can't be found
+        if handover.body_timed_out.get() {
+            debug!("timed out reading request body, closing connection");
+            if !handover.responded.get() {
+                let (res, _) = transport
+                    .write_all(SemanticError::RequestTimeout.as_http_response())
+                    .await;
+                res.wrap_err("writing error response downstream")?;
+            }
+            return Ok(ServeExit::Done(ServeOutcome::TimedOut(Timeout::BodyIdle)));
+        }
 
-        // TODO: if we sent `connection: close` we should close now
+        let resp = res.wrap_err("handling request")?;
         _ = resp;
 
-        client_buf = req_body
-            .into_buf()
-            .ok_or_else(|| eyre::eyre!("request body not drained, have to close connection"))?;
+        if handover.upgraded.get() {
+            debug!("handler took the connection over");
+            return Ok(ServeExit::Done(ServeOutcome::Upgraded));
+        }
+        if handover.tunnel.get() {
+            debug!("handler is done with the tunnel, closing connection");
+            return Ok(ServeExit::Done(ServeOutcome::Tunneled));
+        }
+
+        if pipeline_full {
+            debug!(%pipelined, "too many pipelined requests, closing connection");
+            lingering_close(transport.as_ref(), conf).await;
+            return Ok(ServeExit::Done(ServeOutcome::PipelineTooDeep));
+        }
+
+        if handover.close_after_response.get() {
+            debug!("response said the connection would be closed");
+            return Ok(ServeExit::Done(
+                ServeOutcome::ServerRequestedConnectionClose,
+            ));
+        }
 
-        if connection_close {
+        if !keep_alive {
             debug!("client requested connection close");
-            return Ok(ServeOutcome::ClientRequestedConnectionClose);
+            return Ok(ServeExit::Done(
+                ServeOutcome::ClientRequestedConnectionClose,
+            ));
+        }
+
+        if !req_body.eof() && !drain_body(&mut req_body, conf).await {
+            debug!("request body not drained, have to close connection");
+            return Ok(ServeExit::Done(ServeOutcome::RequestBodyNotDrained));
+        }
+        let Some(next_buf) = req_body.into_buf() else {
+            debug!("request body errored out, have to close connection");
+            return Ok(ServeExit::Done(ServeOutcome::RequestBodyNotDrained));
+        };
+        client_buf = next_buf;
+
+        // responses go out in the order requests came in, since the next
+        // request is only parsed once the previous response is done.
+        if client_buf.is_empty() {
+            pipelined = 0;
+        } else {
+            pipelined += 1;
+        }
+    }
+}
+
+/// Closes our side of the connection, then reads and discards whatever the
+/// client still sends (e.g. pipelined requests we won't answer) until it closes
+/// its side, for at most [ServerConf::drain_timeout]. Closing with unread data
+/// resets the connection, which can make the client lose responses it hasn't
+/// read yet, cf. https://httpwg.org/specs/rfc9112.html#persistent.tear-down
+async fn lingering_close(transport: &impl ReadWriteOwned, conf: &ServerConf) {
+    if let Err(e) = transport.shutdown().await {
+        debug!(?e, "couldn't shut down connection, the client may be gone");
+        return;
+    }
+
+    let discard = async {
+        let mut buf = Vec::with_capacity(4096);
+        loop {
+            let res;
+            (res, buf) = transport.read(buf).await;
+            if !matches!(res, Ok(n) if n > 0) {
+                break;
+            }
+        }
+    };
+    if tokio::time::timeout(conf.drain_timeout, discard)
+        .await
+        .is_err()
+    {
+        debug!("client didn't close the connection in time");
+    }
+}
+
+/// Reads and discards what the handler left of the request body, so the next
+/// request can be parsed. Returns false if that goes over
+/// [ServerConf::max_drain_bytes] or [ServerConf::drain_timeout], or if reading
+/// fails.
+async fn drain_body(body: &mut impl Body, conf: &ServerConf) -> bool {
+    let drain = async {
+        let mut drained = 0;
+        while let BodyChunk::Chunk(chunk) = body.next_chunk().await? {
+            drained += chunk.len() as u64;
+            if drained > conf.max_drain_bytes {
+                return Err(eyre::eyre!(
+                    "request body is over {} bytes",
+                    conf.max_drain_bytes
+                ));
+            }
+        }
+        debug!(%drained, "drained request body");
+        Ok(())
+    };
+
+    match tokio::time::timeout(conf.drain_timeout, drain).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            debug!(?e, "could not drain request body");
+            false
+        }
+        Err(_) => {
+            debug!("timed out draining request body");
+            false
+        }
+    }
+}
+
+/// Decides how the request body is delimited, cf.
+/// https://httpwg.org/specs/rfc9112.html#message.body.length, refusing
+/// requests that different implementations could read differently (request
+/// smuggling). Also returns whether the connection must be closed after the
+/// response, which is only ever true in lenient mode.
+fn request_framing(req: &Request, conf: &ServerConf) -> Result<(H1BodyKind, bool), SemanticError> {
+    let lenient = conf.lenient_parsing;
+    let headers = &req.headers;
+    let has_content_length = headers.contains_key(header::CONTENT_LENGTH);
+
+    if headers.contains_key(header::TRANSFER_ENCODING) {
+        let mut close = false;
+        if req.version == Version::HTTP_10 {
+            if !lenient {
+                return Err(SemanticError::Malformed(
+                    "transfer-encoding in an HTTP/1.0 request".into(),
+                ));
+            }
+            close = true;
+        }
+
+        let codings: Vec<&[u8]> = headers
+            .get_all(header::TRANSFER_ENCODING)
+            .iter()
+            .flat_map(|value| value.split(|&b| b == b','))
+            .map(trim_ows)
+            .filter(|coding| !coding.is_empty())
+            .collect();
+        let is_chunked = |coding: &[u8]| coding.eq_ignore_ascii_case(b"chunked");
+
+        if !codings.last().map_or(false, |coding| is_chunked(coding)) {
+            return Err(SemanticError::Malformed(
+                "chunked isn't the last transfer-coding".into(),
+            ));
         }
+        if codings.iter().filter(|coding| is_chunked(coding)).count() > 1 {
+            return Err(SemanticError::Malformed(
+                "chunked applied more than once".into(),
+            ));
+        }
+        if codings.len() > 1 {
+            return Err(SemanticError::UnsupportedTransferCoding);
+        }
+
+        if has_content_length {
+            if !lenient {
+                return Err(SemanticError::Malformed(
+                    "both transfer-encoding and content-length".into(),
+                ));
+            }
+            close = true;
+        }
+        let limits = ChunkedLimits {
+            max_chunk_header_len: conf.max_chunk_header_len,
+            max_trailers_len: conf.max_trailers_len,
+            max_body_len: conf.max_request_body_len,
+        };
+        return Ok((H1BodyKind::Chunked(limits), close));
+    }
+
+    // lists (`content-length: 42, 42`) are as ambiguous as repeated headers
+    let mut content_len = None;
+    for value in headers
+        .get_all(header::CONTENT_LENGTH)
+        .iter()
+        .flat_map(|value| value.split(|&b| b == b','))
+    {
+        let Some(len) = from_digits(trim_ows(value)) else {
+            return Err(SemanticError::Malformed("invalid content-length".into()));
+        };
+        match content_len {
+            None => content_len = Some(len),
+            Some(prev) if prev == len && lenient => {}
+            Some(_) => {
+                return Err(SemanticError::Malformed(
+                    "several content-length values".into(),
+                ))
+            }
+        }
+    }
+    Ok((
+        H1BodyKind::ContentLength(content_len.unwrap_or_default()),
+        false,
+    ))
+}
+
+fn trim_ows(bytes: &[u8]) -> &[u8] {
+    let is_ows = |b: &u8| *b == b' ' || *b == b'\t';
+    let start = bytes.iter().position(|b| !is_ows(b)).unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|b| !is_ows(b))
+        .map_or(start, |end| end + 1);
+    &bytes[start..end]
+}
+
+/// Returns the client's settings if the request asks to upgrade to h2c, cf.
+/// https://httpwg.org/specs/rfc7540.html#discover-http. We only switch for
+/// requests without a body, since it would have to be read in full first:
+/// other requests are served over HTTP/1.1, as the spec allows.
+fn h2c_upgrade_settings(req: &Request) -> Option<Settings> {
+    let headers = &req.headers;
+    if !has_token(headers, header::UPGRADE, b"h2c")
+        || !has_token(headers, header::CONNECTION, b"upgrade")
+        || !has_token(headers, header::CONNECTION, b"http2-settings")
+        || headers.is_chunked_transfer_encoding()
+        || headers.content_length().unwrap_or_default() != 0
+    {
+        return None;
+    }
+
+    // exactly one `HTTP2-Settings` header, with the base64url-encoded
+    // payload of a SETTINGS frame.
+    let mut values = headers.get_all("http2-settings").iter();
+    let (value, None) = (values.next()?, values.next()) else {
+        return None;
+    };
+    let payload = decode_base64url(value)?;
+    if payload.len() % 6 != 0 {
+        return None;
+    }
+
+    let mut settings = Settings::default();
+    for setting in payload.chunks_exact(6) {
+        let id = u16::from_be_bytes([setting[0], setting[1]]);
+        let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+        settings.apply(id, value).ok()?;
     }
+    Some(settings)
 }
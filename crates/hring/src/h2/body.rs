@@ -1,14 +1,103 @@
+use std::{cell::RefCell, fmt, rc::Rc, time::Duration};
+
 use tokio::sync::mpsc;
+use tracing::debug;
 
-use crate::{Body, BodyChunk};
+use crate::{util::timeout_opt, Body, BodyChunk, BodyErrorReason, Headers};
 use hring_buffet::Piece;
 
-#[derive(Debug)]
+use super::{
+    conn::{release_incoming_capacity, ConnState, HeadersData},
+    encode::H2ConnEvent,
+    parse::StreamId,
+    types::H2StreamError,
+};
+
+/// What the read loop hands over to an [H2Body]
+pub(crate) enum H2BodyItem {
+    Chunk(Piece),
+    /// Always the last item, cf. https://httpwg.org/specs/rfc9113.html#HttpFraming
+    Trailers(Box<Headers>),
+}
+
 pub(crate) struct H2Body {
     pub(crate) content_length: Option<u64>,
     pub(crate) eof: bool,
     // TODO: more specific error handling
-    pub(crate) rx: mpsc::Receiver<eyre::Result<Piece>>,
+    pub(crate) rx: mpsc::UnboundedReceiver<eyre::Result<H2BodyItem>>,
+
+    /// Used to let the peer send more DATA as we consume it
+    pub(crate) stream_id: StreamId,
+    pub(crate) ev_tx: mpsc::Sender<H2ConnEvent>,
+
+    /// Past that many bytes, the body errors out with
+    /// [BodyErrorReason::BodyTooLarge]
+    pub(crate) max_len: Option<u64>,
+    pub(crate) received: u64,
+
+    /// How long to wait for the next item before the body errors out with
+    /// [BodyErrorReason::TimedOutWhileReading], if at all
+    pub(crate) idle_timeout: Option<Duration>,
+
+    /// Lets a request body reset its stream when it times out
+    pub(crate) conn_state: Option<Rc<RefCell<ConnState<HeadersData>>>>,
+}
+
+impl fmt::Debug for H2Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("H2Body")
+            .field("content_length", &self.content_length)
+            .field("eof", &self.eof)
+            .field("stream_id", &self.stream_id)
+            .field("received", &self.received)
+            .finish()
+    }
+}
+
+impl H2Body {
+    /// Resets the stream without cancelling the handler, which is reading
+    /// this body: it finds out once it writes the response.
+    async fn reset_stream(&mut self, e: H2StreamError) {
+        let Some(conn_state) = &self.conn_state else {
+            return;
+        };
+        let error_code = e.as_known_error_code();
+        debug!(stream_id = %self.stream_id, ?error_code, "resetting stream: {e}");
+
+        if self
+            .ev_tx
+            .send(H2ConnEvent::RstStream {
+                stream_id: self.stream_id,
+                error_code,
+            })
+            .await
+            .is_err()
+        {
+            debug!("error sending rst_stream");
+        }
+        conn_state
+            .borrow_mut()
+            .reset_stream(self.stream_id, error_code.into(), false);
+        self.eof = true;
+
+        // DATA that came in but won't be read still counts against the
+        // connection window
+        let mut unread = 0;
+        while let Ok(item) = self.rx.try_recv() {
+            if let Ok(H2BodyItem::Chunk(piece)) = item {
+                unread += piece.len() as u32;
+            }
+        }
+        if unread > 0 {
+            self.release_capacity(StreamId::CONNECTION, unread).await;
+        }
+    }
+
+    async fn release_capacity(&self, stream_id: StreamId, increment: u32) {
+        if let Err(e) = release_incoming_capacity(&self.ev_tx, stream_id, increment).await {
+            debug!("could not release capacity: {e}");
+        }
+    }
 }
 
 impl Body for H2Body {
@@ -24,9 +113,41 @@ impl Body for H2Body {
         let chunk = if self.eof {
             BodyChunk::Done { trailers: None }
         } else {
-            match self.rx.recv().await {
-                Some(piece) => BodyChunk::Chunk(piece?),
-                // TODO: handle trailers
+            let Some(item) = timeout_opt(self.idle_timeout, self.rx.recv()).await else {
+                self.reset_stream(H2StreamError::RequestBodyTimedOut).await;
+                return Err(BodyErrorReason::TimedOutWhileReading
+                    .with_cx(format!("no data for {:?}", self.idle_timeout))
+                    .into());
+            };
+            match item {
+                Some(item) => match item? {
+                    H2BodyItem::Chunk(piece) => {
+                        self.received += piece.len() as u64;
+                        if let Some(max) = self.max_len.filter(|&max| self.received > max) {
+                            // the stream doesn't get its window back, so the
+                            // peer can't send more, but the connection does.
+                            self.release_capacity(StreamId::CONNECTION, piece.len() as _)
+                                .await;
+                            self.reset_stream(H2StreamError::RequestBodyTooLarge).await;
+                            return Err(BodyErrorReason::BodyTooLarge
+                                .with_cx(format!("got {} bytes, max is {max}", self.received))
+                                .into());
+                        }
+
+                        if !piece.is_empty() {
+                            // let the peer replace the data we've just consumed
+                            self.release_capacity(self.stream_id, piece.len() as _)
+                                .await;
+                        }
+                        BodyChunk::Chunk(piece)
+                    }
+                    H2BodyItem::Trailers(trailers) => {
+                        self.eof = true;
+                        BodyChunk::Done {
+                            trailers: Some(trailers),
+                        }
+                    }
+                },
                 None => {
                     self.eof = true;
                     BodyChunk::Done { trailers: None }
@@ -0,0 +1,1007 @@
+use std::{borrow::Cow, cell::RefCell, rc::Rc};
+
+use http::{header::HeaderName, StatusCode, Version};
+use nom::Finish;
+use smallvec::smallvec;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{debug, trace};
+
+use crate::{
+    h1::body::BodyWriteMode,
+    h2::{
+        body::{H2Body, H2BodyItem},
+        conn::{
+            apply_settings, apply_window_update, decode_field_block, end_trailers, h2_write_loop,
+            release_incoming_capacity, send_goaway, send_rst_stream, strip_padding, ConnState,
+            HeadersData, HeadersStage, StreamRxStage,
+        },
+        encode::{EncoderState, H2ConnEvent, H2Encoder, H2Event, H2EventPayload},
+        parse::{
+            self, ContinuationFlags, DataFlags, ErrorCode, Frame, FrameType, GoAway, HeadersFlags,
+            KnownErrorCode, PingFlags, PrioritySpec, SettingIdentifier, Settings, SettingsFlags,
+            StreamId, WindowUpdate,
+        },
+        priority::Priority,
+        types::{H2ConnectionError, H2StreamError},
+    },
+    util::read_and_parse,
+    Body, BodyChunk, ClientDriver, Encoder, Headers, HeadersExt, Request, Response,
+};
+use hring_buffet::{Piece, ReadWriteOwned, Roll, RollMut};
+
+/// HTTP/2 client configuration
+pub struct ClientConf {
+    /// Max size of the HPACK dynamic table the peer may use when encoding
+    /// response headers, advertised as SETTINGS_HEADER_TABLE_SIZE
+    pub header_table_size: u32,
+
+    /// Initial flow-control window size for response bodies, advertised as
+    /// SETTINGS_INITIAL_WINDOW_SIZE. Must be at most 2^31-1.
+    pub initial_window_size: u32,
+
+    /// Largest frame payload we accept, advertised as SETTINGS_MAX_FRAME_SIZE.
+    /// Must be between 2^14 and 2^24-1.
+    pub max_frame_size: u32,
+
+    /// Max size of response headers (as computed by HPACK), advertised as
+    /// SETTINGS_MAX_HEADER_LIST_SIZE. Larger responses error out.
+    pub max_header_list_size: u32,
+}
+
+impl Default for ClientConf {
+    fn default() -> Self {
+        Self {
+            header_table_size: parse::DEFAULT_HEADER_TABLE_SIZE,
+            initial_window_size: parse::DEFAULT_INITIAL_WINDOW_SIZE,
+            max_frame_size: parse::DEFAULT_MAX_FRAME_SIZE,
+            max_header_list_size: 64 * 1024,
+        }
+    }
+}
+
+impl ClientConf {
+    /// The SETTINGS parameters we send to the peer when the connection
+    /// starts. We have no use for server push, so it's disabled.
+    fn settings(&self) -> [(SettingIdentifier, u32); 5] {
+        [
+            (SettingIdentifier::HeaderTableSize, self.header_table_size),
+            (SettingIdentifier::EnablePush, 0),
+            (
+                SettingIdentifier::InitialWindowSize,
+                self.initial_window_size,
+            ),
+            (SettingIdentifier::MaxFrameSize, self.max_frame_size),
+            (
+                SettingIdentifier::MaxHeaderListSize,
+                self.max_header_list_size,
+            ),
+        ]
+    }
+}
+
+/// An HTTP/2 connection to a server, over which any number of requests can
+/// be multiplexed with [request]. Clones are handles to the same connection,
+/// which closes once they're all dropped.
+#[derive(Clone)]
+pub struct Connection {
+    inner: Rc<ConnectionInner>,
+}
+
+struct ConnectionInner {
+    ev_tx: mpsc::Sender<H2ConnEvent>,
+    state: Rc<RefCell<ConnState<ResponseStage>>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for ConnectionInner {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Where the read loop sends a response, for streams we opened
+pub(crate) struct ResponseTx {
+    /// Informational responses, then the final response
+    headers_tx: mpsc::UnboundedSender<eyre::Result<Response>>,
+    body_tx: mpsc::UnboundedSender<eyre::Result<H2BodyItem>>,
+}
+
+/// Where a stream we opened is at until the final response's field block is
+/// in, see [StreamRxStage::Headers]
+pub(crate) enum ResponseStage {
+    /// Waiting for the response's field block. Informational responses leave
+    /// the stream in this stage.
+    Awaiting(ResponseTx),
+
+    /// Receiving the response's field block
+    Headers(ResponseTx, HeadersData),
+}
+
+impl HeadersStage for ResponseStage {
+    fn fail(&self, e: eyre::Report) {
+        let (Self::Awaiting(res_tx) | Self::Headers(res_tx, _)) = self;
+        _ = res_tx.headers_tx.send(Err(e));
+    }
+}
+
+impl Connection {
+    /// Sends the connection preface and our settings, then waits for the
+    /// server's settings, cf. https://httpwg.org/specs/rfc9113.html#preface
+    pub async fn handshake(
+        transport: impl ReadWriteOwned + 'static,
+        conf: Rc<ClientConf>,
+    ) -> eyre::Result<Self> {
+        // validate our own settings the same way we validate the peer's
+        let mut self_settings = Settings::default();
+        let mut settings_payload = Vec::with_capacity(conf.settings().len() * 6);
+        for (id, value) in conf.settings() {
+            if self_settings.apply(id.repr(), value).is_err() {
+                return Err(eyre::eyre!("invalid h2::ClientConf: bad {id:?} ({value})"));
+            }
+
+            use byteorder::{BigEndian, WriteBytesExt};
+            settings_payload.write_u16::<BigEndian>(id.repr())?;
+            settings_payload.write_u32::<BigEndian>(value)?;
+        }
+
+        let transport = Rc::new(transport);
+        let (res, _) = transport.write_all(parse::PREFACE).await;
+        res?;
+        debug!("sent preface");
+
+        let state = Rc::new(RefCell::new(ConnState::default()));
+        let (ev_tx, ev_rx) = mpsc::channel::<H2ConnEvent>(32);
+        let (settings_tx, settings_rx) = oneshot::channel();
+        let server_buf = RollMut::alloc()?;
+
+        let task = tokio_uring::spawn({
+            let ev_tx = ev_tx.clone();
+            let state = state.clone();
+            async move {
+                let read_task = h2_client_read_loop(
+                    ev_tx.clone(),
+                    transport.clone(),
+                    server_buf,
+                    state.clone(),
+                    conf,
+                    self_settings,
+                    settings_tx,
+                );
+                let write_task = h2_write_loop(ev_rx, transport, state.clone(), settings_payload);
+                tokio::pin!(write_task);
+
+                // the connection handle keeps an event sender alive, so the
+                // write loop doesn't end on its own: whichever loop is done
+                // first takes the connection down, except that a GOAWAY
+                // must make it out first.
+                let res = tokio::select! {
+                    res = read_task => match res.map_err(|e| e.downcast::<H2ConnectionError>()) {
+                        Err(Ok(e)) => {
+                            fail_streams(&state);
+                            // we disable server push, so the server never
+                            // opened a stream we could have processed
+                            send_goaway(&ev_tx, StreamId::CONNECTION, &e).await;
+                            // the write loop ends once that's written
+                            write_task.await
+                        }
+                        Err(Err(e)) => Err(e),
+                        Ok(()) => Ok(()),
+                    },
+                    res = &mut write_task => res,
+                };
+                if let Err(e) = res {
+                    debug!("h2 client connection error: {e:?}");
+                }
+                fail_streams(&state);
+            }
+        });
+
+        let conn = Self {
+            inner: Rc::new(ConnectionInner { ev_tx, state, task }),
+        };
+        if settings_rx.await.is_err() {
+            return Err(eyre::eyre!("server went away before sending its settings"));
+        }
+        debug!("got server settings, handshake complete");
+
+        Ok(conn)
+    }
+
+    /// Waits until the server lets us open one more stream, then reserves
+    /// room for its HEADERS in the event queue: stream ids must go up in the
+    /// order the streams are opened, cf. https://httpwg.org/specs/rfc9113.html#StreamIdentifiers
+    async fn reserve_stream(&self) -> eyre::Result<mpsc::Permit<'_, H2ConnEvent>> {
+        let has_room = |state: &ConnState<ResponseStage>| -> eyre::Result<bool> {
+            if state.peer_goaway_last_stream_id.is_some() {
+                return Err(eyre::eyre!("server sent GOAWAY, can't open new streams"));
+            }
+            Ok(match state.peer_settings.max_concurrent_streams {
+                Some(max) => state.streams.len() < max as usize,
+                None => true,
+            })
+        };
+
+        loop {
+            let notify = self.inner.state.borrow().stream_closed_notify.clone();
+            let stream_closed = notify.notified();
+            if !has_room(&self.inner.state.borrow())? {
+                debug!("at SETTINGS_MAX_CONCURRENT_STREAMS, waiting for a stream to close");
+                stream_closed.await;
+                continue;
+            }
+
+            let permit = self
+                .inner
+                .ev_tx
+                .reserve()
+                .await
+                .map_err(|_| eyre::eyre!("h2 connection is closed"))?;
+            // another request may have taken the room while we waited
+            if has_room(&self.inner.state.borrow())? {
+                return Ok(permit);
+            }
+        }
+    }
+}
+
+/// Perform a request over an HTTP/2 connection, on its own stream
+pub async fn request<D>(
+    conn: &Connection,
+    mut req: Request,
+    body: &mut impl Body,
+    mut driver: D,
+) -> eyre::Result<D::Return>
+where
+    D: ClientDriver,
+{
+    let end_stream = match body.content_len() {
+        Some(0) => true,
+        Some(len) => {
+            req.headers.insert(
+                http::header::CONTENT_LENGTH,
+                len.to_string().into_bytes().into(),
+            );
+            false
+        }
+        None => false,
+    };
+
+    let (headers_tx, mut headers_rx) = mpsc::unbounded_channel();
+    let (body_tx, body_rx) = mpsc::unbounded_channel();
+
+    let permit = conn.reserve_stream().await?;
+    let stream_id = {
+        let mut state = conn.inner.state.borrow_mut();
+        let stream_id = match state.last_stream_id {
+            StreamId::CONNECTION => StreamId(1),
+            StreamId(id) => match StreamId::try_from(id + 2) {
+                Ok(stream_id) => stream_id,
+                Err(_) => return Err(eyre::eyre!("ran out of stream ids on this h2 connection")),
+            },
+        };
+        state.last_stream_id = stream_id;
+
+        let mut ss = state.new_stream(StreamRxStage::Headers(ResponseStage::Awaiting(
+            ResponseTx {
+                headers_tx,
+                body_tx,
+            },
+        )));
+        // the request body gets scheduled like response bodies would
+        ss.priority = Priority::from_headers(&req.headers);
+        state.streams.insert(stream_id, ss);
+        stream_id
+    };
+    debug!(%stream_id, "opening stream for request");
+    permit.send(H2ConnEvent::StreamEvent(H2Event {
+        stream_id,
+        payload: H2EventPayload::RequestHeaders { req, end_stream },
+    }));
+
+    let send_body_fut = async {
+        if end_stream {
+            return Ok(());
+        }
+
+        // DATA frames are subject to the same flow control and framing
+        // whichever side sends them.
+        let mut encoder = H2Encoder {
+            stream_id,
+            tx: conn.inner.ev_tx.clone(),
+            state: EncoderState::ExpectResponseBody,
+            conn_state: conn.inner.state.clone(),
+            head_request: false,
+        };
+        // the body write mode is irrelevant for h2
+        let mode = BodyWriteMode::Chunked;
+        loop {
+            match body.next_chunk().await? {
+                BodyChunk::Chunk(chunk) => encoder.write_body_chunk(chunk, mode).await?,
+                BodyChunk::Done { trailers } => {
+                    match trailers {
+                        Some(trailers) => encoder.write_trailers(trailers).await?,
+                        None => encoder.write_body_end(mode).await?,
+                    }
+                    debug!(%stream_id, "done writing request body");
+                    return Ok::<_, eyre::Report>(());
+                }
+            }
+        }
+    };
+
+    let recv_res_fut = async {
+        let res = loop {
+            let res = headers_rx
+                .recv()
+                .await
+                .ok_or_else(|| eyre::eyre!("stream {stream_id} closed before response"))??;
+            if res.status.is_informational() {
+                driver.on_informational_response(res).await?;
+                continue;
+            }
+            break res;
+        };
+        debug!(%stream_id, status = %res.status, "client received response");
+
+        let mut res_body = H2Body {
+            content_length: res.headers.content_length(),
+            eof: false,
+            rx: body_rx,
+            stream_id,
+            ev_tx: conn.inner.ev_tx.clone(),
+            max_len: None,
+            received: 0,
+            idle_timeout: None,
+            conn_state: None,
+        };
+        driver.on_final_response(res, &mut res_body).await
+    };
+
+    tokio::pin!(send_body_fut, recv_res_fut);
+    tokio::select! {
+        biased;
+        body_res = &mut send_body_fut => {
+            if let Err(e) = body_res {
+                // if that mattered, the stream got reset and the response errored out
+                debug!(%stream_id, "error sending request body: {e}");
+            }
+            recv_res_fut.await
+        }
+        ret = &mut recv_res_fut => {
+            // a server that answered early may never give us the window to
+            // send the rest of the body, so stop trying.
+            stop_request_body(conn, stream_id).await;
+            ret
+        }
+    }
+}
+
+/// Resets a stream whose response we're done with while its request body is
+/// still being sent: with NO_ERROR if the response was complete, cf.
+/// https://httpwg.org/specs/rfc9113.html#HttpFraming, with CANCEL otherwise.
+async fn stop_request_body(conn: &Connection, stream_id: StreamId) {
+    let error_code = match conn.inner.state.borrow().streams.get(&stream_id) {
+        // closed or reset already
+        None => return,
+        Some(ss) if matches!(ss.rx_stage, StreamRxStage::Done) => KnownErrorCode::NoError,
+        Some(_) => KnownErrorCode::Cancel,
+    };
+    debug!(%stream_id, ?error_code, "done with the response, no longer sending the request body");
+
+    if conn
+        .inner
+        .ev_tx
+        .send(H2ConnEvent::RstStream {
+            stream_id,
+            error_code,
+        })
+        .await
+        .is_err()
+    {
+        debug!("error sending rst_stream");
+    }
+    // the request body's encoder, once dropped, finds the stream gone
+    conn.inner
+        .state
+        .borrow_mut()
+        .reset_stream(stream_id, error_code.into(), false);
+}
+
+/// Errors out every request still in flight, once the connection is gone
+fn fail_streams(state: &RefCell<ConnState<ResponseStage>>) {
+    let mut state = state.borrow_mut();
+    for (stream_id, ss) in std::mem::take(&mut state.streams) {
+        let err = || eyre::eyre!("h2 connection closed before stream {stream_id} was done");
+        match ss.rx_stage {
+            StreamRxStage::Headers(stage) => stage.fail(err()),
+            StreamRxStage::Body(tx) | StreamRxStage::Trailers(Some(tx), _) => {
+                _ = tx.send(Err(err()));
+            }
+            _ => {}
+        }
+    }
+    // request bodies waiting for capacity find their stream gone
+    state.outgoing_capacity_notify.notify_waiters();
+    state.stream_closed_notify.notify_waiters();
+}
+
+async fn h2_client_read_loop(
+    ev_tx: mpsc::Sender<H2ConnEvent>,
+    transport: Rc<impl ReadWriteOwned>,
+    mut server_buf: RollMut,
+    state: Rc<RefCell<ConnState<ResponseStage>>>,
+    conf: Rc<ClientConf>,
+    self_settings: Settings,
+    settings_tx: oneshot::Sender<()>,
+) -> eyre::Result<()> {
+    let mut rl = ClientReadLoop {
+        ev_tx,
+        state,
+        conf,
+        self_settings,
+        settings_tx: Some(settings_tx),
+        hpack_dec: hring_hpack::Decoder::new(),
+        continuing_headers: None,
+        orphan_headers: None,
+    };
+    // until the server acknowledges our settings, it may still assume the
+    // default table size.
+    rl.hpack_dec.set_max_allowed_table_size(std::cmp::max(
+        self_settings.header_table_size,
+        parse::DEFAULT_HEADER_TABLE_SIZE,
+    ) as usize);
+
+    loop {
+        let frame;
+        (server_buf, frame) =
+            match read_and_parse(Frame::parse, transport.as_ref(), server_buf, 32 * 1024).await? {
+                Some((server_buf, frame)) => (server_buf, frame),
+                None => {
+                    debug!("h2 server closed connection");
+                    return Ok(());
+                }
+            };
+        debug!(?frame, "client received h2 frame");
+
+        let max_frame_size = rl.state.borrow().self_settings.max_frame_size;
+        if frame.len > max_frame_size {
+            let e = H2ConnectionError::FrameTooLarge {
+                frame_size: frame.len,
+                max_frame_size,
+            };
+            return Err(e.into());
+        }
+
+        let payload: Roll = if frame.len == 0 {
+            Roll::empty()
+        } else {
+            let payload;
+            (server_buf, payload) = match read_and_parse(
+                nom::bytes::streaming::take(frame.len as usize),
+                transport.as_ref(),
+                server_buf,
+                frame.len as usize,
+            )
+            .await?
+            {
+                Some(t) => t,
+                None => {
+                    debug!("h2 server closed connection while sending a frame payload");
+                    return Ok(());
+                }
+            };
+            payload
+        };
+
+        rl.handle_frame(frame, payload).await?;
+    }
+}
+
+struct ClientReadLoop {
+    ev_tx: mpsc::Sender<H2ConnEvent>,
+    state: Rc<RefCell<ConnState<ResponseStage>>>,
+    conf: Rc<ClientConf>,
+    self_settings: Settings,
+    /// Fired once the server's first SETTINGS frame has been applied
+    settings_tx: Option<oneshot::Sender<()>>,
+    hpack_dec: hring_hpack::Decoder<'static>,
+    /// Set while a field block continues in CONTINUATION frames
+    continuing_headers: Option<StreamId>,
+    /// A field block for a stream that's gone: it still needs decoding, to
+    /// keep the HPACK state in sync.
+    orphan_headers: Option<HeadersData>,
+}
+
+impl ClientReadLoop {
+    /// Protocol violations that take the whole connection down are returned
+    /// as [H2ConnectionError].
+    async fn handle_frame(&mut self, frame: Frame, mut payload: Roll) -> eyre::Result<()> {
+        if let Some(expected) = self.continuing_headers {
+            if !matches!(frame.frame_type, FrameType::Continuation(_))
+                || frame.stream_id != expected
+            {
+                return Err(H2ConnectionError::ExpectedContinuation {
+                    stream_id: expected,
+                }
+                .into());
+            }
+        }
+
+        match frame.frame_type {
+            FrameType::Data(flags) => {
+                if frame.stream_id == StreamId::CONNECTION {
+                    return Err(H2ConnectionError::DataForIdleStream {
+                        stream_id: frame.stream_id,
+                    }
+                    .into());
+                }
+
+                // the entire payload counts towards flow control, including
+                // padding, cf. https://httpwg.org/specs/rfc9113.html#DATA
+                if !self
+                    .state
+                    .borrow_mut()
+                    .consume_incoming_capacity(frame.stream_id, frame.len)
+                {
+                    return Err(H2ConnectionError::FlowControlWindowExceeded {
+                        stream_id: frame.stream_id,
+                    }
+                    .into());
+                }
+
+                payload = strip_padding(payload, flags.contains(DataFlags::Padded))?;
+                let padding = frame.len - payload.len() as u32;
+
+                let body_tx = {
+                    let mut state = self.state.borrow_mut();
+                    match state.streams.get_mut(&frame.stream_id) {
+                        Some(ss) => match &ss.rx_stage {
+                            StreamRxStage::Body(tx) => {
+                                let tx = tx.clone();
+                                if flags.contains(DataFlags::EndStream) {
+                                    ss.rx_stage = StreamRxStage::Done;
+                                    state.close_stream_if_done(frame.stream_id);
+                                }
+                                Ok(Some(tx))
+                            }
+                            // DATA before the response headers
+                            StreamRxStage::Headers(ResponseStage::Awaiting(_)) => {
+                                Err(H2StreamError::MalformedMessage {
+                                    reason: "data before response headers",
+                                })
+                            }
+                            _ => Err(H2StreamError::DataAfterEndStream),
+                        },
+                        None => Ok(None),
+                    }
+                };
+
+                let body_tx = match body_tx {
+                    Ok(Some(tx)) => tx,
+                    Ok(None) => {
+                        // we've reset that stream, or it's done
+                        release_incoming_capacity(&self.ev_tx, StreamId::CONNECTION, frame.len)
+                            .await?;
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        send_rst_stream(&self.ev_tx, &self.state, frame.stream_id, e).await;
+                        release_incoming_capacity(&self.ev_tx, StreamId::CONNECTION, frame.len)
+                            .await?;
+                        return Ok(());
+                    }
+                };
+
+                // the body gives back capacity as it's read, but padding never
+                // makes it there.
+                let mut unused_capacity = padding;
+                if !payload.is_empty() {
+                    let len = payload.len() as u32;
+                    if body_tx.send(Ok(H2BodyItem::Chunk(payload.into()))).is_err() {
+                        debug!(stream_id = %frame.stream_id, "response body was dropped, cancelling stream");
+                        send_rst_stream(
+                            &self.ev_tx,
+                            &self.state,
+                            frame.stream_id,
+                            H2StreamError::ResponseBodyDropped,
+                        )
+                        .await;
+                        release_incoming_capacity(&self.ev_tx, StreamId::CONNECTION, frame.len)
+                            .await?;
+                        return Ok(());
+                    }
+                    unused_capacity = frame.len - len;
+                }
+
+                if unused_capacity > 0 {
+                    release_incoming_capacity(&self.ev_tx, frame.stream_id, unused_capacity)
+                        .await?;
+                }
+            }
+            FrameType::Headers(flags) => {
+                if frame.stream_id == StreamId::CONNECTION {
+                    return Err(H2ConnectionError::HeadersForIdleStream {
+                        stream_id: frame.stream_id,
+                    }
+                    .into());
+                }
+
+                payload = strip_padding(payload, flags.contains(HeadersFlags::Padded))?;
+                let mut self_dependent = false;
+                if flags.contains(HeadersFlags::Priority) {
+                    // priority signals are deprecated, cf. https://httpwg.org/specs/rfc9113.html#PriorityHere
+                    if payload.len() < PrioritySpec::LEN {
+                        return Err(H2ConnectionError::HeadersFrameTooShortForPriority.into());
+                    }
+                    let pri_spec;
+                    (payload, pri_spec) = PrioritySpec::parse(payload)
+                        .finish()
+                        .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                    // ...but still can't be nonsensical, cf. https://httpwg.org/specs/rfc9113.html#pri-depend
+                    self_dependent = pri_spec.stream_dependency == frame.stream_id;
+                }
+
+                let data = HeadersData {
+                    end_stream: flags.contains(HeadersFlags::EndStream),
+                    rejected: self_dependent.then_some(H2StreamError::SelfDependency),
+                    fragments: smallvec![payload],
+                };
+
+                let known = {
+                    let mut state = self.state.borrow_mut();
+                    match state.streams.get_mut(&frame.stream_id) {
+                        Some(ss) => {
+                            ss.rx_stage =
+                                match std::mem::replace(&mut ss.rx_stage, StreamRxStage::Done) {
+                                    StreamRxStage::Headers(ResponseStage::Awaiting(res_tx)) => {
+                                        StreamRxStage::Headers(ResponseStage::Headers(res_tx, data))
+                                    }
+                                    StreamRxStage::Body(tx) => {
+                                        StreamRxStage::Trailers(Some(tx), data)
+                                    }
+                                    _ => StreamRxStage::Trailers(None, data),
+                                };
+                            true
+                        }
+                        None => {
+                            // servers only open streams with server push,
+                            // which we disable.
+                            if frame.stream_id.0 % 2 == 0 || frame.stream_id > state.last_stream_id
+                            {
+                                return Err(H2ConnectionError::HeadersForIdleStream {
+                                    stream_id: frame.stream_id,
+                                }
+                                .into());
+                            }
+                            self.orphan_headers = Some(data);
+                            false
+                        }
+                    }
+                };
+
+                if flags.contains(HeadersFlags::EndHeaders) {
+                    return self.end_headers(frame.stream_id, known).await;
+                }
+                self.continuing_headers = Some(frame.stream_id);
+            }
+            FrameType::Continuation(flags) => {
+                if self.continuing_headers.is_none() {
+                    return Err(H2ConnectionError::UnexpectedContinuation {
+                        stream_id: frame.stream_id,
+                    }
+                    .into());
+                }
+
+                let known = match &mut self.orphan_headers {
+                    Some(data) => {
+                        data.fragments.push(payload);
+                        false
+                    }
+                    None => {
+                        let mut state = self.state.borrow_mut();
+                        let data = match state.streams.get_mut(&frame.stream_id) {
+                            Some(ss) => match &mut ss.rx_stage {
+                                StreamRxStage::Headers(ResponseStage::Headers(_, data))
+                                | StreamRxStage::Trailers(_, data) => data,
+                                _ => unreachable!(
+                                    "continuation for stream that's not receiving headers"
+                                ),
+                            },
+                            None => {
+                                // it got reset mid-block, and the fragments
+                                // we had are gone with it.
+                                return Err(H2ConnectionError::UnexpectedContinuation {
+                                    stream_id: frame.stream_id,
+                                }
+                                .into());
+                            }
+                        };
+                        data.fragments.push(payload);
+                        true
+                    }
+                };
+
+                if flags.contains(ContinuationFlags::EndHeaders) {
+                    self.continuing_headers = None;
+                    return self.end_headers(frame.stream_id, known).await;
+                }
+            }
+            FrameType::RstStream => {
+                if frame.stream_id == StreamId::CONNECTION {
+                    return Err(H2ConnectionError::RstStreamOnConnectionStream.into());
+                }
+                if frame.len != 4 {
+                    return Err(H2ConnectionError::RstStreamInvalidLength { len: frame.len }.into());
+                }
+                if frame.stream_id > self.state.borrow().last_stream_id {
+                    return Err(H2ConnectionError::RstStreamForIdleStream {
+                        stream_id: frame.stream_id,
+                    }
+                    .into());
+                }
+
+                let (_, error_code) = ErrorCode::parse(payload)
+                    .finish()
+                    .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                debug!(stream_id = %frame.stream_id, ?error_code, "server reset stream");
+                self.state
+                    .borrow_mut()
+                    .reset_stream(frame.stream_id, error_code, true);
+            }
+            FrameType::Settings(flags) => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::SettingsOnStream {
+                        stream_id: frame.stream_id,
+                    }
+                    .into());
+                }
+
+                if flags.contains(SettingsFlags::Ack) {
+                    if frame.len != 0 {
+                        return Err(
+                            H2ConnectionError::SettingsAckWithPayload { len: frame.len }.into()
+                        );
+                    }
+                    debug!("server has acknowledged our settings");
+                    self.state
+                        .borrow_mut()
+                        .apply_self_settings(self.self_settings);
+                    self.hpack_dec
+                        .set_max_allowed_table_size(self.self_settings.header_table_size as usize);
+                    return Ok(());
+                }
+
+                let settings = apply_settings(&self.state, payload)
+                    .map_err(|error_code| H2ConnectionError::InvalidSettings { error_code })?;
+                self.send(H2ConnEvent::AcknowledgeSettings {
+                    header_table_size: settings.header_table_size,
+                })
+                .await?;
+
+                if let Some(settings_tx) = self.settings_tx.take() {
+                    _ = settings_tx.send(());
+                }
+            }
+            FrameType::Ping(flags) => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::PingOnStream {
+                        stream_id: frame.stream_id,
+                    }
+                    .into());
+                }
+                if frame.len != 8 {
+                    return Err(H2ConnectionError::PingInvalidLength { len: frame.len }.into());
+                }
+                if !flags.contains(PingFlags::Ack) {
+                    self.send(H2ConnEvent::Ping(payload)).await?;
+                }
+            }
+            FrameType::GoAway => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::GoAwayOnStream {
+                        stream_id: frame.stream_id,
+                    }
+                    .into());
+                }
+                if frame.len < 8 {
+                    return Err(H2ConnectionError::GoAwayInvalidLength { len: frame.len }.into());
+                }
+
+                let (additional_debug_data, goaway) = GoAway::parse(payload)
+                    .finish()
+                    .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                debug!(
+                    last_stream_id = %goaway.last_stream_id,
+                    error_code = ?goaway.error_code,
+                    additional_debug_data = %String::from_utf8_lossy(&additional_debug_data[..]),
+                    "server sent goaway"
+                );
+
+                // streams above the last stream id were never processed, so
+                // they're safe to retry elsewhere, cf. https://httpwg.org/specs/rfc9113.html#GOAWAY
+                let mut state = self.state.borrow_mut();
+                state.peer_goaway_last_stream_id = Some(goaway.last_stream_id);
+                let unprocessed: Vec<StreamId> = state
+                    .streams
+                    .keys()
+                    .copied()
+                    .filter(|&id| id > goaway.last_stream_id)
+                    .collect();
+                for stream_id in unprocessed {
+                    state.reset_stream(stream_id, KnownErrorCode::RefusedStream.into(), true);
+                }
+                state.stream_closed_notify.notify_waiters();
+            }
+            FrameType::WindowUpdate => {
+                if frame.len != 4 {
+                    return Err(
+                        H2ConnectionError::WindowUpdateInvalidLength { len: frame.len }.into(),
+                    );
+                }
+
+                let (_, update) = WindowUpdate::parse(payload)
+                    .finish()
+                    .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                if let Err(e) = apply_window_update(&self.state, frame.stream_id, update.increment)?
+                {
+                    send_rst_stream(&self.ev_tx, &self.state, frame.stream_id, e).await;
+                }
+            }
+            FrameType::PushPromise => {
+                return Err(H2ConnectionError::PushPromiseReceived.into());
+            }
+            FrameType::PriorityUpdate => {
+                return Err(H2ConnectionError::PriorityUpdateReceived.into());
+            }
+            FrameType::Priority => {
+                // deprecated, cf. https://httpwg.org/specs/rfc9113.html#PRIORITY
+                trace!("ignoring priority frame");
+            }
+            FrameType::Unknown(ft) => {
+                trace!(
+                    "ignoring unknown frame with type 0x{:x}, flags 0x{:x}",
+                    ft.ty,
+                    ft.flags
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send(&self, ev: H2ConnEvent) -> eyre::Result<()> {
+        self.ev_tx
+            .send(ev)
+            .await
+            .map_err(|_| eyre::eyre!("could not send event to h2 write loop"))
+    }
+
+    /// Decodes a field block once it's complete: it's either a response
+    /// (informational or final), or trailers.
+    async fn end_headers(&mut self, stream_id: StreamId, known: bool) -> eyre::Result<()> {
+        if !known {
+            let data = self
+                .orphan_headers
+                .take()
+                .expect("orphan field block must be set");
+            decode_field_block(&mut self.hpack_dec, &data.fragments, |_, _| {})?;
+            return Ok(());
+        }
+
+        let is_trailers = matches!(
+            self.state.borrow().streams.get(&stream_id),
+            Some(ss) if matches!(ss.rx_stage, StreamRxStage::Trailers(..))
+        );
+        if is_trailers {
+            end_trailers(
+                &self.ev_tx,
+                &self.state,
+                self.conf.max_header_list_size,
+                stream_id,
+                &mut self.hpack_dec,
+            )
+            .await?;
+            return Ok(());
+        }
+
+        let (res_tx, data) = {
+            let mut state = self.state.borrow_mut();
+            let ss = state
+                .streams
+                .get_mut(&stream_id)
+                .expect("stream is known, checked above");
+            match std::mem::replace(&mut ss.rx_stage, StreamRxStage::Done) {
+                StreamRxStage::Headers(ResponseStage::Headers(res_tx, data)) => (res_tx, data),
+                _ => unreachable!("stream {stream_id} wasn't receiving response headers"),
+            }
+        };
+
+        let mut status: Option<StatusCode> = None;
+        let mut headers = Headers::default();
+        let mut malformed: Option<&'static str> = None;
+        let mut header_list_size = 0;
+        let max_header_list_size = self.conf.max_header_list_size as usize;
+
+        let cb = |key: Cow<[u8]>, value: Cow<[u8]>| {
+            header_list_size += key.len() + value.len() + 32;
+            if malformed.is_some() {
+                // keep decoding so the HPACK state stays in sync
+                return;
+            }
+            if header_list_size > max_header_list_size {
+                malformed = Some("response headers too large");
+                return;
+            }
+
+            if key.first() == Some(&b':') {
+                // :status is the only response pseudo-header, and it comes
+                // first, cf. https://httpwg.org/specs/rfc9113.html#HttpResponse
+                if &key[..] == b":status" && status.is_none() && headers.is_empty() {
+                    status = StatusCode::from_bytes(&value[..]).ok();
+                }
+                if status.is_none() {
+                    malformed = Some("invalid or misplaced pseudo-header");
+                }
+                return;
+            }
+
+            match HeaderName::from_bytes(&key[..]) {
+                Ok(name) => {
+                    let value: Piece = value.to_vec().into();
+                    headers.append(name, value);
+                }
+                Err(_) => malformed = Some("invalid header name"),
+            }
+        };
+
+        decode_field_block(&mut self.hpack_dec, &data.fragments, cb)?;
+
+        let status = match (status, malformed) {
+            // an informational response can't end the stream
+            (Some(status), None) if status.is_informational() && data.end_stream => {
+                Err("informational response with END_STREAM")
+            }
+            (Some(status), None) => Ok(status),
+            (None, None) => Err("missing :status"),
+            (_, Some(reason)) => Err(reason),
+        }
+        .map_err(|reason| H2StreamError::MalformedMessage { reason });
+        let status = match data.rejected.map(Err).unwrap_or(status) {
+            Ok(status) => status,
+            Err(e) => {
+                debug!(%stream_id, "rejecting response: {e}");
+                // put the response sender back, so the request errors out
+                if let Some(ss) = self.state.borrow_mut().streams.get_mut(&stream_id) {
+                    ss.rx_stage = StreamRxStage::Headers(ResponseStage::Awaiting(res_tx));
+                }
+                send_rst_stream(&self.ev_tx, &self.state, stream_id, e).await;
+                return Ok(());
+            }
+        };
+
+        let res = Response {
+            version: Version::HTTP_2,
+            status,
+            headers,
+        };
+        _ = res_tx.headers_tx.send(Ok(res));
+
+        let mut state = self.state.borrow_mut();
+        if let Some(ss) = state.streams.get_mut(&stream_id) {
+            ss.rx_stage = if status.is_informational() {
+                StreamRxStage::Headers(ResponseStage::Awaiting(res_tx))
+            } else if data.end_stream {
+                // dropping the body sender ends the response body
+                StreamRxStage::Done
+            } else {
+                StreamRxStage::Body(res_tx.body_tx)
+            };
+        }
+        state.close_stream_if_done(stream_id);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1091 @@
+//! State and frame handling shared by the HTTP/2 server and client: the
+//! connection and its streams, flow control, and the write loop.
+
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    rc::Rc,
+    time::Instant,
+};
+
+use enumflags2::BitFlags;
+use http::header::{self, HeaderName};
+use nom::Finish;
+use smallvec::SmallVec;
+use tokio::{
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    h2::{
+        body::H2BodyItem,
+        encode::{H2ConnEvent, H2EventPayload},
+        parse::{
+            self, ContinuationFlags, DataFlags, ErrorCode, Frame, FrameType, HeadersFlags,
+            KnownErrorCode, PingFlags, SettingIdentifier, Settings, SettingsFlags, StreamId,
+        },
+        priority::{Priority, WriteScheduler},
+        types::{H2ConnectionError, H2StreamError, H2StreamReset},
+    },
+    Headers,
+};
+use hring_buffet::{Piece, ReadWriteOwned, Roll};
+
+/// State shared by the read loop, the write loop and the streams of an HTTP/2
+/// connection, as a server or as a client. `H` is what streams hold while
+/// their first field block is pending, see [HeadersStage].
+pub(crate) struct ConnState<H> {
+    /// Streams that are open or half-closed. Closed streams are removed, and
+    /// the length of this map is what SETTINGS_MAX_CONCURRENT_STREAMS limits.
+    pub(crate) streams: HashMap<StreamId, StreamState<H>>,
+
+    /// The highest stream id opened so far: by the peer when serving, by us
+    /// as a client. New streams must have a higher one, cf.
+    /// https://httpwg.org/specs/rfc9113.html#StreamIdentifiers
+    pub(crate) last_stream_id: StreamId,
+
+    /// How many bytes of DATA we can still send on this connection before the
+    /// peer gives us more room with a WINDOW_UPDATE frame.
+    pub(crate) outgoing_capacity: i64,
+
+    /// How many bytes of DATA the peer can still send us on this connection
+    /// before we give it more room.
+    pub(crate) incoming_capacity: i64,
+
+    /// Settings the peer sent us, which constrain what we send.
+    pub(crate) peer_settings: Settings,
+
+    /// Settings we sent the peer, which constrain what it sends. These are
+    /// the defaults until the peer acknowledges ours.
+    pub(crate) self_settings: Settings,
+
+    /// Notified whenever outgoing capacity increases, so that [H2Encoder](super::encode::H2Encoder)s
+    /// waiting to send DATA can try again.
+    pub(crate) outgoing_capacity_notify: Rc<Notify>,
+
+    /// Set once we've sent a GOAWAY frame for a graceful shutdown: streams
+    /// with higher ids are refused, cf. https://httpwg.org/specs/rfc9113.html#GOAWAY
+    pub(crate) goaway_last_stream_id: Option<StreamId>,
+
+    /// Set once the peer sends GOAWAY: as a client, streams we opened with
+    /// higher ids were not processed, and we can't open new ones.
+    pub(crate) peer_goaway_last_stream_id: Option<StreamId>,
+
+    /// Notified when the peer sends GOAWAY, so we can wind down too.
+    pub(crate) peer_goaway_notify: Rc<Notify>,
+
+    /// Notified whenever a stream is closed or reset, so a graceful shutdown
+    /// knows when it's done.
+    pub(crate) stream_closed_notify: Rc<Notify>,
+
+    /// When the connection started, or when its last open stream closed,
+    /// cf. [super::ServerConf::keep_alive_timeout]
+    pub(crate) idle_since: Instant,
+
+    /// Streams we recently reset. The peer may have frames for them in
+    /// flight, which we ignore, whereas frames on other closed streams are
+    /// errors, cf. https://httpwg.org/specs/rfc9113.html#StreamStates
+    pub(crate) reset_streams: VecDeque<StreamId>,
+
+    /// Priorities the peer sent with PRIORITY_UPDATE for streams it hasn't
+    /// opened yet, cf. https://www.rfc-editor.org/rfc/rfc9218#name-the-priority_update-frame
+    pub(crate) idle_stream_priorities: HashMap<StreamId, Priority>,
+}
+
+/// How many of the streams we reset we remember, see [ConnState::reset_streams]
+const MAX_RESET_STREAMS: usize = 64;
+
+/// How many priorities for idle streams we remember, see
+/// [ConnState::idle_stream_priorities]
+pub(crate) const MAX_IDLE_STREAM_PRIORITIES: usize = 64;
+
+impl<H> Default for ConnState<H> {
+    fn default() -> Self {
+        Self {
+            streams: Default::default(),
+            last_stream_id: StreamId::CONNECTION,
+            outgoing_capacity: parse::DEFAULT_INITIAL_WINDOW_SIZE as _,
+            incoming_capacity: parse::DEFAULT_INITIAL_WINDOW_SIZE as _,
+            peer_settings: Default::default(),
+            self_settings: Default::default(),
+            outgoing_capacity_notify: Default::default(),
+            goaway_last_stream_id: None,
+            peer_goaway_last_stream_id: None,
+            peer_goaway_notify: Default::default(),
+            stream_closed_notify: Default::default(),
+            idle_since: Instant::now(),
+            reset_streams: Default::default(),
+            idle_stream_priorities: Default::default(),
+        }
+    }
+}
+
+impl<H> ConnState<H> {
+    pub(crate) fn new_stream(&self, rx_stage: StreamRxStage<H>) -> StreamState<H> {
+        StreamState {
+            rx_stage,
+            tx_done: false,
+            handler: None,
+            content_length_left: None,
+            priority: Default::default(),
+            outgoing_capacity: self.peer_settings.initial_window_size as _,
+            incoming_capacity: self.self_settings.initial_window_size as _,
+        }
+    }
+
+    /// The priority a stream opens with: a PRIORITY_UPDATE frame received
+    /// while it was idle wins over its `priority` header, cf.
+    /// https://www.rfc-editor.org/rfc/rfc9218#name-the-priority_update-frame
+    pub(crate) fn opening_priority(
+        &mut self,
+        stream_id: StreamId,
+        from_headers: Priority,
+    ) -> Priority {
+        self.idle_stream_priorities
+            .remove(&stream_id)
+            .unwrap_or(from_headers)
+    }
+
+    /// Forgets about a stream once both we and the peer are done sending on
+    /// it, cf. https://httpwg.org/specs/rfc9113.html#StreamStates
+    pub(crate) fn close_stream_if_done(&mut self, stream_id: StreamId) {
+        if let Some(ss) = self.streams.get(&stream_id) {
+            if ss.tx_done && matches!(ss.rx_stage, StreamRxStage::Done) {
+                debug!(%stream_id, "stream closed");
+                self.streams.remove(&stream_id);
+                self.on_stream_closed();
+            }
+        }
+    }
+
+    fn on_stream_closed(&mut self) {
+        if self.streams.is_empty() {
+            self.idle_since = Instant::now();
+        }
+        self.stream_closed_notify.notify_waiters();
+    }
+
+    /// Accounts for a flow-controlled frame (ie. DATA) received from the peer.
+    /// Returns false if the peer sent more than we allowed it to.
+    pub(crate) fn consume_incoming_capacity(&mut self, stream_id: StreamId, len: u32) -> bool {
+        let len = len as i64;
+
+        if len > self.incoming_capacity {
+            return false;
+        }
+        self.incoming_capacity -= len;
+
+        if let Some(ss) = self.streams.get_mut(&stream_id) {
+            if len > ss.incoming_capacity {
+                return false;
+            }
+            ss.incoming_capacity -= len;
+        }
+        true
+    }
+
+    /// Applies a WINDOW_UPDATE frame received from the peer. Returns false if
+    /// that would make a window overflow, cf. https://httpwg.org/specs/rfc9113.html#fc-principles
+    pub(crate) fn increase_outgoing_capacity(
+        &mut self,
+        stream_id: StreamId,
+        increment: u32,
+    ) -> bool {
+        let capacity = if stream_id == StreamId::CONNECTION {
+            &mut self.outgoing_capacity
+        } else {
+            match self.streams.get_mut(&stream_id) {
+                Some(ss) => &mut ss.outgoing_capacity,
+                None => {
+                    // that's fine, the stream may have been closed already
+                    debug!("ignoring window update for unknown stream {stream_id}");
+                    return true;
+                }
+            }
+        };
+
+        *capacity += increment as i64;
+        if *capacity > parse::MAX_WINDOW_SIZE as i64 {
+            return false;
+        }
+
+        self.outgoing_capacity_notify.notify_waiters();
+        true
+    }
+
+    /// Gives back connection-level capacity reserved for DATA that won't be
+    /// sent after all, e.g. because its stream was reset: the peer never sees
+    /// those bytes, so it won't send a WINDOW_UPDATE for them.
+    pub(crate) fn refund_outgoing_capacity(&mut self, len: usize) {
+        if len == 0 {
+            return;
+        }
+        self.outgoing_capacity += len as i64;
+        self.outgoing_capacity_notify.notify_waiters();
+    }
+
+    /// Applies settings received from the peer. A change in
+    /// SETTINGS_INITIAL_WINDOW_SIZE adjusts the outgoing capacity of all open
+    /// streams, cf. https://httpwg.org/specs/rfc9113.html#InitialWindowSize
+    pub(crate) fn apply_peer_settings(&mut self, settings: Settings) -> Result<(), KnownErrorCode> {
+        let delta =
+            settings.initial_window_size as i64 - self.peer_settings.initial_window_size as i64;
+        self.peer_settings = settings;
+
+        if delta != 0 {
+            for ss in self.streams.values_mut() {
+                ss.outgoing_capacity += delta;
+                if ss.outgoing_capacity > parse::MAX_WINDOW_SIZE as i64 {
+                    return Err(KnownErrorCode::FlowControlError);
+                }
+            }
+            self.outgoing_capacity_notify.notify_waiters();
+        }
+
+        Ok(())
+    }
+
+    /// Puts our own settings in effect, once the peer has acknowledged them.
+    /// Like for the peer's, a change in SETTINGS_INITIAL_WINDOW_SIZE adjusts
+    /// the incoming capacity of all open streams.
+    pub(crate) fn apply_self_settings(&mut self, settings: Settings) {
+        let delta =
+            settings.initial_window_size as i64 - self.self_settings.initial_window_size as i64;
+        self.self_settings = settings;
+
+        for ss in self.streams.values_mut() {
+            ss.incoming_capacity += delta;
+        }
+    }
+}
+
+impl<H: HeadersStage> ConnState<H> {
+    /// Forgets about a stream that was reset, by us or the peer: whoever is
+    /// waiting on what it receives errors out, and encoders waiting for
+    /// capacity wake up to find it gone. Returns the stream's state, if it
+    /// wasn't closed already.
+    pub(crate) fn reset_stream(
+        &mut self,
+        stream_id: StreamId,
+        error_code: ErrorCode,
+        by_peer: bool,
+    ) -> Option<StreamState<H>> {
+        if !by_peer {
+            if self.reset_streams.len() == MAX_RESET_STREAMS {
+                self.reset_streams.pop_front();
+            }
+            self.reset_streams.push_back(stream_id);
+        }
+
+        let ss = self.streams.remove(&stream_id)?;
+        let reset = || {
+            H2StreamReset {
+                stream_id,
+                error_code,
+                by_peer,
+            }
+            .into()
+        };
+        match &ss.rx_stage {
+            StreamRxStage::Headers(headers) => headers.fail(reset()),
+            StreamRxStage::Body(tx) | StreamRxStage::Trailers(Some(tx), _) => {
+                _ = tx.send(Err(reset()));
+            }
+            StreamRxStage::Trailers(None, _) | StreamRxStage::Done => {}
+        }
+        self.outgoing_capacity_notify.notify_waiters();
+        self.on_stream_closed();
+        Some(ss)
+    }
+}
+
+pub(crate) struct StreamState<H> {
+    pub(crate) rx_stage: StreamRxStage<H>,
+
+    /// Whether we've sent END_STREAM
+    pub(crate) tx_done: bool,
+
+    /// The task running [crate::ServerDriver::handle] for this stream,
+    /// cancelled if the stream gets reset. Streams we open as a client don't
+    /// have one.
+    pub(crate) handler: Option<JoinHandle<()>>,
+
+    /// How many more bytes of DATA the peer has to send, if it announced a
+    /// content-length, cf. https://httpwg.org/specs/rfc9113.html#malformed
+    pub(crate) content_length_left: Option<u64>,
+
+    /// Decides when this stream's frames get written, relative to other
+    /// streams', see [WriteScheduler]
+    pub(crate) priority: Priority,
+
+    /// How many bytes of DATA we can still send on this stream. This goes
+    /// negative if the peer shrinks its initial window size while we have
+    /// data in flight.
+    pub(crate) outgoing_capacity: i64,
+
+    /// How many bytes of DATA the peer can still send us on this stream.
+    pub(crate) incoming_capacity: i64,
+}
+
+pub(crate) enum StreamRxStage<H> {
+    /// Waiting for, or receiving, the first field block, see [HeadersStage]
+    Headers(H),
+    // this doesn't need to be bounded: flow control limits how much the peer
+    // can send us before the body is read.
+    Body(mpsc::UnboundedSender<eyre::Result<H2BodyItem>>),
+    /// Receiving a trailing field block. The body sender is `None` if the
+    /// peer had already ended the stream, which is a stream error.
+    Trailers(
+        Option<mpsc::UnboundedSender<eyre::Result<H2BodyItem>>>,
+        HeadersData,
+    ),
+    Done,
+}
+
+/// What a stream holds until its first field block is in: the request's when
+/// serving, the (final) response's as a client.
+pub(crate) trait HeadersStage {
+    /// Lets whoever is waiting on the field block know it won't come, e.g.
+    /// because the stream was reset.
+    fn fail(&self, e: eyre::Report);
+}
+
+pub(crate) struct HeadersData {
+    /// If true, no DATA frames follow, cf. https://httpwg.org/specs/rfc9113.html#HttpFraming
+    pub(crate) end_stream: bool,
+
+    /// If set, the stream gets reset with that error, e.g. because we're over
+    /// SETTINGS_MAX_CONCURRENT_STREAMS: the field block is still decoded (to
+    /// keep HPACK state in sync) but no handler is called.
+    pub(crate) rejected: Option<H2StreamError>,
+
+    /// The field block fragments
+    pub(crate) fragments: SmallVec<[Roll; 2]>,
+}
+
+impl HeadersStage for HeadersData {
+    fn fail(&self, _e: eyre::Report) {
+        // nobody is waiting on a request's headers: the handler only gets
+        // spawned once they're complete
+    }
+}
+
+/// Removes the padding from a DATA or HEADERS payload, cf.
+/// https://httpwg.org/specs/rfc9113.html#DATA
+pub(crate) fn strip_padding(payload: Roll, padded: bool) -> Result<Roll, H2ConnectionError> {
+    if !padded {
+        return Ok(payload);
+    }
+
+    if payload.is_empty() {
+        return Err(H2ConnectionError::PaddedFrameEmpty);
+    }
+    let (padding_length, payload) = payload.split_at(1);
+    let padding_length = padding_length[0] as usize;
+    if padding_length > payload.len() {
+        return Err(H2ConnectionError::PaddedFrameTooShort {
+            padding_length,
+            payload_len: payload.len(),
+        });
+    }
+
+    let at = payload.len() - padding_length;
+    let (payload, _) = payload.split_at(at);
+    Ok(payload)
+}
+
+/// Applies a WINDOW_UPDATE frame. Problems with the connection window are
+/// connection errors, problems with a stream window are stream errors, cf.
+/// https://httpwg.org/specs/rfc9113.html#WINDOW_UPDATE
+pub(crate) fn apply_window_update<H>(
+    state: &RefCell<ConnState<H>>,
+    stream_id: StreamId,
+    increment: u32,
+) -> Result<Result<(), H2StreamError>, H2ConnectionError> {
+    let on_connection = stream_id == StreamId::CONNECTION;
+    if !on_connection && stream_id > state.borrow().last_stream_id {
+        return Err(H2ConnectionError::WindowUpdateForIdleStream { stream_id });
+    }
+    if increment == 0 {
+        return match on_connection {
+            true => Err(H2ConnectionError::WindowUpdateZeroIncrement),
+            false => Ok(Err(H2StreamError::WindowUpdateZeroIncrement)),
+        };
+    }
+
+    if !state
+        .borrow_mut()
+        .increase_outgoing_capacity(stream_id, increment)
+    {
+        return match on_connection {
+            true => Err(H2ConnectionError::WindowUpdateOverflow),
+            false => Ok(Err(H2StreamError::WindowUpdateOverflow)),
+        };
+    }
+    Ok(Ok(()))
+}
+
+/// Applies the parameters of a SETTINGS frame sent by the peer, cf.
+/// https://httpwg.org/specs/rfc9113.html#SettingValues
+pub(crate) fn apply_settings<H>(
+    state: &RefCell<ConnState<H>>,
+    mut payload: Roll,
+) -> Result<Settings, KnownErrorCode> {
+    if payload.len() % 6 != 0 {
+        return Err(KnownErrorCode::FrameSizeError);
+    }
+
+    let mut state = state.borrow_mut();
+    let mut settings = state.peer_settings;
+
+    while !payload.is_empty() {
+        let (id, value);
+        (payload, (id, value)) = parse::setting(payload)
+            .finish()
+            .map_err(|_| KnownErrorCode::FrameSizeError)?;
+
+        match SettingIdentifier::from_repr(id) {
+            Some(id) => debug!(?id, %value, "peer sent setting"),
+            None => trace!("ignoring unknown setting 0x{id:x}"),
+        }
+        settings.apply(id, value)?;
+    }
+
+    state.apply_peer_settings(settings)?;
+    Ok(settings)
+}
+
+/// Lets the peer know we've processed some DATA, so it can send more. Passing
+/// [StreamId::CONNECTION] only releases connection-level capacity, e.g. for
+/// DATA on streams that are gone.
+pub(crate) async fn release_incoming_capacity(
+    ev_tx: &mpsc::Sender<H2ConnEvent>,
+    stream_id: StreamId,
+    increment: u32,
+) -> eyre::Result<()> {
+    let both = [StreamId::CONNECTION, stream_id];
+    let stream_ids = if stream_id == StreamId::CONNECTION {
+        &both[..1]
+    } else {
+        &both[..]
+    };
+    for &stream_id in stream_ids {
+        if ev_tx
+            .send(H2ConnEvent::WindowUpdate {
+                stream_id,
+                increment,
+            })
+            .await
+            .is_err()
+        {
+            return Err(eyre::eyre!("could not send H2 window update event"));
+        }
+    }
+    Ok(())
+}
+
+/// Lets the peer know about a connection error, cf.
+/// https://httpwg.org/specs/rfc9113.html#ConnectionErrorHandler. The caller
+/// is expected to close the connection right after. `last_stream_id` is the
+/// last stream the peer opened that we may have processed.
+pub(crate) async fn send_goaway(
+    ev_tx: &mpsc::Sender<H2ConnEvent>,
+    last_stream_id: StreamId,
+    e: &H2ConnectionError,
+) {
+    let error_code = e.as_known_error_code();
+    warn!("connection error: {e} ({error_code:?})");
+    debug!("last_stream_id = {last_stream_id}");
+    let additional_debug_data = e.to_string().into_bytes();
+
+    if ev_tx
+        .send(H2ConnEvent::GoAway {
+            error_code,
+            last_stream_id,
+            additional_debug_data: Piece::Vec(additional_debug_data),
+        })
+        .await
+        .is_err()
+    {
+        debug!("error sending goaway");
+    }
+}
+
+/// Resets a stream because of a stream error, cf.
+/// https://httpwg.org/specs/rfc9113.html#StreamErrorHandler. Its handler is
+/// cancelled, since nothing it sends would make it to the peer anyway.
+pub(crate) async fn send_rst_stream<H: HeadersStage>(
+    ev_tx: &mpsc::Sender<H2ConnEvent>,
+    state: &RefCell<ConnState<H>>,
+    stream_id: StreamId,
+    e: H2StreamError,
+) {
+    let error_code = e.as_known_error_code();
+    debug!(%stream_id, ?error_code, "resetting stream: {e}");
+
+    if ev_tx
+        .send(H2ConnEvent::RstStream {
+            stream_id,
+            error_code,
+        })
+        .await
+        .is_err()
+    {
+        debug!("error sending rst_stream");
+    }
+
+    let ss = state
+        .borrow_mut()
+        .reset_stream(stream_id, error_code.into(), false);
+    if let Some(handler) = ss.and_then(|ss| ss.handler) {
+        handler.abort();
+    }
+}
+
+/// How many bytes of DATA the write loop takes in ahead of writing them, for
+/// the [WriteScheduler] to pick from.
+const MAX_SCHEDULED_BYTES: usize = 256 * 1024;
+
+pub(crate) async fn h2_write_loop<H>(
+    mut ev_rx: mpsc::Receiver<H2ConnEvent>,
+    transport: Rc<impl ReadWriteOwned>,
+    state: Rc<RefCell<ConnState<H>>>,
+    settings_payload: Vec<u8>,
+) -> eyre::Result<()> {
+    // we have to send a settings frame first. this happens concurrently
+    // with reading, so a peer that doesn't read until it's done writing
+    // can't make us deadlock.
+    {
+        let frame = Frame::new(
+            FrameType::Settings(Default::default()),
+            StreamId::CONNECTION,
+        )
+        .with_len(settings_payload.len() as u32);
+        frame.write(transport.as_ref()).await?;
+        let (res, _) = transport.write_all(settings_payload).await;
+        res?;
+        debug!("sent settings frame");
+    }
+
+    let mut hpack_enc = hring_hpack::Encoder::new();
+    let mut hpack_enc_table_size = parse::DEFAULT_HEADER_TABLE_SIZE;
+
+    let mut scheduler = WriteScheduler::default();
+
+    loop {
+        // take in new events as long as there are some, so the scheduler has
+        // the full picture, but don't buffer too much DATA: that's what the
+        // channel's backpressure is for.
+        let ev = if scheduler.is_empty() {
+            match ev_rx.recv().await {
+                Some(ev) => Some(ev),
+                None => break,
+            }
+        } else if scheduler.queued_bytes() < MAX_SCHEDULED_BYTES {
+            ev_rx.try_recv().ok()
+        } else {
+            None
+        };
+
+        let ev = match ev {
+            Some(ev) => ev,
+            None => {
+                let next = scheduler.pop(&mut state.borrow_mut());
+                if let Some((stream_id, mut payload)) = next {
+                    // a frame at a time, so other streams get a chance in between
+                    let max_frame_size = state.borrow().peer_settings.max_frame_size as usize;
+                    if let H2EventPayload::BodyChunk(chunk) = payload {
+                        if chunk.len() > max_frame_size {
+                            let (head, rest) = chunk.split_at(max_frame_size);
+                            scheduler.push_front(stream_id, H2EventPayload::BodyChunk(rest));
+                            payload = H2EventPayload::BodyChunk(head);
+                        } else {
+                            payload = H2EventPayload::BodyChunk(chunk);
+                        }
+                    }
+                    write_stream_event(
+                        transport.as_ref(),
+                        &state,
+                        &mut hpack_enc,
+                        stream_id,
+                        payload,
+                    )
+                    .await?;
+                }
+                continue;
+            }
+        };
+
+        trace!("h2_write_loop: received H2 event");
+        match ev {
+            H2ConnEvent::AcknowledgeSettings { header_table_size } => {
+                // the peer's decoder accepts tables up to that size once it
+                // gets our ack. we never need more than the default, though.
+                let table_size = std::cmp::min(header_table_size, parse::DEFAULT_HEADER_TABLE_SIZE);
+                if table_size != hpack_enc_table_size {
+                    debug!(%table_size, "changing hpack encoder table size");
+                    hpack_enc.set_max_table_size(table_size as _);
+                    hpack_enc_table_size = table_size;
+                }
+
+                debug!("acknowledging new settings");
+                let res_frame = Frame::new(
+                    FrameType::Settings(SettingsFlags::Ack.into()),
+                    StreamId::CONNECTION,
+                );
+                res_frame.write(transport.as_ref()).await?;
+            }
+            H2ConnEvent::StreamEvent(ev) => {
+                debug!("Scheduling event: {ev:?}");
+
+                if !state.borrow().streams.contains_key(&ev.stream_id) {
+                    // nothing more may be sent on a stream after RST_STREAM
+                    debug!(stream_id = %ev.stream_id, "stream was reset, dropping event");
+                    if let H2EventPayload::BodyChunk(chunk) = &ev.payload {
+                        state.borrow_mut().refund_outgoing_capacity(chunk.len());
+                    }
+                    continue;
+                }
+
+                match ev.payload {
+                    // field blocks that open a stream go out right away:
+                    // nothing's ahead of them, and a client must open streams
+                    // in order, cf. https://httpwg.org/specs/rfc9113.html#StreamIdentifiers
+                    payload @ (H2EventPayload::Headers(_)
+                    | H2EventPayload::RequestHeaders { .. })
+                        if !scheduler.has_queued(ev.stream_id) =>
+                    {
+                        write_stream_event(
+                            transport.as_ref(),
+                            &state,
+                            &mut hpack_enc,
+                            ev.stream_id,
+                            payload,
+                        )
+                        .await?;
+                    }
+                    payload => scheduler.push_back(ev.stream_id, payload),
+                }
+            }
+            H2ConnEvent::Ping(payload) => {
+                // send pong frame
+                let flags = PingFlags::Ack.into();
+                let frame = Frame::new(FrameType::Ping(flags), StreamId::CONNECTION)
+                    .with_len(payload.len() as u32);
+                frame.write(transport.as_ref()).await?;
+                let (res, _) = transport.write_all(payload).await;
+                res?;
+            }
+            H2ConnEvent::WindowUpdate {
+                stream_id,
+                increment,
+            } => {
+                {
+                    let mut state = state.borrow_mut();
+                    if stream_id == StreamId::CONNECTION {
+                        state.incoming_capacity += increment as i64;
+                    } else if let Some(ss) = state.streams.get_mut(&stream_id) {
+                        ss.incoming_capacity += increment as i64;
+                    }
+                }
+
+                let mut payload = vec![0u8; 4];
+                {
+                    use byteorder::{BigEndian, WriteBytesExt};
+                    let mut payload = &mut payload[..];
+                    payload.write_u32::<BigEndian>(increment)?;
+                }
+
+                debug!(%stream_id, %increment, "sending window update");
+                let frame =
+                    Frame::new(FrameType::WindowUpdate, stream_id).with_len(payload.len() as u32);
+                frame.write(transport.as_ref()).await?;
+                let (res, _) = transport.write_all(payload).await;
+                res?;
+            }
+            H2ConnEvent::RstStream {
+                stream_id,
+                error_code,
+            } => {
+                let mut payload = vec![0u8; 4];
+                {
+                    use byteorder::{BigEndian, WriteBytesExt};
+                    let mut payload = &mut payload[..];
+                    payload.write_u32::<BigEndian>(error_code.repr())?;
+                }
+
+                // whatever was queued for that stream won't be sent
+                scheduler.forget(stream_id, &mut state.borrow_mut());
+
+                debug!(%stream_id, "sending rst_stream frame");
+                let frame =
+                    Frame::new(FrameType::RstStream, stream_id).with_len(payload.len() as u32);
+                frame.write(transport.as_ref()).await?;
+                let (res, _) = transport.write_all(payload).await;
+                res?;
+            }
+            H2ConnEvent::GoAway {
+                error_code,
+                last_stream_id,
+                additional_debug_data,
+            } => {
+                debug!("must send goaway");
+                let mut header = vec![0u8; 8];
+                {
+                    use byteorder::{BigEndian, WriteBytesExt};
+                    let mut header = &mut header[..];
+                    // TODO: do we ever need to write the reserved bit?
+                    header.write_u32::<BigEndian>(last_stream_id.0)?;
+                    header.write_u32::<BigEndian>(error_code.repr())?;
+                }
+
+                debug!("sending goaway frame");
+                let frame = Frame::new(FrameType::GoAway, StreamId::CONNECTION).with_len(
+                    (header.len() + additional_debug_data.len())
+                        .try_into()
+                        .unwrap(),
+                );
+                frame.write(transport.as_ref()).await?;
+                let (res, _) = transport.write_all(header).await;
+                res?;
+                let (res, _) = transport.write_all(additional_debug_data).await;
+                res?;
+
+                if !matches!(error_code, KnownErrorCode::NoError) {
+                    // nothing goes out after a connection error, cf.
+                    // https://httpwg.org/specs/rfc9113.html#ConnectionErrorHandler
+                    debug!("sent goaway for a connection error, done writing");
+                    break;
+                }
+            }
+        }
+    }
+    debug!("h2_write_loop is done");
+
+    Ok(())
+}
+
+/// Writes the frame(s) for a stream event, once the [WriteScheduler] picked it
+async fn write_stream_event<H>(
+    transport: &impl ReadWriteOwned,
+    state: &RefCell<ConnState<H>>,
+    hpack_enc: &mut hring_hpack::Encoder<'_>,
+    stream_id: StreamId,
+    payload: H2EventPayload,
+) -> eyre::Result<()> {
+    match payload {
+        H2EventPayload::Headers(res) => {
+            debug!("Sending headers on stream {}", stream_id);
+
+            // TODO: don't allocate so much for headers
+            // TODO: limt header size
+            let mut headers: Vec<(&[u8], &[u8])> = vec![];
+            headers.push((b":status", res.status.as_str().as_bytes()));
+            for (name, value) in res.headers.iter() {
+                if name == http::header::TRANSFER_ENCODING {
+                    // do not set transfer-encoding: chunked when doing HTTP/2
+                    continue;
+                }
+                headers.push((name.as_str().as_bytes(), value));
+            }
+            let headers_encoded = hpack_enc.encode(headers);
+
+            let max_frame_size = state.borrow().peer_settings.max_frame_size;
+            write_field_block(
+                transport,
+                stream_id,
+                headers_encoded.into(),
+                false,
+                max_frame_size,
+            )
+            .await?;
+        }
+        H2EventPayload::RequestHeaders { req, end_stream } => {
+            debug!("Sending request headers on stream {}", stream_id);
+
+            let method = req.method.into_chunk();
+            let scheme = req.uri.scheme_str().unwrap_or("http");
+            let path = req
+                .uri
+                .path_and_query()
+                .map(|pq| pq.as_str())
+                .unwrap_or("/");
+            // :authority replaces the host header, cf.
+            // https://httpwg.org/specs/rfc9113.html#HttpRequest
+            let authority = req
+                .uri
+                .authority()
+                .map(|a| a.as_str().as_bytes())
+                .or_else(|| req.headers.get(header::HOST).map(|h| &h[..]));
+
+            let mut headers: Vec<(&[u8], &[u8])> = vec![
+                (b":method", &method[..]),
+                (b":scheme", scheme.as_bytes()),
+                (b":path", path.as_bytes()),
+            ];
+            if let Some(authority) = authority {
+                headers.push((b":authority", authority));
+            }
+            if let Some(protocol) = &req.protocol {
+                headers.push((b":protocol", protocol.as_bytes()));
+            }
+            for (name, value) in req.headers.iter() {
+                if is_connection_specific(name) || name == header::HOST {
+                    // not allowed in HTTP/2, cf. https://httpwg.org/specs/rfc9113.html#ConnectionSpecific
+                    continue;
+                }
+                headers.push((name.as_str().as_bytes(), value));
+            }
+            let headers_encoded = hpack_enc.encode(headers);
+
+            let max_frame_size = state.borrow().peer_settings.max_frame_size;
+            write_field_block(
+                transport,
+                stream_id,
+                headers_encoded.into(),
+                end_stream,
+                max_frame_size,
+            )
+            .await?;
+
+            if end_stream {
+                let mut state = state.borrow_mut();
+                if let Some(ss) = state.streams.get_mut(&stream_id) {
+                    ss.tx_done = true;
+                }
+                state.close_stream_if_done(stream_id);
+            }
+        }
+        H2EventPayload::BodyChunk(chunk) => {
+            let flags = BitFlags::<DataFlags>::default();
+            let frame = Frame::new(FrameType::Data(flags), stream_id)
+                .with_len(chunk.len().try_into().unwrap());
+            frame.write(transport).await?;
+            let (res, _) = transport.write_all(chunk).await;
+            res?;
+        }
+        H2EventPayload::BodyEnd => {
+            let flags = DataFlags::EndStream;
+            let frame = Frame::new(FrameType::Data(flags.into()), stream_id);
+            frame.write(transport).await?;
+
+            let mut state = state.borrow_mut();
+            if let Some(ss) = state.streams.get_mut(&stream_id) {
+                ss.tx_done = true;
+            }
+            state.close_stream_if_done(stream_id);
+        }
+        H2EventPayload::Trailers(trailers) => {
+            debug!("Sending trailers on stream {}", stream_id);
+
+            let trailers: Vec<(&[u8], &[u8])> = trailers
+                .iter()
+                .map(|(name, value)| (name.as_str().as_bytes(), &value[..]))
+                .collect();
+            let trailers_encoded = hpack_enc.encode(trailers);
+
+            let max_frame_size = state.borrow().peer_settings.max_frame_size;
+            write_field_block(
+                transport,
+                stream_id,
+                trailers_encoded.into(),
+                true,
+                max_frame_size,
+            )
+            .await?;
+
+            let mut state = state.borrow_mut();
+            if let Some(ss) = state.streams.get_mut(&stream_id) {
+                ss.tx_done = true;
+            }
+            state.close_stream_if_done(stream_id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a header only makes sense for HTTP/1.1 connections
+pub(crate) fn is_connection_specific(name: &HeaderName) -> bool {
+    name == header::CONNECTION
+        || name == header::TRANSFER_ENCODING
+        || name == header::UPGRADE
+        || name == "keep-alive"
+        || name == "proxy-connection"
+}
+
+/// Writes a field block as a HEADERS frame, followed by as many CONTINUATION
+/// frames as needed to stay under the peer's max frame size, cf.
+/// https://httpwg.org/specs/rfc9113.html#FieldBlock
+async fn write_field_block(
+    transport: &impl ReadWriteOwned,
+    stream_id: StreamId,
+    block: Piece,
+    end_stream: bool,
+    max_frame_size: u32,
+) -> eyre::Result<()> {
+    let mut rest = block;
+    let mut first = true;
+    loop {
+        let fragment;
+        let at = std::cmp::min(rest.len(), max_frame_size as usize);
+        (fragment, rest) = rest.split_at(at);
+        let end_headers = rest.is_empty();
+
+        let frame_type = if first {
+            let mut flags = BitFlags::<HeadersFlags>::default();
+            if end_headers {
+                flags |= HeadersFlags::EndHeaders;
+            }
+            // END_STREAM goes on the HEADERS frame, even if CONTINUATION
+            // frames follow
+            if end_stream {
+                flags |= HeadersFlags::EndStream;
+            }
+            FrameType::Headers(flags)
+        } else {
+            let mut flags = BitFlags::<ContinuationFlags>::default();
+            if end_headers {
+                flags |= ContinuationFlags::EndHeaders;
+            }
+            FrameType::Continuation(flags)
+        };
+        first = false;
+
+        let frame = Frame::new(frame_type, stream_id).with_len(fragment.len() as u32);
+        frame.write(transport).await?;
+        let (res, _) = transport.write_all(fragment).await;
+        res?;
+
+        if end_headers {
+            return Ok(());
+        }
+    }
+}
+
+/// Decodes a field block, which may be spread over a HEADERS frame and
+/// CONTINUATION frames, cf. https://httpwg.org/specs/rfc9113.html#FieldBlock
+pub(crate) fn decode_field_block(
+    hpack_dec: &mut hring_hpack::Decoder,
+    fragments: &[Roll],
+    cb: impl FnMut(Cow<[u8]>, Cow<[u8]>),
+) -> Result<(), H2ConnectionError> {
+    match fragments {
+        [] => unreachable!("must have at least one fragment"),
+        [payload] => {
+            hpack_dec
+                .decode_with_cb(&payload[..], cb)
+                .map_err(|e| H2ConnectionError::HpackDecodingError(eyre::eyre!("{e:?}")))?;
+        }
+        _ => {
+            let total_len = fragments.iter().map(|f| f.len()).sum();
+            // this is a slow path, let's do a little heap allocation. we could
+            // be using `RollMut` for this, but it would probably need to resize
+            // a bunch
+            let mut payload = Vec::with_capacity(total_len);
+            for frag in fragments {
+                payload.extend_from_slice(&frag[..]);
+            }
+            hpack_dec
+                .decode_with_cb(&payload[..], cb)
+                .map_err(|e| H2ConnectionError::HpackDecodingError(eyre::eyre!("{e:?}")))?;
+        }
+    };
+    Ok(())
+}
+
+/// Decodes trailers once their field block is complete, and hands them to
+/// the request body. Trailers must end the stream and can't contain
+/// pseudo-headers, cf. https://httpwg.org/specs/rfc9113.html#HttpFraming
+pub(crate) async fn end_trailers<H: HeadersStage>(
+    ev_tx: &mpsc::Sender<H2ConnEvent>,
+    state: &Rc<RefCell<ConnState<H>>>,
+    max_header_list_size: u32,
+    stream_id: StreamId,
+    hpack_dec: &mut hring_hpack::Decoder<'_>,
+) -> Result<(), H2ConnectionError> {
+    let (body_tx, data) = {
+        let mut state = state.borrow_mut();
+        let ss = match state.streams.get_mut(&stream_id) {
+            Some(ss) => ss,
+            None => return Ok(()),
+        };
+        match std::mem::replace(&mut ss.rx_stage, StreamRxStage::Done) {
+            StreamRxStage::Trailers(body_tx, data) => (body_tx, data),
+            _ => unreachable!("stream {stream_id} wasn't receiving trailers"),
+        }
+    };
+
+    let mut trailers = Headers::default();
+    let mut malformed: Option<&'static str> = None;
+    let mut header_list_size = 0;
+
+    let cb = |key: Cow<[u8]>, value: Cow<[u8]>| {
+        header_list_size += key.len() + value.len() + 32;
+        if malformed.is_some() {
+            // keep decoding so the HPACK state stays in sync
+            return;
+        }
+        if header_list_size > max_header_list_size as usize {
+            malformed = Some("trailers too large");
+            return;
+        }
+
+        if key.first() == Some(&b':') {
+            malformed = Some("pseudo-header in trailers");
+            return;
+        }
+
+        match HeaderName::from_bytes(&key[..]) {
+            Ok(name) => {
+                let value: Piece = value.to_vec().into();
+                trailers.append(name, value);
+            }
+            Err(_) => malformed = Some("invalid trailer name"),
+        }
+    };
+
+    decode_field_block(hpack_dec, &data.fragments, cb)?;
+
+    if !data.end_stream {
+        malformed = malformed.or(Some("trailers without END_STREAM"));
+    }
+    let content_length_left = state
+        .borrow()
+        .streams
+        .get(&stream_id)
+        .and_then(|ss| ss.content_length_left);
+    if matches!(content_length_left, Some(left) if left > 0) {
+        malformed = malformed.or(Some("data shorter than content-length"));
+    }
+    let error = data
+        .rejected
+        .or(malformed.map(|reason| H2StreamError::MalformedMessage { reason }));
+    let body_tx = match (body_tx, error) {
+        (Some(tx), None) => tx,
+        (Some(tx), Some(e)) => {
+            // put the body back, so it errors out instead of looking
+            // complete when the stream gets reset
+            if let Some(ss) = state.borrow_mut().streams.get_mut(&stream_id) {
+                ss.rx_stage = StreamRxStage::Body(tx);
+            }
+            send_rst_stream(ev_tx, state, stream_id, e).await;
+            return Ok(());
+        }
+        // the stream is half-closed (remote), cf.
+        // https://httpwg.org/specs/rfc9113.html#StreamStates
+        (None, _) => {
+            let e = H2StreamError::HeadersAfterEndStream;
+            send_rst_stream(ev_tx, state, stream_id, e).await;
+            return Ok(());
+        }
+    };
+
+    debug!(%stream_id, "received {} trailers", trailers.len());
+    _ = body_tx.send(Ok(H2BodyItem::Trailers(Box::new(trailers))));
+    state.borrow_mut().close_stream_if_done(stream_id);
+    Ok(())
+}
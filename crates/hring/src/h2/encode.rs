@@ -1,18 +1,33 @@
-use std::fmt;
+use std::{cell::RefCell, fmt, rc::Rc};
 
 use http::{StatusCode, Version};
 use tokio::sync::mpsc;
 use tracing::{debug, warn};
 
-use crate::{h1::body::BodyWriteMode, Encoder, Response};
+use crate::{h1::body::BodyWriteMode, Encoder, Headers, NoTransport, Request, Response, Upgraded};
 use hring_buffet::{Piece, Roll};
 
-use super::parse::{KnownErrorCode, StreamId};
+use super::{
+    conn::{ConnState, HeadersStage},
+    parse::{KnownErrorCode, StreamId},
+};
 
 pub(crate) enum H2ConnEvent {
     Ping(Roll),
-    ServerEvent(H2Event),
-    AcknowledgeSettings,
+    StreamEvent(H2Event),
+    AcknowledgeSettings {
+        /// The peer's SETTINGS_HEADER_TABLE_SIZE, which bounds our HPACK
+        /// encoder's dynamic table once acknowledged.
+        header_table_size: u32,
+    },
+    WindowUpdate {
+        stream_id: StreamId,
+        increment: u32,
+    },
+    RstStream {
+        stream_id: StreamId,
+        error_code: KnownErrorCode,
+    },
     GoAway {
         error_code: KnownErrorCode,
         last_stream_id: StreamId,
@@ -28,16 +43,26 @@ pub(crate) struct H2Event {
 
 pub(crate) enum H2EventPayload {
     Headers(Response),
+    /// Opens a stream, on the client side. END_STREAM is set on the HEADERS
+    /// frame if there's no request body.
+    RequestHeaders {
+        req: Request,
+        end_stream: bool,
+    },
     BodyChunk(Piece),
     BodyEnd,
+    /// Sent as a HEADERS frame with END_STREAM, instead of [H2EventPayload::BodyEnd]
+    Trailers(Box<Headers>),
 }
 
 impl fmt::Debug for H2EventPayload {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Headers(_) => f.debug_tuple("Headers").finish(),
+            Self::RequestHeaders { .. } => f.debug_tuple("RequestHeaders").finish(),
             Self::BodyChunk(_) => f.debug_tuple("BodyChunk").finish(),
             Self::BodyEnd => write!(f, "BodyEnd"),
+            Self::Trailers(_) => f.debug_tuple("Trailers").finish(),
         }
     }
 }
@@ -48,42 +73,123 @@ pub(crate) enum EncoderState {
     ResponseDone,
 }
 
-pub struct H2Encoder {
+pub(crate) struct H2Encoder<H: HeadersStage + 'static> {
     pub(crate) stream_id: StreamId,
     pub(crate) tx: mpsc::Sender<H2ConnEvent>,
     pub(crate) state: EncoderState,
+    pub(crate) conn_state: Rc<RefCell<ConnState<H>>>,
+    /// Responses to HEAD requests have no content, cf.
+    /// https://httpwg.org/specs/rfc9110.html#HEAD
+    pub(crate) head_request: bool,
 }
 
-impl H2Encoder {
+impl<H: HeadersStage> H2Encoder<H> {
     fn event(&self, payload: H2EventPayload) -> H2ConnEvent {
-        H2ConnEvent::ServerEvent(H2Event {
+        H2ConnEvent::StreamEvent(H2Event {
             payload,
             stream_id: self.stream_id,
         })
     }
 
+    /// A stream is forgotten once it's closed or reset, after which nothing
+    /// may be sent on it.
+    fn stream_is_open(&self) -> bool {
+        self.conn_state
+            .borrow()
+            .streams
+            .contains_key(&self.stream_id)
+    }
+
     async fn send(&self, payload: H2EventPayload) -> eyre::Result<()> {
+        if !self.stream_is_open() {
+            return Err(eyre::eyre!("stream {} was reset", self.stream_id));
+        }
+
         self.tx
             .send(self.event(payload))
             .await
             .map_err(|_| eyre::eyre!("could not send event to h2 connection handler"))?;
         Ok(())
     }
+
+    /// Waits until both the connection and stream flow-control windows have
+    /// room for at least one byte, then reserves up to `max` bytes from them.
+    /// cf. https://httpwg.org/specs/rfc9113.html#FlowControl
+    async fn reserve_capacity(&self, max: usize) -> eyre::Result<usize> {
+        loop {
+            let notify = {
+                let mut state = self.conn_state.borrow_mut();
+                let state = &mut *state;
+                let ss = state
+                    .streams
+                    .get_mut(&self.stream_id)
+                    .ok_or_else(|| eyre::eyre!("stream {} was reset", self.stream_id))?;
+
+                let available = std::cmp::min(state.outgoing_capacity, ss.outgoing_capacity);
+                if available > 0 {
+                    let n = std::cmp::min(available as usize, max);
+                    state.outgoing_capacity -= n as i64;
+                    ss.outgoing_capacity -= n as i64;
+                    return Ok(n);
+                }
+                state.outgoing_capacity_notify.clone()
+            };
+
+            debug!(stream_id = %self.stream_id, "out of flow-control capacity, waiting for window update");
+            notify.notified().await;
+        }
+    }
 }
 
-impl Encoder for H2Encoder {
+impl<H: HeadersStage> Encoder for H2Encoder<H> {
+    type Transport = NoTransport;
+
     async fn write_response(&mut self, res: Response) -> eyre::Result<()> {
         debug!("H2Encoder::write_response");
 
         // TODO: don't panic here
         assert!(matches!(self.state, EncoderState::ExpectResponseHeaders));
 
+        // cf. https://httpwg.org/specs/rfc9113.html#SETTINGS_MAX_HEADER_LIST_SIZE
+        let max_header_list_size = self.conn_state.borrow().peer_settings.max_header_list_size;
+        if let Some(max) = max_header_list_size {
+            // this ignores the `transfer-encoding` header the write loop
+            // strips, which is fine since we err on the side of caution.
+            let size = header_list_size(&res);
+            if size > max as usize {
+                return Err(eyre::eyre!(
+                    "response headers are {size} bytes, peer only accepts {max}"
+                ));
+            }
+        }
+
         self.send(H2EventPayload::Headers(res)).await?;
         self.state = EncoderState::ExpectResponseBody;
 
         Ok(())
     }
 
+    async fn write_tunnel_response(&mut self, res: Response) -> eyre::Result<()> {
+        // the stream is the tunnel, and it's only opened with CONNECT, cf.
+        // https://httpwg.org/specs/rfc9113.html#informational-responses
+        if res.status == StatusCode::SWITCHING_PROTOCOLS {
+            return Err(eyre::eyre!(
+                "HTTP/2 has no 101 Switching Protocols, use extended CONNECT instead"
+            ));
+        }
+        self.write_response(res).await
+    }
+
+    async fn write_upgrade_response(
+        &mut self,
+        _res: Response,
+    ) -> eyre::Result<Upgraded<NoTransport>> {
+        // cf. https://httpwg.org/specs/rfc9113.html#informational-responses
+        Err(eyre::eyre!(
+            "HTTP/2 connections can't be upgraded, use extended CONNECT instead"
+        ))
+    }
+
     // TODO: BodyWriteMode is not relevant for h2
     async fn write_body_chunk(
         &mut self,
@@ -94,7 +200,30 @@ impl Encoder for H2Encoder {
 
         assert!(matches!(self.state, EncoderState::ExpectResponseBody));
 
-        self.send(H2EventPayload::BodyChunk(chunk)).await?;
+        if self.head_request {
+            // the headers describe the body, but it's never sent
+            return Ok(());
+        }
+
+        // send as much as the peer lets us, in frames no larger than it
+        // accepts, and wait for more room if needed.
+        let mut chunk = chunk;
+        while !chunk.is_empty() {
+            let max_frame_size = self.conn_state.borrow().peer_settings.max_frame_size;
+            let max = std::cmp::min(chunk.len(), max_frame_size as usize);
+            let n = self.reserve_capacity(max).await?;
+
+            let head;
+            (head, chunk) = chunk.split_at(n);
+            // if the chunk doesn't make it to the write loop, because sending
+            // fails or the handler is cancelled, the capacity goes back.
+            let mut reserved = ReservedCapacity {
+                conn_state: &self.conn_state,
+                len: n,
+            };
+            self.send(H2EventPayload::BodyChunk(head)).await?;
+            reserved.len = 0;
+        }
         Ok(())
     }
 
@@ -110,18 +239,95 @@ impl Encoder for H2Encoder {
         Ok(())
     }
 
-    // TODO: handle trailers
-    async fn write_trailers(&mut self, _trailers: Box<crate::Headers>) -> eyre::Result<()> {
-        assert!(matches!(self.state, EncoderState::ResponseDone));
+    async fn write_trailers(&mut self, trailers: Box<Headers>) -> eyre::Result<()> {
+        debug!("H2Encoder::write_trailers");
+
+        assert!(matches!(self.state, EncoderState::ExpectResponseBody));
+
+        let max_header_list_size = self.conn_state.borrow().peer_settings.max_header_list_size;
+        if let Some(max) = max_header_list_size {
+            let size = field_section_size(&trailers);
+            if size > max as usize {
+                return Err(eyre::eyre!(
+                    "response trailers are {size} bytes, peer only accepts {max}"
+                ));
+            }
+        }
+
+        self.send(H2EventPayload::Trailers(trailers)).await?;
+        self.state = EncoderState::ResponseDone;
+
+        Ok(())
+    }
+
+    async fn reset_stream(&mut self, error_code: KnownErrorCode) -> eyre::Result<()> {
+        debug!(stream_id = %self.stream_id, ?error_code, "H2Encoder::reset_stream");
+
+        // whatever happens, there's nothing left to send
+        self.state = EncoderState::ResponseDone;
 
-        todo!("write trailers")
+        if !self.stream_is_open() {
+            // the peer beat us to it, or we're done already
+            return Ok(());
+        }
+
+        self.tx
+            .send(H2ConnEvent::RstStream {
+                stream_id: self.stream_id,
+                error_code,
+            })
+            .await
+            .map_err(|_| eyre::eyre!("could not send event to h2 connection handler"))?;
+        self.conn_state
+            .borrow_mut()
+            .reset_stream(self.stream_id, error_code.into(), false);
+
+        Ok(())
     }
 }
 
-impl Drop for H2Encoder {
+/// Counted for every field on top of its name and value, cf.
+/// https://httpwg.org/specs/rfc9113.html#SETTINGS_MAX_HEADER_LIST_SIZE
+const ENTRY_OVERHEAD: usize = 32;
+
+/// The size of a header list, as defined in
+/// https://httpwg.org/specs/rfc9113.html#SETTINGS_MAX_HEADER_LIST_SIZE
+fn header_list_size(res: &Response) -> usize {
+    let status = ":status".len() + 3 + ENTRY_OVERHEAD;
+    status + field_section_size(&res.headers)
+}
+
+/// Same as [header_list_size], without pseudo-headers (e.g. for trailers)
+fn field_section_size(headers: &Headers) -> usize {
+    headers.iter().fold(0, |acc, (name, value)| {
+        acc + name.as_str().len() + value.len() + ENTRY_OVERHEAD
+    })
+}
+
+/// Connection-level capacity taken by [H2Encoder::reserve_capacity], given
+/// back when dropped, cf. [ConnState::refund_outgoing_capacity]
+struct ReservedCapacity<'a, H> {
+    conn_state: &'a RefCell<ConnState<H>>,
+    len: usize,
+}
+
+impl<H> Drop for ReservedCapacity<'_, H> {
+    fn drop(&mut self) {
+        self.conn_state
+            .borrow_mut()
+            .refund_outgoing_capacity(self.len);
+    }
+}
+
+impl<H: HeadersStage> Drop for H2Encoder<H> {
     fn drop(&mut self) {
         let mut evs = vec![];
 
+        if !self.stream_is_open() {
+            // the stream was reset, nothing to clean up
+            return;
+        }
+
         match self.state {
             EncoderState::ExpectResponseHeaders => {
                 evs.push(self.event(H2EventPayload::Headers(Response {
@@ -132,8 +338,12 @@ impl Drop for H2Encoder {
                 evs.push(self.event(H2EventPayload::BodyEnd));
             }
             EncoderState::ExpectResponseBody => {
-                // TODO: this should probably be RST_STREAM instead
-                evs.push(self.event(H2EventPayload::BodyEnd));
+                // ending the body normally would make a truncated response
+                // look complete to the peer
+                evs.push(H2ConnEvent::RstStream {
+                    stream_id: self.stream_id,
+                    error_code: KnownErrorCode::InternalError,
+                });
             }
             EncoderState::ResponseDone => {
                 // ah, good.
@@ -142,12 +352,25 @@ impl Drop for H2Encoder {
 
         if !evs.is_empty() {
             let tx = self.tx.clone();
+            let conn_state = self.conn_state.clone();
+            let stream_id = self.stream_id;
             tokio_uring::spawn(async move {
                 for ev in evs {
+                    let reset = match &ev {
+                        H2ConnEvent::RstStream { error_code, .. } => Some(*error_code),
+                        _ => None,
+                    };
+
                     if tx.send(ev).await.is_err() {
                         warn!("could not send event to h2 connection handler");
                         break;
                     }
+
+                    if let Some(error_code) = reset {
+                        conn_state
+                            .borrow_mut()
+                            .reset_stream(stream_id, error_code.into(), false);
+                    }
                 }
             });
         }
@@ -4,8 +4,19 @@
 mod server;
 pub use server::*;
 
+mod client;
+pub use client::*;
+
 pub(crate) mod parse;
+pub use parse::{ErrorCode, KnownErrorCode, StreamId};
+
+mod types;
+pub use types::*;
+
+mod conn;
 
 mod encode;
 
 mod body;
+
+mod priority;
@@ -9,7 +9,7 @@ use enum_repr::EnumRepr;
 use enumflags2::{bitflags, BitFlags};
 use nom::{
     combinator::map,
-    number::streaming::{be_u24, be_u8},
+    number::streaming::{be_u16, be_u24, be_u32, be_u8},
     sequence::tuple,
     IResult,
 };
@@ -19,9 +19,17 @@ use hring_buffet::{Roll, WriteOwned};
 /// This is sent by h2 clients after negotiating over ALPN, or when doing h2c.
 pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
 
-pub fn preface(i: Roll) -> IResult<Roll, ()> {
-    let (i, _) = nom::bytes::streaming::tag(PREFACE)(i)?;
-    Ok((i, ()))
+/// Doesn't consume anything: returns whether the input starts with the
+/// HTTP/2 preface, as soon as that can be told.
+pub(crate) fn starts_with_preface(i: Roll) -> IResult<Roll, bool> {
+    let n = std::cmp::min(i.len(), PREFACE.len());
+    if i[..n] != PREFACE[..n] {
+        return Ok((i, false));
+    }
+    if n < PREFACE.len() {
+        return Err(nom::Err::Incomplete(nom::Needed::new(PREFACE.len() - n)));
+    }
+    Ok((i, true))
 }
 
 /// See https://httpwg.org/specs/rfc9113.html#FrameTypes
@@ -38,6 +46,8 @@ pub enum RawFrameType {
     GoAway = 0x07,
     WindowUpdate = 0x08,
     Continuation = 0x09,
+    /// cf. https://www.rfc-editor.org/rfc/rfc9218#name-the-priority_update-frame
+    PriorityUpdate = 0x10,
 }
 
 /// Typed flags for various frame types
@@ -53,6 +63,7 @@ pub enum FrameType {
     GoAway,
     WindowUpdate,
     Continuation(BitFlags<ContinuationFlags>),
+    PriorityUpdate,
     Unknown(EncodedFrameType),
 }
 
@@ -135,6 +146,7 @@ impl FrameType {
             FrameType::GoAway => (RawFrameType::GoAway, 0).into(),
             FrameType::WindowUpdate => (RawFrameType::WindowUpdate, 0).into(),
             FrameType::Continuation(f) => (RawFrameType::Continuation, f.bits()).into(),
+            FrameType::PriorityUpdate => (RawFrameType::PriorityUpdate, 0).into(),
             FrameType::Unknown(ft) => ft,
         }
     }
@@ -162,6 +174,7 @@ impl FrameType {
                 RawFrameType::Continuation => FrameType::Continuation(
                     BitFlags::<ContinuationFlags>::from_bits_truncate(ft.flags),
                 ),
+                RawFrameType::PriorityUpdate => FrameType::PriorityUpdate,
             },
             None => FrameType::Unknown(ft),
         }
@@ -295,6 +308,9 @@ pub(crate) struct PrioritySpec {
 }
 
 impl PrioritySpec {
+    /// Size of a priority spec on the wire, cf. https://httpwg.org/specs/rfc9113.html#PRIORITY
+    pub(crate) const LEN: usize = 5;
+
     pub(crate) fn parse(i: Roll) -> IResult<Roll, Self> {
         map(
             tuple((parse_reserved_and_stream_id, be_u8)),
@@ -307,6 +323,205 @@ impl PrioritySpec {
     }
 }
 
+// cf. https://www.rfc-editor.org/rfc/rfc9218#name-the-priority_update-frame
+#[derive(Debug)]
+pub(crate) struct PriorityUpdate {
+    pub prioritized_stream_id: StreamId,
+    /// Same format as the `priority` header
+    pub field_value: Roll,
+}
+
+impl PriorityUpdate {
+    /// Size of the prioritized stream id, which the field value follows
+    pub(crate) const MIN_LEN: usize = 4;
+
+    pub(crate) fn parse(i: Roll) -> IResult<Roll, Self> {
+        let (i, (_, prioritized_stream_id)) = parse_reserved_and_stream_id(i)?;
+        let len = i.len();
+        let (field_value, i) = i.split_at(len);
+        Ok((
+            i,
+            Self {
+                prioritized_stream_id,
+                field_value,
+            },
+        ))
+    }
+}
+
+// cf. https://httpwg.org/specs/rfc9113.html#WINDOW_UPDATE
+#[derive(Debug)]
+pub(crate) struct WindowUpdate {
+    // 1 to 2^31-1, zero is a protocol error
+    pub increment: u32,
+}
+
+impl WindowUpdate {
+    pub(crate) fn parse(i: Roll) -> IResult<Roll, Self> {
+        // the layout is the same as the reserved bit + stream id from the
+        // frame header, and the reserved bit must be ignored
+        map(parse_reserved_and_stream_id, |(_reserved, increment)| {
+            Self {
+                increment: increment.0,
+            }
+        })(i)
+    }
+}
+
+// cf. https://httpwg.org/specs/rfc9113.html#GOAWAY
+#[derive(Debug)]
+pub(crate) struct GoAway {
+    pub last_stream_id: StreamId,
+    pub error_code: ErrorCode,
+}
+
+impl GoAway {
+    /// Parses the fixed part of a GOAWAY frame, leaving the additional debug
+    /// data as the remaining input.
+    pub(crate) fn parse(i: Roll) -> IResult<Roll, Self> {
+        map(
+            tuple((parse_reserved_and_stream_id, ErrorCode::parse)),
+            |((_reserved, last_stream_id), error_code)| Self {
+                last_stream_id,
+                error_code,
+            },
+        )(i)
+    }
+}
+
+/// The initial flow-control window size for both the connection and new
+/// streams, cf. https://httpwg.org/specs/rfc9113.html#InitialWindowSize
+pub(crate) const DEFAULT_INITIAL_WINDOW_SIZE: u32 = 65_535;
+
+/// Flow-control windows must never exceed this, cf.
+/// https://httpwg.org/specs/rfc9113.html#fc-principles
+pub(crate) const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+/// The largest frame payload a peer must accept until it says otherwise
+/// cf. https://httpwg.org/specs/rfc9113.html#SETTINGS_MAX_FRAME_SIZE
+pub(crate) const DEFAULT_MAX_FRAME_SIZE: u32 = 16_384;
+
+/// The largest value SETTINGS_MAX_FRAME_SIZE may take, cf.
+/// https://httpwg.org/specs/rfc9113.html#SETTINGS_MAX_FRAME_SIZE
+pub(crate) const MAX_MAX_FRAME_SIZE: u32 = (1 << 24) - 1;
+
+/// The size of the HPACK dynamic table until the peer says otherwise, cf.
+/// https://httpwg.org/specs/rfc9113.html#SETTINGS_HEADER_TABLE_SIZE
+pub(crate) const DEFAULT_HEADER_TABLE_SIZE: u32 = 4096;
+
+/// See https://httpwg.org/specs/rfc9113.html#SettingValues
+#[EnumRepr(type = "u16")]
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum SettingIdentifier {
+    HeaderTableSize = 0x01,
+    EnablePush = 0x02,
+    MaxConcurrentStreams = 0x03,
+    InitialWindowSize = 0x04,
+    MaxFrameSize = 0x05,
+    MaxHeaderListSize = 0x06,
+    /// cf. https://www.rfc-editor.org/rfc/rfc8441#section-3
+    EnableConnectProtocol = 0x08,
+    /// cf. https://www.rfc-editor.org/rfc/rfc9218#name-disabling-rfc-7540-priorit
+    NoRfc7540Priorities = 0x09,
+}
+
+/// Parses a single setting (identifier and value) from the payload of a
+/// SETTINGS frame, cf. https://httpwg.org/specs/rfc9113.html#SETTINGS
+pub(crate) fn setting(i: Roll) -> IResult<Roll, (u16, u32)> {
+    tuple((be_u16, be_u32))(i)
+}
+
+/// The parameters one endpoint conveys to the other with SETTINGS frames, cf.
+/// https://httpwg.org/specs/rfc9113.html#SettingValues
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Settings {
+    pub header_table_size: u32,
+    pub enable_push: bool,
+    // `None` means unlimited
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_window_size: u32,
+    pub max_frame_size: u32,
+    // `None` means unlimited
+    pub max_header_list_size: Option<u32>,
+    // whether the peer accepts extended CONNECT requests (with `:protocol`)
+    pub enable_connect_protocol: bool,
+    // whether the peer uses RFC 9218 priority signals instead
+    pub no_rfc7540_priorities: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            header_table_size: DEFAULT_HEADER_TABLE_SIZE,
+            enable_push: true,
+            max_concurrent_streams: None,
+            initial_window_size: DEFAULT_INITIAL_WINDOW_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_header_list_size: None,
+            enable_connect_protocol: false,
+            no_rfc7540_priorities: false,
+        }
+    }
+}
+
+impl Settings {
+    /// Validates and applies a single parameter. Unknown identifiers are
+    /// ignored, as required by the spec. On error, returns the code the
+    /// connection must be closed with.
+    pub(crate) fn apply(&mut self, id: u16, value: u32) -> Result<(), KnownErrorCode> {
+        match SettingIdentifier::from_repr(id) {
+            Some(SettingIdentifier::HeaderTableSize) => {
+                self.header_table_size = value;
+            }
+            Some(SettingIdentifier::EnablePush) => {
+                self.enable_push = match value {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(KnownErrorCode::ProtocolError),
+                };
+            }
+            Some(SettingIdentifier::MaxConcurrentStreams) => {
+                self.max_concurrent_streams = Some(value);
+            }
+            Some(SettingIdentifier::InitialWindowSize) => {
+                if value > MAX_WINDOW_SIZE {
+                    return Err(KnownErrorCode::FlowControlError);
+                }
+                self.initial_window_size = value;
+            }
+            Some(SettingIdentifier::MaxFrameSize) => {
+                if !(DEFAULT_MAX_FRAME_SIZE..=MAX_MAX_FRAME_SIZE).contains(&value) {
+                    return Err(KnownErrorCode::ProtocolError);
+                }
+                self.max_frame_size = value;
+            }
+            Some(SettingIdentifier::MaxHeaderListSize) => {
+                self.max_header_list_size = Some(value);
+            }
+            Some(SettingIdentifier::EnableConnectProtocol) => {
+                self.enable_connect_protocol = match value {
+                    // once enabled, it can't be disabled again
+                    0 if self.enable_connect_protocol => return Err(KnownErrorCode::ProtocolError),
+                    0 => false,
+                    1 => true,
+                    _ => return Err(KnownErrorCode::ProtocolError),
+                };
+            }
+            Some(SettingIdentifier::NoRfc7540Priorities) => {
+                self.no_rfc7540_priorities = match value {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(KnownErrorCode::ProtocolError),
+                };
+            }
+            None => {
+                // ignore unknown settings
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct ErrorCode(u32);
 
@@ -319,6 +534,13 @@ impl fmt::Debug for ErrorCode {
     }
 }
 
+impl ErrorCode {
+    /// Parses the error code found in RST_STREAM and GOAWAY frames
+    pub(crate) fn parse(i: Roll) -> IResult<Roll, Self> {
+        map(be_u32, Self)(i)
+    }
+}
+
 impl From<KnownErrorCode> for ErrorCode {
     fn from(e: KnownErrorCode) -> Self {
         Self(e as u32)
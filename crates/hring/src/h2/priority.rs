@@ -0,0 +1,254 @@
+//! Extensible prioritization, cf. https://www.rfc-editor.org/rfc/rfc9218
+
+use std::collections::{HashMap, VecDeque};
+
+use http::header;
+use tracing::debug;
+
+use crate::Headers;
+
+use super::{conn::ConnState, encode::H2EventPayload, parse::StreamId};
+
+/// How urgent a response is, and whether it's useful piece by piece, cf.
+/// https://www.rfc-editor.org/rfc/rfc9218#name-priority-parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Priority {
+    /// 0 (most urgent) to 7 (least urgent)
+    pub(crate) urgency: u8,
+
+    /// Whether the response can be processed as it comes in (e.g. a
+    /// progressive image), and so can share bandwidth with other streams.
+    pub(crate) incremental: bool,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self {
+            urgency: Self::DEFAULT_URGENCY,
+            incremental: false,
+        }
+    }
+}
+
+impl Priority {
+    const DEFAULT_URGENCY: u8 = 3;
+    const MAX_URGENCY: u8 = 7;
+
+    /// Reads the `priority` header(s) of a request, cf.
+    /// https://www.rfc-editor.org/rfc/rfc9218#name-the-priority-http-header-fi
+    pub(crate) fn from_headers(headers: &Headers) -> Self {
+        let mut priority = Self::default();
+        for value in headers.get_all(header::HeaderName::from_static("priority")) {
+            priority.apply(value);
+        }
+        priority
+    }
+
+    /// Parses a priority field value, e.g. from a PRIORITY_UPDATE frame.
+    /// Missing parameters take their default value.
+    pub(crate) fn parse(value: &[u8]) -> Self {
+        let mut priority = Self::default();
+        priority.apply(value);
+        priority
+    }
+
+    /// Applies the members of a priority field value, which is a structured
+    /// field dictionary (cf. https://www.rfc-editor.org/rfc/rfc8941#name-dictionaries).
+    /// Members that are unknown or don't parse are ignored, as required by
+    /// https://www.rfc-editor.org/rfc/rfc9218#name-priority-parameters
+    fn apply(&mut self, value: &[u8]) {
+        let Ok(value) = std::str::from_utf8(value) else {
+            debug!("ignoring non-ASCII priority {value:?}");
+            return;
+        };
+
+        for member in value.split(',') {
+            // parameters (after `;`) have no meaning here
+            let member = member.split(';').next().unwrap_or_default().trim();
+            let (key, value) = match member.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (member, None),
+            };
+
+            match (key, value) {
+                ("u", Some(value)) => match value.parse() {
+                    Ok(urgency) if urgency <= Self::MAX_URGENCY => self.urgency = urgency,
+                    _ => debug!("ignoring invalid urgency {value:?}"),
+                },
+                // a bare key is boolean true
+                ("i", None | Some("?1")) => self.incremental = true,
+                ("i", Some("?0")) => self.incremental = false,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Decides which stream gets to write next: events for a stream stay in
+/// order, but streams are interleaved according to their [Priority], so one
+/// large response doesn't hold up all the others, cf.
+/// https://www.rfc-editor.org/rfc/rfc9218#name-server-scheduling
+pub(crate) struct WriteScheduler {
+    /// Events waiting to be written, per stream. Queues never stay empty.
+    queues: HashMap<StreamId, VecDeque<H2EventPayload>>,
+
+    /// How many bytes of DATA are waiting in `queues`
+    queued_bytes: usize,
+
+    /// The incremental stream that wrote last, so the others get a turn
+    last_incremental: StreamId,
+}
+
+impl Default for WriteScheduler {
+    fn default() -> Self {
+        Self {
+            queues: Default::default(),
+            queued_bytes: 0,
+            last_incremental: StreamId::CONNECTION,
+        }
+    }
+}
+
+impl WriteScheduler {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    pub(crate) fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    /// Whether events for that stream are waiting to be written
+    pub(crate) fn has_queued(&self, stream_id: StreamId) -> bool {
+        self.queues.contains_key(&stream_id)
+    }
+
+    pub(crate) fn push_back(&mut self, stream_id: StreamId, payload: H2EventPayload) {
+        self.queued_bytes += payload_len(&payload);
+        self.queues.entry(stream_id).or_default().push_back(payload);
+    }
+
+    /// Drops whatever is queued for a stream, e.g. because it's being reset.
+    /// The flow-control capacity its DATA took is given back.
+    pub(crate) fn forget<H>(&mut self, stream_id: StreamId, state: &mut ConnState<H>) {
+        if let Some(queue) = self.queues.remove(&stream_id) {
+            let dropped = queue.iter().map(payload_len).sum::<usize>();
+            self.queued_bytes -= dropped;
+            state.refund_outgoing_capacity(dropped);
+        }
+    }
+
+    /// Puts back what's left of an event that was only partly written
+    pub(crate) fn push_front(&mut self, stream_id: StreamId, payload: H2EventPayload) {
+        self.queued_bytes += payload_len(&payload);
+        self.queues
+            .entry(stream_id)
+            .or_default()
+            .push_front(payload);
+    }
+
+    /// Returns the next event to write. Events for streams that were reset
+    /// (or otherwise forgotten) are dropped, since nothing may be sent on
+    /// them anymore, and the capacity their DATA took is given back.
+    pub(crate) fn pop<H>(
+        &mut self,
+        state: &mut ConnState<H>,
+    ) -> Option<(StreamId, H2EventPayload)> {
+        let mut dropped = 0;
+        self.queues.retain(|stream_id, queue| {
+            if state.streams.contains_key(stream_id) {
+                return true;
+            }
+            debug!(%stream_id, "stream was reset, dropping {} events", queue.len());
+            dropped += queue.iter().map(payload_len).sum::<usize>();
+            false
+        });
+        self.queued_bytes -= dropped;
+        state.refund_outgoing_capacity(dropped);
+
+        // lowest urgency first. within an urgency level, non-incremental
+        // streams go one at a time in stream id order, then incremental ones
+        // take turns.
+        let last_incremental = self.last_incremental;
+        let (&stream_id, priority) = self
+            .queues
+            .keys()
+            .map(|stream_id| (stream_id, state.streams[stream_id].priority))
+            .min_by_key(|&(&stream_id, priority)| {
+                let turn = priority.incremental && stream_id <= last_incremental;
+                (priority.urgency, priority.incremental, turn, stream_id)
+            })?;
+        if priority.incremental {
+            self.last_incremental = stream_id;
+        }
+
+        let queue = self.queues.get_mut(&stream_id)?;
+        let payload = queue.pop_front()?;
+        if queue.is_empty() {
+            self.queues.remove(&stream_id);
+        }
+        self.queued_bytes -= payload_len(&payload);
+        Some((stream_id, payload))
+    }
+}
+
+fn payload_len(payload: &H2EventPayload) -> usize {
+    match payload {
+        H2EventPayload::BodyChunk(chunk) => chunk.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hring_buffet::Piece;
+
+    use super::{ConnState, H2EventPayload, Priority, StreamId, WriteScheduler};
+    use crate::h2::conn::{HeadersData, StreamRxStage};
+
+    #[test]
+    fn test_parse_priority() {
+        let p = |urgency, incremental| Priority {
+            urgency,
+            incremental,
+        };
+
+        assert_eq!(Priority::parse(b""), p(3, false));
+        assert_eq!(Priority::parse(b"u=0"), p(0, false));
+        assert_eq!(Priority::parse(b"u=5, i"), p(5, true));
+        assert_eq!(Priority::parse(b"i=?1,u=7"), p(7, true));
+        assert_eq!(Priority::parse(b"u=1, i=?0"), p(1, false));
+        // last one wins
+        assert_eq!(Priority::parse(b"u=1, u=6"), p(6, false));
+        // parameters are ignored
+        assert_eq!(Priority::parse(b"u=2;foo=bar, i;x"), p(2, true));
+        // so is whatever doesn't parse or is out of range
+        assert_eq!(Priority::parse(b"u=8, i=1, x=y"), p(3, false));
+        assert_eq!(Priority::parse(b"u=-1, u=abc, ,,"), p(3, false));
+    }
+
+    #[test]
+    fn test_scheduler_refunds_dropped_data() {
+        let mut state = ConnState::<HeadersData>::default();
+        let capacity = state.outgoing_capacity;
+        let chunk = |len| H2EventPayload::BodyChunk(Piece::Vec(vec![0; len]));
+
+        let mut scheduler = WriteScheduler::default();
+        for stream_id in [StreamId(1), StreamId(3)] {
+            let ss = state.new_stream(StreamRxStage::Done);
+            state.streams.insert(stream_id, ss);
+            scheduler.push_back(stream_id, chunk(10));
+            scheduler.push_back(stream_id, H2EventPayload::BodyEnd);
+        }
+
+        // stream 1 is forgotten right away...
+        scheduler.forget(StreamId(1), &mut state);
+        assert_eq!(state.outgoing_capacity, capacity + 10);
+
+        // ...stream 3 once it's popped after being reset
+        state.streams.remove(&StreamId(3));
+        assert!(scheduler.pop(&mut state).is_none());
+        assert_eq!(state.outgoing_capacity, capacity + 20);
+        assert_eq!(scheduler.queued_bytes(), 0);
+    }
+}
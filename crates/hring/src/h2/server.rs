@@ -1,44 +1,185 @@
-use std::{borrow::Cow, cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
-use enumflags2::BitFlags;
 use http::{
     header::{self, HeaderName},
     uri::{Authority, PathAndQuery, Scheme},
-    Version,
+    StatusCode, Version,
 };
 use nom::Finish;
-use smallvec::{smallvec, SmallVec};
-use tokio::sync::mpsc;
-use tracing::{debug, trace, warn};
+use smallvec::smallvec;
+use tokio::{
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+};
+use tracing::{debug, trace};
 
 use crate::{
     h2::{
-        body::H2Body,
-        encode::{EncoderState, H2ConnEvent, H2Encoder, H2EventPayload},
+        body::{H2Body, H2BodyItem},
+        conn::{
+            apply_settings, apply_window_update, decode_field_block, end_trailers, h2_write_loop,
+            is_connection_specific, release_incoming_capacity, send_goaway, send_rst_stream,
+            strip_padding, ConnState, HeadersData, StreamRxStage, MAX_IDLE_STREAM_PRIORITIES,
+        },
+        encode::{EncoderState, H2ConnEvent, H2Encoder},
         parse::{
-            self, ContinuationFlags, DataFlags, Frame, FrameType, HeadersFlags, KnownErrorCode,
-            PingFlags, PrioritySpec, SettingsFlags, StreamId,
+            self, ContinuationFlags, DataFlags, ErrorCode, Frame, FrameType, GoAway, HeadersFlags,
+            KnownErrorCode, PingFlags, PrioritySpec, PriorityUpdate, SettingIdentifier, Settings,
+            SettingsFlags, StreamId, WindowUpdate,
         },
+        priority::Priority,
+        types::{H2ConnectionError, H2StreamError},
     },
-    util::read_and_parse,
-    ExpectResponseHeaders, Headers, Method, Request, Responder, ServerDriver,
+    util::{read_and_parse, timeout_at_opt, timeout_opt},
+    ExpectResponseHeaders, Headers, HeadersExt, Method, Request, Responder, Response, ServerDriver,
+    Timeout,
 };
 use hring_buffet::{Piece, PieceStr, ReadWriteOwned, Roll, RollMut};
 
 /// HTTP/2 server configuration
 pub struct ServerConf {
+    /// Max number of streams the peer may have open at once, advertised as
+    /// SETTINGS_MAX_CONCURRENT_STREAMS. Streams over that limit get refused.
     pub max_streams: u32,
+
+    /// Max size of the HPACK dynamic table the peer may use when encoding
+    /// request headers, advertised as SETTINGS_HEADER_TABLE_SIZE
+    pub header_table_size: u32,
+
+    /// Initial flow-control window size for request bodies, advertised as
+    /// SETTINGS_INITIAL_WINDOW_SIZE. Must be at most 2^31-1.
+    pub initial_window_size: u32,
+
+    /// Largest frame payload we accept, advertised as SETTINGS_MAX_FRAME_SIZE.
+    /// Must be between 2^14 and 2^24-1.
+    pub max_frame_size: u32,
+
+    /// Max size of request headers (as computed by HPACK), advertised as
+    /// SETTINGS_MAX_HEADER_LIST_SIZE. Larger requests get a 431 response.
+    pub max_header_list_size: u32,
+
+    /// Whether to accept extended CONNECT requests (RFC 8441), e.g. for
+    /// WebSockets over HTTP/2, advertised as SETTINGS_ENABLE_CONNECT_PROTOCOL.
+    /// The handler sees the `:protocol` in [Request::protocol](crate::Request::protocol).
+    pub enable_connect_protocol: bool,
+
+    /// How long in-flight streams get to finish once either side starts a
+    /// graceful shutdown. Streams still open after that are cancelled.
+    pub shutdown_grace_period: Duration,
+
+    /// Max length of a request body, if any. Requests with a larger
+    /// content-length get a 413 without calling the handler; other bodies
+    /// error out with [crate::BodyErrorReason::BodyTooLarge] once they go over.
+    pub max_request_body_len: Option<u64>,
+
+    /// How long the connection may go without open streams, including before
+    /// the first one. Past that, we send GOAWAY and close it.
+    pub keep_alive_timeout: Duration,
+
+    /// How long the client gets to send the connection preface, and to finish
+    /// a field block once it started one: CONTINUATION frames hold up the
+    /// whole connection. Past that, we send GOAWAY and close it.
+    pub header_read_timeout: Duration,
+
+    /// How long reading a request body may wait for more DATA. Past that, the
+    /// stream is reset with CANCEL, and the body errors out with
+    /// [crate::BodyErrorReason::TimedOutWhileReading]. CONNECT streams don't
+    /// time out.
+    pub body_idle_timeout: Duration,
+
+    /// How long a handler gets to handle a request, reading the body and
+    /// writing the response included, if there's a limit. Past that, the
+    /// handler is cancelled: the stream gets a 500 if no response was
+    /// written yet, or is reset otherwise.
+    pub request_deadline: Option<Duration>,
 }
 
 impl Default for ServerConf {
     fn default() -> Self {
-        Self { max_streams: 32 }
+        Self {
+            max_streams: 32,
+            header_table_size: parse::DEFAULT_HEADER_TABLE_SIZE,
+            initial_window_size: parse::DEFAULT_INITIAL_WINDOW_SIZE,
+            max_frame_size: parse::DEFAULT_MAX_FRAME_SIZE,
+            max_header_list_size: 64 * 1024,
+            enable_connect_protocol: true,
+            shutdown_grace_period: Duration::from_secs(30),
+            max_request_body_len: None,
+            keep_alive_timeout: Duration::from_secs(60),
+            header_read_timeout: Duration::from_secs(30),
+            body_idle_timeout: Duration::from_secs(30),
+            request_deadline: None,
+        }
     }
 }
 
+impl ServerConf {
+    /// The SETTINGS parameters we send to the peer when the connection starts.
+    /// We go by RFC 9218 priority signals, not RFC 7540 priority trees.
+    fn settings(&self) -> [(SettingIdentifier, u32); 7] {
+        [
+            (SettingIdentifier::HeaderTableSize, self.header_table_size),
+            (SettingIdentifier::MaxConcurrentStreams, self.max_streams),
+            (SettingIdentifier::NoRfc7540Priorities, 1),
+            (
+                SettingIdentifier::InitialWindowSize,
+                self.initial_window_size,
+            ),
+            (SettingIdentifier::MaxFrameSize, self.max_frame_size),
+            (
+                SettingIdentifier::MaxHeaderListSize,
+                self.max_header_list_size,
+            ),
+            (
+                SettingIdentifier::EnableConnectProtocol,
+                self.enable_connect_protocol as u32,
+            ),
+        ]
+    }
+}
+
+/// Starts a graceful shutdown of the connections it was passed to, see
+/// [serve_with_shutdown]. Cloning it gives a handle to the same signal.
+#[derive(Clone, Default)]
+pub struct GracefulShutdown {
+    inner: Rc<GracefulShutdownInner>,
+}
+
 #[derive(Default)]
-struct ConnState {
-    streams: HashMap<StreamId, StreamState>,
+struct GracefulShutdownInner {
+    started: Cell<bool>,
+    notify: Notify,
+}
+
+impl GracefulShutdown {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sends GOAWAY on all connections: they stop accepting new streams, and
+    /// close once in-flight streams are done, or after
+    /// [ServerConf::shutdown_grace_period].
+    pub fn start(&self) {
+        self.inner.started.set(true);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.inner.started.get()
+    }
+
+    async fn wait(&self) {
+        let notified = self.inner.notify.notified();
+        if self.is_started() {
+            return;
+        }
+        notified.await
+    }
 }
 
 #[derive(Default, Clone, Copy)]
@@ -48,77 +189,230 @@ enum ContinuationState {
     ContinuingHeaders(StreamId),
 }
 
-struct StreamState {
-    rx_stage: StreamRxStage,
-}
+/// How an HTTP/2 connection ended, if it didn't end with an I/O error
+#[derive(Debug)]
+pub enum ServeOutcome {
+    /// The client closed the connection
+    ClientClosedConnection,
 
-enum StreamRxStage {
-    Headers(HeadersData),
-    Body(mpsc::Sender<eyre::Result<Piece>>),
-    Done,
-}
+    /// The client didn't start with the HTTP/2 connection preface
+    // TODO: return buffer there so we can see what they did write?
+    ClientDidntSpeakHttp2,
+
+    /// We shut down gracefully, see [GracefulShutdown]
+    ServerShutDown,
 
-struct HeadersData {
-    /// If true, no DATA frames follow, cf. https://httpwg.org/specs/rfc9113.html#HttpFraming
-    end_stream: bool,
+    /// The client sent GOAWAY, and in-flight streams are done
+    ClientWentAway,
 
-    /// The field block fragments
-    fragments: SmallVec<[Roll; 2]>,
+    /// The client violated the protocol: we sent GOAWAY with the matching
+    /// error code and closed the connection.
+    ConnectionError(H2ConnectionError),
+
+    /// One of the [ServerConf] timeouts ran out: we sent GOAWAY and closed
+    /// the connection.
+    TimedOut(Timeout),
 }
 
 pub async fn serve(
     transport: impl ReadWriteOwned,
     conf: Rc<ServerConf>,
-    mut client_buf: RollMut,
+    client_buf: RollMut,
     driver: Rc<impl ServerDriver + 'static>,
-) -> eyre::Result<()> {
-    debug!("TODO: enforce max_streams {}", conf.max_streams);
+) -> eyre::Result<ServeOutcome> {
+    serve_with_shutdown(transport, conf, client_buf, driver, Default::default()).await
+}
 
-    let state = ConnState::default();
-    let state = Rc::new(RefCell::new(state));
+/// Like [serve], but the connection can be shut down gracefully by calling
+/// [GracefulShutdown::start]
+pub async fn serve_with_shutdown(
+    transport: impl ReadWriteOwned,
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    driver: Rc<impl ServerDriver + 'static>,
+    shutdown: GracefulShutdown,
+) -> eyre::Result<ServeOutcome> {
+    serve_inner(Rc::new(transport), conf, client_buf, driver, shutdown, None).await
+}
+
+/// A request that came in over HTTP/1.1 with `Upgrade: h2c`. Once we've
+/// switched to HTTP/2, it gets answered on stream 1, cf.
+/// https://httpwg.org/specs/rfc7540.html#discover-http
+pub(crate) struct H2cUpgrade {
+    /// The upgrade request, which must not have a body
+    pub(crate) req: Request,
+
+    /// Decoded from the `HTTP2-Settings` header
+    pub(crate) settings: Settings,
+}
+
+/// Serves HTTP/2 on a connection handed over by [crate::h2c::serve]. If the
+/// client upgraded from HTTP/1.1, we've already answered the upgrade request
+/// with `101 Switching Protocols`.
+pub(crate) async fn serve_h2c(
+    transport: Rc<impl ReadWriteOwned>,
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    driver: Rc<impl ServerDriver + 'static>,
+    upgrade: Option<H2cUpgrade>,
+) -> eyre::Result<ServeOutcome> {
+    serve_inner(
+        transport,
+        conf,
+        client_buf,
+        driver,
+        Default::default(),
+        upgrade,
+    )
+    .await
+}
 
-    const MAX_FRAME_LEN: usize = 64 * 1024;
+async fn serve_inner(
+    transport: Rc<impl ReadWriteOwned>,
+    conf: Rc<ServerConf>,
+    mut client_buf: RollMut,
+    driver: Rc<impl ServerDriver + 'static>,
+    shutdown: GracefulShutdown,
+    upgrade: Option<H2cUpgrade>,
+) -> eyre::Result<ServeOutcome> {
+    // validate our own settings the same way we validate the peer's
+    let mut self_settings = Settings::default();
+    let mut settings_payload = Vec::with_capacity(conf.settings().len() * 6);
+    for (id, value) in conf.settings() {
+        if self_settings.apply(id.repr(), value).is_err() {
+            return Err(eyre::eyre!("invalid h2::ServerConf: bad {id:?} ({value})"));
+        }
+
+        use byteorder::{BigEndian, WriteBytesExt};
+        settings_payload.write_u16::<BigEndian>(id.repr())?;
+        settings_payload.write_u32::<BigEndian>(value)?;
+    }
 
-    let transport = Rc::new(transport);
+    let state = ConnState::<HeadersData>::default();
+    let state = Rc::new(RefCell::new(state));
 
-    (client_buf, _) = match read_and_parse(
-        parse::preface,
+    let read = read_and_parse(
+        parse::starts_with_preface,
         transport.as_ref(),
         client_buf,
         parse::PREFACE.len(),
-    )
-    .await?
-    {
-        Some((client_buf, frame)) => (client_buf, frame),
+    );
+    let Ok(res) = tokio::time::timeout(conf.header_read_timeout, read).await else {
+        // we haven't sent our settings yet, so there's no point in a GOAWAY
+        debug!("timed out reading the h2 connection preface");
+        return Ok(ServeOutcome::TimedOut(Timeout::HeaderRead));
+    };
+    let has_preface;
+    (client_buf, has_preface) = match res? {
+        Some(t) => t,
         None => {
             debug!("h2 client closed connection before sending preface");
-            return Ok(());
+            return Ok(ServeOutcome::ClientClosedConnection);
         }
     };
+    if !has_preface {
+        // we may omit GOAWAY here, cf. https://httpwg.org/specs/rfc9113.html#preface
+        debug!("h2 client didn't send the connection preface");
+        return Ok(ServeOutcome::ClientDidntSpeakHttp2);
+    }
+    client_buf.skip(parse::PREFACE.len());
     debug!("read preface");
 
-    // we have to send a settings frame
-    {
-        let frame = Frame::new(
-            FrameType::Settings(Default::default()),
-            StreamId::CONNECTION,
-        );
-        frame.write(transport.as_ref()).await?;
-        debug!("sent settings frame");
-    }
+    debug!(?self_settings, "our settings");
 
     let (ev_tx, ev_rx) = tokio::sync::mpsc::channel::<H2ConnEvent>(32);
 
+    if let Some(upgrade) = upgrade {
+        accept_h2c_upgrade(&ev_tx, &state, upgrade, &driver, &conf)?;
+    }
+
+    let drain_task = h2_drain(ev_tx.clone(), state.clone(), conf.clone(), shutdown);
     let read_task = h2_read_loop(
         driver.clone(),
-        ev_tx.clone(),
+        ev_tx,
         transport.clone(),
         client_buf,
         state.clone(),
+        conf,
+        self_settings,
     );
-    let write_task = h2_write_loop(ev_rx, transport);
-    tokio::try_join!(read_task, write_task)?;
-    debug!("joined read_task / write_task");
+    // once draining is over, we stop reading: that drops the read loop's
+    // event sender, and the write loop ends after flushing what's left.
+    let read_task = async move {
+        tokio::select! {
+            res = read_task => res,
+            res = drain_task => res,
+        }
+    };
+    let write_task = h2_write_loop(ev_rx, transport, state, settings_payload);
+    let (outcome, ()) = tokio::try_join!(read_task, write_task)?;
+    debug!(?outcome, "joined read_task / write_task");
+
+    Ok(outcome)
+}
+
+/// Applies the settings from the upgrade request, and hands the request to
+/// the driver as stream 1, which is half-closed (remote) from the start.
+fn accept_h2c_upgrade(
+    ev_tx: &mpsc::Sender<H2ConnEvent>,
+    state: &Rc<RefCell<ConnState<HeadersData>>>,
+    upgrade: H2cUpgrade,
+    driver: &Rc<impl ServerDriver + 'static>,
+    conf: &ServerConf,
+) -> eyre::Result<()> {
+    let stream_id = StreamId(1);
+    let H2cUpgrade { mut req, settings } = upgrade;
+
+    req.version = Version::HTTP_2;
+    let names: Vec<HeaderName> = req
+        .headers
+        .keys()
+        .filter(|&name| is_connection_specific(name) || name == "http2-settings")
+        .cloned()
+        .collect();
+    for name in names {
+        req.headers.remove(name);
+    }
+
+    let mut state_ref = state.borrow_mut();
+    state_ref
+        .apply_peer_settings(settings)
+        .map_err(|code| eyre::eyre!("invalid HTTP2-Settings: {code:?}"))?;
+    state_ref.last_stream_id = stream_id;
+
+    let responder = Responder {
+        encoder: H2Encoder {
+            stream_id,
+            tx: ev_tx.clone(),
+            state: EncoderState::ExpectResponseHeaders,
+            conn_state: state.clone(),
+            head_request: matches!(req.method, Method::Head),
+        },
+        state: ExpectResponseHeaders,
+    };
+    let (_, piece_rx) = mpsc::unbounded_channel::<eyre::Result<H2BodyItem>>();
+    let req_body = H2Body {
+        content_length: Some(0),
+        eof: true,
+        rx: piece_rx,
+        stream_id,
+        ev_tx: ev_tx.clone(),
+        max_len: None,
+        received: 0,
+        idle_timeout: None,
+        conn_state: None,
+    };
+
+    let mut ss = state_ref.new_stream(StreamRxStage::Done);
+    ss.priority = Priority::from_headers(&req.headers);
+    ss.handler = Some(spawn_handler(
+        driver,
+        req,
+        req_body,
+        responder,
+        conf.request_deadline,
+    ));
+    state_ref.streams.insert(stream_id, ss);
 
     Ok(())
 }
@@ -127,40 +421,118 @@ async fn h2_read_loop(
     driver: Rc<impl ServerDriver + 'static>,
     ev_tx: mpsc::Sender<H2ConnEvent>,
     transport: Rc<impl ReadWriteOwned>,
+    client_buf: RollMut,
+    state: Rc<RefCell<ConnState<HeadersData>>>,
+    conf: Rc<ServerConf>,
+    self_settings: Settings,
+) -> eyre::Result<ServeOutcome> {
+    let res = h2_read_frames(
+        &driver,
+        &ev_tx,
+        transport.as_ref(),
+        client_buf,
+        &state,
+        &conf,
+        self_settings,
+    )
+    .await;
+
+    match res {
+        Ok(()) => Ok(ServeOutcome::ClientClosedConnection),
+        Err(e) => match e.downcast::<H2ConnectionError>() {
+            Ok(e) => {
+                // we never initiate streams, so this is the last one we may
+                // have processed. it can't be higher than in an earlier
+                // GOAWAY, though.
+                let last_stream_id = {
+                    let state = state.borrow();
+                    state.goaway_last_stream_id.unwrap_or(state.last_stream_id)
+                };
+                send_goaway(&ev_tx, last_stream_id, &e).await;
+                // nothing the handlers send would make it to the peer
+                abort_streams(&state);
+                Ok(ServeOutcome::ConnectionError(e))
+            }
+            Err(e) => match e.downcast::<Timeout>() {
+                Ok(timeout) => {
+                    debug!("{timeout}, closing connection");
+                    let debug_data = Piece::Vec(timeout.to_string().into_bytes());
+                    send_goaway_no_error(&ev_tx, &state, debug_data).await;
+                    abort_streams(&state);
+                    Ok(ServeOutcome::TimedOut(timeout))
+                }
+                Err(e) => Err(e),
+            },
+        },
+    }
+}
+
+/// Reads frames until the peer closes the connection. Protocol violations
+/// that take the whole connection down are returned as [H2ConnectionError].
+async fn h2_read_frames(
+    driver: &Rc<impl ServerDriver + 'static>,
+    ev_tx: &mpsc::Sender<H2ConnEvent>,
+    transport: &impl ReadWriteOwned,
     mut client_buf: RollMut,
-    state: Rc<RefCell<ConnState>>,
+    state: &Rc<RefCell<ConnState<HeadersData>>>,
+    conf: &ServerConf,
+    self_settings: Settings,
 ) -> eyre::Result<()> {
     let mut hpack_dec = hring_hpack::Decoder::new();
+    // until the peer acknowledges our settings, it may still assume the
+    // default table size.
+    hpack_dec.set_max_allowed_table_size(std::cmp::max(
+        self_settings.header_table_size,
+        parse::DEFAULT_HEADER_TABLE_SIZE,
+    ) as usize);
     let mut continuation_state = ContinuationState::Idle;
+    // when the field block being read has to be complete
+    let mut field_block_deadline: Option<Instant> = None;
 
     loop {
+        let read = read_and_parse(Frame::parse, transport, client_buf, 32 * 1024);
+        let Some(res) = timeout_at_opt(field_block_deadline, read).await else {
+            return Err(Timeout::HeaderRead.into());
+        };
         let frame;
-        (client_buf, frame) =
-            match read_and_parse(Frame::parse, transport.as_ref(), client_buf, 32 * 1024).await? {
-                Some((client_buf, frame)) => (client_buf, frame),
-                None => {
-                    debug!("h2 client closed connection");
-                    return Ok(());
-                }
-            };
+        (client_buf, frame) = match res? {
+            Some((client_buf, frame)) => (client_buf, frame),
+            None => {
+                debug!("h2 client closed connection");
+                return Ok(());
+            }
+        };
 
         debug!(?frame, "received h2 frame");
 
+        let max_frame_size = state.borrow().self_settings.max_frame_size;
+        if frame.len > max_frame_size {
+            // we can't tell where the next frame starts without reading this
+            // one's payload, so this is a connection error.
+            return Err(H2ConnectionError::FrameTooLarge {
+                frame_size: frame.len,
+                max_frame_size,
+            }
+            .into());
+        }
+
         // TODO: there might be optimizations to be done for `Data` frames later
         // on, but for now, let's unconditionally read the payload (if it's not
         // empty).
         let mut payload: Roll = if frame.len == 0 {
             Roll::empty()
         } else {
-            let payload_roll;
-            (client_buf, payload_roll) = match read_and_parse(
+            let read = read_and_parse(
                 nom::bytes::streaming::take(frame.len as usize),
-                transport.as_ref(),
+                transport,
                 client_buf,
                 frame.len as usize,
-            )
-            .await?
-            {
+            );
+            let Some(res) = timeout_at_opt(field_block_deadline, read).await else {
+                return Err(Timeout::HeaderRead.into());
+            };
+            let payload_roll;
+            (client_buf, payload_roll) = match res? {
                 Some((client_buf, payload)) => (client_buf, payload),
                 None => {
                     debug!(
@@ -173,327 +545,668 @@ async fn h2_read_loop(
             payload_roll
         };
 
-        match continuation_state {
-            ContinuationState::Idle => {
-                match frame.frame_type {
-                    FrameType::Data(flags) => {
-                        if flags.contains(DataFlags::Padded) {
-                            if payload.is_empty() {
-                                todo!("handle connection error: padded data frame, but no padding length");
-                            }
+        if let ContinuationState::ContinuingHeaders(expected_stream_id) = continuation_state {
+            let flags = match frame.frame_type {
+                FrameType::Continuation(flags) if frame.stream_id == expected_stream_id => flags,
+                _ => {
+                    return Err(H2ConnectionError::ExpectedContinuation {
+                        stream_id: expected_stream_id,
+                    }
+                    .into())
+                }
+            };
 
-                            let padding_length_roll;
-                            (padding_length_roll, payload) = payload.split_at(1);
-                            let padding_length = padding_length_roll[0] as usize;
-                            if payload.len() < padding_length {
-                                todo!(
-                            "handle connection error: padded headers frame, but not enough padding"
-                        );
-                            }
+            let end_headers_flag = flags.contains(ContinuationFlags::EndHeaders);
+            let is_trailers = {
+                let mut state = state.borrow_mut();
+                let ss = state.streams.get_mut(&frame.stream_id);
+                match ss.map(|ss| &mut ss.rx_stage) {
+                    Some(StreamRxStage::Headers(headers_data)) => {
+                        headers_data.fragments.push(payload);
+                        false
+                    }
+                    Some(StreamRxStage::Trailers(_, headers_data)) => {
+                        headers_data.fragments.push(payload);
+                        true
+                    }
+                    // the stream was cancelled mid-block, e.g. at the end of
+                    // a graceful shutdown: its fragments are gone.
+                    _ => {
+                        return Err(H2ConnectionError::UnexpectedContinuation {
+                            stream_id: frame.stream_id,
+                        }
+                        .into())
+                    }
+                }
+            };
+
+            if !end_headers_flag {
+                debug!(
+                    "expecting more field block fragments for stream {}",
+                    frame.stream_id
+                );
+                continue;
+            }
+            // we're not reading continuation frames anymore
+            continuation_state = ContinuationState::Idle;
+            field_block_deadline = None;
+
+            if is_trailers {
+                end_trailers(
+                    ev_tx,
+                    state,
+                    conf.max_header_list_size,
+                    frame.stream_id,
+                    &mut hpack_dec,
+                )
+                .await?;
+                continue;
+            }
+
+            let headers_data = {
+                let mut state = state.borrow_mut();
+                let ss = state
+                    .streams
+                    .get_mut(&frame.stream_id)
+                    .expect("stream is known, checked above");
+                match std::mem::replace(&mut ss.rx_stage, StreamRxStage::Done) {
+                    StreamRxStage::Headers(headers_data) => headers_data,
+                    _ => unreachable!("stream was receiving headers, checked above"),
+                }
+            };
+            match end_headers(
+                ev_tx,
+                state,
+                conf,
+                frame.stream_id,
+                &headers_data,
+                driver,
+                &mut hpack_dec,
+            )? {
+                Ok(opened) => {
+                    let mut state = state.borrow_mut();
+                    let priority = state.opening_priority(frame.stream_id, opened.priority);
+                    if let Some(ss) = state.streams.get_mut(&frame.stream_id) {
+                        ss.rx_stage = opened.rx_stage;
+                        ss.handler = opened.handler;
+                        ss.content_length_left = opened.content_length;
+                        ss.priority = priority;
+                    }
+                }
+                Err(e) => send_rst_stream(ev_tx, state, frame.stream_id, e).await,
+            }
+            continue;
+        }
 
-                            let at = payload.len() - padding_length;
-                            (payload, _) = payload.split_at(at);
+        match frame.frame_type {
+            FrameType::Data(flags) => {
+                // the entire payload counts towards flow control, including
+                // padding, cf. https://httpwg.org/specs/rfc9113.html#DATA
+                if !state
+                    .borrow_mut()
+                    .consume_incoming_capacity(frame.stream_id, frame.len)
+                {
+                    return Err(H2ConnectionError::FlowControlWindowExceeded {
+                        stream_id: frame.stream_id,
+                    }
+                    .into());
+                }
+
+                payload = strip_padding(payload, flags.contains(DataFlags::Padded))?;
+
+                let unknown_stream = {
+                    let state = state.borrow();
+                    (!state.streams.contains_key(&frame.stream_id)).then(|| {
+                        // is it idle (never opened) or closed?
+                        frame.stream_id == StreamId::CONNECTION
+                            || frame.stream_id > state.last_stream_id
+                    })
+                };
+                match unknown_stream {
+                    Some(true) => {
+                        return Err(H2ConnectionError::DataForIdleStream {
+                            stream_id: frame.stream_id,
+                        }
+                        .into());
+                    }
+                    Some(false) => {
+                        let reset_by_us = state.borrow().reset_streams.contains(&frame.stream_id);
+                        if reset_by_us {
+                            // the peer may not know yet: ignore the data.
+                            debug!(stream_id = %frame.stream_id, "ignoring data for reset stream");
+                        } else {
+                            // the peer ended the stream, or reset it
+                            send_rst_stream(
+                                ev_tx,
+                                state,
+                                frame.stream_id,
+                                H2StreamError::DataAfterEndStream,
+                            )
+                            .await;
                         }
+                        if frame.len > 0 {
+                            release_incoming_capacity(ev_tx, StreamId::CONNECTION, frame.len)
+                                .await?;
+                        }
+                        continue;
+                    }
+                    None => {}
+                }
 
-                        let body_tx = {
-                            let mut state = state.borrow_mut();
-                            let stream = state
-                                .streams
-                                .get_mut(&frame.stream_id)
-                                // TODO: proper error handling (connection error)
-                                .expect("received data for unknown stream");
-                            match &mut stream.rx_stage {
-                                StreamRxStage::Headers(_) => {
-                                    // TODO: proper error handling (stream error)
-                                    panic!("received data for stream before headers")
+                let body_tx = {
+                    let mut state = state.borrow_mut();
+                    let stream = state
+                        .streams
+                        .get_mut(&frame.stream_id)
+                        .expect("stream is known, checked above");
+                    match &mut stream.rx_stage {
+                        StreamRxStage::Headers(_) | StreamRxStage::Trailers(..) => {
+                            // we'd be in `ContinuationState::ContinuingHeaders`
+                            unreachable!("received data for stream while receiving headers")
+                        }
+                        StreamRxStage::Body(tx) => {
+                            let end_stream = flags.contains(DataFlags::EndStream);
+                            let within_length = match stream.content_length_left.as_mut() {
+                                Some(left) if payload.len() as u64 > *left => {
+                                    Err("data exceeds content-length")
                                 }
-                                StreamRxStage::Body(tx) => {
+                                Some(left) if end_stream && payload.len() as u64 != *left => {
+                                    Err("data shorter than content-length")
+                                }
+                                Some(left) => {
+                                    *left -= payload.len() as u64;
+                                    Ok(())
+                                }
+                                None => Ok(()),
+                            };
+                            match within_length {
+                                Ok(()) => {
                                     // TODO: we can get rid of that clone sometimes
                                     let tx = tx.clone();
-                                    if flags.contains(DataFlags::EndStream) {
+                                    let tx_done = stream.tx_done;
+                                    let checks_length = stream.content_length_left.is_some();
+                                    if end_stream {
                                         stream.rx_stage = StreamRxStage::Done;
+                                        state.close_stream_if_done(frame.stream_id);
                                     }
-                                    tx
-                                }
-                                StreamRxStage::Done => {
-                                    // TODO: proper error handling (stream error)
-                                    panic!("received data for stream after completion");
+                                    Ok(Some((tx, tx_done, checks_length)))
                                 }
+                                Err(reason) => Err(reason),
                             }
-                        };
-
-                        if body_tx.send(Ok(payload.into())).await.is_err() {
-                            warn!("TODO: The body is being ignored, we should reset the stream");
                         }
+                        StreamRxStage::Done => Ok(None),
                     }
-                    FrameType::Headers(flags) => {
-                        {
-                            let state = state.borrow();
-                            if state.streams.contains_key(&frame.stream_id) {
-                                todo!(
-                                    "handle connection error: received headers for existing stream"
-                                );
-                            }
-                        }
+                };
+                let (body_tx, tx_done, checks_length) = match body_tx {
+                    Ok(Some(t)) => t,
+                    Err(reason) => {
+                        send_rst_stream(
+                            ev_tx,
+                            state,
+                            frame.stream_id,
+                            H2StreamError::MalformedMessage { reason },
+                        )
+                        .await;
+                        release_incoming_capacity(ev_tx, StreamId::CONNECTION, frame.len).await?;
+                        continue;
+                    }
+                    Ok(None) => {
+                        // the stream is half-closed (remote), cf.
+                        // https://httpwg.org/specs/rfc9113.html#StreamStates
+                        send_rst_stream(
+                            ev_tx,
+                            state,
+                            frame.stream_id,
+                            H2StreamError::DataAfterEndStream,
+                        )
+                        .await;
+                        release_incoming_capacity(ev_tx, StreamId::CONNECTION, frame.len).await?;
+                        continue;
+                    }
+                };
+
+                // the body gives back capacity as it's read, but padding
+                // never makes it there.
+                let mut unused_capacity = frame.len - payload.len() as u32;
+
+                let payload_len = payload.len() as u32;
+                if body_tx.send(Ok(H2BodyItem::Chunk(payload.into()))).is_err() {
+                    unused_capacity += payload_len;
+
+                    // if the request announced a content-length, we keep
+                    // discarding the body until END_STREAM to tell whether
+                    // it's malformed, cf.
+                    // https://httpwg.org/specs/rfc9113.html#malformed
+                    if tx_done && !checks_length && !flags.contains(DataFlags::EndStream) {
+                        // we've responded already and nobody's reading the body,
+                        // so the peer can stop sending it.
+                        send_rst_stream(
+                            ev_tx,
+                            state,
+                            frame.stream_id,
+                            H2StreamError::RequestBodyIgnored,
+                        )
+                        .await;
+                        release_incoming_capacity(ev_tx, StreamId::CONNECTION, unused_capacity)
+                            .await?;
+                        continue;
+                    }
+                    debug!(stream_id = %frame.stream_id, "request body is being ignored");
+                }
 
-                        let padding_length = if flags.contains(HeadersFlags::Padded) {
-                            if payload.is_empty() {
-                                todo!("handle connection error: padded headers frame, but no padding length");
-                            }
+                if unused_capacity > 0 {
+                    release_incoming_capacity(ev_tx, frame.stream_id, unused_capacity).await?;
+                }
+            }
+            FrameType::Headers(flags) => {
+                payload = strip_padding(payload, flags.contains(HeadersFlags::Padded))?;
 
-                            let padding_length_roll;
-                            (padding_length_roll, payload) = payload.split_at(1);
-                            padding_length_roll[0] as usize
-                        } else {
-                            0
+                let mut self_dependent = false;
+                if flags.contains(HeadersFlags::Priority) {
+                    if payload.len() < PrioritySpec::LEN {
+                        return Err(H2ConnectionError::HeadersFrameTooShortForPriority.into());
+                    }
+                    let pri_spec;
+                    (payload, pri_spec) = PrioritySpec::parse(payload)
+                        .finish()
+                        .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                    debug!(exclusive = %pri_spec.exclusive, stream_dependency = ?pri_spec.stream_dependency, weight = %pri_spec.weight, "received priority, exclusive");
+                    // cf. https://httpwg.org/specs/rfc9113.html#pri-depend
+                    self_dependent = pri_spec.stream_dependency == frame.stream_id;
+                }
+                let self_dependency = self_dependent.then_some(H2StreamError::SelfDependency);
+
+                let is_trailers = state.borrow().streams.contains_key(&frame.stream_id);
+                if is_trailers {
+                    // a field block on an open stream can only be
+                    // trailers, cf. https://httpwg.org/specs/rfc9113.html#HttpFraming
+                    debug!("receiving trailers for stream {}", frame.stream_id);
+                    let headers_data = HeadersData {
+                        end_stream: flags.contains(HeadersFlags::EndStream),
+                        rejected: self_dependency,
+                        fragments: smallvec![payload],
+                    };
+
+                    {
+                        let mut state = state.borrow_mut();
+                        let ss = state
+                            .streams
+                            .get_mut(&frame.stream_id)
+                            .expect("stream is known, checked above");
+                        let body_tx = match std::mem::replace(&mut ss.rx_stage, StreamRxStage::Done)
+                        {
+                            StreamRxStage::Body(tx) => Some(tx),
+                            _ => None,
                         };
+                        ss.rx_stage = StreamRxStage::Trailers(body_tx, headers_data);
+                    }
 
-                        if flags.contains(HeadersFlags::Priority) {
-                            let pri_spec;
-                            (payload, pri_spec) = PrioritySpec::parse(payload)
-                                .finish()
-                                .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
-                            debug!(exclusive = %pri_spec.exclusive, stream_dependency = ?pri_spec.stream_dependency, weight = %pri_spec.weight, "received priority, exclusive");
-                        }
-
-                        if padding_length > 0 {
-                            if payload.len() < padding_length {
-                                todo!(
-                            "handle connection error: padded headers frame, but not enough padding"
-                        );
-                            }
-
-                            let at = payload.len() - padding_length;
-                            (payload, _) = payload.split_at(at);
-                        }
+                    if flags.contains(HeadersFlags::EndHeaders) {
+                        end_trailers(
+                            ev_tx,
+                            state,
+                            conf.max_header_list_size,
+                            frame.stream_id,
+                            &mut hpack_dec,
+                        )
+                        .await?;
+                    } else {
+                        continuation_state = ContinuationState::ContinuingHeaders(frame.stream_id);
+                        field_block_deadline = Some(Instant::now() + conf.header_read_timeout);
+                    }
+                    continue;
+                }
 
-                        debug!("receiving initial headers for stream {}", frame.stream_id);
-                        let headers_data = HeadersData {
-                            end_stream: flags.contains(HeadersFlags::EndStream),
-                            fragments: smallvec![payload],
-                        };
+                let rejected = {
+                    let mut state = state.borrow_mut();
+                    let going_away = state.goaway_last_stream_id.is_some();
 
-                        if flags.contains(HeadersFlags::EndHeaders) {
-                            match end_headers(
-                                &ev_tx,
-                                frame.stream_id,
-                                &headers_data,
-                                &driver,
-                                &mut hpack_dec,
-                            ) {
-                                Err(e) => {
-                                    // TODO: we should also reset the stream here, right?
-                                    send_goaway(
-                                        &ev_tx,
-                                        &state,
-                                        e,
-                                        KnownErrorCode::CompressionError,
-                                    )
-                                    .await;
-                                }
-                                Ok(next_stage) => {
-                                    let mut state = state.borrow_mut();
-                                    state.streams.insert(
-                                        frame.stream_id,
-                                        StreamState {
-                                            rx_stage: next_stage,
-                                        },
-                                    );
-                                }
-                            }
-                        } else {
-                            debug!("expecting more headers for stream {}", frame.stream_id);
-                            continuation_state =
-                                ContinuationState::ContinuingHeaders(frame.stream_id);
-
-                            {
-                                let mut state = state.borrow_mut();
-                                state.streams.insert(
-                                    frame.stream_id,
-                                    StreamState {
-                                        rx_stage: StreamRxStage::Headers(headers_data),
-                                    },
-                                );
-                            }
+                    // client-initiated streams have odd ids, that only go up
+                    if frame.stream_id.0 % 2 == 0 || frame.stream_id <= state.last_stream_id {
+                        return Err(H2ConnectionError::InvalidNewStreamId {
+                            stream_id: frame.stream_id,
+                            last_stream_id: state.last_stream_id,
                         }
+                        .into());
                     }
-                    FrameType::Priority => {
-                        let pri_spec = match PrioritySpec::parse(payload) {
-                            Ok((_rest, pri_spec)) => pri_spec,
-                            Err(e) => todo!("handle connection error: invalid priority frame {e}"),
-                        };
-                        debug!(?pri_spec, "received priority frame");
-                    }
-                    FrameType::RstStream => todo!(),
-                    FrameType::Settings(s) => {
-                        if s.contains(SettingsFlags::Ack) {
-                            debug!("Peer has acknowledged our settings, cool");
-                        } else {
-                            // TODO: actually apply settings
-
-                            if ev_tx.send(H2ConnEvent::AcknowledgeSettings).await.is_err() {
-                                return Err(eyre::eyre!(
-                                    "could not send H2 acknowledge settings event"
-                                ));
-                            }
+                    state.last_stream_id = frame.stream_id;
+                    // priorities for streams that were skipped are moot now
+                    let last_stream_id = state.last_stream_id;
+                    state
+                        .idle_stream_priorities
+                        .retain(|&stream_id, _| stream_id >= last_stream_id);
+                    let refused = going_away || state.streams.len() >= conf.max_streams as usize;
+                    self_dependency.or(refused.then_some(H2StreamError::Refused))
+                };
+
+                debug!("receiving initial headers for stream {}", frame.stream_id);
+                let headers_data = HeadersData {
+                    end_stream: flags.contains(HeadersFlags::EndStream),
+                    rejected,
+                    fragments: smallvec![payload],
+                };
+
+                if flags.contains(HeadersFlags::EndHeaders) {
+                    match end_headers(
+                        ev_tx,
+                        state,
+                        conf,
+                        frame.stream_id,
+                        &headers_data,
+                        driver,
+                        &mut hpack_dec,
+                    )? {
+                        Ok(opened) => {
+                            let mut state = state.borrow_mut();
+                            let mut ss = state.new_stream(opened.rx_stage);
+                            ss.handler = opened.handler;
+                            ss.content_length_left = opened.content_length;
+                            ss.priority = state.opening_priority(frame.stream_id, opened.priority);
+                            state.streams.insert(frame.stream_id, ss);
                         }
+                        Err(e) => send_rst_stream(ev_tx, state, frame.stream_id, e).await,
                     }
-                    FrameType::PushPromise => todo!("push promise not implemented"),
-                    FrameType::Ping(flags) => {
-                        if frame.stream_id != StreamId::CONNECTION {
-                            todo!("handle connection error: ping frame with non-zero stream id");
-                        }
-
-                        if frame.len != 8 {
-                            todo!("handle connection error: ping frame with invalid length");
-                        }
+                } else {
+                    debug!("expecting more headers for stream {}", frame.stream_id);
+                    continuation_state = ContinuationState::ContinuingHeaders(frame.stream_id);
+                    field_block_deadline = Some(Instant::now() + conf.header_read_timeout);
+
+                    let mut state = state.borrow_mut();
+                    let ss = state.new_stream(StreamRxStage::Headers(headers_data));
+                    state.streams.insert(frame.stream_id, ss);
+                }
+            }
+            FrameType::Priority => {
+                if frame.stream_id == StreamId::CONNECTION {
+                    return Err(H2ConnectionError::PriorityOnConnectionStream.into());
+                }
 
-                        if flags.contains(PingFlags::Ack) {
-                            // TODO: check that payload matches the one we sent?
+                if frame.len as usize != PrioritySpec::LEN {
+                    send_rst_stream(
+                        ev_tx,
+                        state,
+                        frame.stream_id,
+                        H2StreamError::PriorityInvalidLength { len: frame.len },
+                    )
+                    .await;
+                    continue;
+                }
+                let (_, pri_spec) = PrioritySpec::parse(payload)
+                    .finish()
+                    .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                debug!(?pri_spec, "received priority frame");
+                // cf. https://httpwg.org/specs/rfc9113.html#pri-depend
+                if pri_spec.stream_dependency == frame.stream_id {
+                    send_rst_stream(ev_tx, state, frame.stream_id, H2StreamError::SelfDependency)
+                        .await;
+                }
+            }
+            FrameType::RstStream => {
+                if frame.stream_id == StreamId::CONNECTION {
+                    return Err(H2ConnectionError::RstStreamOnConnectionStream.into());
+                }
+                if frame.len != 4 {
+                    return Err(H2ConnectionError::RstStreamInvalidLength { len: frame.len }.into());
+                }
+                if frame.stream_id > state.borrow().last_stream_id {
+                    return Err(H2ConnectionError::RstStreamForIdleStream {
+                        stream_id: frame.stream_id,
+                    }
+                    .into());
+                }
 
-                            debug!("received ping ack");
-                            continue;
-                        }
+                let (_, error_code) = ErrorCode::parse(payload)
+                    .finish()
+                    .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                debug!(stream_id = %frame.stream_id, ?error_code, "peer reset stream");
 
-                        if ev_tx.send(H2ConnEvent::Ping(payload)).await.is_err() {
-                            return Err(eyre::eyre!("could not send H2 ping event"));
-                        }
+                let ss = state
+                    .borrow_mut()
+                    .reset_stream(frame.stream_id, error_code, true);
+                if let Some(handler) = ss.and_then(|ss| ss.handler) {
+                    handler.abort();
+                }
+            }
+            FrameType::Settings(s) => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::SettingsOnStream {
+                        stream_id: frame.stream_id,
                     }
-                    FrameType::GoAway => todo!(),
-                    FrameType::WindowUpdate => {
-                        debug!("ignoring window update");
+                    .into());
+                }
+
+                if s.contains(SettingsFlags::Ack) {
+                    if frame.len != 0 {
+                        return Err(
+                            H2ConnectionError::SettingsAckWithPayload { len: frame.len }.into()
+                        );
                     }
-                    FrameType::Continuation(_flags) => {
-                        send_goaway(
-                            &ev_tx,
-                            &state,
-                            eyre::eyre!(
-                                "received unexpected continuation frame for stream {}",
-                                frame.stream_id
-                            ),
-                            KnownErrorCode::ProtocolError,
-                        )
+
+                    debug!("Peer has acknowledged our settings, cool");
+                    state.borrow_mut().apply_self_settings(self_settings);
+                    hpack_dec.set_max_allowed_table_size(self_settings.header_table_size as usize);
+                } else {
+                    let settings = apply_settings(state, payload)
+                        .map_err(|error_code| H2ConnectionError::InvalidSettings { error_code })?;
+
+                    if ev_tx
+                        .send(H2ConnEvent::AcknowledgeSettings {
+                            header_table_size: settings.header_table_size,
+                        })
                         .await
-                    }
-                    FrameType::Unknown(ft) => {
-                        trace!(
-                            "ignoring unknown frame with type 0x{:x}, flags 0x{:x}",
-                            ft.ty,
-                            ft.flags
-                        );
+                        .is_err()
+                    {
+                        return Err(eyre::eyre!("could not send H2 acknowledge settings event"));
                     }
                 }
             }
-            ContinuationState::ContinuingHeaders(expected_stream_id) => {
-                match frame.frame_type {
-                    FrameType::Continuation(flags) => {
-                        if frame.stream_id != expected_stream_id {
-                            send_goaway(
-                                &ev_tx,
-                                &state,
-                                eyre::eyre!("continuation frame for wrong stream"),
-                                KnownErrorCode::ProtocolError,
-                            )
-                            .await;
-                            continue;
-                        }
+            FrameType::PushPromise => {
+                // only servers may push, cf. https://httpwg.org/specs/rfc9113.html#PUSH_PROMISE
+                return Err(H2ConnectionError::PushPromiseReceived.into());
+            }
+            FrameType::Ping(flags) => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::PingOnStream {
+                        stream_id: frame.stream_id,
+                    }
+                    .into());
+                }
+                if frame.len != 8 {
+                    return Err(H2ConnectionError::PingInvalidLength { len: frame.len }.into());
+                }
 
-                        let res = {
-                            let mut state = state.borrow_mut();
-                            let ss = match state.streams.get_mut(&frame.stream_id) {
-                                Some(stream) => stream,
-                                None => {
-                                    todo!("handle connection error: continuation frame for unknown stream");
-                                }
-                            };
-                            match &mut ss.rx_stage {
-                                StreamRxStage::Headers(headers_data) => {
-                                    headers_data.fragments.push(payload);
-                                    if flags.contains(ContinuationFlags::EndHeaders) {
-                                        end_headers(
-                                            &ev_tx,
-                                            frame.stream_id,
-                                            headers_data,
-                                            &driver,
-                                            &mut hpack_dec,
-                                        )
-                                        .map(Some)
-                                    } else {
-                                        debug!(
-                                            "have {} field block fragments so far, will read CONTINUATION frames",
-                                            headers_data.fragments.len()
-                                        );
-                                        Ok(None)
-                                    }
-                                }
-                                _ => {
-                                    todo!("handle connection error: continuation frame for non-headers stream");
-                                }
-                            }
-                        };
+                if flags.contains(PingFlags::Ack) {
+                    // TODO: check that payload matches the one we sent?
 
-                        match res {
-                            Err(e) => {
-                                // TODO: we should also reset the stream state here, right?
-                                send_goaway(&ev_tx, &state, e, KnownErrorCode::CompressionError)
-                                    .await;
-                            }
-                            Ok(Some(next_stage)) => {
-                                // we're not reading continuation frames anymore
-                                continuation_state = ContinuationState::Idle;
+                    debug!("received ping ack");
+                    continue;
+                }
 
-                                let mut state = state.borrow_mut();
-                                let ss = state.streams.get_mut(&frame.stream_id).unwrap();
-                                ss.rx_stage = next_stage;
-                            }
-                            _ => {
-                                // don't care
-                            }
-                        }
+                if ev_tx.send(H2ConnEvent::Ping(payload)).await.is_err() {
+                    return Err(eyre::eyre!("could not send H2 ping event"));
+                }
+            }
+            FrameType::GoAway => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::GoAwayOnStream {
+                        stream_id: frame.stream_id,
                     }
-                    other => {
-                        send_goaway(
-                            &ev_tx,
-                            &state,
-                            eyre::eyre!("expected continuation frame, got {:?}", other),
-                            KnownErrorCode::ProtocolError,
-                        )
-                        .await
+                    .into());
+                }
+                if frame.len < 8 {
+                    return Err(H2ConnectionError::GoAwayInvalidLength { len: frame.len }.into());
+                }
+
+                let (additional_debug_data, goaway) = GoAway::parse(payload)
+                    .finish()
+                    .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                debug!(
+                    last_stream_id = %goaway.last_stream_id,
+                    error_code = ?goaway.error_code,
+                    additional_debug_data = %String::from_utf8_lossy(&additional_debug_data[..]),
+                    "peer sent goaway"
+                );
+
+                // we never initiate streams, so the last stream id is
+                // moot: the peer won't open any more, and we let the
+                // ones in flight finish.
+                let mut state = state.borrow_mut();
+                state.peer_goaway_last_stream_id = Some(goaway.last_stream_id);
+                state.peer_goaway_notify.notify_one();
+            }
+            FrameType::WindowUpdate => {
+                if frame.len != 4 {
+                    return Err(
+                        H2ConnectionError::WindowUpdateInvalidLength { len: frame.len }.into(),
+                    );
+                }
+
+                let (_, update) = WindowUpdate::parse(payload)
+                    .finish()
+                    .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                debug!(increment = %update.increment, stream_id = %frame.stream_id, "received window update");
+
+                if let Err(e) = apply_window_update(state, frame.stream_id, update.increment)? {
+                    send_rst_stream(ev_tx, state, frame.stream_id, e).await;
+                }
+            }
+            FrameType::Continuation(_flags) => {
+                return Err(H2ConnectionError::UnexpectedContinuation {
+                    stream_id: frame.stream_id,
+                }
+                .into());
+            }
+            FrameType::PriorityUpdate => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::PriorityUpdateOnStream {
+                        stream_id: frame.stream_id,
                     }
+                    .into());
                 }
+                if (frame.len as usize) < PriorityUpdate::MIN_LEN {
+                    return Err(
+                        H2ConnectionError::PriorityUpdateInvalidLength { len: frame.len }.into(),
+                    );
+                }
+                let (_, update) = PriorityUpdate::parse(payload)
+                    .finish()
+                    .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                apply_priority_update(state, update)?;
+            }
+            FrameType::Unknown(ft) => {
+                trace!(
+                    "ignoring unknown frame with type 0x{:x}, flags 0x{:x}",
+                    ft.ty,
+                    ft.flags
+                );
             }
         }
     }
 }
 
-async fn send_goaway(
+/// Reprioritizes a stream, cf. https://www.rfc-editor.org/rfc/rfc9218#name-the-priority_update-frame
+fn apply_priority_update(
+    state: &RefCell<ConnState<HeadersData>>,
+    update: PriorityUpdate,
+) -> Result<(), H2ConnectionError> {
+    let stream_id = update.prioritized_stream_id;
+    if stream_id == StreamId::CONNECTION {
+        return Err(H2ConnectionError::PriorityUpdateForConnectionStream);
+    }
+
+    let priority = Priority::parse(&update.field_value[..]);
+    debug!(%stream_id, ?priority, "received priority update");
+
+    let mut state = state.borrow_mut();
+    if let Some(ss) = state.streams.get_mut(&stream_id) {
+        ss.priority = priority;
+    } else if stream_id > state.last_stream_id && stream_id.0 % 2 == 1 {
+        // the stream isn't open yet, remember that for when it is
+        if state.idle_stream_priorities.len() < MAX_IDLE_STREAM_PRIORITIES
+            || state.idle_stream_priorities.contains_key(&stream_id)
+        {
+            state.idle_stream_priorities.insert(stream_id, priority);
+        }
+    } else {
+        // the stream is closed, or one we'd have opened (and we never do)
+        debug!(%stream_id, "ignoring priority update");
+    }
+    Ok(())
+}
+
+/// Waits for either us or the peer to start a graceful shutdown, then for
+/// in-flight streams to finish (or for the grace period to run out), cf.
+/// https://httpwg.org/specs/rfc9113.html#GOAWAY
+async fn h2_drain(
+    ev_tx: mpsc::Sender<H2ConnEvent>,
+    state: Rc<RefCell<ConnState<HeadersData>>>,
+    conf: Rc<ServerConf>,
+    shutdown: GracefulShutdown,
+) -> eyre::Result<ServeOutcome> {
+    let peer_goaway_notify = state.borrow().peer_goaway_notify.clone();
+
+    let outcome = tokio::select! {
+        _ = shutdown.wait() => {
+            debug!("starting graceful shutdown");
+            send_goaway_no_error(&ev_tx, &state, Piece::Static(&[])).await;
+            ServeOutcome::ServerShutDown
+        }
+        _ = peer_goaway_notify.notified() => {
+            debug!("peer started graceful shutdown");
+            ServeOutcome::ClientWentAway
+        }
+        _ = wait_idle(&state, conf.keep_alive_timeout) => {
+            debug!("connection idle for too long, closing it");
+            let timeout = Timeout::KeepAliveIdle;
+            let debug_data = Piece::Vec(timeout.to_string().into_bytes());
+            send_goaway_no_error(&ev_tx, &state, debug_data).await;
+            ServeOutcome::TimedOut(timeout)
+        }
+    };
+    drop(ev_tx);
+
+    let grace_period = tokio::time::sleep(conf.shutdown_grace_period);
+    tokio::pin!(grace_period);
+
+    loop {
+        let stream_closed_notify = state.borrow().stream_closed_notify.clone();
+        let stream_closed = stream_closed_notify.notified();
+        if state.borrow().streams.is_empty() {
+            debug!("all streams are done, closing connection");
+            return Ok(outcome);
+        }
+
+        tokio::select! {
+            _ = stream_closed => {}
+            _ = &mut grace_period => break,
+        }
+    }
+
+    let num_streams = abort_streams(&state);
+    debug!("grace period is over, cancelled {num_streams} in-flight streams");
+
+    Ok(outcome)
+}
+
+/// Tells the peer we won't process streams past the ones we already have, cf.
+/// https://httpwg.org/specs/rfc9113.html#GOAWAY. Those may still finish.
+async fn send_goaway_no_error(
     ev_tx: &mpsc::Sender<H2ConnEvent>,
-    state: &Rc<RefCell<ConnState>>,
-    e: eyre::Report,
-    error_code: KnownErrorCode,
+    state: &RefCell<ConnState<HeadersData>>,
+    additional_debug_data: Piece,
 ) {
-    warn!("connection error: {e:?}");
-    debug!("error_code = {error_code:?}");
-
-    // FIXME: this is almost definitely wrong. we must separate
-    // client-initiated streams from server-initiated streams, and
-    // take stream state into account
-    let last_stream_id = state
-        .borrow()
-        .streams
-        .keys()
-        .copied()
-        .max()
-        .unwrap_or(StreamId::CONNECTION);
-    debug!("last_stream_id = {last_stream_id}");
-    // TODO: is this a good idea?
-    let additional_debug_data = format!("hpack error: {e:?}").into_bytes();
+    let last_stream_id = {
+        let mut state = state.borrow_mut();
+        let last_stream_id = state.last_stream_id;
+        *state.goaway_last_stream_id.get_or_insert(last_stream_id)
+    };
+    debug!(%last_stream_id, "sending goaway");
 
     if ev_tx
         .send(H2ConnEvent::GoAway {
-            error_code,
+            error_code: KnownErrorCode::NoError,
             last_stream_id,
-            additional_debug_data: Piece::Vec(additional_debug_data),
+            additional_debug_data,
         })
         .await
         .is_err()
@@ -502,280 +1215,401 @@ async fn send_goaway(
     }
 }
 
-async fn h2_write_loop(
-    mut ev_rx: mpsc::Receiver<H2ConnEvent>,
-    transport: Rc<impl ReadWriteOwned>,
-) -> eyre::Result<()> {
-    let mut hpack_enc = hring_hpack::Encoder::new();
-
-    let mut index = 0;
-
-    while let Some(ev) = ev_rx.recv().await {
-        trace!("h2_write_loop: received H2 event");
-        match ev {
-            H2ConnEvent::AcknowledgeSettings => {
-                debug!("acknowledging new settings");
-                let res_frame = Frame::new(
-                    FrameType::Settings(SettingsFlags::Ack.into()),
-                    StreamId::CONNECTION,
-                );
-                res_frame.write(transport.as_ref()).await?;
-            }
-            H2ConnEvent::ServerEvent(ev) => {
-                debug!("Sending server event: {ev:?}");
-
-                match ev.payload {
-                    H2EventPayload::Headers(res) => {
-                        debug!("Sending headers on stream {}", ev.stream_id);
-                        let flags = HeadersFlags::EndHeaders;
-                        let mut frame = Frame::new(FrameType::Headers(flags.into()), ev.stream_id);
-
-                        // TODO: don't allocate so much for headers
-                        // TODO: limt header size
-                        let mut headers: Vec<(&[u8], &[u8])> = vec![];
-                        headers.push((b":status", res.status.as_str().as_bytes()));
-                        for (name, value) in res.headers.iter() {
-                            if name == http::header::TRANSFER_ENCODING {
-                                // do not set transfer-encoding: chunked when doing HTTP/2
-                                continue;
-                            }
-                            headers.push((name.as_str().as_bytes(), value));
-                        }
-                        let headers_encoded = hpack_enc.encode(headers);
-                        frame.len = headers_encoded.len() as u32;
-                        frame.write(transport.as_ref()).await?;
+/// Returns once the connection went without open streams for `timeout`
+async fn wait_idle(state: &RefCell<ConnState<HeadersData>>, timeout: Duration) {
+    loop {
+        let stream_closed_notify = state.borrow().stream_closed_notify.clone();
+        let stream_closed = stream_closed_notify.notified();
+        if !state.borrow().streams.is_empty() {
+            stream_closed.await;
+            continue;
+        }
 
-                        let (res, _headers_encoded) = transport.write_all(headers_encoded).await;
-                        res?;
-                    }
-                    H2EventPayload::BodyChunk(chunk) => {
-                        let path = format!("/tmp/chunk-write-{index:06}.bin");
-                        std::fs::write(path, chunk.as_ref()).unwrap();
-                        index += 1;
-
-                        let flags = BitFlags::<DataFlags>::default();
-                        let frame = Frame::new(FrameType::Data(flags), ev.stream_id)
-                            .with_len(chunk.len().try_into().unwrap());
-                        frame.write(transport.as_ref()).await?;
-                        let (res, _) = transport.write_all(chunk).await;
-                        res?;
-                    }
-                    H2EventPayload::BodyEnd => {
-                        let flags = DataFlags::EndStream;
-                        let frame = Frame::new(FrameType::Data(flags.into()), ev.stream_id);
-                        frame.write(transport.as_ref()).await?;
-                    }
-                }
-            }
-            H2ConnEvent::Ping(payload) => {
-                // send pong frame
-                let flags = PingFlags::Ack.into();
-                let frame = Frame::new(FrameType::Ping(flags), StreamId::CONNECTION)
-                    .with_len(payload.len() as u32);
-                frame.write(transport.as_ref()).await?;
-                let (res, _) = transport.write_all(payload).await;
-                res?;
-            }
-            H2ConnEvent::GoAway {
-                error_code,
-                last_stream_id,
-                additional_debug_data,
-            } => {
-                debug!("must send goaway");
-                let mut header = vec![0u8; 8];
-                {
-                    use byteorder::{BigEndian, WriteBytesExt};
-                    let mut header = &mut header[..];
-                    // TODO: do we ever need to write the reserved bit?
-                    header.write_u32::<BigEndian>(last_stream_id.0)?;
-                    header.write_u32::<BigEndian>(error_code.repr())?;
-                }
-
-                debug!("sending goaway frame");
-                let frame = Frame::new(FrameType::GoAway, StreamId::CONNECTION).with_len(
-                    (header.len() + additional_debug_data.len())
-                        .try_into()
-                        .unwrap(),
-                );
-                frame.write(transport.as_ref()).await?;
-                let (res, _) = transport.write_all(header).await;
-                res?;
-                let (res, _) = transport.write_all(additional_debug_data).await;
-                res?;
-            }
+        // streams that open and close while we sleep move the deadline
+        let idle_since = state.borrow().idle_since;
+        tokio::time::sleep_until((idle_since + timeout).into()).await;
+        let state = state.borrow();
+        if state.streams.is_empty() && state.idle_since == idle_since {
+            return;
         }
     }
-    debug!("h2_write_loop is done");
+}
 
-    Ok(())
+/// Forgets about all streams and cancels their handlers, returning how many
+/// there were.
+fn abort_streams(state: &RefCell<ConnState<HeadersData>>) -> usize {
+    // forgetting the streams first means their encoders, when dropped, don't
+    // try to send anything
+    let streams = std::mem::take(&mut state.borrow_mut().streams);
+    let num_streams = streams.len();
+    for (_, ss) in streams {
+        if let Some(handler) = ss.handler {
+            handler.abort();
+        }
+    }
+    num_streams
 }
 
+/// What a stream becomes once its request headers are in
+struct OpenedStream {
+    rx_stage: StreamRxStage<HeadersData>,
+
+    /// The task handling the request, if any
+    handler: Option<JoinHandle<()>>,
+
+    /// The request body's announced length, if any
+    content_length: Option<u64>,
+
+    /// From the request's `priority` header
+    priority: Priority,
+}
+
+/// Decodes a request's field block once it's complete, and hands the request
+/// to the driver. Malformed requests are stream errors, HPACK decoding errors
+/// are connection errors.
 fn end_headers(
     ev_tx: &mpsc::Sender<H2ConnEvent>,
+    state: &Rc<RefCell<ConnState<HeadersData>>>,
+    conf: &ServerConf,
     stream_id: StreamId,
     data: &HeadersData,
     driver: &Rc<impl ServerDriver + 'static>,
     hpack_dec: &mut hring_hpack::Decoder,
-) -> eyre::Result<StreamRxStage> {
+) -> Result<Result<OpenedStream, H2StreamError>, H2ConnectionError> {
     let mut method: Option<Method> = None;
     let mut scheme: Option<Scheme> = None;
     let mut path: Option<PieceStr> = None;
     let mut authority: Option<Authority> = None;
+    let mut protocol: Option<PieceStr> = None;
 
     let mut headers = Headers::default();
 
+    // cf. https://httpwg.org/specs/rfc9113.html#HttpRequest: the first
+    // problem wins, but we keep decoding so the HPACK state stays in sync.
+    let mut malformed: Option<&'static str> = None;
+    let mut saw_regular_header = false;
+
+    // cf. https://httpwg.org/specs/rfc9113.html#SETTINGS_MAX_HEADER_LIST_SIZE
+    let mut header_list_size = 0;
+    let max_header_list_size = if data.rejected.is_some() {
+        // the headers only need decoding, not storing
+        0
+    } else {
+        conf.max_header_list_size as usize
+    };
+
     let cb = |key: Cow<[u8]>, value: Cow<[u8]>| {
+        header_list_size += key.len() + value.len() + 32;
+        if malformed.is_some() || header_list_size > max_header_list_size {
+            // keep decoding so the HPACK state stays in sync, but there's no
+            // point in storing anything.
+            return;
+        }
+
         debug!(
             "HEADER | {}: {}",
             std::str::from_utf8(&key).unwrap_or("<non-utf8-key>"),
             std::str::from_utf8(&value).unwrap_or("<non-utf8-value>"),
         );
 
-        if &key[..1] == b":" {
+        if key.first() == Some(&b':') {
             // it's a pseudo-header!
-            // TODO: reject headers that occur after pseudo-headers
-            match &key[1..] {
-                b"method" => {
-                    // TODO: error handling
-                    let value: PieceStr = Piece::from(value.to_vec()).to_str().unwrap();
-                    if method.replace(Method::try_from(value).unwrap()).is_some() {
-                        unreachable!(); // No duplicate allowed.
-                    }
-                }
-                b"scheme" => {
-                    // TODO: error handling
-                    let value: PieceStr = Piece::from(value.to_vec()).to_str().unwrap();
-                    if scheme.replace(value.parse().unwrap()).is_some() {
-                        unreachable!(); // No duplicate allowed.
-                    }
+            if saw_regular_header {
+                malformed = Some("pseudo-header after regular header");
+                return;
+            }
+
+            let value = match Piece::from(value.to_vec()).to_str() {
+                Ok(value) => value,
+                Err(_) => {
+                    malformed = Some("non-utf8 pseudo-header value");
+                    return;
                 }
+            };
+            malformed = match &key[1..] {
+                b"method" => match method.replace(Method::from(value)) {
+                    Some(_) => Some("duplicate :method"),
+                    None => None,
+                },
+                b"scheme" => match value.parse() {
+                    Ok(value) => match scheme.replace(value) {
+                        Some(_) => Some("duplicate :scheme"),
+                        None => None,
+                    },
+                    Err(_) => Some("invalid :scheme"),
+                },
                 b"path" => {
-                    // TODO: error handling
-                    let value: PieceStr = Piece::from(value.to_vec()).to_str().unwrap();
-                    if value.len() == 0 || path.replace(value).is_some() {
-                        unreachable!(); // No empty path nor duplicate allowed.
-                    }
-                }
-                b"authority" => {
-                    // TODO: error handling
-                    let value: PieceStr = Piece::from(value.to_vec()).to_str().unwrap();
-                    if authority.replace(value.parse().unwrap()).is_some() {
-                        unreachable!(); // No duplicate allowed. (h2spec doesn't seem to test for
-                                        // this case but rejecting for duplicates seems
-                                        // reasonable.)
+                    if value.len() == 0 {
+                        Some("empty :path")
+                    } else {
+                        match path.replace(value) {
+                            Some(_) => Some("duplicate :path"),
+                            None => None,
+                        }
                     }
                 }
-                _ => {
-                    debug!("ignoring pseudo-header");
+                b"authority" => match value.parse() {
+                    // h2spec doesn't seem to test for duplicates, but
+                    // rejecting them seems reasonable.
+                    Ok(value) => match authority.replace(value) {
+                        Some(_) => Some("duplicate :authority"),
+                        None => None,
+                    },
+                    Err(_) => Some("invalid :authority"),
+                },
+                // cf. https://www.rfc-editor.org/rfc/rfc8441#section-4
+                b"protocol" => match protocol.replace(value) {
+                    Some(_) => Some("duplicate :protocol"),
+                    None => None,
+                },
+                _ => Some("unknown pseudo-header"),
+            };
+        } else {
+            saw_regular_header = true;
+
+            // field names must be lowercase in HTTP/2, cf.
+            // https://httpwg.org/specs/rfc9113.html#HttpHeaders
+            if key.iter().any(u8::is_ascii_uppercase) {
+                malformed = Some("uppercase header name");
+                return;
+            }
+            let name = match HeaderName::from_bytes(&key[..]) {
+                Ok(name) => name,
+                Err(_) => {
+                    malformed = Some("invalid header name");
+                    return;
                 }
+            };
+            if is_connection_specific(&name) {
+                malformed = Some("connection-specific header");
+                return;
             }
-        } else {
-            // TODO: what do we do in case of malformed header names?
-            // ignore it? return a 400?
-            let name = HeaderName::from_bytes(&key[..]).expect("malformed header name");
+            if name == header::TE && &value[..] != b"trailers" {
+                malformed = Some("te header other than \"trailers\"");
+                return;
+            }
+
             let value: Piece = value.to_vec().into();
             headers.append(name, value);
         }
     };
 
-    match &data.fragments[..] {
-        [] => unreachable!("must have at least one fragment"),
-        [payload] => {
-            hpack_dec
-                .decode_with_cb(&payload[..], cb)
-                .map_err(|e| eyre::eyre!("hpack error: {e:?}"))?;
-        }
-        _ => {
-            let total_len = data.fragments.iter().map(|f| f.len()).sum();
-            // this is a slow path, let's do a little heap allocation. we could
-            // be using `RollMut` for this, but it would probably need to resize
-            // a bunch
-            let mut payload = Vec::with_capacity(total_len);
-            for frag in &data.fragments {
-                payload.extend_from_slice(&frag[..]);
-            }
-            hpack_dec
-                .decode_with_cb(&payload[..], cb)
-                .map_err(|e| eyre::eyre!("hpack error: {e:?}"))?;
-        }
+    decode_field_block(hpack_dec, &data.fragments, cb)?;
+
+    if let Some(e) = &data.rejected {
+        return Ok(Err(e.clone()));
+    }
+    if let Some(reason) = malformed {
+        return Ok(Err(H2StreamError::MalformedMessage { reason }));
+    }
+
+    let responder = Responder {
+        encoder: H2Encoder {
+            stream_id,
+            tx: ev_tx.clone(),
+            state: EncoderState::ExpectResponseHeaders,
+            conn_state: state.clone(),
+            head_request: matches!(method, Some(Method::Head)),
+        },
+        state: ExpectResponseHeaders,
     };
 
+    let (piece_tx, piece_rx) = mpsc::unbounded_channel::<eyre::Result<H2BodyItem>>();
+
+    let next_rx_stage = if data.end_stream {
+        StreamRxStage::Done
+    } else {
+        StreamRxStage::Body(piece_tx)
+    };
+
+    if header_list_size > conf.max_header_list_size as usize {
+        debug!(%header_list_size, "request headers too large, responding with 431");
+        // any request body is discarded, since the receiver is dropped
+        drop(piece_rx);
+
+        let handler = spawn_error_response(responder, StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        return Ok(Ok(OpenedStream {
+            rx_stage: next_rx_stage,
+            handler: Some(handler),
+            content_length: None,
+            priority: Default::default(),
+        }));
+    }
+
     // TODO: cf. https://httpwg.org/specs/rfc9113.html#HttpRequest
     // A server SHOULD treat a request as malformed if it contains a Host header
     // field that identifies an entity that differs from the entity in the
     // ":authority" pseudo-header field.
 
-    // TODO: proper error handling (return 400)
-    let method = method.unwrap();
-    let scheme = scheme.unwrap();
-
-    let path = path.unwrap();
-    let path_and_query: PathAndQuery = path.parse().unwrap();
-
-    let authority = match authority {
-        Some(authority) => Some(authority),
-        None => headers
-            .get(header::HOST)
-            .map(|host| host.as_str().unwrap().parse().unwrap()),
+    let malformed = |reason| Ok(Err(H2StreamError::MalformedMessage { reason }));
+    let Some(method) = method else {
+        return malformed("missing :method");
     };
+    let is_connect = matches!(method, Method::Connect);
+
+    // extended CONNECT only if we advertised it, cf.
+    // https://www.rfc-editor.org/rfc/rfc8441#section-4
+    if protocol.is_some() && !(is_connect && conf.enable_connect_protocol) {
+        return malformed(":protocol on a request that isn't an extended CONNECT");
+    }
 
     let mut uri_parts: http::uri::Parts = Default::default();
-    uri_parts.scheme = Some(scheme);
-    uri_parts.authority = authority;
-    uri_parts.path_and_query = Some(path_and_query);
+    if is_connect && protocol.is_none() {
+        // a plain CONNECT only names the host to tunnel to, cf.
+        // https://httpwg.org/specs/rfc9113.html#CONNECT
+        if scheme.is_some() || path.is_some() {
+            return malformed(":scheme or :path on CONNECT request");
+        }
+        let Some(authority) = authority else {
+            return malformed("missing :authority on CONNECT request");
+        };
+        uri_parts.authority = Some(authority);
+    } else {
+        let (scheme, path) = match (scheme, path) {
+            (Some(scheme), Some(path)) => (scheme, path),
+            (None, _) => return malformed("missing :scheme"),
+            (_, None) => return malformed("missing :path"),
+        };
 
-    let uri = http::uri::Uri::from_parts(uri_parts).unwrap();
+        let path_and_query: PathAndQuery = match path.parse() {
+            Ok(path_and_query) => path_and_query,
+            Err(_) => return malformed("invalid :path"),
+        };
 
-    let req = Request {
-        method,
-        uri,
-        version: Version::HTTP_2,
-        headers,
+        let authority = match authority {
+            Some(authority) => Some(authority),
+            None => match headers.get(header::HOST) {
+                Some(host) => match host.as_str().ok().and_then(|host| host.parse().ok()) {
+                    Some(authority) => Some(authority),
+                    None => return malformed("invalid host header"),
+                },
+                None => None,
+            },
+        };
+
+        // `:authority` is optional, and a URI can't have a scheme without one
+        if authority.is_some() {
+            uri_parts.scheme = Some(scheme);
+            uri_parts.authority = authority;
+        }
+        uri_parts.path_and_query = Some(path_and_query);
+    }
+
+    let uri = match http::uri::Uri::from_parts(uri_parts) {
+        Ok(uri) => uri,
+        Err(_) => return malformed("invalid request uri"),
     };
 
-    let responder = Responder {
-        encoder: H2Encoder {
-            stream_id,
-            tx: ev_tx.clone(),
-            state: EncoderState::ExpectResponseHeaders,
+    // DATA frames have to add up to the content-length, if any
+    let content_length = match headers.get_all(header::CONTENT_LENGTH).iter().count() {
+        0 => None,
+        1 => match headers.content_length() {
+            Some(len) => Some(len),
+            None => return malformed("invalid content-length"),
         },
-        state: ExpectResponseHeaders,
+        _ => return malformed("multiple content-length headers"),
     };
+    if data.end_stream && matches!(content_length, Some(len) if len > 0) {
+        return malformed("content-length but no data");
+    }
+    if let (Some(len), Some(max)) = (content_length, conf.max_request_body_len) {
+        if len > max {
+            debug!(%len, %max, "request body too large, responding with 413");
+            drop(piece_rx);
+
+            let handler = spawn_error_response(responder, StatusCode::PAYLOAD_TOO_LARGE);
+            return Ok(Ok(OpenedStream {
+                rx_stage: next_rx_stage,
+                handler: Some(handler),
+                content_length: None,
+                priority: Default::default(),
+            }));
+        }
+    }
+    let priority = Priority::from_headers(&headers);
 
-    let (piece_tx, piece_rx) = mpsc::channel::<eyre::Result<Piece>>(1);
-
-    let next_rx_stage = if data.end_stream {
-        StreamRxStage::Done
-    } else {
-        StreamRxStage::Body(piece_tx)
+    let req = Request {
+        method,
+        uri,
+        version: Version::HTTP_2,
+        headers,
+        protocol,
     };
 
     let req_body = H2Body {
-        // FIXME: that's not right. h2 requests can still specify
-        // a content-length
-        content_length: if data.end_stream { Some(0) } else { None },
+        content_length: if data.end_stream {
+            Some(0)
+        } else {
+            content_length
+        },
         eof: data.end_stream,
         rx: piece_rx,
+        stream_id,
+        ev_tx: ev_tx.clone(),
+        max_len: conf.max_request_body_len,
+        received: 0,
+        // tunnels may well stay quiet for a while
+        idle_timeout: (!is_connect).then_some(conf.body_idle_timeout),
+        conn_state: Some(state.clone()),
     };
 
     debug!("Calling handler with the given body");
-    tokio_uring::spawn({
-        let driver = driver.clone();
-        async move {
-            let mut req_body = req_body;
-            let responder = responder;
+    let handler = spawn_handler(driver, req, req_body, responder, conf.request_deadline);
+
+    Ok(Ok(OpenedStream {
+        rx_stage: next_rx_stage,
+        handler: Some(handler),
+        content_length,
+        priority,
+    }))
+}
 
-            match driver.handle(req, &mut req_body, responder).await {
-                Ok(_responder) => {
-                    debug!("Handler completed successfully, gave us a responder");
-                }
-                Err(e) => {
-                    // TODO: actually handle that error.
-                    debug!("Handler returned an error: {e}")
-                }
-            }
+/// Answers a request with an empty response, without calling the driver
+fn spawn_error_response(
+    responder: Responder<H2Encoder<HeadersData>, ExpectResponseHeaders>,
+    status: StatusCode,
+) -> JoinHandle<()> {
+    tokio_uring::spawn(async move {
+        let res = async {
+            responder
+                .write_final_response(Response {
+                    status,
+                    ..Default::default()
+                })
+                .await?
+                .finish_body(None)
+                .await
+        };
+        if let Err(e) = res.await {
+            debug!("could not send {status} response: {e}");
         }
-    });
+    })
+}
 
-    Ok(next_rx_stage)
+/// Calls the driver for a request. Past `deadline`, if any, the handler is
+/// cancelled, and dropping its encoder ends the stream.
+fn spawn_handler(
+    driver: &Rc<impl ServerDriver + 'static>,
+    req: Request,
+    mut req_body: H2Body,
+    responder: Responder<H2Encoder<HeadersData>, ExpectResponseHeaders>,
+    deadline: Option<Duration>,
+) -> JoinHandle<()> {
+    let driver = driver.clone();
+    tokio_uring::spawn(async move {
+        let stream_id = req_body.stream_id;
+        let handle = driver.handle(req, &mut req_body, responder);
+        match timeout_opt(deadline, handle).await {
+            Some(Ok(_responder)) => {
+                debug!("Handler completed successfully, gave us a responder");
+            }
+            Some(Err(e)) => {
+                // TODO: actually handle that error.
+                debug!("Handler returned an error: {e}")
+            }
+            None => {
+                debug!(%stream_id, "request deadline exceeded, handler cancelled");
+            }
+        }
+    })
 }
@@ -0,0 +1,223 @@
+use super::parse::{ErrorCode, KnownErrorCode, StreamId};
+
+/// The error a request body yields when its stream gets reset, cf.
+/// https://httpwg.org/specs/rfc9113.html#RST_STREAM
+#[derive(Debug, thiserror::Error)]
+#[error("stream {stream_id} was reset by {} with {error_code:?}", if *.by_peer { "peer" } else { "us" })]
+pub struct H2StreamReset {
+    pub stream_id: StreamId,
+    pub error_code: ErrorCode,
+
+    /// False if we reset the stream, e.g. because the peer sent something
+    /// invalid on it
+    pub by_peer: bool,
+}
+
+/// A connection error: we send GOAWAY with the matching error code, then
+/// close the connection, cf. https://httpwg.org/specs/rfc9113.html#ConnectionErrorHandler
+#[derive(Debug, thiserror::Error)]
+pub enum H2ConnectionError {
+    #[error("frame of {frame_size} bytes exceeds max frame size {max_frame_size}")]
+    FrameTooLarge {
+        frame_size: u32,
+        max_frame_size: u32,
+    },
+
+    #[error("padded frame is too short to hold a pad length")]
+    PaddedFrameEmpty,
+
+    #[error("pad length {padding_length} is larger than the {payload_len}-byte payload")]
+    PaddedFrameTooShort {
+        padding_length: usize,
+        payload_len: usize,
+    },
+
+    #[error("headers frame is too short to hold a priority")]
+    HeadersFrameTooShortForPriority,
+
+    #[error("field block couldn't be decoded: {0}")]
+    HpackDecodingError(eyre::Report),
+
+    #[error("received more data than allowed by flow control on stream {stream_id}")]
+    FlowControlWindowExceeded { stream_id: StreamId },
+
+    #[error("received data for idle stream {stream_id}")]
+    DataForIdleStream { stream_id: StreamId },
+
+    #[error("stream {stream_id} can't be opened after stream {last_stream_id}")]
+    InvalidNewStreamId {
+        stream_id: StreamId,
+        last_stream_id: StreamId,
+    },
+
+    #[error("received headers for idle stream {stream_id}")]
+    HeadersForIdleStream { stream_id: StreamId },
+
+    #[error("expected continuation frame for stream {stream_id}")]
+    ExpectedContinuation { stream_id: StreamId },
+
+    #[error("received unexpected continuation frame for stream {stream_id}")]
+    UnexpectedContinuation { stream_id: StreamId },
+
+    #[error("priority frame on the connection stream")]
+    PriorityOnConnectionStream,
+
+    #[error("rst_stream frame on the connection stream")]
+    RstStreamOnConnectionStream,
+
+    #[error("rst_stream frame with invalid length {len}")]
+    RstStreamInvalidLength { len: u32 },
+
+    #[error("rst_stream frame for idle stream {stream_id}")]
+    RstStreamForIdleStream { stream_id: StreamId },
+
+    #[error("settings frame on stream {stream_id}")]
+    SettingsOnStream { stream_id: StreamId },
+
+    #[error("settings ack with a {len}-byte payload")]
+    SettingsAckWithPayload { len: u32 },
+
+    #[error("invalid settings ({error_code:?})")]
+    InvalidSettings { error_code: KnownErrorCode },
+
+    #[error("ping frame on stream {stream_id}")]
+    PingOnStream { stream_id: StreamId },
+
+    #[error("ping frame with invalid length {len}")]
+    PingInvalidLength { len: u32 },
+
+    #[error("goaway frame on stream {stream_id}")]
+    GoAwayOnStream { stream_id: StreamId },
+
+    #[error("goaway frame with invalid length {len}")]
+    GoAwayInvalidLength { len: u32 },
+
+    #[error("window update frame with invalid length {len}")]
+    WindowUpdateInvalidLength { len: u32 },
+
+    #[error("priority update frame on stream {stream_id}, expected connection stream")]
+    PriorityUpdateOnStream { stream_id: StreamId },
+
+    #[error("priority update frame with invalid length {len}")]
+    PriorityUpdateInvalidLength { len: u32 },
+
+    #[error("priority update frame for the connection stream")]
+    PriorityUpdateForConnectionStream,
+
+    /// Only clients send PRIORITY_UPDATE, cf. https://www.rfc-editor.org/rfc/rfc9218#name-the-priority_update-frame
+    #[error("received priority update frame from the server")]
+    PriorityUpdateReceived,
+
+    #[error("window update frame for idle stream {stream_id}")]
+    WindowUpdateForIdleStream { stream_id: StreamId },
+
+    #[error("window update with zero increment on the connection stream")]
+    WindowUpdateZeroIncrement,
+
+    #[error("window update overflowed the connection's flow-control window")]
+    WindowUpdateOverflow,
+
+    #[error("received push_promise frame, but server push is disabled")]
+    PushPromiseReceived,
+}
+
+impl H2ConnectionError {
+    /// The error code we send in the GOAWAY frame
+    pub fn as_known_error_code(&self) -> KnownErrorCode {
+        match self {
+            Self::FrameTooLarge { .. }
+            | Self::PaddedFrameEmpty
+            | Self::HeadersFrameTooShortForPriority
+            | Self::RstStreamInvalidLength { .. }
+            | Self::SettingsAckWithPayload { .. }
+            | Self::PingInvalidLength { .. }
+            | Self::GoAwayInvalidLength { .. }
+            | Self::PriorityUpdateInvalidLength { .. }
+            | Self::WindowUpdateInvalidLength { .. } => KnownErrorCode::FrameSizeError,
+            Self::HpackDecodingError(_) => KnownErrorCode::CompressionError,
+            Self::FlowControlWindowExceeded { .. } | Self::WindowUpdateOverflow => {
+                KnownErrorCode::FlowControlError
+            }
+            Self::InvalidSettings { error_code } => *error_code,
+            Self::PaddedFrameTooShort { .. }
+            | Self::DataForIdleStream { .. }
+            | Self::InvalidNewStreamId { .. }
+            | Self::HeadersForIdleStream { .. }
+            | Self::ExpectedContinuation { .. }
+            | Self::UnexpectedContinuation { .. }
+            | Self::PriorityOnConnectionStream
+            | Self::RstStreamOnConnectionStream
+            | Self::RstStreamForIdleStream { .. }
+            | Self::SettingsOnStream { .. }
+            | Self::PingOnStream { .. }
+            | Self::GoAwayOnStream { .. }
+            | Self::PriorityUpdateOnStream { .. }
+            | Self::PriorityUpdateForConnectionStream
+            | Self::PriorityUpdateReceived
+            | Self::WindowUpdateForIdleStream { .. }
+            | Self::WindowUpdateZeroIncrement
+            | Self::PushPromiseReceived => KnownErrorCode::ProtocolError,
+        }
+    }
+}
+
+/// A stream error: we send RST_STREAM with the matching error code, and the
+/// rest of the connection carries on, cf. https://httpwg.org/specs/rfc9113.html#StreamErrorHandler
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum H2StreamError {
+    #[error("stream refused, we're over the concurrent stream limit or going away")]
+    Refused,
+
+    #[error("malformed message: {reason}")]
+    MalformedMessage { reason: &'static str },
+
+    #[error("received data after the peer ended the stream")]
+    DataAfterEndStream,
+
+    #[error("received headers after the peer ended the stream")]
+    HeadersAfterEndStream,
+
+    #[error("priority frame with invalid length {len}")]
+    PriorityInvalidLength { len: u32 },
+
+    #[error("stream depends on itself")]
+    SelfDependency,
+
+    #[error("window update with zero increment")]
+    WindowUpdateZeroIncrement,
+
+    #[error("window update overflowed the stream's flow-control window")]
+    WindowUpdateOverflow,
+
+    #[error("we've responded and the request body isn't being read")]
+    RequestBodyIgnored,
+
+    #[error("the response body was dropped")]
+    ResponseBodyDropped,
+
+    #[error("no request body data came in for too long")]
+    RequestBodyTimedOut,
+
+    #[error("the request body went over the configured max length")]
+    RequestBodyTooLarge,
+}
+
+impl H2StreamError {
+    /// The error code we send in the RST_STREAM frame
+    pub fn as_known_error_code(&self) -> KnownErrorCode {
+        match self {
+            Self::Refused => KnownErrorCode::RefusedStream,
+            Self::MalformedMessage { .. }
+            | Self::SelfDependency
+            | Self::WindowUpdateZeroIncrement => KnownErrorCode::ProtocolError,
+            Self::DataAfterEndStream | Self::HeadersAfterEndStream => KnownErrorCode::StreamClosed,
+            Self::PriorityInvalidLength { .. } => KnownErrorCode::FrameSizeError,
+            Self::WindowUpdateOverflow => KnownErrorCode::FlowControlError,
+            // the peer may stop sending, no harm done, cf. https://httpwg.org/specs/rfc9113.html#HttpFraming
+            Self::RequestBodyIgnored => KnownErrorCode::NoError,
+            Self::ResponseBodyDropped | Self::RequestBodyTimedOut | Self::RequestBodyTooLarge => {
+                KnownErrorCode::Cancel
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Serving HTTP/1.1 and cleartext HTTP/2 on the same port: clients either
+//! start with the HTTP/2 preface ("prior knowledge"), or upgrade from
+//! HTTP/1.1 with `Upgrade: h2c`.
+//!
+//! cf. https://httpwg.org/specs/rfc9113.html#known-http and
+//! https://httpwg.org/specs/rfc7540.html#discover-http
+
+use std::rc::Rc;
+
+use tracing::debug;
+
+use crate::{
+    h1::{self, ServeExit},
+    h2::{
+        self,
+        parse::{starts_with_preface, PREFACE},
+    },
+    util::read_and_parse,
+    ServerDriver,
+};
+use hring_buffet::{ReadWriteOwned, RollMut};
+
+#[derive(Debug)]
+pub enum ServeOutcome {
+    /// The client spoke HTTP/1.1 for the whole connection
+    Http1(h1::ServeOutcome),
+
+    /// The client sent the HTTP/2 preface right away
+    Http2PriorKnowledge(h2::ServeOutcome),
+
+    /// The client upgraded from HTTP/1.1 with `Upgrade: h2c`
+    Http2Upgraded(h2::ServeOutcome),
+}
+
+/// Serves a plaintext connection over HTTP/2 if the client starts with the
+/// connection preface, or over HTTP/1.1 otherwise, accepting h2c upgrades.
+pub async fn serve(
+    transport: impl ReadWriteOwned + 'static,
+    h1_conf: Rc<h1::ServerConf>,
+    h2_conf: Rc<h2::ServerConf>,
+    client_buf: RollMut,
+    driver: Rc<impl ServerDriver + 'static>,
+) -> eyre::Result<ServeOutcome> {
+    let transport = Rc::new(transport);
+
+    let (client_buf, prior_knowledge) = match read_and_parse(
+        starts_with_preface,
+        transport.as_ref(),
+        client_buf,
+        PREFACE.len(),
+    )
+    .await?
+    {
+        Some(t) => t,
+        None => {
+            debug!("client went away before sending anything");
+            return Ok(ServeOutcome::Http1(
+                h1::ServeOutcome::ClientClosedConnectionBetweenRequests,
+            ));
+        }
+    };
+
+    if prior_knowledge {
+        debug!("got HTTP/2 preface, serving h2 with prior knowledge");
+        let outcome = h2::serve_h2c(transport, h2_conf, client_buf, driver, None).await?;
+        return Ok(ServeOutcome::Http2PriorKnowledge(outcome));
+    }
+
+    match h1::serve_inner(
+        transport.clone(),
+        &h1_conf,
+        client_buf,
+        driver.as_ref(),
+        true,
+    )
+    .await?
+    {
+        ServeExit::Done(outcome) => Ok(ServeOutcome::Http1(outcome)),
+        ServeExit::H2cUpgrade {
+            upgrade,
+            client_buf,
+        } => {
+            let outcome =
+                h2::serve_h2c(transport, h2_conf, client_buf, driver, Some(upgrade)).await?;
+            Ok(ServeOutcome::Http2Upgraded(outcome))
+        }
+    }
+}
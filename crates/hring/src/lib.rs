@@ -10,6 +10,8 @@ pub use types::*;
 
 pub mod h1;
 pub mod h2;
+pub mod h2c;
+pub mod ws;
 
 mod responder;
 pub use responder::*;
@@ -30,3 +32,14 @@ pub trait ServerDriver {
         respond: Responder<E, ExpectResponseHeaders>,
     ) -> eyre::Result<Responder<E, ResponseDone>>;
 }
+
+pub trait ClientDriver {
+    type Return;
+
+    async fn on_informational_response(&mut self, res: Response) -> eyre::Result<()>;
+    async fn on_final_response(
+        self,
+        res: Response,
+        body: &mut impl Body,
+    ) -> eyre::Result<Self::Return>;
+}
@@ -1,8 +1,17 @@
-use http::header;
+use std::rc::Rc;
+
+use eyre::Context;
+use http::{header, StatusCode};
 use tracing::debug;
 
-use crate::{h1::body::BodyWriteMode, Body, BodyChunk, Headers, HeadersExt, Response};
-use hring_buffet::Piece;
+use crate::{
+    h1::body::BodyWriteMode, h2::KnownErrorCode, Body, BodyChunk, Headers, HeadersExt, Response,
+};
+use hring_buffet::{Piece, ReadOwned, ReadWriteOwned, RollMut, WriteOwned};
+use tokio_uring::{
+    buf::{IoBuf, IoBufMut},
+    BufResult,
+};
 
 pub trait ResponseState {}
 
@@ -43,7 +52,9 @@ where
 
     /// Send the final response headers
     /// Errors out if the response status is < 200.
-    /// Errors out if the client sent `expect: 100-continue`
+    /// Over HTTP/1.1, if the client sent `expect: 100-continue` and the request
+    /// body wasn't read yet, the response gets `connection: close`, since
+    /// the client may or may not send the body after it.
     pub async fn write_final_response(
         mut self,
         mut res: Response,
@@ -64,11 +75,17 @@ where
                         .insert(header::CONTENT_LENGTH, format!("{len}").into_bytes().into());
                     BodyWriteMode::ContentLength
                 }
-                None => {
+                None if self.encoder.accepts_chunked_body() => {
                     res.headers
                         .insert(header::TRANSFER_ENCODING, "chunked".into());
                     BodyWriteMode::Chunked
                 }
+                None => {
+                    // closing the connection is the only way to end the body, cf.
+                    // https://httpwg.org/specs/rfc9112.html#message.body.length
+                    res.headers.insert(header::CONNECTION, "close".into());
+                    BodyWriteMode::CloseDelimited
+                }
             }
         };
         self.encoder.write_response(res).await?;
@@ -79,6 +96,68 @@ where
         })
     }
 
+    /// Send a response after which the request body and the response body
+    /// carry another protocol's bytes, until either side is done: a `101
+    /// Switching Protocols` over HTTP/1.1, or a 2xx answer to a CONNECT request
+    /// (extended or not) over HTTP/1.1 or HTTP/2. No framing headers are added.
+    /// Errors out if the response status is neither 101 nor 2xx, or if the
+    /// response has framing headers, cf. https://httpwg.org/specs/rfc9110.html#CONNECT
+    pub async fn write_tunnel_response(
+        mut self,
+        res: Response,
+    ) -> eyre::Result<Responder<E, ExpectResponseBody>> {
+        if res.status != StatusCode::SWITCHING_PROTOCOLS && !res.status.is_success() {
+            return Err(eyre::eyre!(
+                "tunnel response must have status code 101 or 2xx"
+            ));
+        }
+
+        if res.headers.contains_key(header::CONTENT_LENGTH)
+            || res.headers.contains_key(header::TRANSFER_ENCODING)
+        {
+            return Err(eyre::eyre!(
+                "tunnel response can't have content-length or transfer-encoding"
+            ));
+        }
+
+        self.encoder.write_tunnel_response(res).await?;
+
+        Ok(Responder {
+            state: ExpectResponseBody {
+                mode: BodyWriteMode::Tunnel,
+            },
+            encoder: self.encoder,
+        })
+    }
+
+    /// Send a `101 Switching Protocols` and take the connection over: the
+    /// returned [Upgraded] has the transport and whatever the client sent after
+    /// the request, which both belong to the new protocol. HTTP/1.1 only, and
+    /// only for requests without a body. Errors out if the response status
+    /// isn't 101, or if it has no `upgrade` header, cf.
+    /// https://httpwg.org/specs/rfc9110.html#status.101
+    pub async fn write_upgrade_response(
+        mut self,
+        res: Response,
+    ) -> eyre::Result<(Responder<E, ResponseDone>, Upgraded<E::Transport>)> {
+        if res.status != StatusCode::SWITCHING_PROTOCOLS {
+            return Err(eyre::eyre!("upgrade response must have status code 101"));
+        }
+        if !res.headers.contains_key(header::UPGRADE) {
+            return Err(eyre::eyre!("upgrade response must have an upgrade header"));
+        }
+
+        let upgraded = self.encoder.write_upgrade_response(res).await?;
+
+        Ok((
+            Responder {
+                state: ResponseDone,
+                encoder: self.encoder,
+            },
+            upgraded,
+        ))
+    }
+
     /// Writes a response with the given body. Sets `content-length` or
     /// `transfer-encoding` as needed.
     pub async fn write_final_response_with_body(
@@ -137,10 +216,14 @@ where
         mut self,
         trailers: Option<Box<Headers>>,
     ) -> eyre::Result<Responder<E, ResponseDone>> {
-        self.encoder.write_body_end(self.state.mode).await?;
-
-        if let Some(trailers) = trailers {
-            self.encoder.write_trailers(trailers).await?;
+        match trailers {
+            // trailers can be discarded, cf. https://httpwg.org/specs/rfc9110.html#trailer.fields
+            Some(_) if self.state.mode == BodyWriteMode::CloseDelimited => {
+                debug!("client can't receive trailers, dropping them");
+                self.encoder.write_body_end(self.state.mode).await?
+            }
+            Some(trailers) => self.encoder.write_trailers(trailers).await?,
+            None => self.encoder.write_body_end(self.state.mode).await?,
         }
 
         // TODO: check announced content-length size vs actual, etc.
@@ -150,11 +233,141 @@ where
             encoder: self.encoder,
         })
     }
+
+    /// Copies bytes both ways between the client and `upstream`, after
+    /// [Responder::write_tunnel_response]: e.g. a forward proxy answers
+    /// `CONNECT example.org:443` with a 200, then splices the connection (or
+    /// HTTP/2 stream) with a TCP connection to `example.org:443`. Returns once
+    /// `upstream` is done sending, or as soon as either side errors out.
+    pub async fn splice(
+        mut self,
+        req_body: &mut impl Body,
+        upstream: impl ReadWriteOwned,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        if self.state.mode != BodyWriteMode::Tunnel {
+            return Err(eyre::eyre!(
+                "can only splice after writing a tunnel response"
+            ));
+        }
+
+        {
+            let client_to_upstream = async {
+                while let BodyChunk::Chunk(chunk) = req_body.next_chunk().await? {
+                    let (res, _) = upstream.write_all(chunk).await;
+                    res.wrap_err("writing to upstream")?;
+                }
+                debug!("client is done sending");
+                Ok::<_, eyre::Report>(())
+            };
+
+            let upstream_to_client = async {
+                let mut buf = RollMut::alloc()?;
+                loop {
+                    buf.reserve()?;
+                    let res;
+                    (res, buf) = buf.read_into(usize::MAX, &upstream).await;
+                    if res.wrap_err("reading from upstream")? == 0 {
+                        debug!("upstream is done sending");
+                        return Ok::<_, eyre::Report>(());
+                    }
+                    self.write_chunk(buf.take_all().into()).await?;
+                }
+            };
+
+            // keep relaying what upstream sends after the client is done, but
+            // there's no way to signal the end of the client's data to `upstream`.
+            tokio::pin!(upstream_to_client);
+            tokio::select! {
+                res = &mut upstream_to_client => res?,
+                res = client_to_upstream => {
+                    res?;
+                    upstream_to_client.await?;
+                }
+            }
+        }
+
+        self.finish_body(None).await
+    }
+}
+
+impl<E, S> Responder<E, S>
+where
+    E: Encoder,
+    S: ResponseState,
+{
+    /// Abandon the response. With HTTP/2, this resets the stream with the
+    /// given error code, cf. https://httpwg.org/specs/rfc9113.html#RST_STREAM
+    /// HTTP/1.1 has no equivalent, so this errors out: the connection should
+    /// be closed instead.
+    pub async fn reset_stream(
+        mut self,
+        error_code: KnownErrorCode,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        self.encoder.reset_stream(error_code).await?;
+
+        Ok(Responder {
+            state: ResponseDone,
+            encoder: self.encoder,
+        })
+    }
+}
+
+/// An HTTP/1.1 connection that switched protocols, cf.
+/// [Responder::write_upgrade_response]. Once the handler returns, `serve`
+/// returns [crate::h1::ServeOutcome::Upgraded] without touching the connection
+/// again, so the new protocol can be spoken from the handler or from a task it
+/// spawned.
+pub struct Upgraded<T> {
+    /// The transport the connection is served over
+    pub transport: Rc<T>,
+
+    /// Bytes the client sent after the request, before reading from the
+    /// transport
+    pub client_buf: RollMut,
+}
+
+/// The [Encoder::Transport] of encoders that can't hand a connection over,
+/// e.g. for HTTP/2 streams. It has no values.
+pub enum NoTransport {}
+
+impl ReadOwned for NoTransport {
+    async fn read<B: IoBufMut>(&self, _buf: B) -> BufResult<usize, B> {
+        match *self {}
+    }
+}
+
+impl WriteOwned for NoTransport {
+    async fn write<B: IoBuf>(&self, _buf: B) -> BufResult<usize, B> {
+        match *self {}
+    }
+
+    async fn shutdown(&self) -> std::io::Result<()> {
+        match *self {}
+    }
 }
 
 pub trait Encoder {
+    /// What [Encoder::write_upgrade_response] hands over
+    type Transport: ReadWriteOwned + 'static;
+
+    /// Whether the client can decode a chunked body (it can't over HTTP/1.0).
+    /// If it can't, bodies of unknown length are delimited by closing the
+    /// connection.
+    fn accepts_chunked_body(&self) -> bool {
+        true
+    }
     async fn write_response(&mut self, res: Response) -> eyre::Result<()>;
+    /// Writes a response after which the connection (HTTP/1.1) or the stream
+    /// (HTTP/2) carries raw bytes both ways
+    async fn write_tunnel_response(&mut self, res: Response) -> eyre::Result<()>;
+    /// Writes a response after which the connection is handed over as-is
+    async fn write_upgrade_response(
+        &mut self,
+        res: Response,
+    ) -> eyre::Result<Upgraded<Self::Transport>>;
     async fn write_body_chunk(&mut self, chunk: Piece, mode: BodyWriteMode) -> eyre::Result<()>;
     async fn write_body_end(&mut self, mode: BodyWriteMode) -> eyre::Result<()>;
+    /// Ends the body with trailers: this is called instead of `write_body_end`
     async fn write_trailers(&mut self, trailers: Box<Headers>) -> eyre::Result<()>;
+    async fn reset_stream(&mut self, error_code: KnownErrorCode) -> eyre::Result<()>;
 }
@@ -28,8 +28,7 @@ impl HeadersExt for HeaderMap<Piece> {
     }
 
     fn is_connection_close(&self) -> bool {
-        self.get(header::CONNECTION)
-            .map_or(false, |value| value.eq_ignore_ascii_case(b"close"))
+        has_token(self, header::CONNECTION, b"close")
     }
 
     fn is_chunked_transfer_encoding(&self) -> bool {
@@ -43,7 +42,21 @@ impl HeadersExt for HeaderMap<Piece> {
     }
 }
 
-fn from_digits(bytes: &[u8]) -> Option<u64> {
+/// Whether a comma-separated header contains the given token (ignoring case)
+pub(crate) fn has_token(headers: &Headers, name: header::HeaderName, token: &[u8]) -> bool {
+    headers.get_all(name).iter().any(|value| {
+        value.split(|&b| b == b',').any(|item| {
+            let start = item.iter().position(|b| !b.is_ascii_whitespace());
+            let end = item.iter().rposition(|b| !b.is_ascii_whitespace());
+            match (start, end) {
+                (Some(start), Some(end)) => item[start..=end].eq_ignore_ascii_case(token),
+                _ => false,
+            }
+        })
+    })
+}
+
+pub(crate) fn from_digits(bytes: &[u8]) -> Option<u64> {
     // cannot use FromStr for u64, since it allows a signed prefix
     let mut result = 0u64;
     const RADIX: u64 = 10;
@@ -3,7 +3,7 @@ use std::fmt::{self, Debug};
 use http::{StatusCode, Uri, Version};
 use tracing::debug;
 
-use hring_buffet::Piece;
+use hring_buffet::{Piece, PieceStr};
 
 mod headers;
 pub use headers::*;
@@ -24,6 +24,10 @@ pub struct Request {
 
     /// Request headers
     pub headers: Headers,
+
+    /// The `:protocol` pseudo-header of an extended CONNECT request over
+    /// HTTP/2 (e.g. `websocket`), cf. https://www.rfc-editor.org/rfc/rfc8441
+    pub protocol: Option<PieceStr>,
 }
 
 impl Default for Request {
@@ -33,6 +37,7 @@ impl Default for Request {
             uri: "/".parse().unwrap(),
             version: Version::HTTP_11,
             headers: Default::default(),
+            protocol: None,
         }
     }
 }
@@ -45,6 +50,7 @@ impl fmt::Debug for Request {
             .field("method", &self.method)
             .field("uri", &self.uri)
             .field("version", &self.version)
+            .field("protocol", &self.protocol.as_deref())
             .finish()?;
 
         for (name, value) in &self.headers {
@@ -106,12 +112,39 @@ pub enum BodyChunk {
     },
 }
 
+/// Which of a server's timeouts ran out, cf. `ServerConf` in [crate::h1]
+/// and [crate::h2]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum Timeout {
+    /// The request headers didn't all come in within `header_read_timeout`
+    #[error("timed out reading request headers")]
+    HeaderRead,
+
+    /// No request body data came in for `body_idle_timeout`
+    #[error("timed out waiting for request body data")]
+    BodyIdle,
+
+    /// The connection sat idle between requests for `keep_alive_timeout`
+    #[error("connection was idle for too long")]
+    KeepAliveIdle,
+
+    /// The handler didn't finish within `request_deadline`
+    #[error("request deadline exceeded")]
+    RequestDeadline,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub struct BodyError {
     reason: BodyErrorReason,
     context: Option<Box<dyn Debug + Send + Sync>>,
 }
 
+impl BodyError {
+    pub fn reason(&self) -> BodyErrorReason {
+        self.reason
+    }
+}
+
 impl fmt::Display for BodyError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "body error: {:?}", self.reason)?;
@@ -159,6 +192,25 @@ pub enum BodyErrorReason {
     // `write_chunk` was called but no content-length was announced, and
     // no chunked transfer-encoding was announced
     CalledWriteBodyChunkWhenNoBodyWasExpected,
+
+    // once the connection became a tunnel, there was a read error
+    ErrorWhileReadingTunnelData,
+
+    // while doing chunked transfer-encoding, the connection was closed
+    // after the last chunk, before the end of the trailer section
+    ClosedWhileReadingTrailers,
+
+    // while doing chunked transfer-encoding, what came after the last
+    // chunk wasn't valid trailer fields followed by CRLF, or it was too long
+    InvalidTrailers,
+
+    // the body went over the configured max length, e.g.
+    // `max_request_body_len` for request bodies
+    BodyTooLarge,
+
+    // no body data came in for the configured idle timeout, e.g.
+    // `body_idle_timeout` for request bodies
+    TimedOutWhileReading,
 }
 
 impl BodyErrorReason {
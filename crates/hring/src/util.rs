@@ -1,3 +1,8 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
 use eyre::Context;
 use nom::IResult;
 use pretty_hex::PrettyHex;
@@ -22,9 +27,14 @@ where
             buf.cap()
         );
         let filled = buf.filled();
+        let filled_len = filled.len();
 
         match parser(filled) {
             Ok((rest, output)) => {
+                // the whole message may have been buffered already
+                if filled_len - rest.len() > max_len {
+                    return Err(SemanticError::BufferLimitReachedWhileParsing.into());
+                }
                 buf.keep(rest);
                 return Ok(Some((buf, output)));
             }
@@ -69,13 +79,37 @@ where
                         debug!(?err, "parsing error");
                         debug!(input = %e.input.to_string_lossy(), "input was");
                     }
-                    return Err(eyre::eyre!("parsing error: {err}"));
+                    return Err(SemanticError::Malformed(format!("parsing error: {err}")).into());
                 }
             }
         };
     }
 }
 
+/// Like [tokio::time::timeout], but `None` means no timeout. Returns `None` if
+/// the timeout ran out, in which case `fut` was dropped.
+pub(crate) async fn timeout_opt<F: Future>(
+    duration: Option<Duration>,
+    fut: F,
+) -> Option<F::Output> {
+    match duration {
+        Some(duration) => tokio::time::timeout(duration, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
+
+/// Like [timeout_opt], but with a deadline rather than a duration, for waits
+/// that span several futures
+pub(crate) async fn timeout_at_opt<F: Future>(
+    deadline: Option<Instant>,
+    fut: F,
+) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
+
 /// Write the filled part of a buffer to the given [TcpStream], returning a
 /// buffer re-using the remaining space.
 pub(crate) async fn write_all_list(
@@ -98,10 +132,78 @@ pub(crate) async fn write_all_list(
     Ok(list.into())
 }
 
+/// Decodes base64url without padding, cf. https://www.rfc-editor.org/rfc/rfc4648#section-5.
+/// Returns `None` if the input isn't valid base64url.
+pub(crate) fn decode_base64url(input: &[u8]) -> Option<Vec<u8>> {
+    // a single leftover character can't encode a whole byte
+    if input.len() % 4 == 1 {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &c in input {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Encodes standard base64 with padding, cf. https://www.rfc-editor.org/rfc/rfc4648#section-4
+pub(crate) fn encode_base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity((input.len() + 2) / 3 * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let acc = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(acc >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
 #[derive(thiserror::Error, Debug)]
 pub(crate) enum SemanticError {
     #[error("buffering limit reached while parsing")]
     BufferLimitReachedWhileParsing,
+
+    #[error("malformed message: {0}")]
+    Malformed(String),
+
+    #[error("unsupported transfer-coding")]
+    UnsupportedTransferCoding,
+
+    #[error("request body too large")]
+    RequestBodyTooLarge,
+
+    #[error("timed out reading request")]
+    RequestTimeout,
+
+    #[error("request deadline exceeded")]
+    DeadlineExceeded,
 }
 
 impl SemanticError {
@@ -110,6 +212,52 @@ impl SemanticError {
             Self::BufferLimitReachedWhileParsing => {
                 b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n"
             }
+            // the message framing can't be trusted, so neither can anything
+            // that follows it on the connection
+            Self::Malformed(_) => {
+                b"HTTP/1.1 400 Bad Request\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"
+            }
+            Self::UnsupportedTransferCoding => {
+                b"HTTP/1.1 501 Not Implemented\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"
+            }
+            // the body isn't read, so the next request can't be found
+            Self::RequestBodyTooLarge => {
+                b"HTTP/1.1 413 Payload Too Large\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"
+            }
+            // whatever the client was sending got cut off
+            Self::RequestTimeout => {
+                b"HTTP/1.1 408 Request Timeout\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"
+            }
+            Self::DeadlineExceeded => {
+                b"HTTP/1.1 503 Service Unavailable\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_base64url, encode_base64};
+
+    #[test]
+    fn test_decode_base64url() {
+        assert_eq!(decode_base64url(b"").unwrap(), b"");
+        assert_eq!(decode_base64url(b"aGk").unwrap(), b"hi");
+        assert_eq!(decode_base64url(b"aGV5").unwrap(), b"hey");
+        assert_eq!(decode_base64url(b"-_8").unwrap(), [0xfb, 0xff]);
+        assert_eq!(decode_base64url(b"AAMAAABkAAQAAP__").unwrap().len(), 12);
+        assert!(decode_base64url(b"aGV5a").is_none());
+        assert!(decode_base64url(b"aGk=").is_none());
+        assert!(decode_base64url(b"a+k/").is_none());
+    }
+
+    #[test]
+    fn test_encode_base64() {
+        assert_eq!(encode_base64(b""), "");
+        assert_eq!(encode_base64(b"f"), "Zg==");
+        assert_eq!(encode_base64(b"fo"), "Zm8=");
+        assert_eq!(encode_base64(b"foo"), "Zm9v");
+        assert_eq!(encode_base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(encode_base64(&[0xfb, 0xff]), "+/8=");
+    }
+}
@@ -0,0 +1,186 @@
+//! WebSocket framing, cf. https://www.rfc-editor.org/rfc/rfc6455#section-5
+//!
+//! This only deals with bytes, so it works the same over an HTTP/1.1
+//! connection that switched protocols and over an HTTP/2 extended CONNECT
+//! stream.
+
+use enum_repr::EnumRepr;
+use nom::{
+    bytes::streaming::take,
+    number::streaming::{be_u16, be_u64, be_u8},
+    IResult,
+};
+
+use hring_buffet::Roll;
+
+/// cf. https://www.rfc-editor.org/rfc/rfc6455#section-11.8
+#[EnumRepr(type = "u8")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OpCode {
+    Continuation = 0x0,
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xA,
+}
+
+impl OpCode {
+    /// Control frames can be sent in the middle of a fragmented message, but
+    /// can't be fragmented themselves, cf.
+    /// https://www.rfc-editor.org/rfc/rfc6455#section-5.5
+    pub(crate) fn is_control(self) -> bool {
+        self.repr() & 0x8 != 0
+    }
+}
+
+/// Control frame payloads fit in the 7-bit length
+pub(crate) const MAX_CONTROL_PAYLOAD_LEN: u64 = 125;
+
+/// Everything that comes before the payload of a frame, cf.
+/// https://www.rfc-editor.org/rfc/rfc6455#section-5.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FrameHeader {
+    /// Whether this is the last fragment of a message
+    pub(crate) fin: bool,
+
+    /// RSV1, RSV2 and RSV3, which must be zero without extensions
+    pub(crate) rsv: u8,
+
+    /// Unknown opcodes are kept as-is, so the caller can fail the connection
+    pub(crate) opcode: u8,
+
+    /// Frames sent by clients must be masked, frames sent by servers must not
+    pub(crate) mask: Option<[u8; 4]>,
+
+    pub(crate) payload_len: u64,
+}
+
+impl FrameHeader {
+    /// Parses a frame header. The payload that follows isn't consumed.
+    pub(crate) fn parse(i: Roll) -> IResult<Roll, Self> {
+        let (i, b0) = be_u8(i)?;
+        let (i, b1) = be_u8(i)?;
+
+        let (i, payload_len) = match b1 & 0x7f {
+            126 => {
+                let (i, len) = be_u16(i)?;
+                (i, len as u64)
+            }
+            127 => be_u64(i)?,
+            len => (i, len as u64),
+        };
+
+        let (i, mask) = if b1 & 0x80 != 0 {
+            let (i, key) = take(4_usize)(i)?;
+            (i, Some([key[0], key[1], key[2], key[3]]))
+        } else {
+            (i, None)
+        };
+
+        let header = Self {
+            fin: b0 & 0x80 != 0,
+            rsv: (b0 >> 4) & 0x7,
+            opcode: b0 & 0xf,
+            mask,
+            payload_len,
+        };
+        Ok((i, header))
+    }
+
+    /// Encodes the frame header, using the shortest payload length encoding
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(14);
+        out.push((self.fin as u8) << 7 | (self.rsv & 0x7) << 4 | (self.opcode & 0xf));
+
+        let mask_bit = (self.mask.is_some() as u8) << 7;
+        match self.payload_len {
+            len @ 0..=125 => out.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                out.push(mask_bit | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                out.push(mask_bit | 127);
+                out.extend_from_slice(&len.to_be_bytes());
+            }
+        }
+
+        if let Some(mask) = self.mask {
+            out.extend_from_slice(&mask);
+        }
+        out
+    }
+}
+
+/// Masks or unmasks a payload in place (it's the same operation), cf.
+/// https://www.rfc-editor.org/rfc/rfc6455#section-5.3
+pub(crate) fn apply_mask(mask: [u8; 4], payload: &mut [u8]) {
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hring_buffet::RollMut;
+
+    use super::{apply_mask, FrameHeader, OpCode};
+
+    fn parse(input: &[u8]) -> Option<(FrameHeader, usize)> {
+        let mut buf = RollMut::alloc().unwrap();
+        buf.put(input).unwrap();
+        let (rest, header) = FrameHeader::parse(buf.filled()).ok()?;
+        Some((header, rest.len()))
+    }
+
+    #[test]
+    fn test_frame_header() {
+        // examples from https://www.rfc-editor.org/rfc/rfc6455#section-5.7
+        let (header, rest) = parse(&[0x81, 0x05, b'H', b'e', b'l', b'l', b'o']).unwrap();
+        assert_eq!(
+            header,
+            FrameHeader {
+                fin: true,
+                rsv: 0,
+                opcode: OpCode::Text.repr(),
+                mask: None,
+                payload_len: 5,
+            }
+        );
+        assert_eq!(rest, 5);
+
+        let masked = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (header, rest) = parse(&masked).unwrap();
+        assert_eq!(header.mask, Some([0x37, 0xfa, 0x21, 0x3d]));
+        let mut payload = masked[masked.len() - rest..].to_vec();
+        apply_mask(header.mask.unwrap(), &mut payload);
+        assert_eq!(payload, b"Hello");
+
+        // a fragment, then extended payload lengths
+        let (header, _) = parse(&[0x01, 0x03, b'H', b'e', b'l']).unwrap();
+        assert!(!header.fin);
+        let (header, _) = parse(&[0x82, 0x7e, 0x01, 0x00]).unwrap();
+        assert_eq!(header.payload_len, 256);
+        let (header, _) = parse(&[0x82, 0x7f, 0, 0, 0, 0, 0, 0x01, 0, 0]).unwrap();
+        assert_eq!(header.payload_len, 65536);
+
+        // incomplete headers don't parse
+        assert!(parse(&[0x82, 0x7e, 0x01]).is_none());
+        assert!(parse(&[0x81, 0x85, 0x37, 0xfa]).is_none());
+
+        // encoding gives the same bytes back
+        for input in [
+            &[0x89, 0x05][..],
+            &[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d],
+            &[0x82, 0x7e, 0x01, 0x00],
+            &[0x82, 0x7f, 0, 0, 0, 0, 0, 0x01, 0, 0],
+            &[0x70, 0x00],
+        ] {
+            let (header, _) = parse(input).unwrap();
+            assert_eq!(header.encode(), input);
+        }
+    }
+}
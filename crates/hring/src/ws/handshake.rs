@@ -0,0 +1,139 @@
+//! The opening handshake, over HTTP/1.1 (cf.
+//! https://www.rfc-editor.org/rfc/rfc6455#section-4.2) and over HTTP/2 (cf.
+//! https://www.rfc-editor.org/rfc/rfc8441#section-5)
+
+use std::rc::Rc;
+
+use http::{header, StatusCode, Version};
+use tracing::debug;
+
+use crate::{
+    types::has_token, util::encode_base64, Body, Encoder, ExpectResponseHeaders, Headers, Method,
+    Request, Responder, Response,
+};
+use hring_buffet::Piece;
+
+use super::{Conf, WebSocket};
+
+/// Appended to the client's key to compute `sec-websocket-accept`
+const ACCEPT_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The only version defined by RFC 6455
+const VERSION: &[u8] = b"13";
+
+/// A request to open a WebSocket, as found by [Upgrade::from_request]
+pub struct Upgrade {
+    /// The `sec-websocket-key` the client sent over HTTP/1.1. HTTP/2 doesn't
+    /// use it, cf. https://www.rfc-editor.org/rfc/rfc8441#section-5
+    key: Option<Piece>,
+}
+
+impl Upgrade {
+    /// Returns `Some` if the request is a valid WebSocket opening handshake:
+    /// a `GET` with `upgrade: websocket` over HTTP/1.1, or an extended CONNECT
+    /// with `:protocol websocket` over HTTP/2. Others are left for the handler
+    /// to answer, e.g. with a `426 Upgrade Required` if `sec-websocket-version`
+    /// isn't 13.
+    pub fn from_request(req: &Request) -> Option<Self> {
+        let headers = &req.headers;
+        if !headers
+            .get(header::SEC_WEBSOCKET_VERSION)
+            .map_or(false, |v| &v[..] == VERSION)
+        {
+            return None;
+        }
+
+        match req.version {
+            Version::HTTP_2 => {
+                let is_websocket = req
+                    .protocol
+                    .as_ref()
+                    .map_or(false, |p| p.eq_ignore_ascii_case("websocket"));
+                if !matches!(req.method, Method::Connect) || !is_websocket {
+                    return None;
+                }
+                Some(Self { key: None })
+            }
+            Version::HTTP_11 => {
+                if !matches!(req.method, Method::Get)
+                    || !has_token(headers, header::UPGRADE, b"websocket")
+                    || !has_token(headers, header::CONNECTION, b"upgrade")
+                {
+                    return None;
+                }
+
+                // a base64-encoded 16-byte nonce
+                let key = headers.get(header::SEC_WEBSOCKET_KEY)?;
+                if key.len() != 24 {
+                    return None;
+                }
+                Some(Self {
+                    key: Some(key.clone()),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Completes the handshake: responds with `101 Switching Protocols`
+    /// (HTTP/1.1) or `200 OK` (HTTP/2), along with the given headers (e.g.
+    /// the chosen `sec-websocket-protocol`), then speaks WebSocket over the
+    /// request and response bodies.
+    pub async fn accept<B, E>(
+        self,
+        conf: Rc<Conf>,
+        req_body: &mut B,
+        respond: Responder<E, ExpectResponseHeaders>,
+        mut headers: Headers,
+    ) -> eyre::Result<WebSocket<'_, B, E>>
+    where
+        B: Body,
+        E: Encoder,
+    {
+        let status = match &self.key {
+            Some(key) => {
+                headers.insert(header::UPGRADE, "websocket".into());
+                headers.insert(header::CONNECTION, "Upgrade".into());
+                headers.insert(
+                    header::SEC_WEBSOCKET_ACCEPT,
+                    accept_key(key).into_bytes().into(),
+                );
+                StatusCode::SWITCHING_PROTOCOLS
+            }
+            None => StatusCode::OK,
+        };
+        debug!(%status, "accepting websocket");
+
+        let respond = respond
+            .write_tunnel_response(Response {
+                status,
+                headers,
+                ..Default::default()
+            })
+            .await?;
+        WebSocket::new(conf, req_body, respond)
+    }
+}
+
+/// Computes `sec-websocket-accept` from `sec-websocket-key`, cf.
+/// https://www.rfc-editor.org/rfc/rfc6455#section-4.2.2
+fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key);
+    sha1.update(ACCEPT_GUID);
+    encode_base64(&sha1.digest().bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::accept_key;
+
+    #[test]
+    fn test_accept_key() {
+        // cf. https://www.rfc-editor.org/rfc/rfc6455#section-1.3
+        assert_eq!(
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}
@@ -0,0 +1,34 @@
+//! WebSocket https://www.rfc-editor.org/rfc/rfc6455
+//! over HTTP/2 https://www.rfc-editor.org/rfc/rfc8441
+//!
+//! From a [ServerDriver](crate::ServerDriver), check for an opening handshake
+//! with [Upgrade::from_request], then [Upgrade::accept] it to get a
+//! [WebSocket]. Messages go over the request and response bodies, which carry
+//! the whole connection after a `101 Switching Protocols` over HTTP/1.1, or
+//! a single extended CONNECT stream over HTTP/2.
+
+mod frame;
+
+mod handshake;
+pub use handshake::*;
+
+mod socket;
+pub use socket::*;
+
+mod types;
+pub use types::*;
+
+pub struct Conf {
+    /// Max size of a message, once its fragments are put together. Peers
+    /// sending larger messages get a close frame with
+    /// [CloseCode::MESSAGE_TOO_BIG].
+    pub max_message_len: usize,
+}
+
+impl Default for Conf {
+    fn default() -> Self {
+        Self {
+            max_message_len: 16 * 1024 * 1024,
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use std::rc::Rc;
+
+use tracing::debug;
+
+use crate::{Body, BodyChunk, Encoder, ExpectResponseBody, Responder, ResponseDone};
+use hring_buffet::{Piece, RollMut};
+
+use super::{
+    frame::{apply_mask, FrameHeader, OpCode, MAX_CONTROL_PAYLOAD_LEN},
+    CloseCode, CloseFrame, Conf, Message, MessageKind, WebSocketError,
+};
+
+/// The server side of a WebSocket, speaking over a request body (what the
+/// client sends) and a response body (what we send). Over HTTP/1.1 that's
+/// the whole connection, over HTTP/2 that's a single stream.
+pub struct WebSocket<'a, B, E>
+where
+    B: Body,
+    E: Encoder,
+{
+    conf: Rc<Conf>,
+    req_body: &'a mut B,
+    respond: Responder<E, ExpectResponseBody>,
+
+    /// Bytes received but not parsed into frames yet
+    buf: RollMut,
+
+    /// The fragments of a data message received so far
+    partial: Option<(MessageKind, Vec<u8>)>,
+
+    /// Whether we've sent the first fragments of a data message, but not the
+    /// last one
+    sending_fragments: bool,
+
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl<'a, B, E> WebSocket<'a, B, E>
+where
+    B: Body,
+    E: Encoder,
+{
+    pub(crate) fn new(
+        conf: Rc<Conf>,
+        req_body: &'a mut B,
+        respond: Responder<E, ExpectResponseBody>,
+    ) -> eyre::Result<Self> {
+        Ok(Self {
+            conf,
+            req_body,
+            respond,
+            buf: RollMut::alloc()?,
+            partial: None,
+            sending_fragments: false,
+            close_sent: false,
+            close_received: false,
+        })
+    }
+
+    /// Returns the next message, or `None` once the closing handshake is
+    /// complete. Pings are answered, and close frames echoed, before they're
+    /// returned. If the peer breaks the protocol, a close frame is sent and
+    /// a [WebSocketError] returned.
+    pub async fn receive(&mut self) -> eyre::Result<Option<Message>> {
+        loop {
+            if self.close_received {
+                return Ok(None);
+            }
+
+            let (header, opcode, payload) = match self.read_frame().await? {
+                Ok(frame) => frame,
+                Err(e) => return Err(self.fail(e).await),
+            };
+
+            match self.handle_frame(header, opcode, payload).await? {
+                Ok(Some(msg)) => return Ok(Some(msg)),
+                Ok(None) => continue,
+                Err(e) => return Err(self.fail(e).await),
+            }
+        }
+    }
+
+    /// Sends a whole message. Sending a [Message::Close] starts the closing
+    /// handshake, after which nothing else may be sent.
+    pub async fn send(&mut self, msg: Message) -> eyre::Result<()> {
+        let (opcode, payload) = match msg {
+            Message::Text(text) => (OpCode::Text, text.into_inner()),
+            Message::Binary(data) => (OpCode::Binary, data),
+            Message::Ping(data) => (OpCode::Ping, data),
+            Message::Pong(data) => (OpCode::Pong, data),
+            Message::Close(frame) => (OpCode::Close, encode_close_payload(frame)),
+        };
+
+        if opcode.is_control() {
+            if payload.len() as u64 > MAX_CONTROL_PAYLOAD_LEN {
+                return Err(eyre::eyre!(
+                    "control frame payload is {} bytes, max is {MAX_CONTROL_PAYLOAD_LEN}",
+                    payload.len()
+                ));
+            }
+        } else if self.sending_fragments {
+            return Err(eyre::eyre!(
+                "can't send a message in the middle of a fragmented one"
+            ));
+        }
+
+        self.write_frame(true, opcode, payload).await
+    }
+
+    /// Sends one fragment of a data message: `kind` is only used for the
+    /// first fragment, and the last one has `fin` set. Control messages may
+    /// be sent in between fragments, cf. https://www.rfc-editor.org/rfc/rfc6455#section-5.4
+    pub async fn send_fragment(
+        &mut self,
+        kind: MessageKind,
+        payload: Piece,
+        fin: bool,
+    ) -> eyre::Result<()> {
+        let opcode = match (self.sending_fragments, kind) {
+            (true, _) => OpCode::Continuation,
+            (false, MessageKind::Text) => OpCode::Text,
+            (false, MessageKind::Binary) => OpCode::Binary,
+        };
+        self.write_frame(fin, opcode, payload).await?;
+        self.sending_fragments = !fin;
+        Ok(())
+    }
+
+    /// Starts the closing handshake (unless the peer did), then waits for
+    /// the peer's close frame, dropping any data message received meanwhile.
+    pub async fn close(&mut self, frame: Option<CloseFrame>) -> eyre::Result<()> {
+        if !self.close_sent {
+            self.send(Message::Close(frame)).await?;
+        }
+        while let Some(msg) = self.receive().await? {
+            debug!(?msg, "dropping message received while closing");
+        }
+        Ok(())
+    }
+
+    /// Ends the response body, which closes the stream (HTTP/2) or lets the
+    /// connection be closed (HTTP/1.1). If no close frame was sent yet, sends
+    /// one with [CloseCode::NORMAL] first, without waiting for the peer's.
+    pub async fn finish(mut self) -> eyre::Result<Responder<E, ResponseDone>> {
+        if !self.close_sent {
+            self.send(Message::Close(Some(CloseFrame {
+                code: CloseCode::NORMAL,
+                reason: "".into(),
+            })))
+            .await?;
+        }
+        self.respond.finish_body(None).await
+    }
+
+    async fn write_frame(&mut self, fin: bool, opcode: OpCode, payload: Piece) -> eyre::Result<()> {
+        if self.close_sent {
+            return Err(eyre::eyre!("can't send anything after a close frame"));
+        }
+        if opcode == OpCode::Close {
+            self.close_sent = true;
+        }
+
+        // servers don't mask what they send, cf.
+        // https://www.rfc-editor.org/rfc/rfc6455#section-5.1
+        let header = FrameHeader {
+            fin,
+            rsv: 0,
+            opcode: opcode.repr(),
+            mask: None,
+            payload_len: payload.len() as u64,
+        };
+        self.respond.write_chunk(header.encode().into()).await?;
+        if !payload.is_empty() {
+            self.respond.write_chunk(payload).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a close frame with the error's code (unless we already sent
+    /// one), then returns the error.
+    async fn fail(&mut self, e: WebSocketError) -> eyre::Report {
+        debug!(%e, "failing websocket");
+        if !self.close_sent && !matches!(e, WebSocketError::ClosedWithoutCloseFrame) {
+            let frame = CloseFrame {
+                code: e.close_code(),
+                reason: "".into(),
+            };
+            if let Err(e) = self.send(Message::Close(Some(frame))).await {
+                debug!(%e, "could not send close frame");
+            }
+        }
+        e.into()
+    }
+
+    /// Reads the next frame, unmasking its payload. The outer error is for
+    /// I/O errors, the inner one for protocol violations.
+    async fn read_frame(
+        &mut self,
+    ) -> eyre::Result<Result<(FrameHeader, OpCode, Vec<u8>), WebSocketError>> {
+        loop {
+            match FrameHeader::parse(self.buf.filled()) {
+                Ok((rest, header)) => {
+                    // check limits before buffering the payload
+                    let opcode = match self.check_header(&header) {
+                        Ok(opcode) => opcode,
+                        Err(e) => return Ok(Err(e)),
+                    };
+
+                    if rest.len() as u64 >= header.payload_len {
+                        let (payload, rest) = rest.split_at(header.payload_len as usize);
+                        self.buf.keep(rest);
+
+                        let mut payload = payload[..].to_vec();
+                        if let Some(mask) = header.mask {
+                            apply_mask(mask, &mut payload);
+                        }
+                        return Ok(Ok((header, opcode, payload)));
+                    }
+                }
+                Err(e) if e.is_incomplete() => {}
+                Err(e) => return Err(eyre::eyre!("parsing error: {e}")),
+            }
+
+            if !self.fill().await? {
+                return Ok(Err(WebSocketError::ClosedWithoutCloseFrame));
+            }
+        }
+    }
+
+    fn check_header(&self, header: &FrameHeader) -> Result<OpCode, WebSocketError> {
+        if header.rsv != 0 {
+            return Err(WebSocketError::ReservedBitsSet { rsv: header.rsv });
+        }
+        let Some(opcode) = OpCode::from_repr(header.opcode) else {
+            return Err(WebSocketError::UnknownOpCode {
+                opcode: header.opcode,
+            });
+        };
+        // cf. https://www.rfc-editor.org/rfc/rfc6455#section-5.1
+        if header.mask.is_none() {
+            return Err(WebSocketError::UnmaskedFrame);
+        }
+
+        if opcode.is_control() {
+            if !header.fin {
+                return Err(WebSocketError::FragmentedControlFrame);
+            }
+            if header.payload_len > MAX_CONTROL_PAYLOAD_LEN {
+                return Err(WebSocketError::ControlFrameTooLarge {
+                    len: header.payload_len,
+                });
+            }
+        } else {
+            let received = self.partial.as_ref().map_or(0, |(_, data)| data.len());
+            let len = received as u64 + header.payload_len;
+            let max = self.conf.max_message_len as u64;
+            if len > max {
+                return Err(WebSocketError::MessageTooLarge { len, max });
+            }
+        }
+        Ok(opcode)
+    }
+
+    /// Returns `Ok(None)` for frames that don't complete a message (that is,
+    /// fragments other than the last one).
+    async fn handle_frame(
+        &mut self,
+        header: FrameHeader,
+        opcode: OpCode,
+        payload: Vec<u8>,
+    ) -> eyre::Result<Result<Option<Message>, WebSocketError>> {
+        let msg = match opcode {
+            OpCode::Text | OpCode::Binary => {
+                if self.partial.is_some() {
+                    return Ok(Err(WebSocketError::ExpectedContinuation));
+                }
+                let kind = match opcode {
+                    OpCode::Text => MessageKind::Text,
+                    _ => MessageKind::Binary,
+                };
+                if !header.fin {
+                    self.partial = Some((kind, payload));
+                    return Ok(Ok(None));
+                }
+                data_message(kind, payload)
+            }
+            OpCode::Continuation => {
+                let Some((kind, data)) = self.partial.as_mut() else {
+                    return Ok(Err(WebSocketError::UnexpectedContinuation));
+                };
+                data.extend_from_slice(&payload);
+                if !header.fin {
+                    return Ok(Ok(None));
+                }
+                let kind = *kind;
+                let data = self
+                    .partial
+                    .take()
+                    .map(|(_, data)| data)
+                    .unwrap_or_default();
+                data_message(kind, data)
+            }
+            OpCode::Ping => {
+                let payload = Piece::from(payload);
+                if !self.close_sent {
+                    self.send(Message::Pong(payload.clone())).await?;
+                }
+                Ok(Message::Ping(payload))
+            }
+            OpCode::Pong => Ok(Message::Pong(payload.into())),
+            OpCode::Close => {
+                let frame = match decode_close_payload(payload) {
+                    Ok(frame) => frame,
+                    Err(e) => return Ok(Err(e)),
+                };
+                self.close_received = true;
+                if !self.close_sent {
+                    // echo the status code, cf. https://www.rfc-editor.org/rfc/rfc6455#section-5.5.1
+                    let echo = frame.as_ref().map(|frame| CloseFrame {
+                        code: frame.code,
+                        reason: "".into(),
+                    });
+                    self.send(Message::Close(echo)).await?;
+                }
+                Ok(Message::Close(frame))
+            }
+        };
+        Ok(msg.map(Some))
+    }
+
+    /// Reads more bytes from the request body into `buf`. Returns false if
+    /// there's nothing left to read.
+    async fn fill(&mut self) -> eyre::Result<bool> {
+        let chunk = match self.req_body.next_chunk().await? {
+            BodyChunk::Chunk(chunk) => chunk,
+            BodyChunk::Done { .. } => return Ok(false),
+        };
+
+        let mut chunk = &chunk[..];
+        while !chunk.is_empty() {
+            if self.buf.cap() == 0 {
+                self.buf.reserve()?;
+            }
+            let n = std::cmp::min(self.buf.cap(), chunk.len());
+            self.buf.put(&chunk[..n])?;
+            chunk = &chunk[n..];
+        }
+        Ok(true)
+    }
+}
+
+fn data_message(kind: MessageKind, data: Vec<u8>) -> Result<Message, WebSocketError> {
+    let data = Piece::from(data);
+    match kind {
+        MessageKind::Text => match data.to_str() {
+            Ok(text) => Ok(Message::Text(text)),
+            Err(_) => Err(WebSocketError::InvalidUtf8),
+        },
+        MessageKind::Binary => Ok(Message::Binary(data)),
+    }
+}
+
+fn encode_close_payload(frame: Option<CloseFrame>) -> Piece {
+    match frame {
+        Some(frame) => {
+            let mut payload = Vec::with_capacity(2 + frame.reason.len());
+            payload.extend_from_slice(&frame.code.0.to_be_bytes());
+            payload.extend_from_slice(frame.reason.as_bytes());
+            payload.into()
+        }
+        None => Piece::from(&b""[..]),
+    }
+}
+
+/// cf. https://www.rfc-editor.org/rfc/rfc6455#section-5.5.1
+fn decode_close_payload(payload: Vec<u8>) -> Result<Option<CloseFrame>, WebSocketError> {
+    match payload.len() {
+        0 => return Ok(None),
+        1 => return Err(WebSocketError::InvalidClosePayload),
+        _ => {}
+    }
+
+    let code = CloseCode(u16::from_be_bytes([payload[0], payload[1]]));
+    if !code.is_valid() {
+        return Err(WebSocketError::InvalidCloseCode { code });
+    }
+    let (_, reason) = Piece::from(payload).split_at(2);
+    let reason = reason.to_str().map_err(|_| WebSocketError::InvalidUtf8)?;
+    Ok(Some(CloseFrame { code, reason }))
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+
+use hring_buffet::{Piece, PieceStr};
+
+/// A complete WebSocket message. Fragmented data messages are put back
+/// together before they're handed out.
+#[derive(Clone)]
+pub enum Message {
+    Text(PieceStr),
+    Binary(Piece),
+    /// Pings get answered with a pong automatically
+    Ping(Piece),
+    Pong(Piece),
+    /// The peer started or completed the closing handshake
+    Close(Option<CloseFrame>),
+}
+
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text(text) => f.debug_tuple("Text").field(text).finish(),
+            Self::Binary(data) => write!(f, "Binary({} bytes)", data.len()),
+            Self::Ping(data) => write!(f, "Ping({} bytes)", data.len()),
+            Self::Pong(data) => write!(f, "Pong({} bytes)", data.len()),
+            Self::Close(frame) => f.debug_tuple("Close").field(frame).finish(),
+        }
+    }
+}
+
+/// Whether a data message is text (valid UTF-8) or binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Text,
+    Binary,
+}
+
+/// The body of a close frame, cf. https://www.rfc-editor.org/rfc/rfc6455#section-5.5.1
+#[derive(Debug, Clone)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+
+    /// Meant for debugging, not necessarily human-readable
+    pub reason: PieceStr,
+}
+
+/// Why a WebSocket was closed, cf. https://www.rfc-editor.org/rfc/rfc6455#section-7.4
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CloseCode(pub u16);
+
+impl CloseCode {
+    pub const NORMAL: Self = Self(1000);
+    pub const GOING_AWAY: Self = Self(1001);
+    pub const PROTOCOL_ERROR: Self = Self(1002);
+    pub const UNSUPPORTED_DATA: Self = Self(1003);
+    pub const INVALID_PAYLOAD: Self = Self(1007);
+    pub const POLICY_VIOLATION: Self = Self(1008);
+    pub const MESSAGE_TOO_BIG: Self = Self(1009);
+    pub const INTERNAL_ERROR: Self = Self(1011);
+
+    /// Whether the code may appear in a close frame: some are reserved for
+    /// local use (e.g. 1006 for connections closed without a close frame),
+    /// cf. https://www.rfc-editor.org/rfc/rfc6455#section-7.4.1
+    pub fn is_valid(self) -> bool {
+        matches!(self.0, 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+impl fmt::Debug for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+/// The peer broke the protocol, cf. https://www.rfc-editor.org/rfc/rfc6455#section-7.1.7
+/// We send a close frame with [WebSocketError::close_code] before returning
+/// these.
+#[derive(Debug, thiserror::Error)]
+pub enum WebSocketError {
+    #[error("client sent an unmasked frame")]
+    UnmaskedFrame,
+
+    #[error("reserved bits set without a negotiated extension: {rsv:#05b}")]
+    ReservedBitsSet { rsv: u8 },
+
+    #[error("unknown opcode {opcode:#x}")]
+    UnknownOpCode { opcode: u8 },
+
+    #[error("control frames can't be fragmented")]
+    FragmentedControlFrame,
+
+    #[error("control frame payload is {len} bytes, max is 125")]
+    ControlFrameTooLarge { len: u64 },
+
+    #[error("continuation frame without a message to continue")]
+    UnexpectedContinuation,
+
+    #[error("new data message while the previous one wasn't finished")]
+    ExpectedContinuation,
+
+    #[error("message is at least {len} bytes, max is {max}")]
+    MessageTooLarge { len: u64, max: u64 },
+
+    #[error("text message or close reason isn't valid UTF-8")]
+    InvalidUtf8,
+
+    #[error("close frame payload can't be a single byte")]
+    InvalidClosePayload,
+
+    #[error("invalid close code {code:?}")]
+    InvalidCloseCode { code: CloseCode },
+
+    #[error("connection closed without a close frame")]
+    ClosedWithoutCloseFrame,
+}
+
+impl WebSocketError {
+    /// The code of the close frame we send because of this error
+    pub fn close_code(&self) -> CloseCode {
+        match self {
+            WebSocketError::MessageTooLarge { .. } => CloseCode::MESSAGE_TOO_BIG,
+            WebSocketError::InvalidUtf8 => CloseCode::INVALID_PAYLOAD,
+            _ => CloseCode::PROTOCOL_ERROR,
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! A bare-bones HTTP/2 peer, for tests that need control over individual
+//! frames (flow control, settings, resets, etc.)
+
+use byteorder::{BigEndian, WriteBytesExt};
+use hring_buffet::ChanReadSend;
+use tokio::sync::mpsc;
+
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+pub(crate) mod frame_type {
+    pub(crate) const DATA: u8 = 0x0;
+    pub(crate) const HEADERS: u8 = 0x1;
+    pub(crate) const PRIORITY: u8 = 0x2;
+    pub(crate) const RST_STREAM: u8 = 0x3;
+    pub(crate) const SETTINGS: u8 = 0x4;
+    pub(crate) const PING: u8 = 0x6;
+    pub(crate) const GOAWAY: u8 = 0x7;
+    pub(crate) const WINDOW_UPDATE: u8 = 0x8;
+    pub(crate) const PRIORITY_UPDATE: u8 = 0x10;
+}
+
+pub(crate) mod flags {
+    pub(crate) const END_STREAM: u8 = 0x1;
+    pub(crate) const END_HEADERS: u8 = 0x4;
+    pub(crate) const PADDED: u8 = 0x8;
+    pub(crate) const PRIORITY: u8 = 0x20;
+}
+
+#[derive(Debug)]
+pub(crate) struct RawFrame {
+    pub(crate) frame_type: u8,
+    pub(crate) flags: u8,
+    pub(crate) stream_id: u32,
+    pub(crate) payload: Vec<u8>,
+}
+
+/// Talks HTTP/2 to a server (or, after [H2Conn::accept], to a client) over a
+/// [hring_buffet::ChanRead] / [hring_buffet::ChanWrite] pair, one frame at a
+/// time.
+pub(crate) struct H2Conn {
+    tx: ChanReadSend,
+    rx: mpsc::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    hpack: hring_hpack::Encoder<'static>,
+}
+
+impl H2Conn {
+    pub(crate) fn new(tx: ChanReadSend, rx: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self {
+            tx,
+            rx,
+            buf: Default::default(),
+            hpack: Default::default(),
+        }
+    }
+
+    /// Sends the connection preface, followed by a SETTINGS frame with the
+    /// given (identifier, value) pairs.
+    pub(crate) async fn handshake(&mut self, settings: &[(u16, u32)]) -> eyre::Result<()> {
+        self.tx.send(PREFACE).await?;
+        let mut payload = Vec::new();
+        for &(id, value) in settings {
+            payload.write_u16::<BigEndian>(id)?;
+            payload.write_u32::<BigEndian>(value)?;
+        }
+        self.send_frame(frame_type::SETTINGS, 0, 0, payload).await
+    }
+
+    /// Reads the client's connection preface, then sends a SETTINGS frame
+    /// with the given (identifier, value) pairs.
+    pub(crate) async fn accept(&mut self, settings: &[(u16, u32)]) -> eyre::Result<()> {
+        while self.buf.len() < PREFACE.len() {
+            match self.rx.recv().await {
+                Some(chunk) => self.buf.extend_from_slice(&chunk),
+                None => eyre::bail!("connection closed before preface"),
+            }
+        }
+        let preface: Vec<u8> = self.buf.drain(..PREFACE.len()).collect();
+        eyre::ensure!(preface == PREFACE, "client sent a bad preface");
+
+        let mut payload = Vec::new();
+        for &(id, value) in settings {
+            payload.write_u16::<BigEndian>(id)?;
+            payload.write_u32::<BigEndian>(value)?;
+        }
+        self.send_frame(frame_type::SETTINGS, 0, 0, payload).await
+    }
+
+    pub(crate) async fn send_frame(
+        &mut self,
+        frame_type: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: impl Into<Vec<u8>>,
+    ) -> eyre::Result<()> {
+        let payload = payload.into();
+        let mut buf = Vec::with_capacity(9 + payload.len());
+        buf.write_u24::<BigEndian>(payload.len() as _)?;
+        buf.write_u8(frame_type)?;
+        buf.write_u8(flags)?;
+        buf.write_u32::<BigEndian>(stream_id)?;
+        buf.extend_from_slice(&payload);
+        self.tx.send(buf).await?;
+        Ok(())
+    }
+
+    /// HPACK-encodes a field block, for tests that build HEADERS frames by hand.
+    pub(crate) fn encode_headers(&mut self, headers: &[(&[u8], &[u8])]) -> Vec<u8> {
+        self.hpack.encode(headers.iter().copied())
+    }
+
+    /// Sends a HEADERS frame with END_HEADERS set.
+    pub(crate) async fn send_headers(
+        &mut self,
+        stream_id: u32,
+        headers: &[(&[u8], &[u8])],
+        end_stream: bool,
+    ) -> eyre::Result<()> {
+        let block = self.encode_headers(headers);
+        let mut f = flags::END_HEADERS;
+        if end_stream {
+            f |= flags::END_STREAM;
+        }
+        self.send_frame(frame_type::HEADERS, f, stream_id, block)
+            .await
+    }
+
+    pub(crate) async fn send_window_update(
+        &mut self,
+        stream_id: u32,
+        increment: u32,
+    ) -> eyre::Result<()> {
+        let mut payload = Vec::new();
+        payload.write_u32::<BigEndian>(increment)?;
+        self.send_frame(frame_type::WINDOW_UPDATE, 0, stream_id, payload)
+            .await
+    }
+
+    /// Reads an HTTP/1.1 response head, e.g. the `101 Switching Protocols`
+    /// that comes before any frame when upgrading to h2c.
+    pub(crate) async fn read_h1_head(&mut self) -> eyre::Result<String> {
+        loop {
+            if let Some(pos) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = self.buf.drain(..pos + 4).collect();
+                return Ok(String::from_utf8(head)?);
+            }
+
+            match self.rx.recv().await {
+                Some(chunk) => self.buf.extend_from_slice(&chunk),
+                None => eyre::bail!("connection closed before response head"),
+            }
+        }
+    }
+
+    /// Returns the next frame sent by the peer, or `None` if it closed the
+    /// connection.
+    pub(crate) async fn read_frame(&mut self) -> eyre::Result<Option<RawFrame>> {
+        loop {
+            if self.buf.len() >= 9 {
+                let len = u32::from_be_bytes([0, self.buf[0], self.buf[1], self.buf[2]]) as usize;
+                if self.buf.len() >= 9 + len {
+                    let frame = RawFrame {
+                        frame_type: self.buf[3],
+                        flags: self.buf[4],
+                        stream_id: u32::from_be_bytes(self.buf[5..9].try_into().unwrap())
+                            & 0x7fff_ffff,
+                        payload: self.buf[9..9 + len].to_vec(),
+                    };
+                    self.buf.drain(..9 + len);
+                    return Ok(Some(frame));
+                }
+            }
+
+            match self.rx.recv().await {
+                Some(chunk) => self.buf.extend_from_slice(&chunk),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Returns the next frame that's not a SETTINGS or WINDOW_UPDATE frame.
+    pub(crate) async fn read_significant_frame(&mut self) -> eyre::Result<Option<RawFrame>> {
+        loop {
+            match self.read_frame().await? {
+                Some(frame)
+                    if frame.frame_type == frame_type::SETTINGS
+                        || frame.frame_type == frame_type::WINDOW_UPDATE =>
+                {
+                    continue
+                }
+                other => return Ok(other),
+            }
+        }
+    }
+}
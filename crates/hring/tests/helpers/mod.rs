@@ -1,6 +1,8 @@
 use std::future::Future;
 
+pub(crate) mod h2;
 pub(crate) mod tracing_common;
+pub(crate) mod ws;
 
 pub(crate) fn run(test: impl Future<Output = eyre::Result<()>>) {
     tokio_uring::start(async {
@@ -0,0 +1,144 @@
+//! A bare-bones WebSocket client, for tests that need control over
+//! individual frames (masking, fragmentation, close codes, etc.)
+
+use hring_buffet::ChanReadSend;
+use tokio::sync::mpsc;
+
+pub(crate) mod opcode {
+    pub(crate) const CONTINUATION: u8 = 0x0;
+    pub(crate) const TEXT: u8 = 0x1;
+    pub(crate) const BINARY: u8 = 0x2;
+    pub(crate) const CLOSE: u8 = 0x8;
+    pub(crate) const PING: u8 = 0x9;
+    pub(crate) const PONG: u8 = 0xA;
+}
+
+/// Clients must mask what they send, any key will do
+const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+#[derive(Debug)]
+pub(crate) struct WsFrame {
+    pub(crate) fin: bool,
+    pub(crate) opcode: u8,
+    pub(crate) payload: Vec<u8>,
+}
+
+/// Encodes a frame the way a client would, masked. The payload length must
+/// fit in 16 bits.
+pub(crate) fn encode_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    encode_frame_with_mask(fin, opcode, payload, Some(MASK))
+}
+
+pub(crate) fn encode_frame_with_mask(
+    fin: bool,
+    opcode: u8,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) -> Vec<u8> {
+    let mut out = vec![(fin as u8) << 7 | opcode];
+    let mask_bit = (mask.is_some() as u8) << 7;
+    if payload.len() < 126 {
+        out.push(mask_bit | payload.len() as u8);
+    } else {
+        out.push(mask_bit | 126);
+        out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    }
+
+    match mask {
+        Some(mask) => {
+            out.extend_from_slice(&mask);
+            out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => out.extend_from_slice(payload),
+    }
+    out
+}
+
+/// Takes a complete frame off the front of `buf`, if there's one. Panics if
+/// the frame is masked or has a 64-bit length, which a server never sends in
+/// these tests.
+pub(crate) fn decode_frame(buf: &mut Vec<u8>) -> Option<WsFrame> {
+    if buf.len() < 2 {
+        return None;
+    }
+    assert_eq!(buf[1] & 0x80, 0, "server frames must not be masked");
+
+    let (len, offset) = match buf[1] & 0x7f {
+        126 => {
+            if buf.len() < 4 {
+                return None;
+            }
+            (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4)
+        }
+        127 => panic!("unexpected 64-bit payload length"),
+        len => (len as usize, 2),
+    };
+    if buf.len() < offset + len {
+        return None;
+    }
+
+    let frame = WsFrame {
+        fin: buf[0] & 0x80 != 0,
+        opcode: buf[0] & 0xf,
+        payload: buf[offset..offset + len].to_vec(),
+    };
+    buf.drain(..offset + len);
+    Some(frame)
+}
+
+/// Talks to a server over a [hring_buffet::ChanRead] / [hring_buffet::ChanWrite]
+/// pair: an HTTP/1.1 opening handshake, then WebSocket frames.
+pub(crate) struct WsConn {
+    tx: ChanReadSend,
+    rx: mpsc::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl WsConn {
+    pub(crate) fn new(tx: ChanReadSend, rx: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self {
+            tx,
+            rx,
+            buf: Default::default(),
+        }
+    }
+
+    pub(crate) async fn send(&mut self, data: impl Into<Vec<u8>>) -> eyre::Result<()> {
+        self.tx.send(data.into()).await?;
+        Ok(())
+    }
+
+    /// Reads the HTTP/1.1 response head, e.g. `101 Switching Protocols`
+    pub(crate) async fn read_h1_head(&mut self) -> eyre::Result<String> {
+        loop {
+            if let Some(pos) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = self.buf.drain(..pos + 4).collect();
+                return Ok(String::from_utf8(head)?);
+            }
+            self.recv().await?;
+        }
+    }
+
+    /// Returns the next frame sent by the server, or `None` if it closed the
+    /// connection.
+    pub(crate) async fn read_frame(&mut self) -> eyre::Result<Option<WsFrame>> {
+        loop {
+            if let Some(frame) = decode_frame(&mut self.buf) {
+                return Ok(Some(frame));
+            }
+            if !self.recv().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    async fn recv(&mut self) -> eyre::Result<bool> {
+        match self.rx.recv().await {
+            Some(chunk) => {
+                self.buf.extend_from_slice(&chunk);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
@@ -7,18 +7,19 @@ mod helpers;
 use bytes::BytesMut;
 use curl::easy::{Easy, HttpVersion, List};
 use hring::{
-    h1, h2, Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, HeadersExt, Method, Request,
-    Responder, Response, ResponseDone, ServerDriver,
+    h1, h2, Body, BodyChunk, BodyError, BodyErrorReason, Encoder, ExpectResponseHeaders, Headers,
+    HeadersExt, Method, Request, Responder, Response, ResponseDone, ServerDriver, Timeout,
 };
 use hring_buffet::{ChanRead, ChanWrite, Piece, ReadWritePair, RollMut};
 use http::{header, StatusCode};
 use httparse::{Status, EMPTY_HEADER};
 use pretty_assertions::assert_eq;
 use pretty_hex::PrettyHex;
-use std::{future::Future, net::SocketAddr, rc::Rc, time::Duration};
+use std::{collections::HashMap, future::Future, net::SocketAddr, rc::Rc, time::Duration};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
+    sync::Notify,
 };
 use tracing::debug;
 
@@ -177,7 +178,14 @@ fn request_api() {
         let request_fut = tokio_uring::spawn(async {
             #[allow(clippy::let_unit_value)]
             let mut body = ();
-            h1::request(Rc::new(transport), req, &mut body, driver).await
+            h1::request(
+                Rc::new(transport),
+                Default::default(),
+                req,
+                &mut body,
+                driver,
+            )
+            .await
         });
 
         let mut req_buf = BytesMut::new();
@@ -1131,3 +1139,3431 @@ fn h2_basic_get() {
         Ok(())
     });
 }
+
+#[test]
+fn h2_flow_control() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    const BODY_LEN: usize = 3000;
+    const INITIAL_WINDOW_SIZE: u32 = 1000;
+
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                _req: Request,
+                _req_body: &mut impl Body,
+                respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                let mut respond = respond
+                    .write_final_response(Response {
+                        status: StatusCode::OK,
+                        ..Default::default()
+                    })
+                    .await?;
+                respond.write_chunk(vec![b'a'; BODY_LEN].into()).await?;
+                respond.finish_body(None).await
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let conf = Rc::new(h2::ServerConf::default());
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            transport,
+            conf,
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        // SETTINGS_INITIAL_WINDOW_SIZE
+        conn.handshake(&[(0x4, INITIAL_WINDOW_SIZE)]).await?;
+        conn.send_headers(
+            1,
+            &[
+                (b":method", b"GET"),
+                (b":scheme", b"http"),
+                (b":authority", b"localhost"),
+                (b":path", b"/"),
+            ],
+            true,
+        )
+        .await?;
+
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::HEADERS);
+
+        async fn read_data(conn: &mut H2Conn, len: usize) -> eyre::Result<bool> {
+            let mut received = 0;
+            let mut end_stream = false;
+            while received < len {
+                let frame = conn.read_significant_frame().await?.unwrap();
+                assert_eq!(frame.frame_type, frame_type::DATA);
+                assert_eq!(frame.stream_id, 1);
+                received += frame.payload.len();
+                end_stream = frame.flags & flags::END_STREAM != 0;
+            }
+            assert_eq!(received, len);
+            Ok(end_stream)
+        }
+
+        // the stream window only lets the server send that much
+        let end_stream = read_data(&mut conn, INITIAL_WINDOW_SIZE as _).await?;
+        assert!(!end_stream);
+        let res =
+            tokio::time::timeout(Duration::from_millis(100), conn.read_significant_frame()).await;
+        assert!(res.is_err(), "server sent more than the window allowed");
+
+        // opening the window lets the rest through
+        conn.send_window_update(1, BODY_LEN as u32 - INITIAL_WINDOW_SIZE)
+            .await?;
+        let mut remaining = BODY_LEN - INITIAL_WINDOW_SIZE as usize;
+        let mut end_stream = false;
+        while remaining > 0 {
+            let frame = conn.read_significant_frame().await?.unwrap();
+            match frame.frame_type {
+                frame_type::DATA => {
+                    remaining -= frame.payload.len();
+                    end_stream = frame.flags & flags::END_STREAM != 0;
+                }
+                other => panic!("unexpected frame type {other}"),
+            }
+        }
+        if !end_stream {
+            let frame = conn.read_significant_frame().await?.unwrap();
+            assert_eq!(frame.frame_type, frame_type::DATA);
+            assert!(frame.payload.is_empty());
+            assert_ne!(frame.flags & flags::END_STREAM, 0);
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_settings() {
+    use helpers::h2::{frame_type, H2Conn};
+
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                _req: Request,
+                _req_body: &mut impl Body,
+                _respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                unreachable!("no request is ever made")
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let conf = Rc::new(h2::ServerConf {
+            initial_window_size: 1024 * 1024,
+            max_frame_size: 32 * 1024,
+            ..Default::default()
+        });
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            transport,
+            conf,
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        // SETTINGS_ENABLE_PUSH may only be 0 or 1
+        conn.handshake(&[(0x2, 2)]).await?;
+
+        // the server advertises our configuration
+        let frame = conn.read_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::SETTINGS);
+        let settings = frame
+            .payload
+            .chunks(6)
+            .map(|s| {
+                (
+                    u16::from_be_bytes([s[0], s[1]]),
+                    u32::from_be_bytes([s[2], s[3], s[4], s[5]]),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert!(settings.contains(&(0x4, 1024 * 1024)));
+        assert!(settings.contains(&(0x5, 32 * 1024)));
+        // extended CONNECT is enabled by default
+        assert!(settings.contains(&(0x8, 1)));
+
+        // and doesn't put up with invalid settings
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::GOAWAY);
+        let error_code = u32::from_be_bytes(frame.payload[4..8].try_into().unwrap());
+        // PROTOCOL_ERROR
+        assert_eq!(error_code, 0x1);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_protocol_errors() {
+    use helpers::h2::{frame_type, H2Conn};
+
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                _req: Request,
+                _req_body: &mut impl Body,
+                _respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                unreachable!("malformed requests never make it to the driver")
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let serve_fut = tokio_uring::spawn(h2::serve(
+            transport,
+            Default::default(),
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+
+        // a malformed request is a stream error...
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"GET"),
+            (b":scheme", b"http"),
+            (b":path", b"/"),
+            (b"Uppercase", b"not allowed"),
+        ];
+        conn.send_headers(1, headers, true).await?;
+
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+        assert_eq!(frame.stream_id, 1);
+        // PROTOCOL_ERROR
+        assert_eq!(frame.payload, [0x0, 0x0, 0x0, 0x1]);
+
+        // so is a content-length that doesn't match the DATA frames
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"POST"),
+            (b":scheme", b"http"),
+            (b":path", b"/"),
+            (b"content-length", b"5"),
+        ];
+        conn.send_headers(3, headers, true).await?;
+
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+        assert_eq!(frame.stream_id, 3);
+        // PROTOCOL_ERROR
+        assert_eq!(frame.payload, [0x0, 0x0, 0x0, 0x1]);
+
+        // ...while a PING on a stream is a connection error
+        conn.send_frame(frame_type::PING, 0, 1, vec![0x0; 8])
+            .await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::GOAWAY);
+        let error_code = u32::from_be_bytes(frame.payload[4..8].try_into().unwrap());
+        // PROTOCOL_ERROR
+        assert_eq!(error_code, 0x1);
+        assert!(conn.read_significant_frame().await?.is_none());
+
+        let outcome = serve_fut.await??;
+        assert!(matches!(
+            outcome,
+            h2::ServeOutcome::ConnectionError(h2::H2ConnectionError::PingOnStream { .. })
+        ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_padding_and_priority() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                _req: Request,
+                req_body: &mut impl Body,
+                respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                let mut body = Vec::new();
+                while let BodyChunk::Chunk(chunk) = req_body.next_chunk().await? {
+                    body.extend_from_slice(&chunk[..]);
+                }
+
+                let mut respond = respond
+                    .write_final_response(Response {
+                        status: StatusCode::OK,
+                        ..Default::default()
+                    })
+                    .await?;
+                respond.write_chunk(body.into()).await?;
+                respond.finish_body(None).await
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            transport,
+            Default::default(),
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+
+        // padded HEADERS with a priority spec, then padded DATA: neither the
+        // padding nor the priority spec make it into the request.
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"POST"),
+            (b":scheme", b"http"),
+            (b":path", b"/"),
+            (b"content-length", b"5"),
+        ];
+        let mut payload = vec![3];
+        // exclusive, depends on stream 0, weight 16
+        payload.extend_from_slice(&[0x80, 0x0, 0x0, 0x0, 15]);
+        payload.extend(conn.encode_headers(headers));
+        payload.extend_from_slice(&[0x0; 3]);
+        let f = flags::END_HEADERS | flags::PADDED | flags::PRIORITY;
+        conn.send_frame(frame_type::HEADERS, f, 1, payload).await?;
+
+        let mut payload = vec![4];
+        payload.extend_from_slice(b"hello");
+        payload.extend_from_slice(&[0x0; 4]);
+        let f = flags::END_STREAM | flags::PADDED;
+        conn.send_frame(frame_type::DATA, f, 1, payload).await?;
+
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::HEADERS);
+        assert_eq!(frame.stream_id, 1);
+        let mut body = Vec::new();
+        loop {
+            let frame = conn.read_significant_frame().await?.unwrap();
+            assert_eq!(frame.frame_type, frame_type::DATA);
+            body.extend_from_slice(&frame.payload);
+            if frame.flags & flags::END_STREAM != 0 {
+                break;
+            }
+        }
+        assert_eq!(body, b"hello");
+
+        // a stream can't depend on itself, be it in HEADERS...
+        let get: &[(&[u8], &[u8])] = &[
+            (b":method", b"GET"),
+            (b":scheme", b"http"),
+            (b":path", b"/"),
+        ];
+        let mut payload = vec![0x0, 0x0, 0x0, 0x3, 15];
+        payload.extend(conn.encode_headers(get));
+        let f = flags::END_HEADERS | flags::END_STREAM | flags::PRIORITY;
+        conn.send_frame(frame_type::HEADERS, f, 3, payload).await?;
+
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+        assert_eq!(frame.stream_id, 3);
+        // PROTOCOL_ERROR
+        assert_eq!(frame.payload, [0x0, 0x0, 0x0, 0x1]);
+
+        // ...or in PRIORITY
+        conn.send_frame(frame_type::PRIORITY, 0, 5, vec![0x0, 0x0, 0x0, 0x5, 15])
+            .await?;
+
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+        assert_eq!(frame.stream_id, 5);
+        // PROTOCOL_ERROR
+        assert_eq!(frame.payload, [0x0, 0x0, 0x0, 0x1]);
+
+        // the connection is still usable, and HPACK state still in sync
+        conn.send_headers(7, get, true).await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::HEADERS);
+        assert_eq!(frame.stream_id, 7);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_padding_flow_control() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    helpers::run(async move {
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let serve_fut = tokio_uring::spawn(h2::serve(
+            ReadWritePair(read, write),
+            Default::default(),
+            RollMut::alloc()?,
+            Rc::new(UploadDriver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"POST"),
+            (b":scheme", b"http"),
+            (b":path", b"/upload"),
+        ];
+        conn.send_headers(1, headers, false).await?;
+
+        // the pad length field and the padding count against flow control,
+        // so the whole frame is given back, not just the 5 body bytes
+        let mut payload = vec![4];
+        payload.extend_from_slice(b"hello");
+        payload.extend_from_slice(&[0x0; 4]);
+        let f = flags::END_STREAM | flags::PADDED;
+        conn.send_frame(frame_type::DATA, f, 1, payload).await?;
+
+        let mut conn_increments = 0;
+        let mut body = Vec::new();
+        loop {
+            let frame = conn.read_frame().await?.unwrap();
+            match frame.frame_type {
+                frame_type::WINDOW_UPDATE if frame.stream_id == 0 => {
+                    conn_increments += u32::from_be_bytes(frame.payload[..4].try_into().unwrap());
+                }
+                frame_type::DATA => {
+                    body.extend_from_slice(&frame.payload);
+                    if frame.flags & flags::END_STREAM != 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(body, b"got 5 bytes");
+        while let Ok(frame) =
+            tokio::time::timeout(Duration::from_millis(50), conn.read_frame()).await
+        {
+            let frame = frame?.unwrap();
+            if frame.frame_type == frame_type::WINDOW_UPDATE && frame.stream_id == 0 {
+                conn_increments += u32::from_be_bytes(frame.payload[..4].try_into().unwrap());
+            }
+        }
+        assert_eq!(conn_increments, 10);
+
+        // padding can't be longer than the frame
+        conn.send_headers(3, headers, false).await?;
+        let mut payload = vec![6];
+        payload.extend_from_slice(b"hello");
+        conn.send_frame(frame_type::DATA, flags::PADDED, 3, payload)
+            .await?;
+        let frame = loop {
+            let frame = conn.read_significant_frame().await?.unwrap();
+            if frame.frame_type == frame_type::GOAWAY {
+                break frame;
+            }
+        };
+        let error_code = u32::from_be_bytes(frame.payload[4..8].try_into().unwrap());
+        // PROTOCOL_ERROR
+        assert_eq!(error_code, 0x1);
+
+        let outcome = serve_fut.await??;
+        assert!(matches!(
+            outcome,
+            h2::ServeOutcome::ConnectionError(h2::H2ConnectionError::PaddedFrameTooShort { .. })
+        ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_prioritization() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    helpers::run(async move {
+        struct TestDriver {
+            big_body_queued: Rc<Notify>,
+        }
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                req: Request,
+                _req_body: &mut impl Body,
+                respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                let mut respond = respond
+                    .write_final_response(Response {
+                        status: StatusCode::OK,
+                        ..Default::default()
+                    })
+                    .await?;
+                if req.uri.path() == "/big" {
+                    respond.write_chunk(vec![b'b'; 48 * 1024].into()).await?;
+                    self.big_body_queued.notify_one();
+                } else {
+                    // by the time this is written, the big body is waiting too
+                    self.big_body_queued.notified().await;
+                    respond.write_chunk("small".into()).await?;
+                }
+                respond.finish_body(None).await
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let driver = TestDriver {
+            big_body_queued: Default::default(),
+        };
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            transport,
+            Default::default(),
+            RollMut::alloc()?,
+            Rc::new(driver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+
+        let frame = conn.read_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::SETTINGS);
+        // SETTINGS_NO_RFC7540_PRIORITIES
+        assert!(frame
+            .payload
+            .chunks(6)
+            .any(|s| s == [0x0, 0x9, 0x0, 0x0, 0x0, 0x1]));
+
+        // the big response is made less urgent before its stream opens...
+        let mut payload = vec![0x0, 0x0, 0x0, 0x1];
+        payload.extend_from_slice(b"u=6");
+        conn.send_frame(frame_type::PRIORITY_UPDATE, 0, 0, payload)
+            .await?;
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"GET"),
+            (b":scheme", b"http"),
+            (b":path", b"/big"),
+        ];
+        conn.send_headers(1, headers, true).await?;
+
+        // ...while the small one asks to be more urgent than the default
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"GET"),
+            (b":scheme", b"http"),
+            (b":path", b"/small"),
+            (b"priority", b"u=1"),
+        ];
+        conn.send_headers(3, headers, true).await?;
+
+        let mut ended = vec![];
+        let mut big_len = 0;
+        while ended.len() < 2 {
+            let frame = conn.read_significant_frame().await?.unwrap();
+            if frame.frame_type != frame_type::DATA {
+                continue;
+            }
+            if frame.stream_id == 1 {
+                assert!(frame.payload.len() <= 16_384);
+                big_len += frame.payload.len();
+            }
+            if frame.flags & flags::END_STREAM != 0 {
+                ended.push(frame.stream_id);
+            }
+        }
+        assert_eq!(ended, [3, 1]);
+        assert_eq!(big_len, 48 * 1024);
+
+        // PRIORITY_UPDATE can't be about the connection itself
+        conn.send_frame(frame_type::PRIORITY_UPDATE, 0, 0, vec![0x0; 4])
+            .await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::GOAWAY);
+        let error_code = u32::from_be_bytes(frame.payload[4..8].try_into().unwrap());
+        // PROTOCOL_ERROR
+        assert_eq!(error_code, 0x1);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_max_streams() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                _req: Request,
+                req_body: &mut impl Body,
+                respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                while let BodyChunk::Chunk(_) = req_body.next_chunk().await? {}
+
+                respond
+                    .write_final_response(Response {
+                        status: StatusCode::OK,
+                        ..Default::default()
+                    })
+                    .await?
+                    .finish_body(None)
+                    .await
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let conf = Rc::new(h2::ServerConf {
+            max_streams: 1,
+            ..Default::default()
+        });
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            transport,
+            conf,
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+
+        let frame = conn.read_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::SETTINGS);
+        // SETTINGS_MAX_CONCURRENT_STREAMS
+        assert!(frame
+            .payload
+            .chunks(6)
+            .any(|s| s == [0x0, 0x3, 0x0, 0x0, 0x0, 0x1]));
+
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"POST"),
+            (b":scheme", b"http"),
+            (b":authority", b"localhost"),
+            (b":path", b"/"),
+        ];
+
+        // this one stays open until we're done sending the body
+        conn.send_headers(1, headers, false).await?;
+        // ...so this one is over the limit
+        conn.send_headers(3, headers, true).await?;
+
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+        assert_eq!(frame.stream_id, 3);
+        // REFUSED_STREAM
+        assert_eq!(frame.payload, [0x0, 0x0, 0x0, 0x7]);
+
+        // the first stream is unaffected
+        conn.send_frame(frame_type::DATA, flags::END_STREAM, 1, b"hi".to_vec())
+            .await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::HEADERS);
+        assert_eq!(frame.stream_id, 1);
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::DATA);
+        assert_eq!(frame.stream_id, 1);
+        assert_ne!(frame.flags & flags::END_STREAM, 0);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_rst_stream() {
+    use helpers::h2::{frame_type, H2Conn};
+    use std::cell::Cell;
+
+    helpers::run(async move {
+        struct DropGuard(Rc<Cell<bool>>);
+
+        impl Drop for DropGuard {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        struct TestDriver {
+            cancelled: Rc<Cell<bool>>,
+        }
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                req: Request,
+                req_body: &mut impl Body,
+                respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                if req.uri.path() == "/reset" {
+                    return respond
+                        .reset_stream(h2::KnownErrorCode::EnhanceYourCalm)
+                        .await;
+                }
+
+                // the client never finishes this body, it resets the stream
+                let _guard = DropGuard(self.cancelled.clone());
+                while let BodyChunk::Chunk(_) = req_body.next_chunk().await? {}
+                unreachable!("the request body should never complete")
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let conf = Rc::new(h2::ServerConf::default());
+        let cancelled: Rc<Cell<bool>> = Default::default();
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            transport,
+            conf,
+            RollMut::alloc()?,
+            Rc::new(TestDriver {
+                cancelled: cancelled.clone(),
+            }),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+
+        let headers = |path: &'static [u8]| -> [(&[u8], &[u8]); 4] {
+            [
+                (b":method", b"POST"),
+                (b":scheme", b"http"),
+                (b":authority", b"localhost"),
+                (b":path", path),
+            ]
+        };
+
+        // server-initiated reset
+        conn.send_headers(1, &headers(b"/reset"), true).await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+        assert_eq!(frame.stream_id, 1);
+        // ENHANCE_YOUR_CALM
+        assert_eq!(frame.payload, [0x0, 0x0, 0x0, 0xb]);
+
+        // client-initiated reset
+        conn.send_headers(3, &headers(b"/cancel-me"), false).await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!cancelled.get());
+
+        // CANCEL
+        conn.send_frame(frame_type::RST_STREAM, 0, 3, vec![0x0, 0x0, 0x0, 0x8])
+            .await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(cancelled.get(), "handler should have been cancelled");
+
+        // the connection is still usable
+        conn.send_headers(5, &headers(b"/reset"), true).await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+        assert_eq!(frame.stream_id, 5);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_reset_streams_give_back_capacity() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    const BODY_LEN: usize = 64 * 1024;
+
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                _req: Request,
+                _req_body: &mut impl Body,
+                respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                let mut respond = respond
+                    .write_final_response(Response {
+                        status: StatusCode::OK,
+                        ..Default::default()
+                    })
+                    .await?;
+                respond.write_chunk(vec![b'a'; BODY_LEN].into()).await?;
+                respond.finish_body(None).await
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            ReadWritePair(read, write),
+            Rc::new(h2::ServerConf::default()),
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        // SETTINGS_INITIAL_WINDOW_SIZE: only the connection window limits
+        // what the server sends, and it's exactly one body's worth.
+        conn.handshake(&[(0x4, 1 << 20)]).await?;
+        conn.send_window_update(0, (BODY_LEN - 65535) as u32)
+            .await?;
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"GET"),
+            (b":scheme", b"http"),
+            (b":authority", b"localhost"),
+            (b":path", b"/"),
+        ];
+
+        // we don't read anything yet, so DATA piles up on the server's side,
+        // then we cancel those downloads
+        let cancelled = [1, 3, 5];
+        for stream_id in cancelled {
+            conn.send_headers(stream_id, headers, true).await?;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        for stream_id in cancelled {
+            // CANCEL
+            conn.send_frame(
+                frame_type::RST_STREAM,
+                0,
+                stream_id,
+                vec![0x0, 0x0, 0x0, 0x8],
+            )
+            .await?;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // whatever DATA made it out counts against the window, like it would
+        // for any client
+        let mut received = 0;
+        while let Ok(frame) =
+            tokio::time::timeout(Duration::from_millis(100), conn.read_frame()).await
+        {
+            let frame = frame?.unwrap();
+            if frame.frame_type == frame_type::DATA {
+                received += frame.payload.len();
+            }
+        }
+        assert!(received < 3 * BODY_LEN);
+        if received > 0 {
+            conn.send_window_update(0, received as u32).await?;
+        }
+
+        // what the cancelled streams didn't send is available again
+        conn.send_headers(7, headers, true).await?;
+        let mut body_len = 0;
+        loop {
+            let frame = tokio::time::timeout(Duration::from_secs(1), conn.read_significant_frame())
+                .await
+                .map_err(|_| eyre::eyre!("stalled after {body_len} bytes"))??
+                .unwrap();
+            assert_eq!(frame.stream_id, 7);
+            if frame.frame_type == frame_type::DATA {
+                body_len += frame.payload.len();
+                if frame.flags & flags::END_STREAM != 0 {
+                    break;
+                }
+            }
+        }
+        assert_eq!(body_len, BODY_LEN);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_graceful_shutdown() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                _req: Request,
+                req_body: &mut impl Body,
+                respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                while let BodyChunk::Chunk(_) = req_body.next_chunk().await? {}
+
+                respond
+                    .write_final_response(Response {
+                        status: StatusCode::OK,
+                        ..Default::default()
+                    })
+                    .await?
+                    .finish_body(None)
+                    .await
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let conf = Rc::new(h2::ServerConf::default());
+        let shutdown = h2::GracefulShutdown::new();
+        let serve_fut = tokio_uring::spawn(h2::serve_with_shutdown(
+            transport,
+            conf,
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+            shutdown.clone(),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"POST"),
+            (b":scheme", b"http"),
+            (b":authority", b"localhost"),
+            (b":path", b"/"),
+        ];
+
+        // in flight when the shutdown starts
+        conn.send_headers(1, headers, false).await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.start();
+
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::GOAWAY);
+        // last stream id 1, NO_ERROR
+        assert_eq!(frame.payload, [0x0, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0x0]);
+
+        // too late for new streams
+        conn.send_headers(3, headers, true).await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+        assert_eq!(frame.stream_id, 3);
+        // REFUSED_STREAM
+        assert_eq!(frame.payload, [0x0, 0x0, 0x0, 0x7]);
+
+        // ...but the one in flight completes
+        conn.send_frame(frame_type::DATA, flags::END_STREAM, 1, b"hi".to_vec())
+            .await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::HEADERS);
+        assert_eq!(frame.stream_id, 1);
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::DATA);
+        assert_ne!(frame.flags & flags::END_STREAM, 0);
+
+        // and then the connection closes
+        assert!(conn.read_significant_frame().await?.is_none());
+        serve_fut.await??;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_peer_goaway() {
+    use helpers::h2::{frame_type, H2Conn};
+
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                _req: Request,
+                req_body: &mut impl Body,
+                _respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                // the client never finishes this body
+                while let BodyChunk::Chunk(_) = req_body.next_chunk().await? {}
+                unreachable!("the request body should never complete")
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let conf = Rc::new(h2::ServerConf {
+            shutdown_grace_period: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let serve_fut = tokio_uring::spawn(h2::serve(
+            transport,
+            conf,
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"POST"),
+            (b":scheme", b"http"),
+            (b":authority", b"localhost"),
+            (b":path", b"/"),
+        ];
+        conn.send_headers(1, headers, false).await?;
+
+        // last stream id 0, NO_ERROR
+        conn.send_frame(frame_type::GOAWAY, 0, 0, vec![0x0; 8])
+            .await?;
+
+        // the stream doesn't finish within the grace period, so the server
+        // gives up on it and closes the connection
+        let before = std::time::Instant::now();
+        assert!(conn.read_significant_frame().await?.is_none());
+        assert!(before.elapsed() >= Duration::from_millis(80));
+        serve_fut.await??;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_trailers() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                _req: Request,
+                req_body: &mut impl Body,
+                respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                let req_trailers = loop {
+                    match req_body.next_chunk().await? {
+                        BodyChunk::Chunk(_) => {}
+                        BodyChunk::Done { trailers } => break trailers,
+                    }
+                };
+                let req_trailers = req_trailers.expect("request should have trailers");
+                assert_eq!(&req_trailers.get("x-checksum").unwrap()[..], b"abc");
+
+                let mut respond = respond
+                    .write_final_response(Response {
+                        status: StatusCode::OK,
+                        ..Default::default()
+                    })
+                    .await?;
+                respond.write_chunk("ok".into()).await?;
+
+                let mut trailers = Headers::default();
+                trailers.insert("grpc-status", "0".into());
+                respond.finish_body(Some(Box::new(trailers))).await
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let conf = Rc::new(h2::ServerConf::default());
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            transport,
+            conf,
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"POST"),
+            (b":scheme", b"http"),
+            (b":authority", b"localhost"),
+            (b":path", b"/"),
+        ];
+        conn.send_headers(1, headers, false).await?;
+        conn.send_frame(frame_type::DATA, 0, 1, b"hi".to_vec())
+            .await?;
+        conn.send_headers(1, &[(b"x-checksum", b"abc")], true)
+            .await?;
+
+        let mut hpack_dec = hring_hpack::Decoder::new();
+
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::HEADERS);
+        assert_eq!(frame.flags & flags::END_STREAM, 0);
+        hpack_dec.decode(&frame.payload).unwrap();
+
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::DATA);
+        assert_eq!(frame.flags & flags::END_STREAM, 0);
+        assert_eq!(frame.payload, b"ok");
+
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::HEADERS);
+        assert_eq!(frame.stream_id, 1);
+        assert_ne!(frame.flags & flags::END_STREAM, 0);
+        let trailers = hpack_dec.decode(&frame.payload).unwrap();
+        assert_eq!(trailers, [(b"grpc-status".to_vec(), b"0".to_vec())]);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_extended_connect() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                req: Request,
+                req_body: &mut impl Body,
+                respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                let mut headers = Headers::default();
+                headers.insert("x-target", req.uri.to_string().into_bytes().into());
+                if let Some(protocol) = &req.protocol {
+                    headers.insert("x-protocol", protocol.as_bytes().to_vec().into());
+                }
+
+                // the tunnel is up as soon as the 2xx goes out, then whatever
+                // comes in is echoed back.
+                let mut respond = respond
+                    .write_final_response(Response {
+                        status: StatusCode::OK,
+                        headers,
+                        ..Default::default()
+                    })
+                    .await?;
+                while let BodyChunk::Chunk(chunk) = req_body.next_chunk().await? {
+                    respond.write_chunk(chunk).await?;
+                }
+                respond.finish_body(None).await
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            transport,
+            Default::default(),
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+        let mut hpack_dec = hring_hpack::Decoder::new();
+
+        let websocket: &[(&[u8], &[u8])] = &[
+            (b":method", b"CONNECT"),
+            (b":protocol", b"websocket"),
+            (b":scheme", b"https"),
+            (b":path", b"/chat"),
+            (b":authority", b"localhost"),
+        ];
+        let plain: &[(&[u8], &[u8])] = &[
+            (b":method", b"CONNECT"),
+            (b":authority", b"example.org:443"),
+        ];
+        for (stream_id, headers, target, protocol) in [
+            (
+                1,
+                websocket,
+                &b"https://localhost/chat"[..],
+                Some(&b"websocket"[..]),
+            ),
+            (3, plain, &b"example.org:443"[..], None),
+        ] {
+            conn.send_headers(stream_id, headers, false).await?;
+
+            let frame = conn.read_significant_frame().await?.unwrap();
+            assert_eq!(frame.frame_type, frame_type::HEADERS);
+            assert_eq!(frame.stream_id, stream_id);
+            assert_eq!(frame.flags & flags::END_STREAM, 0);
+            let res_headers = hpack_dec.decode(&frame.payload).unwrap();
+            let get = |name: &[u8]| {
+                res_headers
+                    .iter()
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| &v[..])
+            };
+            assert_eq!(get(b":status"), Some(&b"200"[..]));
+            assert_eq!(get(b"x-target"), Some(target));
+            assert_eq!(get(b"x-protocol"), protocol);
+
+            // bytes flow both ways before either side ends the stream
+            conn.send_frame(frame_type::DATA, 0, stream_id, b"hello".to_vec())
+                .await?;
+            let frame = conn.read_significant_frame().await?.unwrap();
+            assert_eq!(frame.frame_type, frame_type::DATA);
+            assert_eq!(frame.flags & flags::END_STREAM, 0);
+            assert_eq!(frame.payload, b"hello");
+
+            conn.send_frame(
+                frame_type::DATA,
+                flags::END_STREAM,
+                stream_id,
+                b"bye".to_vec(),
+            )
+            .await?;
+            let mut body = Vec::new();
+            loop {
+                let frame = conn.read_significant_frame().await?.unwrap();
+                assert_eq!(frame.frame_type, frame_type::DATA);
+                assert_eq!(frame.stream_id, stream_id);
+                body.extend_from_slice(&frame.payload);
+                if frame.flags & flags::END_STREAM != 0 {
+                    break;
+                }
+            }
+            assert_eq!(body, b"bye");
+        }
+
+        // a plain CONNECT has no :scheme or :path, and :protocol only goes
+        // with CONNECT
+        let bad: [&[(&[u8], &[u8])]; 3] = [
+            &[
+                (b":method", b"CONNECT"),
+                (b":scheme", b"https"),
+                (b":path", b"/"),
+                (b":authority", b"example.org:443"),
+            ],
+            &[(b":method", b"CONNECT")],
+            &[
+                (b":method", b"GET"),
+                (b":protocol", b"websocket"),
+                (b":scheme", b"https"),
+                (b":path", b"/chat"),
+            ],
+        ];
+        for (stream_id, headers) in [5, 7, 9].into_iter().zip(bad) {
+            conn.send_headers(stream_id, headers, true).await?;
+            let frame = conn.read_significant_frame().await?.unwrap();
+            assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+            assert_eq!(frame.stream_id, stream_id);
+            // PROTOCOL_ERROR
+            assert_eq!(frame.payload, [0x0, 0x0, 0x0, 0x1]);
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_client() {
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                req: Request,
+                req_body: &mut impl Body,
+                respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                let mut headers = Headers::default();
+                headers.insert("x-path", req.uri.path().to_string().into_bytes().into());
+                let res = Response {
+                    status: StatusCode::OK,
+                    headers,
+                    ..Default::default()
+                };
+                respond.write_final_response_with_body(res, req_body).await
+            }
+        }
+
+        struct ClientDriver;
+
+        impl hring::ClientDriver for ClientDriver {
+            type Return = (Response, usize);
+
+            async fn on_informational_response(&mut self, _res: Response) -> eyre::Result<()> {
+                Ok(())
+            }
+
+            async fn on_final_response(
+                self,
+                res: Response,
+                body: &mut impl Body,
+            ) -> eyre::Result<Self::Return> {
+                let mut len = 0;
+                while let BodyChunk::Chunk(chunk) = body.next_chunk().await? {
+                    len += chunk.len();
+                }
+                Ok((res, len))
+            }
+        }
+
+        let ln = tokio_uring::net::TcpListener::bind("127.0.0.1:0".parse()?)?;
+        let ln_addr = ln.local_addr()?;
+        let _server_fut = tokio_uring::spawn(async move {
+            let (transport, _) = ln.accept().await?;
+            let conf = Rc::new(h2::ServerConf::default());
+            h2::serve(transport, conf, RollMut::alloc()?, Rc::new(TestDriver)).await
+        });
+
+        let transport = tokio_uring::net::TcpStream::connect(ln_addr).await?;
+        let conn = h2::Connection::handshake(transport, Default::default()).await?;
+
+        let request = |path: &'static str| {
+            let conn = conn.clone();
+            async move {
+                let req = Request {
+                    method: Method::Post,
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                };
+                let mut body = SampleBody::default();
+                h2::request(&conn, req, &mut body, ClientDriver).await
+            }
+        };
+
+        // bodies are larger than the initial window, so streams have to
+        // wait for the peer to release capacity while sharing the connection.
+        let (a, b, c) = tokio::try_join!(request("/a"), request("/b"), request("/c"))?;
+        let expected_len = b"this is a big chunk".len() * 256 * 128;
+        for ((res, len), path) in [(a, "/a"), (b, "/b"), (c, "/c")] {
+            assert_eq!(res.status, StatusCode::OK);
+            assert_eq!(&res.headers.get("x-path").unwrap()[..], path.as_bytes());
+            assert_eq!(len, expected_len);
+        }
+
+        let req = Request {
+            method: Method::Get,
+            uri: "/empty".parse().unwrap(),
+            ..Default::default()
+        };
+        #[allow(clippy::let_unit_value)]
+        let mut body = ();
+        let (res, len) = h2::request(&conn, req, &mut body, ClientDriver).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(len, 0);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_client_connection_error() {
+    use helpers::h2::{frame_type, H2Conn};
+
+    helpers::run(async move {
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let handshake =
+            tokio_uring::spawn(h2::Connection::handshake(transport, Default::default()));
+
+        let mut server = H2Conn::new(tx, rx);
+        server.accept(&[]).await?;
+        let conn = handshake.await??;
+
+        // the client's SETTINGS_MAX_FRAME_SIZE is the default 16384
+        server
+            .send_frame(frame_type::PING, 0, 0, vec![0u8; 16385])
+            .await?;
+
+        let frame = server.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::GOAWAY);
+        assert_eq!(frame.stream_id, 0);
+        // push is disabled, so the server opened no stream the client could
+        // have processed
+        assert_eq!(frame.payload[..4], [0, 0, 0, 0]);
+        // FRAME_SIZE_ERROR
+        assert_eq!(frame.payload[4..8], [0, 0, 0, 6]);
+
+        // and then the client closes the connection
+        assert!(server.read_frame().await?.is_none());
+        drop(conn);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_client_early_response() {
+    use helpers::h2::{frame_type, H2Conn};
+
+    helpers::run(async move {
+        struct ClientDriver;
+
+        impl hring::ClientDriver for ClientDriver {
+            type Return = Response;
+
+            async fn on_informational_response(&mut self, _res: Response) -> eyre::Result<()> {
+                Ok(())
+            }
+
+            async fn on_final_response(
+                self,
+                res: Response,
+                body: &mut impl Body,
+            ) -> eyre::Result<Self::Return> {
+                while let BodyChunk::Chunk(_) = body.next_chunk().await? {}
+                Ok(res)
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let handshake =
+            tokio_uring::spawn(h2::Connection::handshake(transport, Default::default()));
+
+        let mut server = H2Conn::new(tx, rx);
+        server.accept(&[]).await?;
+        let conn = handshake.await??;
+
+        // the body is much larger than the initial window, which the server
+        // never grows
+        let request = tokio_uring::spawn({
+            let conn = conn.clone();
+            async move {
+                let req = Request {
+                    method: Method::Post,
+                    uri: "/upload".parse().unwrap(),
+                    ..Default::default()
+                };
+                let mut body = SampleBody::default();
+                h2::request(&conn, req, &mut body, ClientDriver).await
+            }
+        });
+
+        let frame = server.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::HEADERS);
+        assert_eq!(frame.stream_id, 1);
+
+        let mut received = 0;
+        while received < 65535 {
+            let frame = server.read_significant_frame().await?.unwrap();
+            assert_eq!(frame.frame_type, frame_type::DATA);
+            received += frame.payload.len();
+        }
+        assert_eq!(received, 65535);
+
+        // answer without reading the rest
+        server
+            .send_headers(1, &[(b":status", b"413")], true)
+            .await?;
+
+        let res = tokio::time::timeout(Duration::from_secs(1), request).await???;
+        assert_eq!(res.status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // the client stops sending the body, without error, cf.
+        // https://httpwg.org/specs/rfc9113.html#HttpFraming
+        let frame = server.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+        assert_eq!(frame.stream_id, 1);
+        assert_eq!(frame.payload, [0, 0, 0, 0]);
+        drop(conn);
+
+        Ok(())
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+enum H2cMode {
+    PriorKnowledge,
+    Upgrade,
+}
+
+#[test]
+fn h2c_prior_knowledge() {
+    h2c(H2cMode::PriorKnowledge)
+}
+
+#[test]
+fn h2c_upgrade() {
+    h2c(H2cMode::Upgrade)
+}
+
+fn h2c(mode: H2cMode) {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                req: Request,
+                _req_body: &mut impl Body,
+                respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                // connection-specific headers don't survive the upgrade
+                assert!(!req.headers.contains_key(header::UPGRADE));
+                assert!(!req.headers.contains_key("http2-settings"));
+
+                let mut respond = respond
+                    .write_final_response(Response {
+                        status: StatusCode::OK,
+                        ..Default::default()
+                    })
+                    .await?;
+                let body = format!("{} {:?}", req.uri.path(), req.version);
+                respond.write_chunk(body.into_bytes().into()).await?;
+                respond.finish_body(None).await
+            }
+        }
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let serve_fut = tokio_uring::spawn(hring::h2c::serve(
+            transport,
+            Rc::new(h1::ServerConf::default()),
+            Rc::new(h2::ServerConf::default()),
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        if let H2cMode::Upgrade = mode {
+            // SETTINGS_MAX_CONCURRENT_STREAMS = 100, SETTINGS_INITIAL_WINDOW_SIZE = 65535
+            tx.send(
+                "GET /upgrade HTTP/1.1\r\n\
+                host: localhost\r\n\
+                connection: Upgrade, HTTP2-Settings\r\n\
+                upgrade: h2c\r\n\
+                http2-settings: AAMAAABkAAQAAP__\r\n\
+                \r\n",
+            )
+            .await?;
+        }
+
+        let mut conn = H2Conn::new(tx, rx);
+        let mut next_stream_id = 1;
+        if let H2cMode::Upgrade = mode {
+            let head = conn.read_h1_head().await?;
+            assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+            next_stream_id = 3;
+        }
+        conn.handshake(&[]).await?;
+
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"GET"),
+            (b":scheme", b"http"),
+            (b":authority", b"localhost"),
+            (b":path", b"/h2"),
+        ];
+        conn.send_headers(next_stream_id, headers, true).await?;
+
+        let mut expected = vec![(next_stream_id, "/h2 HTTP/2.0")];
+        if let H2cMode::Upgrade = mode {
+            // the upgrade request is answered as stream 1
+            expected.insert(0, (1, "/upgrade HTTP/2.0"));
+        }
+
+        // streams may be interleaved, but each stream's frames come in order
+        let mut frames: HashMap<u32, Vec<_>> = HashMap::new();
+        for _ in 0..expected.len() * 3 {
+            let frame = conn.read_significant_frame().await?.unwrap();
+            frames.entry(frame.stream_id).or_default().push(frame);
+        }
+        for (stream_id, body) in expected {
+            let frames = &frames[&stream_id];
+            assert_eq!(frames.len(), 3);
+            assert_eq!(frames[0].frame_type, frame_type::HEADERS);
+
+            assert_eq!(frames[1].frame_type, frame_type::DATA);
+            assert_eq!(frames[1].payload, body.as_bytes());
+
+            assert_eq!(frames[2].frame_type, frame_type::DATA);
+            assert_ne!(frames[2].flags & flags::END_STREAM, 0);
+        }
+
+        drop(conn);
+        let outcome = serve_fut.await??;
+        use hring::{h2::ServeOutcome as H2Outcome, h2c::ServeOutcome};
+        match mode {
+            H2cMode::PriorKnowledge => assert!(matches!(
+                outcome,
+                ServeOutcome::Http2PriorKnowledge(H2Outcome::ClientClosedConnection)
+            )),
+            H2cMode::Upgrade => assert!(matches!(
+                outcome,
+                ServeOutcome::Http2Upgraded(H2Outcome::ClientClosedConnection)
+            )),
+        }
+
+        Ok(())
+    })
+}
+
+/// Accepts WebSockets and echoes data messages back, binary ones in two
+/// fragments.
+struct WebSocketEcho;
+
+impl ServerDriver for WebSocketEcho {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        use hring::ws::{Message, MessageKind, Upgrade};
+
+        let Some(upgrade) = Upgrade::from_request(&req) else {
+            // cf. https://www.rfc-editor.org/rfc/rfc6455#section-4.4
+            let mut headers = Headers::default();
+            headers.insert(header::SEC_WEBSOCKET_VERSION, "13".into());
+            headers.insert(header::CONTENT_LENGTH, "0".into());
+            let res = Response {
+                status: StatusCode::UPGRADE_REQUIRED,
+                headers,
+                ..Default::default()
+            };
+            return respond.write_final_response(res).await?.finish_body(None).await;
+        };
+
+        let mut ws = upgrade
+            .accept(Default::default(), req_body, respond, Default::default())
+            .await?;
+        while let Some(msg) = ws.receive().await? {
+            match msg {
+                Message::Text(_) => ws.send(msg).await?,
+                Message::Binary(data) => {
+                    let half = data.len() / 2;
+                    let (a, b) = data.split_at(half);
+                    ws.send_fragment(MessageKind::Binary, a, false).await?;
+                    ws.send_fragment(MessageKind::Binary, b, true).await?;
+                }
+                _ => {}
+            }
+        }
+        ws.finish().await
+    }
+}
+
+#[test]
+fn ws_h1_echo() {
+    use helpers::ws::{encode_frame, opcode, WsConn};
+
+    helpers::run(async move {
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let serve_fut = tokio_uring::spawn(h1::serve(
+            transport,
+            Rc::new(h1::ServerConf::default()),
+            RollMut::alloc()?,
+            WebSocketEcho,
+        ));
+
+        let mut conn = WsConn::new(tx, rx);
+
+        // without `sec-websocket-version: 13`, it's not a websocket handshake
+        conn.send(
+            "GET /chat HTTP/1.1\r\n\
+            host: localhost\r\n\
+            upgrade: websocket\r\n\
+            connection: Upgrade\r\n\
+            sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            \r\n",
+        )
+        .await?;
+        let head = conn.read_h1_head().await?;
+        assert!(head.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+
+        conn.send(
+            "GET /chat HTTP/1.1\r\n\
+            host: localhost\r\n\
+            upgrade: websocket\r\n\
+            connection: keep-alive, Upgrade\r\n\
+            sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            sec-websocket-version: 13\r\n\
+            \r\n",
+        )
+        .await?;
+        let head = conn.read_h1_head().await?.to_lowercase();
+        assert!(head.starts_with("http/1.1 101 switching protocols\r\n"));
+        assert!(head.contains("\r\nsec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo=\r\n"));
+        assert!(head.contains("\r\nupgrade: websocket\r\n"));
+        assert!(!head.contains("transfer-encoding"));
+
+        conn.send(encode_frame(true, opcode::TEXT, b"hello"))
+            .await?;
+        let frame = conn.read_frame().await?.unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, opcode::TEXT);
+        assert_eq!(frame.payload, b"hello");
+
+        // a fragmented message, with a ping in the middle
+        conn.send(encode_frame(false, opcode::TEXT, "héllo, ".as_bytes()))
+            .await?;
+        conn.send(encode_frame(true, opcode::PING, b"still there?"))
+            .await?;
+        let frame = conn.read_frame().await?.unwrap();
+        assert_eq!(frame.opcode, opcode::PONG);
+        assert_eq!(frame.payload, b"still there?");
+
+        // a multi-byte character split across fragments is fine
+        let world = "wörld".as_bytes();
+        conn.send(encode_frame(false, opcode::CONTINUATION, &world[..2]))
+            .await?;
+        conn.send(encode_frame(true, opcode::CONTINUATION, &world[2..]))
+            .await?;
+        let frame = conn.read_frame().await?.unwrap();
+        assert_eq!(frame.opcode, opcode::TEXT);
+        assert_eq!(frame.payload, "héllo, wörld".as_bytes());
+
+        // large enough for a 16-bit length
+        let data = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+        conn.send(encode_frame(true, opcode::BINARY, &data)).await?;
+        let frame = conn.read_frame().await?.unwrap();
+        assert!(!frame.fin);
+        assert_eq!(frame.opcode, opcode::BINARY);
+        let mut echoed = frame.payload;
+        let frame = conn.read_frame().await?.unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, opcode::CONTINUATION);
+        echoed.extend(frame.payload);
+        assert_eq!(echoed, data);
+
+        // the server echoes the close code, then closes the connection
+        let mut close = 1000u16.to_be_bytes().to_vec();
+        close.extend_from_slice(b"bye");
+        conn.send(encode_frame(true, opcode::CLOSE, &close)).await?;
+        let frame = conn.read_frame().await?.unwrap();
+        assert_eq!(frame.opcode, opcode::CLOSE);
+        assert_eq!(frame.payload, 1000u16.to_be_bytes());
+
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut).await???;
+        assert_eq!(outcome, h1::ServeOutcome::Tunneled);
+        assert!(conn.read_frame().await?.is_none());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn ws_protocol_errors() {
+    use helpers::ws::{encode_frame, encode_frame_with_mask, opcode, WsConn};
+
+    helpers::run(async move {
+        let cases = [
+            // clients must mask frames
+            (
+                encode_frame_with_mask(true, opcode::TEXT, b"hi", None),
+                1002,
+            ),
+            // text must be UTF-8
+            (encode_frame(true, opcode::TEXT, &[0xc3, 0x28]), 1007),
+            // control frames are small and can't be fragmented
+            (encode_frame(true, opcode::PING, &[0; 126]), 1002),
+            (encode_frame(false, opcode::PING, b""), 1002),
+            // nothing to continue
+            (encode_frame(true, opcode::CONTINUATION, b"hi"), 1002),
+            // unknown opcode, reserved bit
+            (encode_frame(true, 0x3, b""), 1002),
+            (encode_frame(true, 0x40 | opcode::TEXT, b"hi"), 1002),
+            // invalid close codes
+            (encode_frame(true, opcode::CLOSE, &[0x3]), 1002),
+            (
+                encode_frame(true, opcode::CLOSE, &1005u16.to_be_bytes()),
+                1002,
+            ),
+        ];
+
+        for (frame, code) in cases {
+            let (tx, read) = ChanRead::new();
+            let (rx, write) = ChanWrite::new();
+            let transport = ReadWritePair(read, write);
+            let serve_fut = tokio_uring::spawn(h1::serve(
+                transport,
+                Rc::new(h1::ServerConf::default()),
+                RollMut::alloc()?,
+                WebSocketEcho,
+            ));
+
+            let mut conn = WsConn::new(tx, rx);
+            conn.send(
+                "GET / HTTP/1.1\r\n\
+                upgrade: websocket\r\n\
+                connection: upgrade\r\n\
+                sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                sec-websocket-version: 13\r\n\
+                \r\n",
+            )
+            .await?;
+            let head = conn.read_h1_head().await?;
+            assert!(head.starts_with("HTTP/1.1 101 "));
+
+            conn.send(frame).await?;
+            let frame = conn.read_frame().await?.unwrap();
+            assert_eq!(frame.opcode, opcode::CLOSE);
+            assert_eq!(frame.payload[..2], (code as u16).to_be_bytes());
+
+            // the handler fails, which closes the connection
+            let res = tokio::time::timeout(Duration::from_secs(5), serve_fut).await??;
+            assert!(res.is_err());
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn ws_h2_echo() {
+    use helpers::{
+        h2::{flags, frame_type, H2Conn},
+        ws::{decode_frame, encode_frame, opcode},
+    };
+
+    helpers::run(async move {
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            transport,
+            Default::default(),
+            RollMut::alloc()?,
+            Rc::new(WebSocketEcho),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"CONNECT"),
+            (b":protocol", b"websocket"),
+            (b":scheme", b"https"),
+            (b":path", b"/chat"),
+            (b":authority", b"localhost"),
+            (b"sec-websocket-version", b"13"),
+        ];
+        conn.send_headers(1, headers, false).await?;
+
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::HEADERS);
+        assert_eq!(frame.flags & flags::END_STREAM, 0);
+        let res_headers = hring_hpack::Decoder::new().decode(&frame.payload).unwrap();
+        assert_eq!(res_headers[0], (b":status".to_vec(), b"200".to_vec()));
+        // no HTTP/1.1 handshake headers over HTTP/2
+        assert!(!res_headers
+            .iter()
+            .any(|(k, _)| k == b"sec-websocket-accept"));
+
+        // websocket frames can straddle DATA frames
+        let hello = encode_frame(true, opcode::TEXT, b"hello");
+        let (a, b) = hello.split_at(3);
+        conn.send_frame(frame_type::DATA, 0, 1, a.to_vec()).await?;
+        conn.send_frame(frame_type::DATA, 0, 1, b.to_vec()).await?;
+
+        let mut close = 1001u16.to_be_bytes().to_vec();
+        close.extend_from_slice(b"going away");
+        conn.send_frame(
+            frame_type::DATA,
+            0,
+            1,
+            encode_frame(true, opcode::CLOSE, &close),
+        )
+        .await?;
+
+        // the echo, the close frame, then the end of the stream
+        let mut buf = Vec::new();
+        loop {
+            let frame = conn.read_significant_frame().await?.unwrap();
+            assert_eq!(frame.frame_type, frame_type::DATA);
+            assert_eq!(frame.stream_id, 1);
+            buf.extend_from_slice(&frame.payload);
+            if frame.flags & flags::END_STREAM != 0 {
+                break;
+            }
+        }
+        let frame = decode_frame(&mut buf).unwrap();
+        assert_eq!(frame.opcode, opcode::TEXT);
+        assert_eq!(frame.payload, b"hello");
+        let frame = decode_frame(&mut buf).unwrap();
+        assert_eq!(frame.opcode, opcode::CLOSE);
+        assert_eq!(frame.payload, 1001u16.to_be_bytes());
+        assert!(buf.is_empty());
+
+        Ok(())
+    })
+}
+
+/// Switches to a protocol that answers every chunk with its uppercase version
+struct ShoutUpgrade;
+
+impl ServerDriver for ShoutUpgrade {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        use hring_buffet::WriteOwned;
+
+        if req.headers.get(header::UPGRADE).map(|v| &v[..]) != Some(&b"shout"[..]) {
+            let res = Response {
+                status: StatusCode::NO_CONTENT,
+                ..Default::default()
+            };
+            return respond.write_final_response_with_body(res, req_body).await;
+        }
+
+        let mut headers = Headers::default();
+        headers.insert(header::UPGRADE, "shout".into());
+        headers.insert(header::CONNECTION, "Upgrade".into());
+        let res = Response {
+            status: StatusCode::SWITCHING_PROTOCOLS,
+            headers,
+            ..Default::default()
+        };
+        let (respond, upgraded) = respond.write_upgrade_response(res).await?;
+        let transport = upgraded.transport;
+
+        let mut buf = upgraded.client_buf;
+        loop {
+            if buf.is_empty() {
+                buf.reserve()?;
+                let res;
+                (res, buf) = buf.read_into(usize::MAX, transport.as_ref()).await;
+                if res? == 0 {
+                    break;
+                }
+            }
+            let chunk = buf.take_all();
+            let (res, _) = transport.write_all(chunk.to_ascii_uppercase()).await;
+            res?;
+        }
+
+        Ok(respond)
+    }
+}
+
+/// Reads from the server until `needle` was received, returns everything up
+/// to and including it
+async fn read_until(
+    rx: &mut tokio::sync::mpsc::Receiver<Vec<u8>>,
+    received: &mut Vec<u8>,
+    needle: &[u8],
+) -> eyre::Result<Vec<u8>> {
+    loop {
+        if let Some(pos) = received.windows(needle.len()).position(|w| w == needle) {
+            return Ok(received.drain(..pos + needle.len()).collect());
+        }
+        let chunk = rx
+            .recv()
+            .await
+            .ok_or_else(|| eyre::eyre!("server closed the connection"))?;
+        received.extend(chunk);
+    }
+}
+
+#[test]
+fn h1_upgrade() {
+    helpers::run(async move {
+        let (tx, read) = ChanRead::new();
+        let (mut rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let serve_fut = tokio_uring::spawn(h1::serve(
+            transport,
+            Rc::new(h1::ServerConf::default()),
+            RollMut::alloc()?,
+            ShoutUpgrade,
+        ));
+
+        let mut received = Vec::new();
+
+        // requests that don't ask for an upgrade are served as usual
+        tx.send("GET / HTTP/1.1\r\nhost: localhost\r\n\r\n").await?;
+        let head = read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+        assert!(head.starts_with(b"HTTP/1.1 204 No Content\r\n"));
+
+        // bytes sent right after the request belong to the new protocol
+        tx.send(
+            "GET / HTTP/1.1\r\n\
+            host: localhost\r\n\
+            upgrade: shout\r\n\
+            connection: upgrade\r\n\
+            \r\n\
+            hello;",
+        )
+        .await?;
+        let head = String::from_utf8(read_until(&mut rx, &mut received, b"\r\n\r\n").await?)?
+            .to_lowercase();
+        assert!(head.starts_with("http/1.1 101 switching protocols\r\n"));
+        assert!(head.contains("\r\nupgrade: shout\r\n"));
+        assert_eq!(read_until(&mut rx, &mut received, b";").await?, b"HELLO;");
+
+        tx.send("world;").await?;
+        assert_eq!(read_until(&mut rx, &mut received, b";").await?, b"WORLD;");
+
+        drop(tx);
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut).await???;
+        assert_eq!(outcome, h1::ServeOutcome::Upgraded);
+
+        // connections aren't handed over in the middle of a request body
+        let (tx, read) = ChanRead::new();
+        let (_rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let serve_fut = tokio_uring::spawn(h1::serve(
+            transport,
+            Rc::new(h1::ServerConf::default()),
+            RollMut::alloc()?,
+            ShoutUpgrade,
+        ));
+        tx.send(
+            "POST / HTTP/1.1\r\n\
+            host: localhost\r\n\
+            upgrade: shout\r\n\
+            connection: upgrade\r\n\
+            content-length: 5\r\n\
+            \r\n\
+            hello",
+        )
+        .await?;
+        let res = tokio::time::timeout(Duration::from_secs(5), serve_fut).await??;
+        let err = res.expect_err("upgrade should have been refused");
+        assert!(format!("{err:?}").contains("after a request with a body"));
+
+        Ok(())
+    })
+}
+
+/// A forward proxy that tunnels CONNECT requests to `example.org:443`, which
+/// is a channel pair the test drives.
+struct ConnectProxy {
+    upstream: std::cell::RefCell<Option<ReadWritePair<ChanRead, ChanWrite>>>,
+}
+
+impl ServerDriver for ConnectProxy {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let status = if !matches!(req.method, Method::Connect) {
+            Some(StatusCode::METHOD_NOT_ALLOWED)
+        } else if req.uri.authority().map(|a| a.as_str()) != Some("example.org:443") {
+            Some(StatusCode::BAD_GATEWAY)
+        } else {
+            None
+        };
+        if let Some(status) = status {
+            let mut headers = Headers::default();
+            headers.insert(header::CONTENT_LENGTH, "0".into());
+            let res = Response {
+                status,
+                headers,
+                ..Default::default()
+            };
+            return respond
+                .write_final_response(res)
+                .await?
+                .finish_body(None)
+                .await;
+        }
+
+        let upstream = self
+            .upstream
+            .borrow_mut()
+            .take()
+            .ok_or_else(|| eyre::eyre!("only one tunnel per test"))?;
+        let res = Response {
+            status: StatusCode::OK,
+            ..Default::default()
+        };
+        respond
+            .write_tunnel_response(res)
+            .await?
+            .splice(req_body, upstream)
+            .await
+    }
+}
+
+#[test]
+fn h1_connect_tunnel() {
+    helpers::run(async move {
+        let (up_tx, up_read) = ChanRead::new();
+        let (mut up_rx, up_write) = ChanWrite::new();
+        let driver = ConnectProxy {
+            upstream: Some(ReadWritePair(up_read, up_write)).into(),
+        };
+
+        let (tx, read) = ChanRead::new();
+        let (mut rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let serve_fut = tokio_uring::spawn(h1::serve(
+            transport,
+            Rc::new(h1::ServerConf::default()),
+            RollMut::alloc()?,
+            driver,
+        ));
+        let mut received = Vec::new();
+
+        for (req, status_line) in [
+            (
+                "GET / HTTP/1.1\r\nhost: example.org\r\n\r\n",
+                &b"HTTP/1.1 405 Method Not Allowed\r\n"[..],
+            ),
+            (
+                "CONNECT other.org:443 HTTP/1.1\r\nhost: other.org:443\r\n\r\n",
+                &b"HTTP/1.1 502 Bad Gateway\r\n"[..],
+            ),
+        ] {
+            tx.send(req).await?;
+            let head = read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+            assert!(head.starts_with(status_line));
+        }
+
+        tx.send("CONNECT example.org:443 HTTP/1.1\r\nhost: example.org:443\r\n\r\n")
+            .await?;
+        let head = read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+        let head = String::from_utf8(head)?.to_lowercase();
+        assert!(head.starts_with("http/1.1 200 ok\r\n"));
+        assert!(!head.contains("content-length"));
+        assert!(!head.contains("transfer-encoding"));
+
+        // bytes go through as-is, both ways
+        tx.send("ping").await?;
+        assert_eq!(up_rx.recv().await.unwrap(), b"ping");
+        up_tx.send("pong").await?;
+        assert_eq!(read_until(&mut rx, &mut received, b"pong").await?, b"pong");
+
+        // once upstream is done, so is the connection
+        drop(up_tx);
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut).await???;
+        assert_eq!(outcome, h1::ServeOutcome::Tunneled);
+        assert!(rx.recv().await.is_none());
+
+        // the target of a CONNECT must have a port
+        let (tx, read) = ChanRead::new();
+        let (_rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let serve_fut = tokio_uring::spawn(h1::serve(
+            transport,
+            Rc::new(h1::ServerConf::default()),
+            RollMut::alloc()?,
+            ConnectProxy {
+                upstream: None.into(),
+            },
+        ));
+        tx.send("CONNECT example.org HTTP/1.1\r\nhost: example.org\r\n\r\n")
+            .await?;
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut).await???;
+        assert_eq!(outcome, h1::ServeOutcome::ClientDidntSpeakHttp11);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_connect_tunnel() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    helpers::run(async move {
+        let (up_tx, up_read) = ChanRead::new();
+        let (mut up_rx, up_write) = ChanWrite::new();
+        let driver = ConnectProxy {
+            upstream: Some(ReadWritePair(up_read, up_write)).into(),
+        };
+
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            transport,
+            Default::default(),
+            RollMut::alloc()?,
+            Rc::new(driver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+        let mut hpack_dec = hring_hpack::Decoder::new();
+
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"CONNECT"),
+            (b":authority", b"example.org:443"),
+        ];
+        conn.send_headers(1, headers, false).await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::HEADERS);
+        assert_eq!(frame.flags & flags::END_STREAM, 0);
+        let res_headers = hpack_dec.decode(&frame.payload).unwrap();
+        assert_eq!(res_headers, [(b":status".to_vec(), b"200".to_vec())]);
+
+        conn.send_frame(frame_type::DATA, 0, 1, b"ping".to_vec())
+            .await?;
+        assert_eq!(up_rx.recv().await.unwrap(), b"ping");
+        up_tx.send("pong").await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::DATA);
+        assert_eq!(frame.flags & flags::END_STREAM, 0);
+        assert_eq!(frame.payload, b"pong");
+
+        // the client is done sending, upstream still has things to say
+        conn.send_frame(frame_type::DATA, flags::END_STREAM, 1, b"bye".to_vec())
+            .await?;
+        assert_eq!(up_rx.recv().await.unwrap(), b"bye");
+        up_tx.send("see you").await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::DATA);
+        assert_eq!(frame.payload, b"see you");
+
+        // upstream closing ends the stream
+        drop(up_tx);
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::DATA);
+        assert_eq!(frame.stream_id, 1);
+        assert_ne!(frame.flags & flags::END_STREAM, 0);
+        assert!(frame.payload.is_empty());
+
+        Ok(())
+    })
+}
+
+/// Counts the bytes of request bodies, except for `/too-big`, which is
+/// refused without reading the body. Bodies over the configured limit get a
+/// 413 once the limit is hit.
+struct UploadDriver;
+
+impl ServerDriver for UploadDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let mut headers = Headers::default();
+        if req.uri.path() == "/too-big" {
+            headers.insert(header::CONTENT_LENGTH, "0".into());
+            let res = Response {
+                status: StatusCode::PAYLOAD_TOO_LARGE,
+                headers,
+                ..Default::default()
+            };
+            return respond
+                .write_final_response(res)
+                .await?
+                .finish_body(None)
+                .await;
+        }
+
+        let mut len = 0;
+        let trailers = loop {
+            match req_body.next_chunk().await {
+                Ok(BodyChunk::Chunk(chunk)) => len += chunk.len(),
+                Ok(BodyChunk::Done { trailers }) => break trailers,
+                Err(e) => {
+                    let too_large = e
+                        .downcast_ref::<BodyError>()
+                        .map_or(false, |e| e.reason() == BodyErrorReason::BodyTooLarge);
+                    if !too_large {
+                        return Err(e);
+                    }
+
+                    // the rest of the body won't be read
+                    if req.version < http::Version::HTTP_2 {
+                        headers.insert(header::CONNECTION, "close".into());
+                    }
+                    headers.insert(header::CONTENT_LENGTH, "0".into());
+                    let res = Response {
+                        status: StatusCode::PAYLOAD_TOO_LARGE,
+                        headers,
+                        ..Default::default()
+                    };
+                    return respond
+                        .write_final_response(res)
+                        .await?
+                        .finish_body(None)
+                        .await;
+                }
+            }
+        };
+
+        let mut body = format!("got {len} bytes");
+        for (name, value) in trailers.iter().flat_map(|trailers| trailers.iter()) {
+            body += &format!(", {name}: {}", String::from_utf8_lossy(value));
+        }
+        let body = body.into_bytes();
+        headers.insert(
+            header::CONTENT_LENGTH,
+            body.len().to_string().into_bytes().into(),
+        );
+        let res = Response {
+            status: StatusCode::OK,
+            headers,
+            ..Default::default()
+        };
+        let mut respond = respond.write_final_response(res).await?;
+        respond.write_chunk(body.into()).await?;
+        respond.finish_body(None).await
+    }
+}
+
+#[test]
+fn h1_expect_100_continue() {
+    helpers::run(async move {
+        let (tx, read) = ChanRead::new();
+        let (mut rx, write) = ChanWrite::new();
+        let transport = ReadWritePair(read, write);
+        let serve_fut = tokio_uring::spawn(h1::serve(
+            transport,
+            Rc::new(h1::ServerConf::default()),
+            RollMut::alloc()?,
+            UploadDriver,
+        ));
+        let mut received = Vec::new();
+
+        // the body is only sent once the server asks for it
+        tx.send(
+            "POST /upload HTTP/1.1\r\n\
+            host: localhost\r\n\
+            content-length: 5\r\n\
+            expect: 100-continue\r\n\
+            \r\n",
+        )
+        .await?;
+        let head = read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+        assert_eq!(head, b"HTTP/1.1 100 Continue\r\n\r\n");
+        tx.send("hello").await?;
+        let head = read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+        assert!(head.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert_eq!(
+            read_until(&mut rx, &mut received, b"bytes").await?,
+            b"got 5 bytes"
+        );
+
+        // no `100 Continue` for HTTP/1.0 clients, or without a body
+        for req in [
+            "POST /upload HTTP/1.0\r\nconnection: keep-alive\r\ncontent-length: 2\r\nexpect: 100-continue\r\n\r\nhi",
+            "POST /upload HTTP/1.1\r\ncontent-length: 0\r\nexpect: 100-continue\r\n\r\n",
+        ] {
+            tx.send(req).await?;
+            let head = read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+            assert!(head.starts_with(b"HTTP/1.1 200 OK\r\n"));
+            read_until(&mut rx, &mut received, b"bytes").await?;
+        }
+
+        // refusing the body before reading it: the client may or may not
+        // send it, so the connection is closed after the response.
+        tx.send(
+            "POST /too-big HTTP/1.1\r\n\
+            host: localhost\r\n\
+            content-length: 100000\r\n\
+            expect: 100-continue\r\n\
+            \r\n",
+        )
+        .await?;
+        let head = read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+        let head = String::from_utf8(head)?.to_lowercase();
+        assert!(head.starts_with("http/1.1 413 payload too large\r\n"));
+        assert!(head.contains("\r\nconnection: close\r\n"));
+
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut).await???;
+        assert_eq!(outcome, h1::ServeOutcome::ServerRequestedConnectionClose);
+        assert!(rx.recv().await.is_none());
+        assert!(received.is_empty());
+
+        Ok(())
+    })
+}
+
+/// A request body that's sent in one go
+#[derive(Debug)]
+struct FixedBody(Option<Vec<u8>>);
+
+impl Body for FixedBody {
+    fn content_len(&self) -> Option<u64> {
+        Some(self.0.as_ref().map_or(0, |data| data.len() as u64))
+    }
+
+    fn eof(&self) -> bool {
+        self.0.is_none()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        Ok(match self.0.take() {
+            Some(data) => BodyChunk::Chunk(data.into()),
+            None => BodyChunk::Done { trailers: None },
+        })
+    }
+}
+
+/// Records informational responses, and the final response's status and body
+#[derive(Default)]
+struct RecordingClientDriver {
+    informational: Vec<Response>,
+}
+
+impl h1::ClientDriver for RecordingClientDriver {
+    type Return = (Vec<Response>, StatusCode, Vec<u8>);
+
+    async fn on_informational_response(&mut self, res: Response) -> eyre::Result<()> {
+        self.informational.push(res);
+        Ok(())
+    }
+
+    async fn on_final_response(
+        self,
+        res: Response,
+        body: &mut impl Body,
+    ) -> eyre::Result<Self::Return> {
+        let mut data = Vec::new();
+        while let BodyChunk::Chunk(chunk) = body.next_chunk().await? {
+            data.extend_from_slice(&chunk[..]);
+        }
+        Ok((self.informational, res.status, data))
+    }
+}
+
+#[test]
+fn h1_client_expect_100_continue() {
+    helpers::run(async move {
+        let (tx, read) = ChanRead::new();
+        let (mut rx, write) = ChanWrite::new();
+        let transport = Rc::new(ReadWritePair(read, write));
+        let mut received = Vec::new();
+
+        let conf = Rc::new(h1::ClientConf {
+            expect_continue_timeout: Duration::from_millis(300),
+        });
+        let upload = |transport| {
+            let conf = conf.clone();
+            let mut headers = Headers::default();
+            headers.insert(header::EXPECT, "100-continue".into());
+            let req = Request {
+                method: Method::Post,
+                uri: "/upload".parse().unwrap(),
+                headers,
+                ..Default::default()
+            };
+            tokio_uring::spawn(async move {
+                let mut body = FixedBody(Some(b"hello".to_vec()));
+                h1::request(
+                    transport,
+                    conf,
+                    req,
+                    &mut body,
+                    RecordingClientDriver::default(),
+                )
+                .await
+            })
+        };
+
+        // the body waits for `100 Continue`, and every 1xx reaches the driver
+        let request_fut = upload(transport.clone());
+        let head = read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+        assert!(head.starts_with(b"POST /upload HTTP/1.1\r\n"));
+        let nothing = tokio::time::timeout(Duration::from_millis(100), rx.recv()).await;
+        assert!(nothing.is_err(), "body was sent before 100 Continue");
+
+        tx.send("HTTP/1.1 103 Early Hints\r\nlink: </style.css>; rel=preload\r\n\r\n")
+            .await?;
+        tx.send("HTTP/1.1 100 Continue\r\n\r\n").await?;
+        assert_eq!(
+            read_until(&mut rx, &mut received, b"hello").await?,
+            b"hello"
+        );
+        tx.send("HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+            .await?;
+
+        let (transport, (informational, status, body)) = request_fut.await??;
+        let statuses: Vec<_> = informational
+            .iter()
+            .map(|res| res.status.as_u16())
+            .collect();
+        assert_eq!(statuses, [103, 100]);
+        assert_eq!(
+            informational[0].headers.get(header::LINK).map(|v| &v[..]),
+            Some(&b"</style.css>; rel=preload"[..])
+        );
+        assert_eq!((status, &body[..]), (StatusCode::OK, &b"ok"[..]));
+        let transport = transport.expect("connection should be reusable");
+
+        // servers that don't know about `expect` get the body after a while
+        let request_fut = upload(transport.clone());
+        read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+        let started = std::time::Instant::now();
+        assert_eq!(
+            read_until(&mut rx, &mut received, b"hello").await?,
+            b"hello"
+        );
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        tx.send("HTTP/1.1 204 No Content\r\n\r\n").await?;
+        let (transport, (informational, status, _)) = request_fut.await??;
+        assert!(informational.is_empty());
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let transport = transport.expect("connection should be reusable");
+
+        // a 2xx instead of `100 Continue` still gets the body
+        let request_fut = upload(transport.clone());
+        read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+        tx.send("HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .await?;
+        assert_eq!(
+            read_until(&mut rx, &mut received, b"hello").await?,
+            b"hello"
+        );
+        let (transport, (_, status, _)) = request_fut.await??;
+        assert_eq!(status, StatusCode::OK);
+        let transport = transport.expect("connection should be reusable");
+
+        // any other final response: the body isn't sent, and the connection
+        // can't be reused
+        let request_fut = upload(transport);
+        read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+        tx.send("HTTP/1.1 413 Payload Too Large\r\ncontent-length: 0\r\n\r\n")
+            .await?;
+        let (transport, (_, status, _)) = request_fut.await??;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(transport.is_none());
+        let nothing = tokio::time::timeout(Duration::from_millis(100), rx.recv()).await;
+        assert!(nothing.is_err(), "body was sent after a final response");
+        assert!(received.is_empty());
+
+        Ok(())
+    })
+}
+
+/// Sends a single request to a fresh connection that's expected to get closed
+/// after the response, returns everything the server sent
+async fn serve_closing(
+    conf: h1::ServerConf,
+    driver: impl ServerDriver + 'static,
+    req: &str,
+) -> eyre::Result<(String, h1::ServeOutcome)> {
+    let (tx, read) = ChanRead::new();
+    let (mut rx, write) = ChanWrite::new();
+    let serve_fut = tokio_uring::spawn(h1::serve(
+        ReadWritePair(read, write),
+        Rc::new(conf),
+        RollMut::alloc()?,
+        driver,
+    ));
+    tx.send(req.to_string()).await?;
+
+    let mut received = Vec::new();
+    while let Some(chunk) = rx.recv().await {
+        received.extend_from_slice(&chunk);
+    }
+    // the server may be waiting for us to close our side too
+    drop(tx);
+    let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut).await???;
+    Ok((String::from_utf8(received)?, outcome))
+}
+
+#[test]
+fn h1_request_smuggling() {
+    helpers::run(async move {
+        let bad_request = "HTTP/1.1 400 Bad Request\r\nconnection: close\r\n";
+        for (req, status) in [
+            (
+                "POST /upload HTTP/1.1\r\ncontent-length: 5\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+                bad_request,
+            ),
+            (
+                "POST /upload HTTP/1.1\r\ncontent-length: 5\r\ncontent-length: 6\r\n\r\nhello!",
+                bad_request,
+            ),
+            (
+                "POST /upload HTTP/1.1\r\ncontent-length: 5\r\ncontent-length: 5\r\n\r\nhello",
+                bad_request,
+            ),
+            (
+                "POST /upload HTTP/1.1\r\ncontent-length: 5, 5\r\n\r\nhello",
+                bad_request,
+            ),
+            (
+                "POST /upload HTTP/1.1\r\ncontent-length: +5\r\n\r\nhello",
+                bad_request,
+            ),
+            (
+                "POST /upload HTTP/1.1\r\ntransfer-encoding: chunked, identity\r\n\r\n",
+                bad_request,
+            ),
+            (
+                "POST /upload HTTP/1.1\r\ntransfer-encoding: chunked\r\ntransfer-encoding: chunked\r\n\r\n",
+                bad_request,
+            ),
+            (
+                "POST /upload HTTP/1.1\r\ntransfer-encoding: xchunked\r\n\r\n",
+                bad_request,
+            ),
+            (
+                "POST /upload HTTP/1.0\r\ntransfer-encoding: chunked\r\n\r\n0\r\n\r\n",
+                bad_request,
+            ),
+            (
+                "POST /upload HTTP/1.1\r\ncontent-length : 5\r\n\r\nhello",
+                bad_request,
+            ),
+            (
+                "POST /upload HTTP/1.1\r\nx-folded: a\r\n b\r\ncontent-length: 0\r\n\r\n",
+                bad_request,
+            ),
+            (
+                "POST /upload HTTP/1.1\r\ntransfer-encoding: gzip, chunked\r\n\r\n",
+                "HTTP/1.1 501 Not Implemented\r\nconnection: close\r\n",
+            ),
+        ] {
+            let (res, outcome) = serve_closing(h1::ServerConf::default(), UploadDriver, req).await?;
+            assert!(res.starts_with(status), "{req:?} got {res:?}");
+            assert_eq!(outcome, h1::ServeOutcome::ClientDidntSpeakHttp11);
+        }
+
+        // lenient mode reads the body as chunked, then closes the connection
+        // since whoever sent this might think otherwise.
+        let lenient = || h1::ServerConf {
+            lenient_parsing: true,
+            ..Default::default()
+        };
+        for req in [
+            "POST /upload HTTP/1.1\r\ncontent-length: 3\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+            "POST /upload HTTP/1.0\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        ] {
+            let (res, outcome) = serve_closing(lenient(), UploadDriver, req).await?;
+            assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{req:?} got {res:?}");
+            assert!(res.contains("\r\nconnection: close\r\n"));
+            assert!(res.ends_with("got 5 bytes"));
+            assert_eq!(outcome, h1::ServeOutcome::ServerRequestedConnectionClose);
+        }
+
+        // identical content-length values and folded lines are fine in
+        // lenient mode, as is whitespace around values in any mode.
+        for (conf, req) in [
+            (
+                lenient(),
+                "POST /upload HTTP/1.1\r\ncontent-length: 5\r\ncontent-length: 5\r\nx-folded: a\r\n b\r\nconnection: close\r\n\r\nhello",
+            ),
+            (
+                h1::ServerConf::default(),
+                "POST /upload HTTP/1.1\r\ncontent-length:\t5 \r\nconnection: close\r\n\r\nhello",
+            ),
+        ] {
+            let (res, outcome) = serve_closing(conf, UploadDriver, req).await?;
+            assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{req:?} got {res:?}");
+            assert!(res.ends_with("got 5 bytes"));
+            assert_eq!(outcome, h1::ServeOutcome::ClientRequestedConnectionClose);
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_chunk_extensions_and_trailers() {
+    helpers::run(async move {
+        let (tx, read) = ChanRead::new();
+        let (mut rx, write) = ChanWrite::new();
+        let serve_fut = tokio_uring::spawn(h1::serve(
+            ReadWritePair(read, write),
+            Rc::new(h1::ServerConf::default()),
+            RollMut::alloc()?,
+            UploadDriver,
+        ));
+        let mut received = Vec::new();
+
+        tx.send(
+            "POST /upload HTTP/1.1\r\n\
+            transfer-encoding: chunked\r\n\
+            trailer: x-checksum, grpc-status\r\n\
+            \r\n\
+            5;name=value;quoted=\"a;b\"\r\nhello\r\n\
+            1 ; flag\r\n!\r\n\
+            0\r\n\
+            x-checksum: 1234\r\n\
+            grpc-status: 0\r\n\
+            \r\n",
+        )
+        .await?;
+        let head = read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+        assert!(head.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        let body = read_until(&mut rx, &mut received, b"grpc-status: 0").await?;
+        assert_eq!(body, b"got 6 bytes, x-checksum: 1234, grpc-status: 0");
+
+        // no trailers
+        tx.send("POST /upload HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n2\r\nhi\r\n0\r\n\r\n")
+            .await?;
+        read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+        assert_eq!(
+            read_until(&mut rx, &mut received, b"bytes").await?,
+            b"got 2 bytes"
+        );
+
+        // invalid extensions make the body unreadable
+        tx.send(
+            "POST /upload HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n2;a=b c\r\nhi\r\n0\r\n\r\n",
+        )
+        .await?;
+        let res = tokio::time::timeout(Duration::from_secs(5), serve_fut).await??;
+        assert!(res.is_err());
+        assert!(rx.recv().await.is_none());
+
+        // so do extensions and trailers that are too long
+        let conf = || h1::ServerConf {
+            max_chunk_header_len: 64,
+            max_trailers_len: 64,
+            ..Default::default()
+        };
+        let long = "a".repeat(100);
+        for req in [
+            format!("POST /upload HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n2;{long}\r\nhi\r\n0\r\n\r\n"),
+            format!("POST /upload HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n2\r\nhi\r\n0\r\nx-long: {long}\r\n\r\n"),
+        ] {
+            let (tx, read) = ChanRead::new();
+            let (_rx, write) = ChanWrite::new();
+            let serve_fut = tokio_uring::spawn(h1::serve(
+                ReadWritePair(read, write),
+                Rc::new(conf()),
+                RollMut::alloc()?,
+                UploadDriver,
+            ));
+            tx.send(req).await?;
+            let res = tokio::time::timeout(Duration::from_secs(5), serve_fut).await??;
+            assert!(res.is_err());
+        }
+
+        Ok(())
+    })
+}
+
+/// Answers `/stream` with a body of unknown length, `/close` with
+/// `connection: close`, and anything else with a content-length body
+struct PersistenceDriver;
+
+impl ServerDriver for PersistenceDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        _req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let mut headers = Headers::default();
+        match req.uri.path() {
+            "/stream" => {}
+            "/close" => {
+                headers.insert(header::CONNECTION, "close".into());
+                headers.insert(header::CONTENT_LENGTH, "5".into());
+            }
+            _ => {
+                headers.insert(header::CONTENT_LENGTH, "5".into());
+            }
+        }
+        let res = Response {
+            status: StatusCode::OK,
+            headers,
+            ..Default::default()
+        };
+        let mut respond = respond.write_final_response(res).await?;
+        respond.write_chunk("hello".into()).await?;
+        respond.finish_body(None).await
+    }
+}
+
+#[test]
+fn h1_persistence() {
+    helpers::run(async move {
+        let conf = h1::ServerConf::default;
+
+        // HTTP/1.0 closes by default
+        let (res, outcome) =
+            serve_closing(conf(), PersistenceDriver, "GET / HTTP/1.0\r\n\r\n").await?;
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!res.contains("keep-alive"));
+        assert!(res.ends_with("\r\n\r\nhello"));
+        assert_eq!(outcome, h1::ServeOutcome::ClientRequestedConnectionClose);
+
+        // ...unless the client asks for keep-alive, which the response confirms.
+        // Bodies of unknown length then end with the connection.
+        let (res, outcome) = serve_closing(
+            conf(),
+            PersistenceDriver,
+            "GET / HTTP/1.0\r\nconnection: keep-alive\r\n\r\n\
+            GET /stream HTTP/1.0\r\nconnection: Keep-Alive\r\n\r\n",
+        )
+        .await?;
+        let (first, second) = res.split_once("hello").unwrap();
+        assert!(first.contains("\r\nconnection: keep-alive\r\n"));
+        assert!(second.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(second.contains("\r\nconnection: close\r\n"));
+        assert!(!second.contains("transfer-encoding"));
+        assert!(second.ends_with("\r\n\r\nhello"));
+        assert_eq!(outcome, h1::ServeOutcome::ServerRequestedConnectionClose);
+
+        // HTTP/1.1 persists unless either side says `close`, anywhere in the
+        // header
+        let (res, outcome) = serve_closing(
+            conf(),
+            PersistenceDriver,
+            "GET /stream HTTP/1.1\r\n\r\n\
+            GET / HTTP/1.1\r\nconnection: keep-alive, Close\r\n\r\n",
+        )
+        .await?;
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.contains("\r\ntransfer-encoding: chunked\r\n"));
+        assert!(res.contains("5\r\nhello\r\n0\r\n\r\nHTTP/1.1 200 OK\r\n"));
+        assert_eq!(outcome, h1::ServeOutcome::ClientRequestedConnectionClose);
+
+        let (res, outcome) = serve_closing(
+            conf(),
+            PersistenceDriver,
+            "GET /close HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+        )
+        .await?;
+        assert!(res.contains("\r\nconnection: close\r\n"));
+        assert_eq!(res.matches("HTTP/1.1 200 OK").count(), 1);
+        assert_eq!(outcome, h1::ServeOutcome::ServerRequestedConnectionClose);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_pipelining() {
+    helpers::run(async move {
+        // pipelined requests are answered in order, bodies included
+        let (res, outcome) = serve_closing(
+            h1::ServerConf::default(),
+            UploadDriver,
+            "POST /upload HTTP/1.1\r\ncontent-length: 3\r\n\r\nabc\
+            POST /upload HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n1\r\na\r\n0\r\n\r\n\
+            GET /upload HTTP/1.1\r\n\r\n\
+            POST /upload HTTP/1.1\r\nconnection: close\r\ncontent-length: 2\r\n\r\nab",
+        )
+        .await?;
+        let bodies: Vec<_> = res
+            .match_indices("got ")
+            .map(|(i, _)| &res[i..i + 11])
+            .collect();
+        assert_eq!(
+            bodies,
+            ["got 3 bytes", "got 1 bytes", "got 0 bytes", "got 2 bytes"]
+        );
+        assert_eq!(outcome, h1::ServeOutcome::ClientRequestedConnectionClose);
+
+        // past the configured depth, the connection is closed, and the last
+        // response says so
+        let conf = h1::ServerConf {
+            max_pipeline_depth: 1,
+            ..Default::default()
+        };
+        let (res, outcome) = serve_closing(
+            conf,
+            PersistenceDriver,
+            "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+        )
+        .await?;
+        let responses: Vec<_> = res.split("HTTP/1.1 200 OK").skip(1).collect();
+        assert_eq!(responses.len(), 2);
+        assert!(!responses[0].contains("connection: close"));
+        assert!(responses[1].contains("connection: close"));
+        assert_eq!(outcome, h1::ServeOutcome::PipelineTooDeep);
+
+        // the next request is found even if the handler leaves the body
+        let (res, outcome) = serve_closing(
+            h1::ServerConf::default(),
+            PersistenceDriver,
+            "POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello\
+            GET / HTTP/1.1\r\nconnection: close\r\n\r\n",
+        )
+        .await?;
+        assert_eq!(res.matches("HTTP/1.1 200 OK").count(), 2);
+        assert_eq!(outcome, h1::ServeOutcome::ClientRequestedConnectionClose);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_drain_request_body() {
+    helpers::run(async move {
+        let conf = || h1::ServerConf {
+            max_drain_bytes: 16,
+            drain_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+
+        // bodies the handler didn't read are drained, so the connection stays open
+        let (res, outcome) = serve_closing(
+            conf(),
+            PersistenceDriver,
+            "POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello\
+            POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nx-trailer: 1\r\n\r\n\
+            GET / HTTP/1.1\r\nconnection: close\r\n\r\n",
+        )
+        .await?;
+        assert_eq!(res.matches("HTTP/1.1 200 OK").count(), 3);
+        assert_eq!(res.matches("connection: close").count(), 0);
+        assert_eq!(outcome, h1::ServeOutcome::ClientRequestedConnectionClose);
+
+        // rejecting a small body early doesn't lose keep-alive...
+        let (tx, read) = ChanRead::new();
+        let (mut rx, write) = ChanWrite::new();
+        let serve_fut = tokio_uring::spawn(h1::serve(
+            ReadWritePair(read, write),
+            Rc::new(conf()),
+            RollMut::alloc()?,
+            UploadDriver,
+        ));
+        let mut received = Vec::new();
+        tx.send("POST /too-big HTTP/1.1\r\ncontent-length: 10\r\n\r\n0123456789")
+            .await?;
+        let head = read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+        let head = String::from_utf8(head)?;
+        assert!(head.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(!head.contains("connection: close"));
+
+        // ...but a content-length over the drain limit does
+        tx.send("POST /too-big HTTP/1.1\r\ncontent-length: 100000\r\n\r\n")
+            .await?;
+        let head = read_until(&mut rx, &mut received, b"\r\n\r\n").await?;
+        let head = String::from_utf8(head)?;
+        assert!(head.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(head.contains("\r\nconnection: close\r\n"));
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut).await???;
+        assert_eq!(outcome, h1::ServeOutcome::ServerRequestedConnectionClose);
+        assert!(rx.recv().await.is_none());
+
+        // bodies of unknown length are only drained up to the limit, and only
+        // for so long
+        for req in [
+            format!(
+                "POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n20\r\n{}\r\n0\r\n\r\n",
+                "a".repeat(32)
+            ),
+            "POST / HTTP/1.1\r\ncontent-length: 10\r\n\r\nhello".to_string(),
+        ] {
+            let (res, outcome) = serve_closing(conf(), PersistenceDriver, &req).await?;
+            assert_eq!(res.matches("HTTP/1.1 200 OK").count(), 1);
+            assert_eq!(outcome, h1::ServeOutcome::RequestBodyNotDrained);
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_max_request_body_len() {
+    helpers::run(async move {
+        let conf = || h1::ServerConf {
+            max_request_body_len: Some(8),
+            ..Default::default()
+        };
+
+        // a content-length over the limit is refused before calling the handler
+        let (res, outcome) = serve_closing(
+            conf(),
+            UploadDriver,
+            "POST /upload HTTP/1.1\r\ncontent-length: 9\r\n\r\n123456789",
+        )
+        .await?;
+        assert!(res.starts_with("HTTP/1.1 413 Payload Too Large\r\nconnection: close\r\n"));
+        assert!(!res.contains("got"));
+        assert_eq!(outcome, h1::ServeOutcome::RequestBodyTooLarge);
+
+        // chunked bodies error out once they go over
+        let (res, outcome) = serve_closing(
+            conf(),
+            UploadDriver,
+            "POST /upload HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n",
+        )
+        .await?;
+        assert!(res.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert_eq!(outcome, h1::ServeOutcome::ServerRequestedConnectionClose);
+
+        // bodies within the limit are fine either way
+        let (res, _) = serve_closing(
+            conf(),
+            UploadDriver,
+            "POST /upload HTTP/1.1\r\ncontent-length: 8\r\n\r\n12345678\
+            POST /upload HTTP/1.1\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n\
+            4\r\nabcd\r\n4\r\nefgh\r\n0\r\n\r\n",
+        )
+        .await?;
+        assert_eq!(res.matches("got 8 bytes").count(), 2);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_max_request_body_len() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    helpers::run(async move {
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let conf = h2::ServerConf {
+            max_request_body_len: Some(8),
+            ..Default::default()
+        };
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            ReadWritePair(read, write),
+            Rc::new(conf),
+            RollMut::alloc()?,
+            Rc::new(UploadDriver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+        let mut hpack_dec = hring_hpack::Decoder::new();
+
+        let headers = |content_length: Option<&'static [u8]>| {
+            let mut headers: Vec<(&[u8], &[u8])> = vec![
+                (b":method", b"POST"),
+                (b":scheme", b"http"),
+                (b":authority", b"localhost"),
+                (b":path", b"/upload"),
+            ];
+            if let Some(len) = content_length {
+                headers.push((b"content-length", len));
+            }
+            headers
+        };
+
+        // a content-length over the limit is refused before calling the handler
+        conn.send_headers(1, &headers(Some(b"9")), false).await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::HEADERS);
+        assert_eq!(frame.stream_id, 1);
+        let res_headers = hpack_dec.decode(&frame.payload).unwrap();
+        assert_eq!(res_headers[0], (b":status".to_vec(), b"413".to_vec()));
+
+        // bodies without one error out once they go over, and the stream is
+        // reset: the window taken by the chunk that went over isn't given back
+        conn.send_headers(3, &headers(None), false).await?;
+        conn.send_frame(frame_type::DATA, 0, 3, b"hello".to_vec())
+            .await?;
+        conn.send_frame(frame_type::DATA, flags::END_STREAM, 3, b"world".to_vec())
+            .await?;
+        let mut stream_increments = 0;
+        let frame = loop {
+            let frame = conn.read_frame().await?.unwrap();
+            if frame.stream_id != 3 {
+                continue;
+            }
+            if frame.frame_type == frame_type::WINDOW_UPDATE {
+                stream_increments += u32::from_be_bytes(frame.payload[..4].try_into().unwrap());
+                continue;
+            }
+            break frame;
+        };
+        assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+        // CANCEL
+        assert_eq!(frame.payload, [0x0, 0x0, 0x0, 0x8]);
+        assert_eq!(stream_increments, 5);
+
+        // bodies within the limit are fine
+        conn.send_headers(5, &headers(None), false).await?;
+        conn.send_frame(frame_type::DATA, flags::END_STREAM, 5, b"12345678".to_vec())
+            .await?;
+        let frame = loop {
+            let frame = conn.read_significant_frame().await?.unwrap();
+            if frame.stream_id == 5 && frame.frame_type == frame_type::DATA {
+                break frame;
+            }
+        };
+        assert_eq!(frame.payload, b"got 8 bytes");
+
+        Ok(())
+    })
+}
+
+/// Answers right away, without reading the request body
+struct IgnoreBodyDriver;
+
+impl ServerDriver for IgnoreBodyDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        _req: Request,
+        _req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let mut respond = respond
+            .write_final_response(Response {
+                status: StatusCode::OK,
+                ..Default::default()
+            })
+            .await?;
+        respond.write_chunk(b"hello".to_vec().into()).await?;
+        respond.finish_body(None).await
+    }
+}
+
+#[test]
+fn h2_unread_body_content_length() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    helpers::run(async move {
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            ReadWritePair(read, write),
+            Rc::new(h2::ServerConf::default()),
+            RollMut::alloc()?,
+            Rc::new(IgnoreBodyDriver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+
+        async fn read_response(conn: &mut H2Conn, stream_id: u32) -> eyre::Result<()> {
+            loop {
+                let frame = conn.read_significant_frame().await?.unwrap();
+                assert_eq!(frame.stream_id, stream_id);
+                if frame.flags & flags::END_STREAM != 0 {
+                    return Ok(());
+                }
+            }
+        }
+
+        let headers = |content_length: Option<&'static [u8]>| {
+            let mut headers: Vec<(&[u8], &[u8])> = vec![
+                (b":method", b"POST"),
+                (b":scheme", b"http"),
+                (b":authority", b"localhost"),
+                (b":path", b"/"),
+            ];
+            if let Some(len) = content_length {
+                headers.push((b"content-length", len));
+            }
+            headers
+        };
+
+        // the response is out before the body comes in, but the body still
+        // has to match its content-length
+        conn.send_headers(1, &headers(Some(b"10")), false).await?;
+        read_response(&mut conn, 1).await?;
+        conn.send_frame(frame_type::DATA, 0, 1, b"hello".to_vec())
+            .await?;
+        conn.send_frame(frame_type::DATA, flags::END_STREAM, 1, b"hi".to_vec())
+            .await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+        assert_eq!(frame.stream_id, 1);
+        // PROTOCOL_ERROR
+        assert_eq!(frame.payload, [0x0, 0x0, 0x0, 0x1]);
+
+        // without one, there's nothing to check, so the peer can stop sending
+        conn.send_headers(3, &headers(None), false).await?;
+        read_response(&mut conn, 3).await?;
+        conn.send_frame(frame_type::DATA, 0, 3, b"hello".to_vec())
+            .await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+        assert_eq!(frame.stream_id, 3);
+        // NO_ERROR
+        assert_eq!(frame.payload, [0x0, 0x0, 0x0, 0x0]);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_head_response_has_no_body() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    helpers::run(async move {
+        let (tx, read) = ChanRead::new();
+        let (rx, write) = ChanWrite::new();
+        let _serve_fut = tokio_uring::spawn(h2::serve(
+            ReadWritePair(read, write),
+            Rc::new(h2::ServerConf::default()),
+            RollMut::alloc()?,
+            Rc::new(IgnoreBodyDriver),
+        ));
+
+        let mut conn = H2Conn::new(tx, rx);
+        conn.handshake(&[]).await?;
+
+        for (stream_id, method, body_len) in [(1, &b"HEAD"[..], 0), (3, &b"GET"[..], 5)] {
+            let headers: &[(&[u8], &[u8])] = &[
+                (b":method", method),
+                (b":scheme", b"http"),
+                (b":authority", b"localhost"),
+                (b":path", b"/"),
+            ];
+            conn.send_headers(stream_id, headers, true).await?;
+
+            let mut received = 0;
+            loop {
+                let frame = conn.read_significant_frame().await?.unwrap();
+                assert_eq!(frame.stream_id, stream_id);
+                if frame.frame_type == frame_type::DATA {
+                    received += frame.payload.len();
+                }
+                if frame.flags & flags::END_STREAM != 0 {
+                    break;
+                }
+            }
+            assert_eq!(received, body_len);
+        }
+
+        Ok(())
+    })
+}
+
+/// Takes its time before answering, without reading the request body
+struct SlowDriver;
+
+impl ServerDriver for SlowDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        _req: Request,
+        _req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let mut headers = Headers::default();
+        headers.insert(header::CONTENT_LENGTH, "0".into());
+        let res = Response {
+            status: StatusCode::OK,
+            headers,
+            ..Default::default()
+        };
+        respond
+            .write_final_response(res)
+            .await?
+            .finish_body(None)
+            .await
+    }
+}
+
+#[test]
+fn h1_timeouts() {
+    helpers::run(async move {
+        let conf = || h1::ServerConf {
+            keep_alive_timeout: Duration::from_millis(100),
+            header_read_timeout: Duration::from_millis(100),
+            body_idle_timeout: Duration::from_millis(100),
+            request_deadline: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+
+        // unlike `serve_closing`, the client never closes its side
+        async fn serve_stalling(
+            conf: h1::ServerConf,
+            driver: impl ServerDriver + 'static,
+            req: &str,
+        ) -> eyre::Result<(String, h1::ServeOutcome)> {
+            let (tx, read) = ChanRead::new();
+            let (mut rx, write) = ChanWrite::new();
+            let serve_fut = tokio_uring::spawn(h1::serve(
+                ReadWritePair(read, write),
+                Rc::new(conf),
+                RollMut::alloc()?,
+                driver,
+            ));
+            if !req.is_empty() {
+                tx.send(req.to_string()).await?;
+            }
+
+            let mut received = Vec::new();
+            while let Some(chunk) = rx.recv().await {
+                received.extend_from_slice(&chunk);
+            }
+            let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut).await???;
+            drop(tx);
+            Ok((String::from_utf8(received)?, outcome))
+        }
+
+        // connections that never send anything are closed
+        let (res, outcome) = serve_stalling(conf(), PersistenceDriver, "").await?;
+        assert_eq!(res, "");
+        assert_eq!(outcome, h1::ServeOutcome::TimedOut(Timeout::KeepAliveIdle));
+
+        // so are idle connections between requests
+        let (res, outcome) =
+            serve_stalling(conf(), PersistenceDriver, "GET / HTTP/1.1\r\n\r\n").await?;
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!res.contains("408"));
+        assert_eq!(outcome, h1::ServeOutcome::TimedOut(Timeout::KeepAliveIdle));
+
+        // request headers have to come in quickly once they start
+        let (res, outcome) =
+            serve_stalling(conf(), PersistenceDriver, "GET / HTTP/1.1\r\nhost: ").await?;
+        assert!(res.starts_with("HTTP/1.1 408 Request Timeout\r\nconnection: close\r\n"));
+        assert_eq!(outcome, h1::ServeOutcome::TimedOut(Timeout::HeaderRead));
+
+        // so does the body, as long as the handler reads it
+        let (res, outcome) = serve_stalling(
+            conf(),
+            UploadDriver,
+            "POST /upload HTTP/1.1\r\ncontent-length: 10\r\n\r\nhello",
+        )
+        .await?;
+        assert!(res.starts_with("HTTP/1.1 408 Request Timeout\r\nconnection: close\r\n"));
+        assert_eq!(outcome, h1::ServeOutcome::TimedOut(Timeout::BodyIdle));
+
+        // handlers only get so long: a client that's still sending gets a 408...
+        let stall_body = || h1::ServerConf {
+            body_idle_timeout: Duration::from_secs(5),
+            ..conf()
+        };
+        let (res, outcome) = serve_stalling(
+            stall_body(),
+            UploadDriver,
+            "POST /upload HTTP/1.1\r\ncontent-length: 10\r\n\r\nhello",
+        )
+        .await?;
+        assert!(res.starts_with("HTTP/1.1 408 Request Timeout\r\nconnection: close\r\n"));
+        assert_eq!(
+            outcome,
+            h1::ServeOutcome::TimedOut(Timeout::RequestDeadline)
+        );
+
+        // ...otherwise it's on us
+        let (res, outcome) =
+            serve_stalling(stall_body(), SlowDriver, "GET / HTTP/1.1\r\n\r\n").await?;
+        assert!(res.starts_with("HTTP/1.1 503 Service Unavailable\r\nconnection: close\r\n"));
+        assert_eq!(
+            outcome,
+            h1::ServeOutcome::TimedOut(Timeout::RequestDeadline)
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_timeouts() {
+    use helpers::h2::{flags, frame_type, H2Conn};
+
+    helpers::run(async move {
+        let mut hpack_dec = hring_hpack::Decoder::new();
+        fn connect(
+            driver: impl ServerDriver + 'static,
+        ) -> (
+            H2Conn,
+            tokio::task::JoinHandle<eyre::Result<h2::ServeOutcome>>,
+        ) {
+            let conf = h2::ServerConf {
+                keep_alive_timeout: Duration::from_millis(200),
+                header_read_timeout: Duration::from_millis(100),
+                body_idle_timeout: Duration::from_millis(100),
+                request_deadline: Some(Duration::from_millis(300)),
+                ..Default::default()
+            };
+            let (tx, read) = ChanRead::new();
+            let (rx, write) = ChanWrite::new();
+            let serve_fut = tokio_uring::spawn(h2::serve(
+                ReadWritePair(read, write),
+                Rc::new(conf),
+                RollMut::alloc().unwrap(),
+                Rc::new(driver),
+            ));
+            (H2Conn::new(tx, rx), serve_fut)
+        }
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"POST"),
+            (b":scheme", b"http"),
+            (b":authority", b"localhost"),
+            (b":path", b"/upload"),
+        ];
+
+        // the preface has to come in quickly
+        let (conn, serve_fut) = connect(UploadDriver);
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut).await???;
+        assert!(matches!(
+            outcome,
+            h2::ServeOutcome::TimedOut(Timeout::HeaderRead)
+        ));
+        drop(conn);
+
+        // and so do field blocks, since they hold up the whole connection
+        let (mut conn, serve_fut) = connect(UploadDriver);
+        conn.handshake(&[]).await?;
+        let block = conn.encode_headers(headers);
+        conn.send_frame(frame_type::HEADERS, 0, 1, block).await?;
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::GOAWAY);
+        // NO_ERROR
+        assert_eq!(frame.payload[4..8], [0x0, 0x0, 0x0, 0x0]);
+        assert!(conn.read_significant_frame().await?.is_none());
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut).await???;
+        assert!(matches!(
+            outcome,
+            h2::ServeOutcome::TimedOut(Timeout::HeaderRead)
+        ));
+
+        // streams whose request body stalls are reset...
+        let (mut conn, serve_fut) = connect(UploadDriver);
+        conn.handshake(&[]).await?;
+        conn.send_headers(1, headers, false).await?;
+        conn.send_frame(frame_type::DATA, 0, 1, b"hello".to_vec())
+            .await?;
+        let frame = loop {
+            let frame = conn.read_significant_frame().await?.unwrap();
+            if frame.stream_id == 1 && frame.frame_type != frame_type::WINDOW_UPDATE {
+                break frame;
+            }
+        };
+        assert_eq!(frame.frame_type, frame_type::RST_STREAM);
+        // CANCEL
+        assert_eq!(frame.payload, [0x0, 0x0, 0x0, 0x8]);
+
+        // ...and once there are none left, the connection is closed
+        let frame = loop {
+            let frame = conn.read_significant_frame().await?.unwrap();
+            if frame.frame_type == frame_type::GOAWAY {
+                break frame;
+            }
+        };
+        // last stream id 1, NO_ERROR
+        assert_eq!(frame.payload[..8], [0x0, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0x0]);
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut).await???;
+        assert!(matches!(
+            outcome,
+            h2::ServeOutcome::TimedOut(Timeout::KeepAliveIdle)
+        ));
+
+        // the idle time counts from when the last stream closed, even if it
+        // opened while we were already waiting
+        let (mut conn, serve_fut) = connect(UploadDriver);
+        conn.handshake(&[]).await?;
+        conn.send_headers(1, headers, true).await?;
+        loop {
+            let frame = conn.read_significant_frame().await?.unwrap();
+            if frame.frame_type == frame_type::DATA && frame.flags & flags::END_STREAM != 0 {
+                break;
+            }
+        }
+        let closed_at = std::time::Instant::now();
+        let frame = conn.read_significant_frame().await?.unwrap();
+        assert_eq!(frame.frame_type, frame_type::GOAWAY);
+        let idle = closed_at.elapsed();
+        assert!(idle >= Duration::from_millis(150), "idle for {idle:?}");
+        assert!(idle < Duration::from_millis(300), "idle for {idle:?}");
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut).await???;
+        assert!(matches!(
+            outcome,
+            h2::ServeOutcome::TimedOut(Timeout::KeepAliveIdle)
+        ));
+
+        // slow handlers are cancelled
+        let (mut conn, _serve_fut) = connect(SlowDriver);
+        conn.handshake(&[]).await?;
+        conn.send_headers(1, headers, true).await?;
+        let frame = loop {
+            let frame = conn.read_significant_frame().await?.unwrap();
+            if frame.stream_id == 1 && frame.frame_type == frame_type::HEADERS {
+                break frame;
+            }
+        };
+        let res_headers = hpack_dec.decode(&frame.payload).unwrap();
+        assert_eq!(res_headers[0], (b":status".to_vec(), b"500".to_vec()));
+
+        Ok(())
+    })
+}
@@ -4,11 +4,10 @@
 #![feature(async_fn_in_trait)]
 
 use hring::{
-    h1, Body, BodyChunk, Encoder, ExpectResponseHeaders, HeadersExt, Responder, Response,
-    ResponseDone, ServerDriver,
+    h1, Body, BodyChunk, Encoder, ExpectResponseHeaders, Responder, Response, ResponseDone,
+    ServerDriver,
 };
 use hring_buffet::RollMut;
-use http::StatusCode;
 use std::{cell::RefCell, future::Future, net::SocketAddr, rc::Rc};
 use tracing::debug;
 
@@ -24,17 +23,8 @@ impl ServerDriver for ProxyDriver {
         &self,
         req: hring::Request,
         req_body: &mut impl Body,
-        mut respond: Responder<E, ExpectResponseHeaders>,
+        respond: Responder<E, ExpectResponseHeaders>,
     ) -> eyre::Result<Responder<E, ResponseDone>> {
-        if req.headers.expects_100_continue() {
-            debug!("Sending 100-continue");
-            let res = Response {
-                status: StatusCode::CONTINUE,
-                ..Default::default()
-            };
-            respond.write_interim_response(res).await?;
-        }
-
         let transport = {
             let mut pool = self.pool.borrow_mut();
             pool.pop()
@@ -50,7 +40,8 @@ impl ServerDriver for ProxyDriver {
 
         let driver = ProxyClientDriver { respond };
 
-        let (transport, res) = h1::request(transport, req, req_body, driver).await?;
+        let (transport, res) =
+            h1::request(transport, Default::default(), req, req_body, driver).await?;
 
         if let Some(transport) = transport {
             let mut pool = self.pool.borrow_mut();
@@ -75,8 +66,8 @@ where
     type Return = Responder<E, ResponseDone>;
 
     async fn on_informational_response(&mut self, res: Response) -> eyre::Result<()> {
-        debug!("Got informational response {}", res.status);
-        Ok(())
+        debug!("Got informational response {}, passing it on", res.status);
+        self.respond.write_interim_response(res).await
     }
 
     async fn on_final_response(
@@ -11,9 +11,12 @@ use hring::{
     ResponseDone, ServerDriver,
 };
 use tokio::process::Command;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
+mod report;
+use report::{CaseResult, Report};
+
 fn main() {
     color_eyre::install().unwrap();
     tracing_subscriber::fmt()
@@ -34,7 +37,10 @@ fn main() {
         }
     };
 
-    hring::tokio_uring::start(async move { real_main(h2spec_binary).await.unwrap() })
+    let success = hring::tokio_uring::start(async move { real_main(h2spec_binary).await.unwrap() });
+    if !success {
+        std::process::exit(1);
+    }
 }
 
 struct SDriver;
@@ -91,7 +97,9 @@ impl Body for TestBody {
     }
 }
 
-async fn real_main(h2spec_binary: PathBuf) -> color_eyre::Result<()> {
+/// Runs h2spec against an in-process server, returns whether all test cases
+/// passed.
+async fn real_main(h2spec_binary: PathBuf) -> color_eyre::Result<bool> {
     let addr: SocketAddr = "[::]:0".parse()?;
     let ln = TcpListener::bind(addr)?;
     let addr = ln.local_addr()?;
@@ -103,19 +111,58 @@ async fn real_main(h2spec_binary: PathBuf) -> color_eyre::Result<()> {
     if matches!(args.get(0).map(|s| s.as_str()), Some("--")) {
         args.pop_front();
     }
+
+    // `--report <path>` writes a JSON report of every test case, which is
+    // meant to be checked in and compared over time.
+    let mut report_path = None;
+    if let Some(i) = args.iter().position(|a| a == "--report") {
+        args.remove(i);
+        report_path = Some(PathBuf::from(args.remove(i).ok_or_else(|| {
+            color_eyre::eyre::eyre!("--report needs a path, e.g. --report h2spec-report.json")
+        })?));
+    }
     tracing::info!("Custom args: {args:?}");
 
-    Command::new(h2spec_binary)
+    let junit_path = std::env::temp_dir().join(format!("hring-h2spec-{}.xml", std::process::id()));
+
+    let status = Command::new(h2spec_binary)
         .arg("-p")
         .arg(&format!("{}", addr.port()))
         .arg("-o")
         .arg("1")
+        .arg("-j")
+        .arg(&junit_path)
         .args(&args)
         .spawn()?
         .wait()
         .await?;
 
-    Ok(())
+    let report = Report::from_junit(&std::fs::read_to_string(&junit_path)?)?;
+    std::fs::remove_file(&junit_path)?;
+
+    let failed = report.count(CaseResult::Failed);
+    info!(
+        "h2spec: {} passed, {failed} failed, {} skipped",
+        report.count(CaseResult::Passed),
+        report.count(CaseResult::Skipped),
+    );
+    for case in report
+        .cases
+        .iter()
+        .filter(|c| c.result == CaseResult::Failed)
+    {
+        warn!(
+            "FAILED {} ({}): {}",
+            case.id, case.section, case.description
+        );
+    }
+
+    if let Some(report_path) = report_path {
+        std::fs::write(&report_path, report.to_json())?;
+        info!("Wrote report to {}", report_path.display());
+    }
+
+    Ok(status.success() && failed == 0)
 }
 
 async fn run_server(ln: TcpListener) -> color_eyre::Result<()> {
@@ -126,8 +173,9 @@ async fn run_server(ln: TcpListener) -> color_eyre::Result<()> {
         let client_buf = RollMut::alloc()?;
         let driver = Rc::new(SDriver);
         tokio_uring::spawn(async move {
-            if let Err(e) = hring::h2::serve(stream, conf, client_buf, driver).await {
-                tracing::error!("error serving client {}: {}", addr, e);
+            match hring::h2::serve(stream, conf, client_buf, driver).await {
+                Ok(outcome) => tracing::info!(%addr, ?outcome, "done serving client"),
+                Err(e) => tracing::error!("error serving client {}: {}", addr, e),
             }
         });
     }
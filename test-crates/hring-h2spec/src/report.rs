@@ -0,0 +1,228 @@
+//! Turns h2spec's JUnit output into a JSON report that's stable across runs
+//! (no timings, no ports), so it can be checked in and diffed.
+
+use std::fmt::Write;
+
+use color_eyre::eyre::{self, eyre};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseResult {
+    Passed,
+    Failed,
+    Skipped,
+}
+
+impl CaseResult {
+    fn as_str(self) -> &'static str {
+        match self {
+            CaseResult::Passed => "passed",
+            CaseResult::Failed => "failed",
+            CaseResult::Skipped => "skipped",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Case {
+    /// e.g. `http2/6.5.3/1`, which is how h2spec lets you select a single
+    /// test case on the command line.
+    pub id: String,
+    /// e.g. `6.5.3. Settings Synchronization`
+    pub section: String,
+    pub description: String,
+    pub result: CaseResult,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub cases: Vec<Case>,
+}
+
+impl Report {
+    /// Parses the output of `h2spec -j <path>`
+    pub fn from_junit(xml: &str) -> eyre::Result<Self> {
+        let doc = roxmltree::Document::parse(xml)?;
+        let mut cases = Vec::new();
+
+        for suite in doc.descendants().filter(|n| n.has_tag_name("testsuite")) {
+            let package = suite
+                .attribute("package")
+                .ok_or_else(|| eyre!("testsuite without package"))?;
+            let section = suite.attribute("name").unwrap_or_default();
+
+            for (i, case) in suite
+                .children()
+                .filter(|n| n.has_tag_name("testcase"))
+                .enumerate()
+            {
+                let result = if case
+                    .children()
+                    .any(|n| n.has_tag_name("failure") || n.has_tag_name("error"))
+                {
+                    CaseResult::Failed
+                } else if case.children().any(|n| n.has_tag_name("skipped")) {
+                    CaseResult::Skipped
+                } else {
+                    CaseResult::Passed
+                };
+
+                cases.push(Case {
+                    id: format!("{package}/{}", i + 1),
+                    section: section.to_string(),
+                    description: case.attribute("classname").unwrap_or_default().to_string(),
+                    result,
+                });
+            }
+        }
+
+        Ok(Self { cases })
+    }
+
+    pub fn count(&self, result: CaseResult) -> usize {
+        self.cases.iter().filter(|c| c.result == result).count()
+    }
+
+    /// Serializes the report as JSON, one case per line, in h2spec order.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        out.push_str("{\n");
+        writeln!(
+            out,
+            "  \"summary\": {{ \"total\": {}, \"passed\": {}, \"failed\": {}, \"skipped\": {} }},",
+            self.cases.len(),
+            self.count(CaseResult::Passed),
+            self.count(CaseResult::Failed),
+            self.count(CaseResult::Skipped),
+        )
+        .unwrap();
+        out.push_str("  \"cases\": [\n");
+        for (i, case) in self.cases.iter().enumerate() {
+            let comma = if i + 1 == self.cases.len() { "" } else { "," };
+            writeln!(
+                out,
+                "    {{ \"id\": {}, \"section\": {}, \"description\": {}, \"result\": {} }}{comma}",
+                json_str(&case.id),
+                json_str(&case.section),
+                json_str(&case.description),
+                json_str(case.result.as_str()),
+            )
+            .unwrap();
+        }
+        out.push_str("  ]\n}\n");
+        out
+    }
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_str, CaseResult, Report};
+
+    const JUNIT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites>
+  <testsuite name="3.5. HTTP/2 Connection Preface" package="http2/3.5" id="3.5" tests="2" skipped="0" failures="1" errors="0">
+    <testcase package="http2/3.5" classname="Sends client connection preface" time="0.0010"></testcase>
+    <testcase package="http2/3.5" classname="Sends invalid connection preface" time="0.0020">
+      <failure message="connection was not closed">Expected: GOAWAY</failure>
+    </testcase>
+  </testsuite>
+  <testsuite name="5.1.1. Stream identifiers" package="http2/5.1.1" id="5.1.1" tests="1" skipped="1" failures="0" errors="0">
+    <testcase package="http2/5.1.1" classname="Sends even-numbered stream identifier" time="0.0000">
+      <skipped message="not applicable"></skipped>
+    </testcase>
+  </testsuite>
+</testsuites>"#;
+
+    #[test]
+    fn test_from_junit() {
+        let report = Report::from_junit(JUNIT).unwrap();
+        let cases: Vec<_> = report
+            .cases
+            .iter()
+            .map(|c| {
+                (
+                    c.id.as_str(),
+                    c.section.as_str(),
+                    c.description.as_str(),
+                    c.result,
+                )
+            })
+            .collect();
+        assert_eq!(
+            cases,
+            [
+                (
+                    "http2/3.5/1",
+                    "3.5. HTTP/2 Connection Preface",
+                    "Sends client connection preface",
+                    CaseResult::Passed
+                ),
+                (
+                    "http2/3.5/2",
+                    "3.5. HTTP/2 Connection Preface",
+                    "Sends invalid connection preface",
+                    CaseResult::Failed
+                ),
+                (
+                    "http2/5.1.1/1",
+                    "5.1.1. Stream identifiers",
+                    "Sends even-numbered stream identifier",
+                    CaseResult::Skipped
+                ),
+            ]
+        );
+
+        assert!(Report::from_junit("<testsuite name=\"no package\"/>").is_err());
+        assert!(Report::from_junit("not xml").is_err());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let report = Report::from_junit(JUNIT).unwrap();
+        assert_eq!(
+            report.to_json(),
+            r#"{
+  "summary": { "total": 3, "passed": 1, "failed": 1, "skipped": 1 },
+  "cases": [
+    { "id": "http2/3.5/1", "section": "3.5. HTTP/2 Connection Preface", "description": "Sends client connection preface", "result": "passed" },
+    { "id": "http2/3.5/2", "section": "3.5. HTTP/2 Connection Preface", "description": "Sends invalid connection preface", "result": "failed" },
+    { "id": "http2/5.1.1/1", "section": "5.1.1. Stream identifiers", "description": "Sends even-numbered stream identifier", "result": "skipped" }
+  ]
+}
+"#
+        );
+
+        let empty = Report { cases: vec![] };
+        assert_eq!(
+            empty.to_json(),
+            "{\n  \"summary\": { \"total\": 0, \"passed\": 0, \"failed\": 0, \"skipped\": 0 },\n  \"cases\": [\n  ]\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_json_str() {
+        assert_eq!(json_str("plain"), r#""plain""#);
+        assert_eq!(json_str(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(json_str(r"C:\path"), r#""C:\\path""#);
+        assert_eq!(json_str("a\nb\rc\td"), r#""a\nb\rc\td""#);
+        assert_eq!(json_str("\u{0}\u{1f}"), r#""\u0000\u001f""#);
+        assert_eq!(json_str("ünïcödé ✓"), "\"ünïcödé ✓\"");
+    }
+}